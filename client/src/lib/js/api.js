@@ -1,8 +1,49 @@
+import protocolSchema from '../protocol.schema.json';
+
+/** @typedef {import('./protocol').ClientMessageKind} ClientMessageKind */
+/** @typedef {import('./protocol').ServerMessageKind} ServerMessageKind */
+
+export const PROTOCOL_VERSION = protocolSchema.version;
+
+// Field names are escaped as in JSON pointers
+function parsePointer(path) {
+    return path.split('/').slice(1).map((key) => key.replaceAll('~1', '/').replaceAll('~0', '~'));
+}
+
+function lookup(state, keys) {
+    return keys.reduce((value, key) => value[key], state);
+}
+
+/** @param {import('./protocol').Op} op */
+function applyOp(state, op) {
+    if ('Set' in op) {
+        const keys = parsePointer(op.Set.path);
+        const field = keys.pop();
+        lookup(state, keys)[field] = op.Set.value;
+    } else if ('Insert' in op) {
+        lookup(state, parsePointer(op.Insert.path)).splice(op.Insert.index, 0, op.Insert.value);
+    } else if ('Remove' in op) {
+        lookup(state, parsePointer(op.Remove.path)).splice(op.Remove.index, 1);
+    } else if ('Move' in op) {
+        const nodes = lookup(state, parsePointer(op.Move.path));
+        const [node] = nodes.splice(op.Move.id, 1);
+        nodes.splice(op.Move.new_id, 0, node);
+    }
+}
+
 export class Api extends EventTarget {
-    constructor(host, port) {
+    constructor(host, port, name = null) {
         super();
         this.host = host;
         this.port = port;
+        this.name = name;
+        this.clientId = null;
+        /** @type {import('./protocol').ClientInfo[]} */
+        this.clients = [];
+        /** @type {[import('./protocol').Node, number][]} */
+        this.locks = [];
+        /** @type {import('./protocol').PreviewState | null} */
+        this.preview = null;
         this.connectedMidiInputs = [];
         this.availableMidiInputs = [];
         this.cache = {
@@ -10,6 +51,8 @@ export class Api extends EventTarget {
             control_nodes: [],
             controller: {},
         };
+        this.revision = 0;
+        this.catchingUp = false;
         this.connect();
     }
 
@@ -26,7 +69,11 @@ export class Api extends EventTarget {
     }
 
     connect() {
-        this.socket = new WebSocket(`ws://${this.host}:${this.port}/ws`);
+        let params = `protocol=${PROTOCOL_VERSION}`;
+        if (this.name) {
+            params += `&name=${encodeURIComponent(this.name)}`;
+        }
+        this.socket = new WebSocket(`ws://${this.host}:${this.port}/ws?${params}`);
         this.idCounter = 0;
         this.requestCallbacks = {};
 
@@ -59,6 +106,10 @@ export class Api extends EventTarget {
         this.socket.close();
     }
 
+    /**
+     * @param {ClientMessageKind} msg
+     * @param {boolean} request
+     */
     send(msg, request = false) {
         this.socket.send(JSON.stringify({
             id: ++this.idCounter,
@@ -68,6 +119,11 @@ export class Api extends EventTarget {
         return this.idCounter;
     }
 
+    /**
+     * @param {ClientMessageKind} msg
+     * @param {number} timeout
+     * @returns {Promise<ServerMessageKind>}
+     */
     async request(msg, timeout = 1000) {
         const id = this.send(msg, true);
         return new Promise((resolve, reject) => {
@@ -88,6 +144,47 @@ export class Api extends EventTarget {
         })
     }
 
+    /** @param {import('./protocol').Topic[]} topics */
+    async subscribe(topics) {
+        return await this.request({
+            'Subscribe': topics
+        })
+    }
+
+    async setName(name) {
+        this.name = name;
+        return await this.request({
+            'SetName': name
+        })
+    }
+
+    /** @param {import('./protocol').Node} node e.g. { RenderNode: 0 } */
+    async lockNode(node) {
+        return await this.request({
+            'Lock': node
+        })
+    }
+
+    /** @param {import('./protocol').Node} node */
+    async unlockNode(node) {
+        return await this.request({
+            'Unlock': node
+        })
+    }
+
+    /**
+     * Client holding the lock of the node, or null
+     * @param {import('./protocol').Node} node
+     * @returns {import('./protocol').ClientInfo | null}
+     */
+    lockHolder(node) {
+        const lock = this.locks.find(([locked]) => JSON.stringify(locked) === JSON.stringify(node));
+        if (!lock) {
+            return null;
+        }
+        return this.clients.find((client) => client.id === lock[1]) ?? null;
+    }
+
     async rendererRequest(req, timeout) {
         return await this.request({
             'RendererRequest': req
@@ -100,6 +197,40 @@ export class Api extends EventTarget {
         });
     }
 
+    /**
+     * Plays the preset with a voice outside of the node list, resolves once the file is loaded
+     * @param {string} file
+     * @param {number} bank
+     * @param {number} preset
+     * @param {boolean} followKeyboard plays the MIDI input instead of the phrase
+     */
+    async startPreview(file, bank, preset, followKeyboard = false) {
+        return await this.rendererRequest({
+            'StartPreview': { file, bank, preset, follow_keyboard: followKeyboard }
+        }, 30000);
+    }
+
+    async replayPreview() {
+        return await this.rendererRequest('ReplayPreview');
+    }
+
+    async stopPreview() {
+        return await this.rendererRequest('StopPreview');
+    }
+
+    async setPreviewGain(gain) {
+        return await this.rendererRequest({
+            'SetPreviewGain': gain
+        });
+    }
+
+    /** @param {import('./protocol').PhraseNote[]} phrase */
+    async setPreviewPhrase(phrase) {
+        return await this.rendererRequest({
+            'SetPreviewPhrase': phrase
+        });
+    }
+
     async addRenderNode(kind) {
         return await this.rendererRequest({
             'AddNode': { kind }
@@ -314,6 +445,18 @@ export class Api extends EventTarget {
         });
     }
 
+    async saveSession(path) {
+        return await this.controllerRequest({
+            'SaveSession': path
+        });
+    }
+
+    async loadSession(path) {
+        return await this.controllerRequest({
+            'LoadSession': path
+        });
+    }
+
     async addControlNode(kind) {
         return await this.controllerRequest({
             'AddNode': { kind }
@@ -418,27 +561,106 @@ export class Api extends EventTarget {
         });
     }
 
-    async readDir(path) {
+    /**
+     * Entries with their size and modification time, null if the path is invalid
+     * @returns {Promise<import('./protocol').DirEntry[] | null>}
+     */
+    async listDir(path) {
         const res = (await this.request({
             'ReadDir': path
         })).DirInfo;
 
         if(!Array.isArray(res))
             return null;
+        return res;
+    }
+
+    async readDir(path) {
+        const entries = await this.listDir(path);
+        if(entries === null)
+            return null;
 
         const dirs = [];
         const files = [];
 
-        for(const rec of res) {
-            if(rec[0])
-                dirs.push(rec[1]);
+        for(const entry of entries) {
+            if(entry.is_dir)
+                dirs.push(entry.path);
             else
-                files.push(rec[1]);
+                files.push(entry.path);
         }
 
         return [dirs.sort(), files.sort()]
     }
 
+    async copyFile(path, newPath) {
+        return await this.request({
+            'CopyFile': [path, newPath]
+        })
+    }
+
+    downloadUrl(path) {
+        return `http://${this.host}:${this.port}/api/files/download?path=${encodeURIComponent(path)}`;
+    }
+
+    /**
+     * Uploads the file in chunks, each one is appended at the size the server
+     * reported for the previous one. The file is replaced once all of it arrived.
+     * @param {string} path
+     * @param {Blob} file
+     * @param {(uploaded: number, total: number) => void} onProgress
+     */
+    async uploadFile(path, file, onProgress = () => {}) {
+        const chunkSize = 1024 * 1024;
+        let offset = 0;
+        do {
+            const chunk = file.slice(offset, offset + chunkSize);
+            const url = `http://${this.host}:${this.port}/api/files/upload`
+                + `?path=${encodeURIComponent(path)}&offset=${offset}&size=${file.size}`;
+            const res = await fetch(url, {
+                method: 'PUT',
+                body: chunk,
+                credentials: 'include',
+            });
+            if (!res.ok) {
+                throw new Error((await res.json().catch(() => null))?.message ?? res.statusText);
+            }
+            offset = (await res.json()).size;
+            onProgress(offset, file.size);
+        } while (offset < file.size);
+    }
+
+    async libraryRequest(req, timeout) {
+        return (await this.request({
+            'LibraryRequest': req
+        }, timeout)).LibraryResponse;
+    }
+
+    /**
+     * Presets of the indexed SoundFont and SFZ files matching every word of the query
+     * @param {string} query
+     * @param {{category?: string, kind?: import('./protocol').FileKind, limit?: number}} filter
+     * @returns {Promise<import('./protocol').SearchResult[] | null>}
+     */
+    async searchLibrary(query, { category = null, kind = null, limit = null } = {}) {
+        const res = await this.libraryRequest({
+            'Search': { query, category, kind, limit }
+        });
+        return res?.Results ?? null;
+    }
+
+    /** @returns {Promise<import('./protocol').LibraryFile | null>} */
+    async getLibraryFile(path) {
+        const res = await this.libraryRequest({
+            'GetFile': path
+        });
+        return res?.File ?? null;
+    }
+
+    async rescanLibrary() {
+        return await this.libraryRequest('Rescan');
+    }
+
     _addRequest(id, resolve, reject) {
         this.requestCallbacks[id] = {
             resolve,
@@ -465,8 +687,23 @@ export class Api extends EventTarget {
         }, timeout)
     }
 
+    /** @param {ServerMessageKind} msg */
     _onBroadcast(msg) {
-        if ('MidiEvent' in msg) {
+        if (typeof msg !== 'object') {
+            return;
+        }
+        if ('Hello' in msg) {
+            this.clientId = msg.Hello.client_id;
+            if (msg.Hello.protocol_version !== PROTOCOL_VERSION) {
+                this.dispatchEvent(new CustomEvent('incompatible', {
+                    detail: msg.Hello.protocol_version
+                }));
+            }
+        } else if ('Error' in msg) {
+            this.dispatchEvent(new CustomEvent('server-error', {
+                detail: msg.Error
+            }));
+        } else if ('MidiEvent' in msg) {
             this.dispatchEvent(new CustomEvent('midi', {
                 detail: msg.MidiEvent
             }));
@@ -480,127 +717,93 @@ export class Api extends EventTarget {
             this.dispatchEvent(new CustomEvent('connected-midi-inputs', {
                 detail: this.connectedMidiInputs
             }));
+        } else if ('Presence' in msg) {
+            this.clients = msg.Presence.clients;
+            this.locks = msg.Presence.locks;
+            this.dispatchEvent(new CustomEvent('presence', {
+                detail: msg.Presence
+            }));
         } else if ('Cache' in msg) {
-            this._onCacheReceived(msg.Cache);
-        } else if ('RendererUpdate' in msg) {
-            this._onRendererUpdate(msg.RendererUpdate);
+            this._onCacheReceived(msg.Cache.state, msg.Cache.revision);
+        } else if ('Delta' in msg) {
+            this._onDelta(msg.Delta);
+        } else if ('RendererUpdate' in msg && 'Preview' in msg.RendererUpdate) {
+            this.preview = msg.RendererUpdate.Preview;
+            this.dispatchEvent(new CustomEvent('preview', {
+                detail: this.preview
+            }));
         } else if ('ControllerUpdate' in msg) {
             this._onControllerUpdate(msg.ControllerUpdate);
+        } else if ('LibraryUpdate' in msg) {
+            this.dispatchEvent(new CustomEvent('library-update', {
+                detail: msg.LibraryUpdate
+            }));
         } else if ('DrumMachineUpdates' in msg) {
             this._onDrumMachineUpdates(msg.DrumMachineUpdates);
         }
     }
 
-    _onCacheReceived(cache) {
+    _onCacheReceived(cache, revision) {
         this.cache = cache;
-        this.dispatchEvent(new CustomEvent('cache-update', {
-            detail: cache
-        }));
-    }
-
-    _onRendererUpdate(update) {
-        if('NodeUpdates' in update) {
-            const nodeId = update.NodeUpdates.id;
-            const updates = update.NodeUpdates.updates;
-            this._onRenderNodeUpdates(nodeId, updates);
-        } else if('AddNode' in update) {
-            this._onAddRenderNode(update.AddNode);
-        } else if('RemoveNode' in update) {
-            this._onRemoveRenderNode(update.RemoveNode.id);
-        } else if('CloneNode' in update) {
-            this._onCloneRenderNode(update.CloneNode.id);
-        }
-
-        this.dispatchEvent(new CustomEvent('cache-update', {
-            detail: this.cache
-        }));
+        this.revision = revision;
+        this._dispatchCacheUpdate();
     }
 
-    _onRenderNodeUpdates(id, updates) {
-        const instance = this.cache.render_nodes[id].instance;
-        for(const [key, value] of updates) {
-            instance[key] = value;
+    _onDelta(delta) {
+        if (delta.revision <= this.revision) {
+            // Already part of the last snapshot
+            return;
         }
-    }
-
-    _onAddRenderNode(node) {
-        this.cache.render_nodes.push(node);
-    }
-
-    _onRemoveRenderNode(id) {
-        this.cache.render_nodes.splice(id, 1);
-    }
-
-    _onCloneRenderNode(id) {
-        const node = JSON.parse(JSON.stringify(this.cache.render_nodes[id]));
-        this.cache.render_nodes.push(node);
-    }
-
-    _onControllerUpdate(update) {
-        if('Enabled' in update) {
-            this._onControllerEnabledChange(update.Enabled);
-        } else if('TempoBpm' in update) {
-            this._onControllerTempoBpmChange(update.TempoBpm);
-        } else if('Rhythm' in update) {
-            this._onControllerRhythmChange(update.Rhythm);
-        } else if('BeatState' in update) {
-            this._onControllerBeatStateChange(update.BeatState);
-        } else if('NodeUpdates' in update) {
-            const nodeId = update.NodeUpdates.id;
-            const updates = update.NodeUpdates.updates;
-            this._onControlNodeUpdates(nodeId, updates);
-        } else if('AddNode' in update) {
-            this._onAddControlNode(update.AddNode);
-        } else if('RemoveNode' in update) {
-            this._onRemoveControlNode(update.RemoveNode.id);
-        } else if('CloneNode' in update) {
-            this._onCloneControlNode(update.CloneNode.id);
+        if (delta.revision !== this.revision + 1) {
+            this._catchUp();
+            return;
         }
-    }
-
-    _onControllerEnabledChange(enabled) {
-        this.cache.controller.enabled = enabled;
+        this._applyDelta(delta);
         this._dispatchCacheUpdate();
     }
 
-    _onControllerTempoBpmChange(tempoBpm) {
-        this.cache.controller.tempo_bpm = tempoBpm;
-        this._dispatchCacheUpdate();
-    }
-
-    _onControllerRhythmChange(rhythm) {
-        this.cache.controller.rhythm = rhythm;
-        this._dispatchCacheUpdate();
-    }
-
-    _onControllerBeatStateChange(beatState) {
-        this.dispatchEvent(new CustomEvent('beat-state', {
-            detail: beatState
-        }));
-    }
-
-    _onControlNodeUpdates(id, updates) {
-        const instance = this.cache.control_nodes[id].instance;
-        for(const [key, value] of updates) {
-            instance[key] = value;
+    async _catchUp() {
+        if (this.catchingUp) {
+            return;
+        }
+        this.catchingUp = true;
+        try {
+            const res = await this.request({
+                'GetChanges': { since: this.revision }
+            });
+            if ('Changes' in res) {
+                for (const delta of res.Changes) {
+                    if (delta.revision === this.revision + 1) {
+                        this._applyDelta(delta);
+                    }
+                }
+                this._dispatchCacheUpdate();
+            } else if ('Cache' in res) {
+                this._onCacheReceived(res.Cache.state, res.Cache.revision);
+            }
+        } finally {
+            this.catchingUp = false;
         }
-        this._dispatchCacheUpdate();
-    }
-
-    _onAddControlNode(node) {
-        this.cache.control_nodes.push(node);
-        this._dispatchCacheUpdate();
     }
 
-    _onRemoveControlNode(id) {
-        this.cache.control_nodes.splice(id, 1);
-        this._dispatchCacheUpdate();
+    _applyDelta(delta) {
+        for (const op of delta.ops) {
+            applyOp(this.cache, op);
+        }
+        this.revision = delta.revision;
+        if (delta.author != null && delta.author !== this.clientId) {
+            this.dispatchEvent(new CustomEvent('remote-change', {
+                detail: delta
+            }));
+        }
     }
 
-    _onCloneControlNode(id) {
-        const node = JSON.parse(JSON.stringify(this.cache.control_nodes[id]));
-        this.cache.control_nodes.push(node);
-        this._dispatchCacheUpdate();
+    _onControllerUpdate(update) {
+        if('BeatState' in update) {
+            this.dispatchEvent(new CustomEvent('beat-state', {
+                detail: update.BeatState
+            }));
+        }
     }
 
     _dispatchCacheUpdate() {
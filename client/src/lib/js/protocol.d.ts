@@ -0,0 +1,406 @@
+// Generated from the Rust protocol types, do not edit.
+// Regenerate with: AMI_UPDATE_PROTOCOL=1 cargo test protocol
+
+export declare const PROTOCOL_VERSION = 8;
+
+export type AutomationRequestKind =
+    | "Clear"
+    | { AddLane: AutomationTarget }
+    | { RemoveLane: number }
+    | { SetLaneEnabled: [number, boolean] }
+    | { SetInterpolation: [number, Interpolation] }
+    | { SetBreakpoints: [number, Breakpoint[]] };
+
+export type AutomationTarget =
+    | "TempoBpm"
+    | { RenderNode: { id: string; uid: number } }
+    | { ControlNode: { id: string; uid: number } };
+
+export type Breakpoint = { bar: number; beat: number; value: Value };
+
+export type CachedNode = { instance: unknown; kind: string; uid: number };
+
+export type ClientInfo = { device: DeviceKind; id: number; name: string; role: Role };
+
+export type ClientMessage = { id: number; payload: ClientMessageKind; request: boolean };
+
+export type ClientMessageKind =
+    | "Ping"
+    | { Report: string }
+    | { ConnectMidiInput: [number, string] }
+    | { DisconnectMidiInput: number }
+    | { RendererRequest: RendererRequestKind }
+    | { ControllerRequest: ControllerRequestKind }
+    | { SceneRequest: SceneRequestKind }
+    | { SetlistRequest: SetlistRequestKind }
+    | { MappingRequest: MappingRequestKind }
+    | { LibraryRequest: LibraryRequestKind }
+    | { Subscribe: Topic[] }
+    | { GetChanges: { since: number } }
+    | { SetName: string }
+    | { Lock: Node }
+    | { Unlock: Node }
+    | { ReadDir: string }
+    | { MakeDir: string }
+    | { DeleteFile: string }
+    | { RenameFile: [string, string] }
+    | { CopyFile: [string, string] };
+
+export type ControlChangeKind = "BankSelectMsb" | "ModulationWheelMsb" | "BreathControllerMsb" | "Undefined0Msb" | "FootControllerMsb" | "PortamentoTimeMsb" | "DataEntryMsb" | "ChannelVolumeMsb" | "BalanceMsb" | "Undefined1Msb" | "PanMsb" | "ExpressionControllerMsb" | "EffectControl1Msb" | "EffectControl2Msb" | "Undefined2Msb" | "Undefined3Msb" | "GeneralPurposeController1Msb" | "GeneralPurposeController2Msb" | "GeneralPurposeController3Msb" | "GeneralPurposeController4Msb" | "Undefined4Msb" | "Undefined5Msb" | "Undefined6Msb" | "Undefined7Msb" | "Undefined8Msb" | "Undefined9Msb" | "Undefined10Msb" | "Undefined11Msb" | "Undefined12Msb" | "Undefined13Msb" | "Undefined14Msb" | "Undefined15Msb" | "BankSelectLsb" | "ModulationWheelLsb" | "BreathControllerLsb" | "Undefined0Lsb" | "FootControllerLsb" | "PortamentoTimeLsb" | "DataEntryLsb" | "ChannelVolumeLsb" | "BalanceLsb" | "Undefined1Lsb" | "PanLsb" | "ExpressionControllerLsb" | "EffectControl1Lsb" | "EffectControl2Lsb" | "Undefined2Lsb" | "Undefined3Lsb" | "GeneralPurposeController1Lsb" | "GeneralPurposeController2Lsb" | "GeneralPurposeController3Lsb" | "GeneralPurposeController4Lsb" | "Undefined4Lsb" | "Undefined5Lsb" | "Undefined6Lsb" | "Undefined7Lsb" | "Undefined8Lsb" | "Undefined9Lsb" | "Undefined10Lsb" | "Undefined11Lsb" | "Undefined12Lsb" | "Undefined13Lsb" | "Undefined14Lsb" | "Undefined15Lsb" | "DamperPedal" | "Portamento" | "Sostenuto" | "SoftPedal" | "LegatoFootswitch" | "Hold2" | "SoundController1" | "SoundController2" | "SoundController3" | "SoundController4" | "SoundController5" | "SoundController6" | "SoundController7" | "SoundController8" | "SoundController9" | "SoundController10" | "GeneralPurposeController5" | "GeneralPurposeController6" | "GeneralPurposeController7" | "GeneralPurposeController8" | "PortamentoControl" | "Undefined16" | "Undefined17" | "Undefined18" | "HighResolutionVelocityPrefix" | "Undefined19" | "Undefined20" | "Effects1Depth" | "Effects2Depth" | "Effects3Depth" | "Effects4Depth" | "Effects5Depth" | "DataIncrement" | "DataDecrement" | "NonRegisteredParameterNumberLsb" | "NonRegisteredParameterNumberMsb" | "RegisteredParameterNumberLsb" | "RegisteredParameterNumberMsb" | "Undefined21" | "Undefined22" | "Undefined23" | "Undefined24" | "Undefined25" | "Undefined26" | "Undefined27" | "Undefined28" | "Undefined29" | "Undefined30" | "Undefined31" | "Undefined32" | "Undefined33" | "Undefined34" | "Undefined35" | "Undefined36" | "Undefined37" | "Undefined38" | "AllSoundsOff" | "ResetAllControllers" | "LocalControl" | "AllNotesOff" | "OmniModeOff" | "OmniModeOn" | "MonoModeOn" | "PolyModeOn";
+
+export type ControlNodeRequestKind =
+    | "AddVoice" | "ClearVoices" | "StartRecording" | "StartOverdub" | "StartPlayback" | "Stop" | "UndoLayer" | "ClearTake" | "LearnChord"
+    | { SetName: string }
+    | { SetEnabled: boolean }
+    | { LoadPreset: string }
+    | { SavePreset: string }
+    | { SetUserPresetEnabled: [number, boolean] }
+    | { RemoveVoice: number }
+    | { SetVoiceName: [number, string] }
+    | { SetVoiceInstrument: [number, number | null] }
+    | { SetVoiceNote: [number, number] }
+    | { SetVoiceVelocity: [number, number] }
+    | { SetVoiceChannel: [number, number] }
+    | { SetSlot: [number, number, boolean] }
+    | { SetInstrument: number | null }
+    | { SetNumBars: number }
+    | { ExportMidiFile: string }
+    | { SetPattern: Pattern }
+    | { SetNumOctaves: number }
+    | { SetGate: number }
+    | { SetLatch: boolean }
+    | { SetTargets: number[] }
+    | { SetHarmonyMode: HarmonizerMode }
+    | { SetChord: number[] }
+    | { SetKey: number }
+    | { SetScale: Scale }
+    | { SetDegrees: number[] }
+    | { SetVoicing: Voicing }
+    | { SetInversion: number }
+    | { SetVoiceVelocityScale: [number, number] };
+
+export type ControlNodeResponseKind =
+    | "InvalidId" | "Denied" | "Ok"
+    | { Failed: { reason: Error } };
+
+export type ControllerRequestKind =
+    | "Reset"
+    | { SetEnabled: boolean }
+    | { SetTempoBpm: number }
+    | { SetRhythm: Rhythm }
+    | { SetUserPreset: number }
+    | { NodeRequest: { id: number; kind: ControlNodeRequestKind } }
+    | { AddNode: { kind: string } }
+    | { RemoveNode: { id: number } }
+    | { CloneNode: { id: number } }
+    | { MoveNode: { id: number; new_id: number } }
+    | { ApplyScene: Scene }
+    | { SaveSession: string }
+    | { LoadSession: string }
+    | { ListParameters: number }
+    | { GetParameter: [number, string] }
+    | { SetParameter: [number, string, Value] }
+    | { Automation: AutomationRequestKind };
+
+export type ControllerResponseKind =
+    | "InvalidNodeKind" | "InvalidId" | "Denied" | "Ok" | "InvalidParameter" | "InvalidValue"
+    | { Failed: { reason: Error } }
+    | { NodeResponse: { id: number; kind: ControlNodeResponseKind } }
+    | { Parameters: Parameter[] }
+    | { ParameterValue: Value };
+
+export type ControllerUpdateKind =
+    | { BeatState: { beat: number; div: number } };
+
+export type Curve = "Linear" | "Exponential" | "Logarithmic";
+
+export type Delta = { author?: number | null; ops: Op[]; revision: number };
+
+export type DeviceKind = "Phone" | "Tablet" | "Desktop" | "Unknown";
+
+export type DirEntry = { is_dir: boolean; modified?: number | null; path: string; size: number };
+
+export type Error = { code: ErrorCode; message: string };
+
+export type ErrorCode = "InvalidValue" | "InvalidPath" | "NotFound" | "Io" | "Serialization" | "Deserialization" | "NotLoaded" | "LoadFailed" | "MidiConnect" | "InvalidSlot" | "Unavailable" | "Unsupported" | "InvalidState" | "Locked";
+
+export type FileKind = "Sf2" | "Sf3" | "Sfz";
+
+export type HarmonizerMode = "ChordMemory" | "Diatonic";
+
+export type Interpolation = "Linear" | "Step";
+
+export type LibraryFile = { kind: FileKind; modified?: number | null; name: string; path: string; presets: LibraryPreset[]; size: number; tags: string[] };
+
+export type LibraryPreset = { bank: number; category?: string | null; key_range?: [number, number] | null; name: string; program: number };
+
+export type LibraryRequestKind =
+    | "Rescan"
+    | { Search: { category?: string | null; kind?: FileKind | null; limit?: number | null; query: string } }
+    | { GetFile: string };
+
+export type LibraryResponseKind =
+    | "Ok"
+    | { Results: SearchResult[] }
+    | { File: LibraryFile }
+    | { Failed: { reason: Error } };
+
+export type LibraryUpdateKind =
+    | "Scanning"
+    | { Indexed: { files: number; presets: number } };
+
+export type Mapping = { curve: Curve; max: number; min: number; mode: MappingMode; parameter: MappingParameter; source: Source };
+
+export type MappingMode = "Absolute" | "Toggle" | "Momentary";
+
+export type MappingParameter =
+    | "ControllerEnabled" | "ControllerTempoBpm" | "SceneRecall"
+    | { RenderNode: { id: string; uid: number } }
+    | { ControlNode: { id: string; uid: number } };
+
+export type MappingRequestKind =
+    | "CancelLearn" | "ClearMappings"
+    | { Learn: MappingParameter }
+    | { AddMapping: Mapping }
+    | { SetMapping: [number, Mapping] }
+    | { RemoveMapping: number }
+    | { Load: string }
+    | { Save: string };
+
+export type MappingResponseKind =
+    | "InvalidId" | "Ok"
+    | { Failed: { reason: Error } };
+
+export type MappingUpdateKind =
+    | { Mappings: Mapping[] }
+    | { Learning: MappingParameter | null };
+
+export type Message = { channel: number; kind: MessageKind };
+
+export type MessageKind =
+    | { NoteOff: { note: number; velocity: number } }
+    | { NoteOn: { note: number; velocity: number } }
+    | { PolyphonicAftertouch: { note: number; pressure: number } }
+    | { ControlChange: { kind: ControlChangeKind; value: number } }
+    | { ProgramChange: { program: number } }
+    | { ChannelAftertouch: { pressure: number } }
+    | { PitchWheel: { value: number } };
+
+export type MidiFilterUpdateKind =
+    | { Enabled: boolean }
+    | { Channel: [number, boolean] }
+    | { Channels: boolean[] }
+    | { Note: [number, boolean] }
+    | { Notes: boolean[] }
+    | { ControlChange: [number, boolean] }
+    | { ControlChanges: boolean[] }
+    | { ProgramChange: boolean }
+    | { ChannelAftertouch: boolean }
+    | { PitchWheel: boolean };
+
+export type Node =
+    | { RenderNode: number }
+    | { ControlNode: number };
+
+export type Op =
+    | { Set: { path: string; value: unknown } }
+    | { Insert: { index: number; path: string; value: unknown } }
+    | { Remove: { index: number; path: string } }
+    | { Move: { id: number; new_id: number; path: string } };
+
+export type Parameter = { default: Value; id: string; kind: ParameterKind; unit: Unit };
+
+export type ParameterKind =
+    | "Bool"
+    | { Int: { max: number; min: number } }
+    | { Float: { max: number; min: number } };
+
+export type Pattern = "Up" | "Down" | "UpDown" | "Random" | "AsPlayed" | "Chord";
+
+export type PhraseNote = { duration: number; note: number; start: number; velocity: number };
+
+export type PreviewParams = { bank: number; file: string; follow_keyboard: boolean; preset: number };
+
+export type PreviewState = { gain: number; loaded: boolean; params: PreviewParams };
+
+export type RenderNodeRequestKind =
+    | "AddDrumMachineVoice" | "ClearDrumMachineVoices"
+    | { SetName: string }
+    | { SetEnabled: boolean }
+    | { LoadFile: string }
+    | { PreloadFile: string }
+    | { SetGain: number }
+    | { SetPan: number }
+    | { SetOutput: number }
+    | { SetTransposition: number }
+    | { SetVelocityMapping: VelocityMapKind }
+    | { SetIgnoreGlobalTransposition: boolean }
+    | { SetBankAndPreset: [number, number] }
+    | { MidiMessage: MessageKind }
+    | { SetSfReverbActive: boolean }
+    | { SetSfReverbParams: { damping: number; level: number; room_size: number; width: number } }
+    | { RemoveDrumMachineVoice: number }
+    | { SetDrumMachineVoiceInstrument: [number, number | null] }
+    | { SetDrumMachineVoiceNote: [number, number] }
+    | { SetDrumMachineSlot: [number, number, number] }
+    | { UpdateMidiFilter: MidiFilterUpdateKind }
+    | { UpdateZones: ZoneUpdateKind }
+    | { SetUserPresetEnabled: [number, boolean] };
+
+export type RenderNodeResponseKind =
+    | "InvalidId" | "Denied" | "Ok"
+    | { Failed: { reason: Error } };
+
+export type RendererRequestKind =
+    | "ReplayPreview" | "StopPreview"
+    | { SetUserPreset: number }
+    | { NodeRequest: { id: number; kind: RenderNodeRequestKind } }
+    | { AddNode: { kind: string } }
+    | { RemoveNode: { id: number } }
+    | { CloneNode: { id: number } }
+    | { MoveNode: { id: number; new_id: number } }
+    | { SetGlobalTransposition: number }
+    | { SetMasterTuning: { a4_hz: number; fine_tune_cents: number } }
+    | { ApplyScene: [unknown, [number, unknown][]] }
+    | { LoadNodes: [unknown, CachedNode[]] }
+    | { ListParameters: number }
+    | { GetParameter: [number, string] }
+    | { SetParameter: [number, string, Value] }
+    | { StartPreview: PreviewParams }
+    | { SetPreviewGain: number }
+    | { SetPreviewPhrase: PhraseNote[] };
+
+export type RendererResponseKind =
+    | "InvalidNodeKind" | "InvalidId" | "Denied" | "Ok" | "InvalidParameter" | "InvalidValue"
+    | { Failed: { reason: Error } }
+    | { NodeResponse: { id: number; kind: RenderNodeResponseKind } }
+    | { Parameters: Parameter[] }
+    | { ParameterValue: Value };
+
+export type RendererUpdateKind =
+    | { Preview: PreviewState | null };
+
+export type Rhythm = { num_beats: number; num_divs: number };
+
+export type Role = "Performer" | "Admin";
+
+export type Scale = "Major" | "NaturalMinor" | "HarmonicMinor" | "MelodicMinor" | "Dorian" | "Phrygian" | "Lydian" | "Mixolydian" | "Locrian";
+
+export type Scene = { control_nodes: [number, unknown][]; controller: unknown; name: string; render_nodes: [number, unknown][]; renderer?: unknown };
+
+export type SceneRequestKind =
+    | { Load: string }
+    | { Save: string }
+    | { Capture: { name: string; scene: number } }
+    | { Recall: number }
+    | { Clear: number }
+    | { SetSceneSafe: { field: string; flag: boolean; target: SceneTarget } }
+    | { SetProgramChangeRecall: boolean }
+    | { SetFootswitch: number | null };
+
+export type SceneResponseKind =
+    | "InvalidId" | "Empty" | "Ok"
+    | { Failed: { reason: Error } };
+
+export type SceneTarget =
+    | "Controller"
+    | { RenderNode: number }
+    | { ControlNode: number };
+
+export type SceneUpdateKind =
+    | { Scenes: (Scene | null)[] }
+    | { Scene: { id: number; scene?: Scene | null } }
+    | { Current: number | null }
+    | { SceneSafe: [SceneTarget, string][] }
+    | { ProgramChangeRecall: boolean }
+    | { Footswitch: number | null };
+
+export type SearchResult = { kind: FileKind; path: string; preset: LibraryPreset };
+
+export type ServerMessage = { id: number; payload: ServerMessageKind; response: boolean };
+
+export type ServerMessageKind =
+    | "Pong" | "Ack" | "Denied"
+    | { Hello: { client_id: number; protocol_version: number } }
+    | { Error: Error }
+    | { Log: string }
+    | { MidiEvent: Message }
+    | { AvailableMidiInputs: string[] }
+    | { ConnectedMidiInputs: (string | null)[] }
+    | { Cache: { revision: number; state: unknown } }
+    | { Delta: Delta }
+    | { Changes: Delta[] }
+    | { Presence: { clients: ClientInfo[]; locks: [Node, number][] } }
+    | { RendererResponse: RendererResponseKind }
+    | { RendererUpdate: RendererUpdateKind }
+    | { ControllerResponse: ControllerResponseKind }
+    | { ControllerUpdate: ControllerUpdateKind }
+    | { SceneResponse: SceneResponseKind }
+    | { SceneUpdate: SceneUpdateKind }
+    | { SetlistResponse: SetlistResponseKind }
+    | { SetlistUpdate: SetlistUpdateKind }
+    | { MappingResponse: MappingResponseKind }
+    | { MappingUpdate: MappingUpdateKind }
+    | { LibraryResponse: LibraryResponseKind }
+    | { LibraryUpdate: LibraryUpdateKind }
+    | { DirInfo: DirEntry[] };
+
+export type SetlistRequestKind =
+    | "Next" | "Previous"
+    | { Load: string }
+    | { Save: string }
+    | { SetName: string }
+    | { AddSong: string }
+    | { UpdateSong: number }
+    | { RemoveSong: number }
+    | { MoveSong: { id: number; new_id: number } }
+    | { Jump: number }
+    | { SetProgramChangeSelect: boolean }
+    | { SetNextCc: number | null }
+    | { SetPreviousCc: number | null };
+
+export type SetlistResponseKind =
+    | "InvalidId" | "Ok"
+    | { Failed: { reason: Error } };
+
+export type SetlistUpdateKind =
+    | { Setlist: { name: string; songs: string[] } }
+    | { Current: number | null }
+    | { ProgramChangeSelect: boolean }
+    | { NextCc: number | null }
+    | { PreviousCc: number | null };
+
+export type Source =
+    | { ControlChange: { cc: number; channel?: number | null } }
+    | { Note: { channel?: number | null; note: number } }
+    | { ProgramChange: { channel?: number | null } };
+
+export type Topic = "Midi" | "MidiPorts" | "Beat" | "State" | "Presence" | "Log";
+
+export type Unit = "None" | "Semitones" | "Octaves" | "Bars";
+
+export type Value =
+    | { Bool: boolean }
+    | { Int: number }
+    | { Float: number };
+
+export type VelocityMapKind =
+    | "Identity" | "Exponential" | "Logarithmic" | "SCurve"
+    | { Linear: { max: number; min: number } }
+    | { Table: number[] }
+    | { Fixed: number };
+
+export type Voicing = "Close" | "Drop2" | "Spread";
+
+export type Zone = { crossfade: number; key_max: number; key_min: number; transposition: number; velocity_max: number; velocity_min: number };
+
+export type ZoneUpdateKind =
+    | "Clear"
+    | { Add: Zone }
+    | { Remove: number }
+    | { SetKeyRange: [number, number, number] }
+    | { SetVelocityRange: [number, number, number] }
+    | { SetTransposition: [number, number] }
+    | { SetCrossfade: [number, number] };
+
+export type Protocol = { client: ClientMessage; server: ServerMessage };
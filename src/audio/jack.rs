@@ -0,0 +1,231 @@
+use crate::{
+    midi,
+    render::{realtime::Core, MAX_CHANNELS},
+};
+use ::jack::{
+    AsyncClient, AudioOut, Client, ClientOptions, Control, MidiIn, MidiOut, Port, PortFlags,
+    ProcessHandler, ProcessScope, RawMidi,
+};
+use ringbuf::{
+    traits::{Consumer, Observer, Producer, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+use std::{fmt, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+
+// Messages kept for the process callback in each direction
+const MIDI_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    OpenClient(::jack::Error),
+    RegisterPort(::jack::Error),
+    Activate(::jack::Error),
+    ConnectPort(::jack::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OpenClient(e) => write!(f, "Failed to open JACK client: {e}"),
+            Error::RegisterPort(e) => write!(f, "Failed to register JACK port: {e}"),
+            Error::Activate(e) => write!(f, "Failed to activate JACK client: {e}"),
+            Error::ConnectPort(e) => write!(f, "Failed to connect JACK port: {e}"),
+        }
+    }
+}
+
+pub struct ClientParams<'a> {
+    pub client_name: &'a str,
+    pub num_channels: usize,
+    pub autoconnect: bool,
+}
+
+// Client with registered ports whose process callback is not running yet
+pub struct OpenClient {
+    client: Client,
+    audio_out: Vec<Port<AudioOut>>,
+    midi_in: Port<MidiIn>,
+    midi_out: Port<MidiOut>,
+    autoconnect: bool,
+}
+
+pub type ActiveClient = AsyncClient<(), Process>;
+
+pub struct ConnectedClient {
+    pub client: ActiveClient,
+    pub sample_rate: u32,
+    pub buffer_size: usize,
+    pub num_channels: usize,
+}
+
+// Renders in the process callback. MIDI goes through rings that tasks of
+// the runtime fill and drain, the callback never waits for a lock.
+pub struct Process {
+    core: Core,
+    audio_out: Vec<Port<AudioOut>>,
+    midi_in: Port<MidiIn>,
+    midi_out: Port<MidiOut>,
+    midi_in_tx: HeapProd<midi::Message>,
+    midi_out_rx: HeapCons<midi::Message>,
+}
+
+pub fn open(params: ClientParams) -> Result<OpenClient, Error> {
+    let (client, _) = Client::new(params.client_name, ClientOptions::NO_START_SERVER)
+        .map_err(Error::OpenClient)?;
+    let num_channels = params.num_channels.clamp(2, MAX_CHANNELS);
+    let audio_out = (0..num_channels)
+        .map(|n| client.register_port(&output_port_name(n, num_channels), AudioOut))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::RegisterPort)?;
+    let midi_in = client
+        .register_port("midi_in", MidiIn)
+        .map_err(Error::RegisterPort)?;
+    let midi_out = client
+        .register_port("midi_out", MidiOut)
+        .map_err(Error::RegisterPort)?;
+    Ok(OpenClient {
+        client,
+        audio_out,
+        midi_in,
+        midi_out,
+        autoconnect: params.autoconnect,
+    })
+}
+
+impl OpenClient {
+    pub fn sample_rate(&self) -> u32 {
+        self.client.sample_rate() as u32
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.audio_out.len()
+    }
+
+    // Starts the process callback, which renders `core` with as many channels as there are
+    // ports. Incoming MIDI is sent to `midi_tx` and everything received on `midi_rx` is
+    // written to the MIDI out port, so `midi_rx` must not carry the incoming messages back.
+    pub fn activate(
+        self,
+        core: Core,
+        midi_tx: midi::Sender,
+        midi_rx: midi::Receiver,
+    ) -> Result<ConnectedClient, Error> {
+        let sample_rate = self.sample_rate();
+        let buffer_size = self.client.buffer_size() as usize;
+        let num_channels = self.audio_out.len();
+        let port_names: Vec<String> = self
+            .audio_out
+            .iter()
+            .filter_map(|port| port.name().ok())
+            .collect();
+        let (midi_in_tx, midi_in_rx) = HeapRb::new(MIDI_CAPACITY).split();
+        let (midi_out_tx, midi_out_rx) = HeapRb::new(MIDI_CAPACITY).split();
+        tokio::spawn(forward_midi_in(midi_in_rx, midi_tx));
+        tokio::spawn(forward_midi_out(midi_rx, midi_out_tx));
+        let process = Process {
+            core,
+            audio_out: self.audio_out,
+            midi_in: self.midi_in,
+            midi_out: self.midi_out,
+            midi_in_tx,
+            midi_out_rx,
+        };
+        let client = self
+            .client
+            .activate_async((), process)
+            .map_err(Error::Activate)?;
+        if self.autoconnect {
+            connect_to_playback(client.as_client(), &port_names)?;
+        }
+        Ok(ConnectedClient {
+            client,
+            sample_rate,
+            buffer_size,
+            num_channels,
+        })
+    }
+}
+
+impl Process {
+    fn send_midi(&mut self, ps: &ProcessScope) {
+        let mut writer = self.midi_out.writer(ps);
+        while let Some(msg) = self.midi_out_rx.try_pop() {
+            let (bytes, len) = msg.encode();
+            _ = writer.write(&RawMidi {
+                time: 0,
+                bytes: &bytes[..len],
+            });
+        }
+    }
+
+    fn receive_midi(&mut self, ps: &ProcessScope) {
+        for event in self.midi_in.iter(ps) {
+            if let Some(msg) = midi::Message::decode(event.bytes) {
+                // Dropped when the runtime falls behind
+                _ = self.midi_in_tx.try_push(msg);
+            }
+        }
+    }
+}
+
+// Both tasks end with the process callback
+async fn forward_midi_in(mut midi_in_rx: HeapCons<midi::Message>, midi_tx: midi::Sender) {
+    while midi_in_rx.write_is_held() {
+        while let Some(msg) = midi_in_rx.try_pop() {
+            if midi_tx.receiver_count() > 0 {
+                _ = midi_tx.send(msg);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+async fn forward_midi_out(mut midi_rx: midi::Receiver, mut midi_out_tx: HeapProd<midi::Message>) {
+    while midi_out_tx.read_is_held() {
+        match midi_rx.recv().await {
+            Ok(msg) => _ = midi_out_tx.try_push(msg),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+impl ProcessHandler for Process {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        self.send_midi(ps);
+        self.receive_midi(ps);
+
+        let num_channels = self.audio_out.len();
+        let mut outs: [&mut [f32]; MAX_CHANNELS] = Default::default();
+        for (out, port) in outs.iter_mut().zip(&mut self.audio_out) {
+            *out = port.as_mut_slice(ps);
+        }
+        self.core.fill(&mut outs[..num_channels]);
+        Control::Continue
+    }
+}
+
+fn output_port_name(index: usize, num_channels: usize) -> String {
+    match (num_channels, index) {
+        (2, 0) => "out_l".into(),
+        (2, 1) => "out_r".into(),
+        _ => format!("out_{}", index + 1),
+    }
+}
+
+fn connect_to_playback(client: &Client, port_names: &[String]) -> Result<(), Error> {
+    let playback = client.ports(
+        None,
+        Some(::jack::jack_sys::FLOAT_MONO_AUDIO),
+        PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL,
+    );
+    for (source, destination) in port_names.iter().zip(playback.iter()) {
+        client
+            .connect_ports_by_name(source, destination)
+            .map_err(Error::ConnectPort)?;
+    }
+    Ok(())
+}
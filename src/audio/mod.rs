@@ -1,3 +1,27 @@
-
 pub mod info;
-pub mod output;
\ No newline at end of file
+#[cfg(feature = "jack")]
+pub mod jack;
+pub mod output;
+
+use crate::render::realtime;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Backend {
+    Cpal,
+    Jack,
+}
+
+// Keeps the selected backend running until dropped
+pub enum Stream {
+    Cpal(cpal::Stream),
+    #[cfg(feature = "jack")]
+    Jack(jack::ActiveClient),
+}
+
+// Running backend and the sender to the render core it plays
+pub struct Output {
+    pub stream: Stream,
+    pub sample_rate: u32,
+    pub num_channels: usize,
+    pub rt_tx: realtime::Sender,
+}
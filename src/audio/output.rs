@@ -1,26 +1,20 @@
 use super::info;
+use crate::render::realtime::BLOCK_SIZE;
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     BufferSize, Device, FromSample, Host, SampleFormat, SampleRate, SizedSample, Stream,
     StreamConfig,
 };
-use ringbuf::traits::{Consumer, Observer, Split};
+use ringbuf::{
+    traits::{Consumer, Observer, Producer, Split},
+    HeapCons, HeapProd,
+};
 use std::{
-    sync::{atomic::AtomicUsize, Arc},
-    time::Duration,
+    sync::{Arc, OnceLock},
+    thread::{self, Thread},
 };
 use tracing::error;
 
-pub type BufferTx = ringbuf::wrap::caching::Caching<
-    Arc<ringbuf::SharedRb<ringbuf::storage::Heap<f32>>>,
-    true,
-    false,
->;
-pub type BufferRx = ringbuf::wrap::caching::Caching<
-    Arc<ringbuf::SharedRb<ringbuf::storage::Heap<f32>>>,
-    false,
-    true,
->;
 pub type OutputResult = Result<ConnectedOutput, Error>;
 
 #[derive(Debug)]
@@ -40,9 +34,7 @@ pub struct ConnectedOutput {
     pub sample_rate: u32,
     pub buffer_size: usize,
     pub num_channels: usize,
-    pub required_num_samples: Arc<AtomicUsize>,
-    pub lbuf_tx: BufferTx,
-    pub rbuf_tx: BufferTx,
+    pub buf_tx: BufferTx,
 }
 
 pub struct OutputDeviceParams<'a> {
@@ -63,9 +55,22 @@ struct StreamParams {
     sample_format: cpal::SampleFormat,
     device: Device,
     cfg: StreamConfig,
-    required_num_samples: Arc<AtomicUsize>,
-    lbuf_rx: BufferRx,
-    rbuf_rx: BufferRx,
+    buf_rx: BufferRx,
+}
+
+// Interleaved frames of all the device channels. Only whole frames are pushed
+// and popped, so the channels cannot get out of step.
+pub struct BufferTx {
+    tx: HeapProd<f32>,
+    num_channels: usize,
+    waiting: Arc<OnceLock<Thread>>,
+}
+
+pub struct BufferRx {
+    rx: HeapCons<f32>,
+    num_channels: usize,
+    // The thread filling the buffer, woken up whenever frames were taken
+    waiting: Arc<OnceLock<Thread>>,
 }
 
 pub fn connect_to_default_output_device(params: DefaultOutputDeviceParams) -> OutputResult {
@@ -78,7 +83,7 @@ pub fn connect_to_default_output_device(params: DefaultOutputDeviceParams) -> Ou
         device_name: &device_name,
         sample_rate: params.sample_rate,
         buffer_size: params.buffer_size,
-        num_channels: 2,
+        num_channels: params.num_channels,
     })
 }
 
@@ -87,24 +92,19 @@ pub fn connect_to_output_device(params: OutputDeviceParams) -> OutputResult {
     let device = find_output_device(host, params.device_name).ok_or(Error::DeviceNotFound)?;
     let sample_format = sample_format(&device)?;
     let cfg = create_stream_config(&params);
-    let ((lbuf_tx, lbuf_rx), (rbuf_tx, rbuf_rx)) = create_buffers(params.buffer_size);
-    let required_num_samples = Arc::new(AtomicUsize::new(0));
+    let (buf_tx, buf_rx) = create_buffer(params.buffer_size, params.num_channels);
     let stream = create_stream_dispatched(StreamParams {
         sample_format,
         device,
         cfg,
-        required_num_samples: Arc::clone(&required_num_samples),
-        lbuf_rx,
-        rbuf_rx,
+        buf_rx,
     })?;
     Ok(ConnectedOutput {
         stream,
         sample_rate: params.sample_rate,
         buffer_size: params.buffer_size,
         num_channels: params.num_channels,
-        required_num_samples,
-        lbuf_tx,
-        rbuf_tx,
+        buf_tx,
     })
 }
 
@@ -160,7 +160,6 @@ fn create_stream<T>(mut params: StreamParams) -> Result<Stream, Error>
 where
     T: SizedSample + FromSample<f32>,
 {
-    let channels = params.cfg.channels as usize;
     // let mut next_value = move || 0.0;
     let err_fn = |err| error!("An error occurred on stream: {}", err); //TODO: handle this case
 
@@ -169,30 +168,9 @@ where
         .build_output_stream(
             &params.cfg,
             move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                let curr_buf_size = data.len() / channels;
-
-                params.lbuf_rx.clear();
-                params.rbuf_rx.clear();
-
-                params
-                    .required_num_samples
-                    .store(curr_buf_size, std::sync::atomic::Ordering::Relaxed);
-
-                while params.lbuf_rx.occupied_len() < curr_buf_size
-                    || params.rbuf_rx.occupied_len() < curr_buf_size
-                {
-                    std::thread::sleep(Duration::from_micros(10));
-                }
-
-                for frame in data.chunks_mut(channels) {
-                    let lval = params.lbuf_rx.try_pop().expect("Sample expected");
-                    let rval = params.rbuf_rx.try_pop().expect("Sample expected");
-                    let values = [T::from_sample(lval), T::from_sample(rval)];
-
-                    for (k, sample) in frame.iter_mut().enumerate() {
-                        *sample = values[k & 1];
-                    }
-                }
+                // The render thread fills the buffer ahead, an underrun plays silence
+                let len = params.buf_rx.pop_frames(data, T::from_sample);
+                data[len..].fill(T::from_sample(0.0));
 
                 // futures::executor::block_on(async {
                 //     let mut renderer = renderer.lock().await;
@@ -213,14 +191,102 @@ where
     Ok(stream)
 }
 
-fn create_buffers(buffer_size: usize) -> ((BufferTx, BufferRx), (BufferTx, BufferRx)) {
-    let lbuf = ringbuf::HeapRb::<f32>::new(buffer_size);
-    let rbuf = ringbuf::HeapRb::<f32>::new(buffer_size);
-    (lbuf.split(), rbuf.split())
+// Room for one device buffer and the block being rendered, more would only add latency
+pub fn create_buffer(buffer_size: usize, num_channels: usize) -> (BufferTx, BufferRx) {
+    let num_channels = num_channels.max(1);
+    let capacity = (buffer_size + BLOCK_SIZE) * num_channels;
+    let (tx, rx) = ringbuf::HeapRb::<f32>::new(capacity).split();
+    let waiting = Arc::new(OnceLock::new());
+    (
+        BufferTx {
+            tx,
+            num_channels,
+            waiting: waiting.clone(),
+        },
+        BufferRx {
+            rx,
+            num_channels,
+            waiting,
+        },
+    )
+}
+
+impl BufferTx {
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    pub fn has_room(&self, num_frames: usize) -> bool {
+        self.tx.vacant_len() >= num_frames * self.num_channels
+    }
+
+    // Parks the calling thread until the next frames were taken. A wakeup
+    // in between is not lost, the park returns right away then.
+    pub fn wait_for_room(&self) {
+        self.waiting.get_or_init(thread::current);
+        thread::park();
+    }
+
+    // Pushes the block of every channel, or nothing when it does not fit.
+    // Channels missing from the block are silent.
+    pub fn push_block(&mut self, channels: &[Vec<f32>]) -> bool {
+        let len = channels.first().map_or(0, Vec::len);
+        if !self.has_room(len) {
+            return false;
+        }
+        for frame in 0..len {
+            for channel in 0..self.num_channels {
+                let sample = channels.get(channel).map_or(0.0, |buf| buf[frame]);
+                _ = self.tx.try_push(sample);
+            }
+        }
+        true
+    }
+}
+
+impl BufferRx {
+    // Pops as many whole frames as are ready and fit into `data`, returns the
+    // number of samples written
+    pub fn pop_frames<T>(&mut self, data: &mut [T], convert: impl Fn(f32) -> T) -> usize {
+        let num_frames = (self.rx.occupied_len().min(data.len())) / self.num_channels;
+        let len = num_frames * self.num_channels;
+        for (sample, value) in data[..len].iter_mut().zip(self.rx.pop_iter()) {
+            *sample = convert(value);
+        }
+        self.wake_up(num_frames);
+        len
+    }
+
+    fn wake_up(&self, num_frames: usize) {
+        if num_frames > 0 {
+            if let Some(thread) = self.waiting.get() {
+                thread.unpark();
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_frames() {
+        let (mut buf_tx, mut buf_rx) = create_buffer(2, 3);
+        let block = vec![
+            vec![1.0; BLOCK_SIZE],
+            vec![2.0; BLOCK_SIZE],
+            vec![3.0; BLOCK_SIZE],
+        ];
+        assert!(buf_tx.push_block(&block));
+        assert!(!buf_tx.push_block(&block));
+
+        // a device buffer that does not end on a frame only takes the whole frames
+        let mut data = [0.0; 8];
+        assert_eq!(buf_rx.pop_frames(&mut data, |x| x), 6);
+        assert_eq!(data, [1.0, 2.0, 3.0, 1.0, 2.0, 3.0, 0.0, 0.0]);
+    }
+
     // use crate::{audio::info, control, path::VirtualPaths, render};
     // use std::sync::Arc;
     // use tokio::sync::Mutex;
@@ -0,0 +1,296 @@
+use crate::{parameter::Value, rhythm::Rhythm, webserver::cache::NodeUid};
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "AutomationTarget")]
+pub enum Target {
+    // The node is kept by its uid, so the lane stays with it when nodes are moved
+    RenderNode { uid: NodeUid, id: String },
+    ControlNode { uid: NodeUid, id: String },
+    TempoBpm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Interpolation {
+    Linear,
+    Step,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Breakpoint {
+    pub bar: u32,
+    pub beat: f32,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Lane {
+    pub target: Target,
+    pub enabled: bool,
+    pub interpolation: Interpolation,
+    // Sorted by position, loaded lanes with invalid breakpoints are rejected
+    #[serde(deserialize_with = "deserialize_breakpoints")]
+    #[schemars(with = "Vec<Breakpoint>")]
+    pub breakpoints: Vec<Breakpoint>,
+    #[serde(skip)]
+    last_value: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "AutomationRequestKind")]
+pub enum RequestKind {
+    AddLane(Target),
+    RemoveLane(usize),
+    SetLaneEnabled(usize, bool),
+    SetInterpolation(usize, Interpolation),
+    SetBreakpoints(usize, Vec<Breakpoint>),
+    Clear,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InvalidId,
+    InvalidBreakpoint,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Automation {
+    pub lanes: Vec<Lane>,
+}
+
+impl Breakpoint {
+    pub fn position(&self, rhythm: Rhythm) -> f32 {
+        (self.bar * rhythm.num_beats as u32) as f32 + self.beat
+    }
+
+    fn is_valid(&self) -> bool {
+        let finite_value = match self.value {
+            Value::Float(v) => v.is_finite(),
+            _ => true,
+        };
+        self.beat.is_finite() && self.beat >= 0.0 && finite_value
+    }
+}
+
+impl Lane {
+    pub fn new(target: Target) -> Self {
+        Self {
+            target,
+            enabled: true,
+            interpolation: Interpolation::Linear,
+            breakpoints: Vec::new(),
+            last_value: None,
+        }
+    }
+
+    // Value at a position in beats, held constant before the first and after the last breakpoint
+    pub fn value_at(&self, position: f32, rhythm: Rhythm) -> Option<Value> {
+        let next = self
+            .breakpoints
+            .iter()
+            .position(|b| b.position(rhythm) > position);
+        match next {
+            None => self.breakpoints.last().map(|b| b.value),
+            Some(0) => self.breakpoints.first().map(|b| b.value),
+            Some(i) => {
+                let (a, b) = (&self.breakpoints[i - 1], &self.breakpoints[i]);
+                let (start, end) = (a.position(rhythm), b.position(rhythm));
+                let t = (position - start) / (end - start);
+                Some(match self.interpolation {
+                    Interpolation::Linear => interpolate(a.value, b.value, t),
+                    Interpolation::Step => a.value,
+                })
+            }
+        }
+    }
+}
+
+impl Automation {
+    pub fn process_request(&mut self, kind: RequestKind) -> Result<(), Error> {
+        match kind {
+            RequestKind::AddLane(target) => self.lanes.push(Lane::new(target)),
+            RequestKind::RemoveLane(id) => {
+                self.lane_mut(id)?;
+                self.lanes.remove(id);
+            }
+            RequestKind::SetLaneEnabled(id, flag) => self.lane_mut(id)?.enabled = flag,
+            RequestKind::SetInterpolation(id, interpolation) => {
+                self.lane_mut(id)?.interpolation = interpolation
+            }
+            RequestKind::SetBreakpoints(id, mut breakpoints) => {
+                if !breakpoints.iter().all(Breakpoint::is_valid) {
+                    return Err(Error::InvalidBreakpoint);
+                }
+                sort_breakpoints(&mut breakpoints);
+                let lane = self.lane_mut(id)?;
+                lane.breakpoints = breakpoints;
+                lane.last_value = None;
+            }
+            RequestKind::Clear => self.lanes.clear(),
+        }
+        Ok(())
+    }
+
+    // Values of the enabled lanes which changed since the last evaluation
+    pub fn evaluate(&mut self, position: f32, rhythm: Rhythm) -> Vec<(Target, Value)> {
+        let mut changes = Vec::new();
+        for lane in self.lanes.iter_mut().filter(|l| l.enabled) {
+            if let Some(value) = lane.value_at(position, rhythm) {
+                if lane.last_value != Some(value) {
+                    lane.last_value = Some(value);
+                    changes.push((lane.target.clone(), value));
+                }
+            }
+        }
+        changes
+    }
+
+    // Makes the next evaluation send all values again
+    pub fn rewind(&mut self) {
+        for lane in &mut self.lanes {
+            lane.last_value = None;
+        }
+    }
+
+    // Makes the next evaluation send the values of the target again
+    pub fn forget_value(&mut self, target: &Target) {
+        for lane in self.lanes.iter_mut().filter(|l| l.target == *target) {
+            lane.last_value = None;
+        }
+    }
+
+    fn lane_mut(&mut self, id: usize) -> Result<&mut Lane, Error> {
+        self.lanes.get_mut(id).ok_or(Error::InvalidId)
+    }
+}
+
+// Only called with valid breakpoints, their beats are finite
+fn sort_breakpoints(breakpoints: &mut [Breakpoint]) {
+    breakpoints.sort_by(|a, b| (a.bar, a.beat).partial_cmp(&(b.bar, b.beat)).unwrap());
+}
+
+fn deserialize_breakpoints<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Breakpoint>, D::Error> {
+    let mut breakpoints = Vec::<Breakpoint>::deserialize(deserializer)?;
+    if !breakpoints.iter().all(Breakpoint::is_valid) {
+        return Err(de::Error::custom("Invalid breakpoint"));
+    }
+    sort_breakpoints(&mut breakpoints);
+    Ok(breakpoints)
+}
+
+fn interpolate(a: Value, b: Value, t: f32) -> Value {
+    match (a, b) {
+        (Value::Float(a), Value::Float(b)) => Value::Float(a + (b - a) * t),
+        (Value::Int(a), Value::Int(b)) => {
+            Value::Int((a as f32 + (b - a) as f32 * t).round() as i32)
+        }
+        _ => a,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lane(breakpoints: &[(u32, f32, Value)]) -> Lane {
+        let mut lane = Lane::new(Target::TempoBpm);
+        lane.breakpoints = breakpoints
+            .iter()
+            .map(|&(bar, beat, value)| Breakpoint { bar, beat, value })
+            .collect();
+        lane
+    }
+
+    #[test]
+    fn value_at() {
+        let rhythm = Rhythm::default();
+        let mut fade = lane(&[(0, 2.0, Value::Float(0.0)), (1, 2.0, Value::Float(1.0))]);
+        assert_eq!(fade.value_at(0.0, rhythm), Some(Value::Float(0.0)));
+        assert_eq!(fade.value_at(4.0, rhythm), Some(Value::Float(0.5)));
+        assert_eq!(fade.value_at(9.0, rhythm), Some(Value::Float(1.0)));
+        fade.interpolation = Interpolation::Step;
+        assert_eq!(fade.value_at(4.0, rhythm), Some(Value::Float(0.0)));
+        assert_eq!(lane(&[]).value_at(0.0, rhythm), None);
+
+        let steps = lane(&[(0, 0.0, Value::Int(0)), (1, 0.0, Value::Int(3))]);
+        assert_eq!(steps.value_at(2.0, rhythm), Some(Value::Int(2)));
+    }
+
+    #[test]
+    fn evaluate_reports_changes_only() {
+        let rhythm = Rhythm::default();
+        let mut automation = Automation::default();
+        automation.lanes.push(lane(&[
+            (0, 0.0, Value::Float(100.0)),
+            (1, 0.0, Value::Float(120.0)),
+        ]));
+        assert_eq!(
+            automation.evaluate(0.0, rhythm),
+            vec![(Target::TempoBpm, Value::Float(100.0))]
+        );
+        assert_eq!(automation.evaluate(0.0, rhythm), vec![]);
+        assert_eq!(automation.evaluate(2.0, rhythm).len(), 1);
+        automation.rewind();
+        assert_eq!(automation.evaluate(2.0, rhythm).len(), 1);
+        assert_eq!(
+            automation.process_request(RequestKind::RemoveLane(1)),
+            Err(Error::InvalidId)
+        );
+    }
+
+    #[test]
+    fn forgotten_value_is_sent_again() {
+        let rhythm = Rhythm::default();
+        let mut automation = Automation::default();
+        let target = Target::RenderNode {
+            uid: 3,
+            id: "gain".into(),
+        };
+        let mut gain = lane(&[(0, 0.0, Value::Float(0.5))]);
+        gain.target = target.clone();
+        automation.lanes.push(gain);
+        automation
+            .lanes
+            .push(lane(&[(0, 0.0, Value::Float(100.0))]));
+        assert_eq!(automation.evaluate(0.0, rhythm).len(), 2);
+        automation.forget_value(&target);
+        assert_eq!(
+            automation.evaluate(1.0, rhythm),
+            vec![(target, Value::Float(0.5))]
+        );
+    }
+
+    #[test]
+    fn loaded_breakpoints() {
+        let lane = |beat: serde_json::Value| {
+            serde_json::json!({
+                "target": "TempoBpm",
+                "enabled": true,
+                "interpolation": "Linear",
+                "breakpoints": [
+                    { "bar": 1, "beat": 0.0, "value": { "Float": 120.0 } },
+                    { "bar": 0, "beat": beat, "value": { "Float": 100.0 } },
+                ],
+            })
+        };
+        let loaded: Lane = serde_json::from_value(lane(2.0.into())).unwrap();
+        let bars: Vec<_> = loaded.breakpoints.iter().map(|b| b.bar).collect();
+        assert_eq!(bars, vec![0, 1]);
+        assert!(serde_json::from_value::<Lane>(lane((-1.0).into())).is_err());
+
+        let mut automation = Automation::default();
+        automation.lanes.push(Lane::new(Target::TempoBpm));
+        let nan = Breakpoint {
+            bar: 0,
+            beat: 0.0,
+            value: Value::Float(f32::NAN),
+        };
+        assert_eq!(
+            automation.process_request(RequestKind::SetBreakpoints(0, vec![nan])),
+            Err(Error::InvalidBreakpoint)
+        );
+    }
+}
@@ -1,21 +1,37 @@
-use super::node::{self, ControlPtr};
+use super::{
+    automation::{self, Automation},
+    node::{self, ControlPtr},
+};
 use crate::{
     control,
-    json::{deser_field_opt, expect_serialize, DeserializationResult, JsonFieldUpdate},
+    error::{Error, ErrorCode},
+    json::{deser_field_opt, expect_serialize, DeserializationResult},
     midi,
+    parameter::{self, Parameter},
     path::VirtualPaths,
+    render::renderer,
     rhythm::Rhythm,
-    webserver::{Cache, Clients, ServerMessageKind},
+    scene::Scene,
+    webserver::{
+        cache::{self, CachedNode},
+        presence::{ClientId, Node},
+        Cache, Clients, ServerMessageKind,
+    },
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, time::SystemTime};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 use tokio::sync::mpsc;
-use tokio::sync::oneshot;
+use tokio::sync::oneshot::{self, error::TryRecvError};
 use tracing::error;
 
-pub type Requester = mpsc::Sender<(RequestKind, Responder)>;
-pub type RequestListener = mpsc::Receiver<(RequestKind, Responder)>;
+pub type Requester = mpsc::Sender<(RequestKind, Option<ClientId>, Responder)>;
+pub type RequestListener = mpsc::Receiver<(RequestKind, Option<ClientId>, Responder)>;
 pub type Responder = oneshot::Sender<ResponseKind>;
 pub type ResponseListener = oneshot::Receiver<ResponseKind>;
 
@@ -27,7 +43,27 @@ pub fn create_response_channel() -> (Responder, ResponseListener) {
     oneshot::channel()
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub async fn send_request(req_tx: &Requester, req: RequestKind) -> Option<ResponseKind> {
+    send_request_from(req_tx, None, req).await
+}
+
+// The changes made by the request are attributed to the client
+pub async fn send_request_from(
+    req_tx: &Requester,
+    author: Option<ClientId>,
+    req: RequestKind,
+) -> Option<ResponseKind> {
+    let (res_tx, res_rx) = create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, author, res_tx)).await {
+        res_rx.await.ok()
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "ControllerRequestKind")]
 pub enum RequestKind {
     Reset,
     SetEnabled(bool),
@@ -39,46 +75,58 @@ pub enum RequestKind {
     RemoveNode { id: usize },
     CloneNode { id: usize },
     MoveNode { id: usize, new_id: usize },
+    // Applies the controller part and hands the rest to the renderer, the
+    // controller part is left out when the renderer fails
+    ApplyScene(Scene),
+    // All nodes with their uids and the state of the renderer and the controller
+    SaveSession(PathBuf),
+    // Replaces all nodes, nothing changes when the renderer rejects its part
+    LoadSession(PathBuf),
+    ListParameters(usize),
+    GetParameter(usize, String),
+    SetParameter(usize, String, parameter::Value),
+    Automation(automation::RequestKind),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "ControllerResponseKind")]
 pub enum ResponseKind {
     InvalidNodeKind,
     InvalidId,
     Denied,
-    Failed,
+    Failed { reason: Error },
     Ok,
     NodeResponse { id: usize, kind: node::ResponseKind },
+    InvalidParameter,
+    InvalidValue,
+    Parameters(Vec<Parameter>),
+    ParameterValue(parameter::Value),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+// The controller state reaches the clients as cache deltas, only what is
+// not kept in the cache is sent as an update
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "ControllerUpdateKind")]
 pub enum UpdateKind {
-    Enabled(bool),
-    TempoBpm(f32),
-    Rhythm(Rhythm),
-    BeatState {
-        beat: u8,
-        div: u8,
-    },
-    AddNode {
-        id: usize,
-        kind: String,
-        instance: serde_json::Value,
-    },
-    RemoveNode {
-        id: usize,
-    },
-    CloneNode {
-        id: usize,
-    },
-    MoveNode {
-        id: usize,
-        new_id: usize,
-    },
-    NodeUpdates {
-        id: usize,
-        updates: Vec<JsonFieldUpdate>,
-    },
+    BeatState { beat: u8, div: u8 },
+}
+
+// Content of a session file. The nodes keep their uids, so the scenes,
+// setlists and mappings saved along find them after a restart.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Session {
+    render_nodes: Vec<CachedNode>,
+    control_nodes: Vec<CachedNode>,
+    renderer: serde_json::Value,
+    controller: serde_json::Value,
 }
 
 pub type NodeKindConstructor = Box<dyn Fn() -> ControlPtr + 'static + Sync + Send>;
@@ -90,15 +138,23 @@ pub struct Controller {
     midi_rx: midi::Receiver,
     req_rx: RequestListener,
     ctr_tx: control::CtrSender,
+    rnd_req_tx: renderer::Requester,
     tempo_bpm: f32,
     rhythm: Rhythm,
     virtual_paths: VirtualPaths,
     clients: Clients,
     cache: Cache,
+    // clients whose requests edited a node since the last json updates
+    node_authors: HashMap<usize, ClientId>,
     last_start: SystemTime,
     last_time: f32,
     current_beat: u8,
     current_div: u8,
+    automation: Automation,
+    // divisions since the last reset
+    num_ticks: u32,
+    // automated parameters sent to the renderer, the beat tick doesn't wait for them
+    automation_responses: Vec<renderer::ResponseListener>,
 }
 
 impl Controller {
@@ -106,6 +162,7 @@ impl Controller {
         midi_rx: midi::Receiver,
         req_rx: RequestListener,
         ctr_tx: control::CtrSender,
+        rnd_req_tx: renderer::Requester,
         virtual_paths: VirtualPaths,
         clients: Clients,
         cache: Cache,
@@ -118,15 +175,20 @@ impl Controller {
             midi_rx,
             req_rx,
             ctr_tx,
+            rnd_req_tx,
             tempo_bpm: 90.0,
             rhythm,
             virtual_paths,
             clients,
             cache,
+            node_authors: HashMap::new(),
             last_start: SystemTime::now(),
             last_time: 0.0,
             current_beat: rhythm.num_beats - 1,
             current_div: rhythm.num_divs - 1,
+            automation: Default::default(),
+            num_ticks: 0,
+            automation_responses: Vec::new(),
         }
     }
 
@@ -142,6 +204,9 @@ impl Controller {
         self.receive_requests().await;
         self.receive_midi_messages();
         self.process_json_updates().await;
+        for node in &mut self.nodes {
+            node.1.update().await;
+        }
 
         if self.enabled {
             let time = self.timestamp();
@@ -163,21 +228,27 @@ impl Controller {
     }
 
     pub async fn receive_requests(&mut self) {
-        while let Ok((kind, responder)) = self.req_rx.try_recv() {
+        while let Ok((kind, author, responder)) = self.req_rx.try_recv() {
+            if let Some(author) = author {
+                for id in edited_nodes(&kind) {
+                    self.node_authors.insert(id, author);
+                }
+            }
+            self.cache.set_author(author);
             self.process_request(kind, responder).await;
         }
+        self.cache.set_author(None);
     }
 
     pub async fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
         deser_field_opt(source, "enabled", |v| self.enabled = v)?;
         deser_field_opt(source, "tempo_bpm", |v| self.tempo_bpm = v)?;
         deser_field_opt(source, "rhythm", |v| self.rhythm = v)?;
+        deser_field_opt(source, "automation", |v| self.automation = v)?;
         self.cache.set_controller_enabled(self.enabled).await;
         self.cache.set_controller_tempo_bpm(self.tempo_bpm).await;
         self.cache.set_controller_rhythm(self.rhythm).await;
-        self.broadcast_update(UpdateKind::Enabled(self.enabled));
-        self.broadcast_update(UpdateKind::TempoBpm(self.tempo_bpm));
-        self.broadcast_update(UpdateKind::Rhythm(self.rhythm));
+        self.cache.set_controller_automation(&self.automation).await;
         Ok(())
     }
 
@@ -186,6 +257,7 @@ impl Controller {
             "enabled": expect_serialize(self.enabled),
             "tempo_bpm": expect_serialize(self.tempo_bpm),
             "rhythm": expect_serialize(self.rhythm),
+            "automation": expect_serialize(&self.automation),
         })
     }
 
@@ -200,12 +272,12 @@ impl Controller {
     async fn process_json_updates(&mut self) {
         for (id, node) in self.nodes.iter_mut().enumerate() {
             if let Some(updates) = node.1.json_updates() {
+                self.cache.set_author(self.node_authors.get(&id).copied());
                 self.cache.control_node_updates(id, &updates).await;
-                self.clients.broadcast(ServerMessageKind::ControllerUpdate(
-                    UpdateKind::NodeUpdates { id, updates },
-                ));
             }
         }
+        self.cache.set_author(None);
+        self.node_authors.clear();
     }
 
     async fn process_request(&mut self, kind: RequestKind, responder: Responder) {
@@ -231,7 +303,11 @@ impl Controller {
                     self.set_user_preset(preset);
                     respond(responder, ResponseKind::Ok);
                 } else {
-                    respond(responder, ResponseKind::Failed);
+                    let reason = format!("Invalid user preset: {preset}");
+                    respond(
+                        responder,
+                        ResponseKind::failed(ErrorCode::InvalidValue, reason),
+                    );
                 }
             }
             RequestKind::NodeRequest { id, kind } => self.process_node_request(responder, id, kind),
@@ -241,6 +317,190 @@ impl Controller {
             RequestKind::MoveNode { id, new_id } => {
                 self.process_move_node(responder, id, new_id).await
             }
+            RequestKind::ApplyScene(scene) => self.process_apply_scene(responder, scene).await,
+            RequestKind::SaveSession(path) => {
+                let res = self.save_session(&path).await;
+                respond(responder, res);
+            }
+            RequestKind::LoadSession(path) => {
+                let res = self.load_session(&path).await;
+                respond(responder, res);
+            }
+            RequestKind::ListParameters(id) => self.process_list_parameters(responder, id),
+            RequestKind::GetParameter(id, parameter) => {
+                self.process_get_parameter(responder, id, &parameter)
+            }
+            RequestKind::SetParameter(id, parameter, value) => {
+                self.process_set_parameter(responder, id, &parameter, value)
+            }
+            RequestKind::Automation(kind) => self.process_automation_request(responder, kind).await,
+        }
+    }
+
+    async fn process_apply_scene(&mut self, responder: Responder, scene: Scene) {
+        // Nodes removed since the scene was captured are skipped
+        let mut nodes = Vec::new();
+        for (uid, state) in scene.control_nodes {
+            if let Some(id) = self.cache.control_node_index(uid).await {
+                nodes.push((id, state));
+            }
+        }
+        // Nothing is applied when a node is locked by another client
+        if let Some(author) = self.cache.author() {
+            let locked: Vec<_> = nodes.iter().map(|(id, _)| Node::ControlNode(*id)).collect();
+            if let Err(reason) = self.clients.check_nodes(Some(author), &locked) {
+                respond(responder, ResponseKind::Failed { reason });
+                return;
+            }
+        }
+        let state = scene.controller;
+        let mut tempo_bpm = None;
+        let mut rhythm = None;
+        let mut automation = None;
+        let res = deser_field_opt(&state, "tempo_bpm", |v| tempo_bpm = Some(v))
+            .and_then(|()| deser_field_opt(&state, "rhythm", |v| rhythm = Some(v)))
+            .and_then(|()| deser_field_opt(&state, "automation", |v| automation = Some(v)));
+        if let Err(e) = res {
+            respond(responder, ResponseKind::Failed { reason: e.into() });
+            return;
+        }
+
+        let rnd_res = renderer::send_request_from(
+            &self.rnd_req_tx,
+            self.cache.author(),
+            renderer::RequestKind::ApplyScene(scene.renderer, scene.render_nodes),
+        )
+        .await;
+        match rnd_res {
+            Some(renderer::ResponseKind::Ok) => {}
+            Some(renderer::ResponseKind::Failed { reason }) => {
+                respond(responder, ResponseKind::Failed { reason });
+                return;
+            }
+            _ => {
+                let reason = "The renderer did not apply the scene";
+                respond(
+                    responder,
+                    ResponseKind::failed(ErrorCode::Unavailable, reason),
+                );
+                return;
+            }
+        }
+
+        if let Some(tempo_bpm) = tempo_bpm {
+            self.set_tempo_bpm(tempo_bpm).await;
+        }
+        if let Some(rhythm) = rhythm {
+            self.set_rhythm(rhythm).await;
+        }
+        if let Some(automation) = automation {
+            self.automation = automation;
+            self.update_automation().await;
+        }
+
+        let mut result = ResponseKind::Ok;
+        for (id, state) in nodes {
+            let Some((_, node)) = self.nodes.get_mut(id) else {
+                continue;
+            };
+            match node.apply_state(&state) {
+                Ok(updates) => self.cache.control_node_updates(id, &updates).await,
+                Err(e) => result = ResponseKind::Failed { reason: e.into() },
+            }
+        }
+        respond(responder, result);
+    }
+
+    async fn save_session(&self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let cache = self.cache.to_json().await;
+        let nodes = |nodes: &str| serde_json::from_value(cache[nodes].clone()).unwrap_or_default();
+        let session = Session {
+            render_nodes: nodes("render_nodes"),
+            control_nodes: nodes("control_nodes"),
+            renderer: cache["renderer"].clone(),
+            controller: self.serialize().await,
+        };
+        let content = match serde_json::to_string_pretty(&session) {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::failed(ErrorCode::Serialization, e.to_string()),
+        };
+        match tokio::fs::write(file, content).await {
+            Ok(()) => ResponseKind::Ok,
+            Err(e) => ResponseKind::Failed { reason: e.into() },
+        }
+    }
+
+    async fn load_session(&mut self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let content = match tokio::fs::read_to_string(file).await {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::Failed { reason: e.into() },
+        };
+        let session: Session = match serde_json::from_str(&content) {
+            Ok(session) => session,
+            Err(e) => return ResponseKind::failed(ErrorCode::Deserialization, e.to_string()),
+        };
+        if !cache::has_unique_uids(session.render_nodes.iter().chain(&session.control_nodes)) {
+            let reason = "The nodes do not have unique uids";
+            return ResponseKind::failed(ErrorCode::Deserialization, reason);
+        }
+        let mut loaded = Vec::new();
+        for cached in session.control_nodes {
+            let Some(constructor) = self.registered_node_kinds.get(&cached.kind) else {
+                return ResponseKind::InvalidNodeKind;
+            };
+            let mut node = constructor();
+            if let Err(e) = node.deserialize(&cached.instance) {
+                return ResponseKind::Failed { reason: e.into() };
+            }
+            loaded.push((cached, node));
+        }
+
+        let res = renderer::send_request_from(
+            &self.rnd_req_tx,
+            self.cache.author(),
+            renderer::RequestKind::LoadNodes(session.renderer, session.render_nodes),
+        )
+        .await;
+        match res {
+            Some(renderer::ResponseKind::Ok) => {}
+            Some(renderer::ResponseKind::Failed { reason }) => {
+                return ResponseKind::Failed { reason }
+            }
+            Some(renderer::ResponseKind::InvalidNodeKind) => return ResponseKind::InvalidNodeKind,
+            _ => {
+                let reason = "The renderer did not load its nodes";
+                return ResponseKind::failed(ErrorCode::Unavailable, reason);
+            }
+        }
+
+        // The nodes get the restored tempo and rhythm when they are added
+        let res = self.deserialize(&session.controller).await;
+        self.node_authors.clear();
+        while let Some(id) = self.nodes.len().checked_sub(1) {
+            self.nodes.remove(id);
+            self.cache.remove_control_node(id).await;
+        }
+        for (cached, node) in loaded {
+            let value = node.serialize().unwrap_or(cached.instance);
+            self.add_node(cached.kind.clone(), node);
+            self.cache
+                .restore_control_node(&cached.kind, &value, cached.uid)
+                .await;
+        }
+        self.reset();
+        match res {
+            Ok(()) => ResponseKind::Ok,
+            Err(e) => ResponseKind::Failed { reason: e.into() },
         }
     }
 
@@ -256,7 +516,6 @@ impl Controller {
             self.reset();
         }
         self.cache.set_controller_enabled(flag).await;
-        self.broadcast_update(UpdateKind::Enabled(flag));
     }
 
     async fn set_tempo_bpm(&mut self, tempo_bpm: f32) {
@@ -267,7 +526,6 @@ impl Controller {
         }
 
         self.cache.set_controller_tempo_bpm(tempo_bpm).await;
-        self.broadcast_update(UpdateKind::TempoBpm(tempo_bpm));
     }
 
     async fn set_rhythm(&mut self, rhythm: Rhythm) {
@@ -278,7 +536,104 @@ impl Controller {
             node.1.set_rhythm(rhythm);
         }
         self.cache.set_controller_rhythm(rhythm).await;
-        self.broadcast_update(UpdateKind::Rhythm(rhythm));
+    }
+
+    fn process_list_parameters(&mut self, responder: Responder, id: usize) {
+        if let Some((_, node)) = self.nodes.get(id) {
+            respond(responder, ResponseKind::Parameters(node.parameters()));
+        } else {
+            respond(responder, ResponseKind::InvalidId);
+        }
+    }
+
+    fn process_get_parameter(&mut self, responder: Responder, id: usize, parameter: &str) {
+        match self.nodes.get(id) {
+            Some((_, node)) => match node.parameter(parameter) {
+                Some(value) => respond(responder, ResponseKind::ParameterValue(value)),
+                None => respond(responder, ResponseKind::InvalidParameter),
+            },
+            None => respond(responder, ResponseKind::InvalidId),
+        }
+    }
+
+    fn process_set_parameter(
+        &mut self,
+        responder: Responder,
+        id: usize,
+        parameter: &str,
+        value: parameter::Value,
+    ) {
+        let Some((_, node)) = self.nodes.get_mut(id) else {
+            respond(responder, ResponseKind::InvalidId);
+            return;
+        };
+        // Changes are announced through the regular node updates
+        let res = match parameter::set(node.as_mut(), parameter, value) {
+            Ok(()) => ResponseKind::Ok,
+            Err(parameter::Error::UnknownParameter) => ResponseKind::InvalidParameter,
+            Err(parameter::Error::InvalidValue) => ResponseKind::InvalidValue,
+            Err(parameter::Error::Failed) => {
+                ResponseKind::failed(ErrorCode::InvalidValue, "The node rejected the value")
+            }
+        };
+        respond(responder, res);
+    }
+
+    async fn process_automation_request(
+        &mut self,
+        responder: Responder,
+        kind: automation::RequestKind,
+    ) {
+        match self.automation.process_request(kind) {
+            Ok(()) => {
+                respond(responder, ResponseKind::Ok);
+                self.update_automation().await;
+            }
+            Err(automation::Error::InvalidId) => respond(responder, ResponseKind::InvalidId),
+            Err(automation::Error::InvalidBreakpoint) => {
+                respond(responder, ResponseKind::InvalidValue)
+            }
+        }
+    }
+
+    async fn update_automation(&mut self) {
+        self.cache.set_controller_automation(&self.automation).await;
+    }
+
+    // Drives the automated parameters through the same requests the clients use
+    async fn run_automation(&mut self) {
+        let position = self.num_ticks as f32 / self.rhythm.num_divs as f32;
+        self.num_ticks += 1;
+        self.automation_responses
+            .retain_mut(|res_rx| matches!(res_rx.try_recv(), Err(TryRecvError::Empty)));
+        for (target, value) in self.automation.evaluate(position, self.rhythm) {
+            match &target {
+                // the beat tick does not wait for the renderer, the value is
+                // sent again on the next tick when the channel is full
+                automation::Target::RenderNode { uid, id } => {
+                    let Some(index) = self.cache.render_node_index(*uid).await else {
+                        continue;
+                    };
+                    let req = renderer::RequestKind::SetParameter(index, id.clone(), value);
+                    let (res_tx, res_rx) = renderer::create_response_channel();
+                    if let Ok(()) = self.rnd_req_tx.try_send((req, None, res_tx)) {
+                        self.automation_responses.push(res_rx);
+                    } else {
+                        self.automation.forget_value(&target);
+                    }
+                }
+                automation::Target::ControlNode { uid, id } => {
+                    let index = self.cache.control_node_index(*uid).await;
+                    if let Some((_, node)) = index.and_then(|i| self.nodes.get_mut(i)) {
+                        _ = parameter::set(node.as_mut(), id, value);
+                    }
+                }
+                automation::Target::TempoBpm if value.as_f32() > 0.0 => {
+                    self.set_tempo_bpm(value.as_f32()).await;
+                }
+                automation::Target::TempoBpm => {}
+            }
+        }
     }
 
     fn process_node_request(&mut self, responder: Responder, id: usize, kind: node::RequestKind) {
@@ -297,17 +652,16 @@ impl Controller {
         }
 
         let node: ControlPtr = self.registered_node_kinds[&kind]();
-        if let Ok(value) = node.serialize() {
-            self.add_node(kind.clone(), node);
-            self.cache.add_control_node(&kind, &value).await;
-            respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::AddNode {
-                id: self.nodes.len() - 1,
-                kind,
-                instance: value,
-            });
-        } else {
-            respond(responder, ResponseKind::Failed);
+        match node.serialize() {
+            Ok(value) => {
+                self.add_node(kind.clone(), node);
+                self.cache.add_control_node(&kind, &value).await;
+                respond(responder, ResponseKind::Ok);
+            }
+            Err(e) => respond(
+                responder,
+                ResponseKind::failed(ErrorCode::Serialization, e.0),
+            ),
         }
     }
 
@@ -318,7 +672,6 @@ impl Controller {
             self.nodes.remove(id);
             self.cache.remove_control_node(id).await;
             respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::RemoveNode { id });
         }
     }
 
@@ -330,7 +683,6 @@ impl Controller {
             self.add_node(node.0.clone(), node.1.clone_node());
             self.cache.clone_control_node(id).await;
             respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::CloneNode { id });
         }
     }
 
@@ -342,7 +694,6 @@ impl Controller {
             self.nodes.insert(new_id, node);
             self.cache.move_control_node(id, new_id).await;
             respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::MoveNode { id, new_id });
         }
     }
 
@@ -351,6 +702,8 @@ impl Controller {
         self.last_time = self.timestamp() - self.period();
         self.current_beat = self.rhythm.num_beats - 1;
         self.current_div = self.rhythm.num_divs - 1;
+        self.num_ticks = 0;
+        self.automation.rewind();
 
         for node in &mut self.nodes {
             node.1.reset();
@@ -366,6 +719,7 @@ impl Controller {
         for node in &mut self.nodes {
             node.1.beat_tick(beat_num, div_num).await;
         }
+        self.run_automation().await;
         self.broadcast_update(UpdateKind::BeatState {
             beat: beat_num,
             div: div_num,
@@ -401,6 +755,14 @@ impl Controller {
     }
 }
 
+// Nodes whose json updates are caused by the request
+pub fn edited_nodes(kind: &RequestKind) -> Vec<usize> {
+    match kind {
+        RequestKind::NodeRequest { id, .. } | RequestKind::SetParameter(id, ..) => vec![*id],
+        _ => Vec::new(),
+    }
+}
+
 fn respond(responder: Responder, response_kind: ResponseKind) {
     if let Err(e) = responder.send(response_kind) {
         error!("Failed to send a response: {e:?}");
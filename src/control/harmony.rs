@@ -0,0 +1,125 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Scale {
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+}
+
+impl Scale {
+    pub fn intervals(&self) -> [i32; 7] {
+        match *self {
+            Scale::Major => [0, 2, 4, 5, 7, 9, 11],
+            Scale::NaturalMinor => [0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => [0, 2, 3, 5, 7, 8, 11],
+            Scale::MelodicMinor => [0, 2, 3, 5, 7, 9, 11],
+            Scale::Dorian => [0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => [0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => [0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => [0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => [0, 1, 3, 5, 6, 8, 10],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Voicing {
+    Close,
+    // second voice from the top is moved an octave down
+    Drop2,
+    // every other voice is moved an octave up
+    Spread,
+}
+
+// Moves the note by the given number of scale degrees, notes outside the scale keep
+// their distance from the scale degree below them
+pub fn diatonic_transpose(note: i32, key: u8, scale: Scale, degrees: i32) -> i32 {
+    let intervals = scale.intervals();
+    let relative = note - (key % 12) as i32;
+    let octave = relative.div_euclid(12);
+    let pitch_class = relative.rem_euclid(12);
+    let degree = intervals
+        .iter()
+        .rposition(|i| *i <= pitch_class)
+        .unwrap_or(0) as i32;
+    let chromatic = pitch_class - intervals[degree as usize];
+
+    let target = degree + degrees;
+    let octave = octave + target.div_euclid(7);
+    let degree = target.rem_euclid(7) as usize;
+    (key % 12) as i32 + octave * 12 + intervals[degree] + chromatic
+}
+
+// Rearranges the notes of the chord, the payload stays attached to its note
+pub fn apply_voicing<T>(chord: &mut [(i32, T)], voicing: Voicing, inversion: u8) {
+    chord.sort_by_key(|(note, _)| *note);
+    if chord.is_empty() {
+        return;
+    }
+
+    for _ in 0..inversion as usize % chord.len().max(1) {
+        chord[0].0 += 12;
+        chord.sort_by_key(|(note, _)| *note);
+    }
+
+    match voicing {
+        Voicing::Close => {}
+        Voicing::Drop2 => {
+            if chord.len() >= 2 {
+                let index = chord.len() - 2;
+                chord[index].0 -= 12;
+            }
+        }
+        Voicing::Spread => {
+            for (i, (note, _)) in chord.iter_mut().enumerate() {
+                if i % 2 == 1 {
+                    *note += 12;
+                }
+            }
+        }
+    }
+    chord.sort_by_key(|(note, _)| *note);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diatonic() {
+        // C major: E up a third is G, B up a third is D above
+        assert_eq!(diatonic_transpose(64, 0, Scale::Major, 2), 67);
+        assert_eq!(diatonic_transpose(71, 0, Scale::Major, 2), 74);
+        assert_eq!(diatonic_transpose(60, 0, Scale::Major, -2), 57);
+        // A minor: C up a fifth is G
+        assert_eq!(diatonic_transpose(60, 9, Scale::NaturalMinor, 4), 67);
+        // C# in C major stays a semitone above the resulting degree
+        assert_eq!(diatonic_transpose(61, 0, Scale::Major, 2), 65);
+    }
+
+    #[test]
+    fn voicing() {
+        let notes = |chord: &[(i32, usize)]| chord.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+
+        let mut chord = [(60, 0), (64, 1), (67, 2)];
+        apply_voicing(&mut chord, Voicing::Close, 1);
+        assert_eq!(notes(&chord), vec![64, 67, 72]);
+        assert_eq!(chord[2].1, 0);
+
+        let mut chord = [(60, 0), (64, 1), (67, 2), (71, 3)];
+        apply_voicing(&mut chord, Voicing::Drop2, 0);
+        assert_eq!(notes(&chord), vec![55, 60, 64, 71]);
+
+        let mut chord = [(60, 0), (64, 1), (67, 2)];
+        apply_voicing(&mut chord, Voicing::Spread, 0);
+        assert_eq!(notes(&chord), vec![60, 67, 76]);
+    }
+}
@@ -2,9 +2,12 @@ use crate::midi;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 
+pub mod automation;
 pub mod drum_machine;
+pub mod harmony;
 pub mod node;
 pub mod controller;
+pub mod take;
 pub mod voices;
 
 pub const MAX_BUFFER_SIZE: usize = 192000;
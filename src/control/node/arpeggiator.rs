@@ -0,0 +1,610 @@
+use super::{ControlPtr, RequestKind, ResponseCallback, ResponseKind};
+use crate::{
+    control::{ControlMessage, CtrSender},
+    error::ErrorCode,
+    json::{
+        deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate, SerializationResult,
+    },
+    json_try, midi,
+    parameter::{self, Parameter, Parameters, Unit, Value},
+    path::VirtualPaths,
+    rhythm::Rhythm,
+};
+use axum::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{mem, time::Instant};
+
+const DEFAULT_NAME: &str = "Arpeggiator";
+const DEFAULT_TEMPO_BPM: f32 = 90.0;
+const DEFAULT_GATE: f32 = 0.5;
+const MIN_GATE: f32 = 0.05;
+const MAX_NUM_OCTAVES: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Pattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+    Chord,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Note {
+    note: u8,
+    velocity: u8,
+}
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    pattern: Pattern,
+    num_octaves: u8,
+    gate: f32,
+    latch: bool,
+    instrument_index: Option<usize>,
+    sender: Option<CtrSender>,
+    tempo_bpm: f32,
+    rhythm: Rhythm,
+    // notes taking part in the arpeggio, in the order they were played
+    notes: Vec<Note>,
+    // keys physically held down, differs from `notes` only when latched
+    pressed: Vec<u8>,
+    channel: u8,
+    step: usize,
+    rng_state: u32,
+    // ticks since the node was created, and when the last one happened
+    num_ticks: u64,
+    last_tick: Instant,
+    // note-offs to send with the tick position they are due at, gate 1.0 makes them
+    // due at the next tick, before its note-ons
+    note_offs: Vec<(f64, usize, midi::Message)>,
+    user_presets: Vec<bool>,
+    json_updates: Vec<JsonFieldUpdate>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: String) -> ResponseKind {
+        self.name = name;
+        json_try! {
+            self.json_updates.push(("name".to_owned(), serialize(&self.name)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> ResponseKind {
+        self.enabled = flag;
+        json_try! {
+            self.json_updates.push(("enabled".to_owned(), serialize(flag)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_instrument(&mut self, instrument_index: Option<usize>) -> ResponseKind {
+        self.instrument_index = instrument_index;
+        json_try! {
+            self.json_updates.push(("instrument_index".into(), serialize(instrument_index)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_pattern(&mut self, pattern: Pattern) -> ResponseKind {
+        self.pattern = pattern;
+        self.step = 0;
+        json_try! {
+            self.json_updates.push(("pattern".into(), serialize(pattern)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_num_octaves(&mut self, num_octaves: u8) -> ResponseKind {
+        if num_octaves == 0 || num_octaves > MAX_NUM_OCTAVES {
+            let reason = format!("The number of octaves must be between 1 and {MAX_NUM_OCTAVES}");
+            return ResponseKind::failed(ErrorCode::InvalidValue, reason);
+        }
+        self.num_octaves = num_octaves;
+        json_try! {
+            self.json_updates.push(("num_octaves".into(), serialize(num_octaves)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_gate(&mut self, gate: f32) -> ResponseKind {
+        if !gate.is_finite() {
+            return ResponseKind::failed(ErrorCode::InvalidValue, "Invalid gate");
+        }
+        self.gate = gate.clamp(MIN_GATE, 1.0);
+        json_try! {
+            self.json_updates.push(("gate".into(), serialize(self.gate)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_latch(&mut self, flag: bool) -> ResponseKind {
+        self.latch = flag;
+        if !flag {
+            let pressed = &self.pressed;
+            self.notes.retain(|n| pressed.contains(&n.note));
+        }
+        json_try! {
+            self.json_updates.push(("latch".into(), serialize(flag)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_user_preset_enabled(&mut self, preset: usize, flag: bool) -> ResponseKind {
+        if preset >= self.user_presets.len() {
+            ResponseKind::failed(
+                ErrorCode::InvalidValue,
+                format!("Invalid user preset: {preset}"),
+            )
+        } else {
+            self.user_presets[preset] = flag;
+            json_try! {
+                self.json_updates.push(("user_presets".into(), serialize(&self.user_presets)?))
+            }
+            ResponseKind::Ok
+        }
+    }
+
+    fn period(&self) -> f32 {
+        60.0 / (self.tempo_bpm * self.rhythm.num_divs as f32)
+    }
+
+    fn press(&mut self, note: u8, velocity: u8) {
+        // with latch on, a new chord starts once all the keys were released
+        if self.latch && self.pressed.is_empty() {
+            self.notes.clear();
+            self.step = 0;
+        }
+        self.pressed.retain(|n| *n != note);
+        self.pressed.push(note);
+        self.notes.retain(|n| n.note != note);
+        self.notes.push(Note { note, velocity });
+    }
+
+    fn release(&mut self, note: u8) {
+        self.pressed.retain(|n| *n != note);
+        if !self.latch {
+            self.notes.retain(|n| n.note != note);
+        }
+    }
+
+    fn next_random(&mut self) -> usize {
+        // xorshift32
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+        x as usize
+    }
+
+    fn next_step(&mut self) -> Vec<Note> {
+        let steps = arpeggio_steps(&self.notes, self.pattern, self.num_octaves);
+        if steps.is_empty() {
+            return vec![];
+        }
+        let index = if self.pattern == Pattern::Random {
+            self.next_random() % steps.len()
+        } else {
+            self.step % steps.len()
+        };
+        self.step = self.step.wrapping_add(1);
+        steps[index].clone()
+    }
+
+    fn position(&self) -> f64 {
+        let elapsed = self.last_tick.elapsed().as_secs_f64() / self.period() as f64;
+        self.num_ticks as f64 + elapsed.min(1.0)
+    }
+
+    async fn send_note_offs(&mut self, position: f64) {
+        let Some(sender) = self.sender.clone() else {
+            return;
+        };
+        let (due, pending): (Vec<_>, Vec<_>) = mem::take(&mut self.note_offs)
+            .into_iter()
+            .partition(|(tick, ..)| *tick <= position);
+        self.note_offs = pending;
+        for (_, instrument_id, midi_msg) in due {
+            _ = sender
+                .send(ControlMessage {
+                    instrument_id,
+                    midi_msg,
+                })
+                .await;
+        }
+    }
+}
+
+fn transposed(notes: &[Note], octave: u8) -> impl Iterator<Item = Note> + '_ {
+    notes.iter().filter_map(move |n| {
+        let note = n.note as usize + 12 * octave as usize;
+        (note <= 127).then_some(Note {
+            note: note as u8,
+            velocity: n.velocity,
+        })
+    })
+}
+
+fn arpeggio_steps(notes: &[Note], pattern: Pattern, num_octaves: u8) -> Vec<Vec<Note>> {
+    let mut sorted = notes.to_vec();
+    sorted.sort_by_key(|n| n.note);
+
+    let spread = |notes: &[Note]| -> Vec<Note> {
+        (0..num_octaves.max(1))
+            .flat_map(|octave| transposed(notes, octave).collect::<Vec<_>>())
+            .collect()
+    };
+
+    let single = |notes: Vec<Note>| notes.into_iter().map(|n| vec![n]).collect();
+
+    match pattern {
+        Pattern::Up | Pattern::Random => single(spread(&sorted)),
+        Pattern::Down => {
+            let mut notes = spread(&sorted);
+            notes.reverse();
+            single(notes)
+        }
+        Pattern::UpDown => {
+            let up = spread(&sorted);
+            let down = up
+                .iter()
+                .rev()
+                .skip(1)
+                .take(up.len().saturating_sub(2))
+                .copied()
+                .collect::<Vec<_>>();
+            single(up.into_iter().chain(down).collect())
+        }
+        Pattern::AsPlayed => single(spread(notes)),
+        Pattern::Chord => (0..num_octaves.max(1))
+            .map(|octave| transposed(&sorted, octave).collect::<Vec<_>>())
+            .filter(|chord| !chord.is_empty())
+            .collect(),
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            pattern: Pattern::Up,
+            num_octaves: 1,
+            gate: DEFAULT_GATE,
+            latch: false,
+            instrument_index: None,
+            sender: None,
+            tempo_bpm: DEFAULT_TEMPO_BPM,
+            rhythm: Rhythm::default(),
+            notes: vec![],
+            pressed: vec![],
+            channel: 0,
+            step: 0,
+            rng_state: 0x2545_f491,
+            num_ticks: 0,
+            last_tick: Instant::now(),
+            note_offs: vec![],
+            user_presets: vec![true; super::NUM_USER_PRESETS],
+            json_updates: Default::default(),
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            pattern: self.pattern,
+            num_octaves: self.num_octaves,
+            gate: self.gate,
+            latch: self.latch,
+            instrument_index: self.instrument_index,
+            sender: None,
+            tempo_bpm: self.tempo_bpm,
+            rhythm: self.rhythm,
+            notes: vec![],
+            pressed: vec![],
+            channel: 0,
+            step: 0,
+            rng_state: self.rng_state,
+            num_ticks: 0,
+            last_tick: Instant::now(),
+            note_offs: vec![],
+            user_presets: self.user_presets.clone(),
+            json_updates: Default::default(),
+        }
+    }
+}
+
+impl Parameters for Node {
+    fn parameters(&self) -> Vec<Parameter> {
+        vec![
+            Parameter::bool("enabled", true),
+            Parameter::int("num_octaves", 1, MAX_NUM_OCTAVES as i32, 1, Unit::Octaves),
+            Parameter::float("gate", MIN_GATE, 1.0, DEFAULT_GATE, Unit::None),
+            Parameter::bool("latch", false),
+        ]
+    }
+
+    fn parameter(&self, id: &str) -> Option<Value> {
+        match id {
+            "enabled" => Some(Value::Bool(self.enabled)),
+            "num_octaves" => Some(Value::Int(self.num_octaves as i32)),
+            "gate" => Some(Value::Float(self.gate)),
+            "latch" => Some(Value::Bool(self.latch)),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: Value) -> parameter::SetResult {
+        let res = match id {
+            "enabled" => self.set_enabled(value.as_bool()),
+            "num_octaves" => self.set_num_octaves(value.as_i32() as u8),
+            "gate" => self.set_gate(value.as_f32()),
+            "latch" => self.set_latch(value.as_bool()),
+            _ => return Err(parameter::Error::UnknownParameter),
+        };
+        parameter::result(res)
+    }
+}
+
+#[async_trait]
+impl super::Control for Node {
+    fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    async fn beat_tick(&mut self, _beat_num: u8, _div_num: u8) {
+        self.num_ticks += 1;
+        self.last_tick = Instant::now();
+        self.send_note_offs(self.num_ticks as f64).await;
+        if !self.enabled {
+            return;
+        }
+        let (Some(sender), Some(instrument_id)) = (self.sender.clone(), self.instrument_index)
+        else {
+            return;
+        };
+
+        let notes = self.next_step();
+        if notes.is_empty() {
+            return;
+        }
+
+        let channel = self.channel;
+        for n in &notes {
+            _ = sender
+                .send(ControlMessage {
+                    instrument_id,
+                    midi_msg: midi::Message {
+                        kind: midi::MessageKind::NoteOn {
+                            note: n.note,
+                            velocity: n.velocity,
+                        },
+                        channel,
+                    },
+                })
+                .await;
+        }
+
+        let due = self.num_ticks as f64 + self.gate as f64;
+        for n in notes {
+            let midi_msg = midi::Message {
+                kind: midi::MessageKind::NoteOff {
+                    note: n.note,
+                    velocity: 0,
+                },
+                channel,
+            };
+            self.note_offs.push((due, instrument_id, midi_msg));
+        }
+    }
+
+    async fn update(&mut self) {
+        if !self.note_offs.is_empty() {
+            self.send_note_offs(self.position()).await;
+        }
+    }
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    fn set_rhythm(&mut self, rhythm: Rhythm) {
+        self.rhythm = rhythm;
+    }
+
+    fn set_tempo_bpm(&mut self, tempo_bpm: f32) {
+        self.tempo_bpm = tempo_bpm;
+    }
+
+    fn set_control_sender(&mut self, sender: CtrSender) {
+        self.sender = Some(sender);
+    }
+
+    fn set_user_preset(&mut self, preset: usize) {
+        if preset < self.user_presets.len() {
+            self.enabled = self.user_presets[preset];
+            json_try! {
+                self.json_updates.push(("enabled".into(), serialize(self.enabled)?))
+            }
+        }
+    }
+
+    fn receive_midi_message(&mut self, message: &midi::Message) {
+        match message.kind {
+            midi::MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                self.channel = message.channel;
+                self.press(note, velocity);
+            }
+            midi::MessageKind::NoteOn { note, .. } | midi::MessageKind::NoteOff { note, .. } => {
+                self.release(note)
+            }
+            _ => {}
+        }
+    }
+
+    fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
+        match kind {
+            RequestKind::SetName(name) => cb(self.set_name(name)),
+            RequestKind::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RequestKind::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
+            RequestKind::SetInstrument(index) => cb(self.set_instrument(index)),
+            RequestKind::SetPattern(pattern) => cb(self.set_pattern(pattern)),
+            RequestKind::SetNumOctaves(num_octaves) => cb(self.set_num_octaves(num_octaves)),
+            RequestKind::SetGate(gate) => cb(self.set_gate(gate)),
+            RequestKind::SetLatch(flag) => cb(self.set_latch(flag)),
+            _ => cb(ResponseKind::Denied),
+        }
+    }
+
+    fn render_node_moved(&mut self, id: usize, new_id: usize) {
+        if self.instrument_index == Some(id) {
+            self.instrument_index = Some(new_id);
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "pattern": serialize(self.pattern)?,
+            "num_octaves": serialize(self.num_octaves)?,
+            "gate": serialize(self.gate)?,
+            "latch": serialize(self.latch)?,
+            "instrument_index": serialize(self.instrument_index)?,
+            "user_presets": serialize(&self.user_presets)?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "pattern", |v| self.pattern = v)?;
+        deser_field_opt(source, "num_octaves", |v: u8| {
+            self.num_octaves = v.clamp(1, MAX_NUM_OCTAVES)
+        })?;
+        deser_field_opt(source, "gate", |v: f32| self.gate = v.clamp(MIN_GATE, 1.0))?;
+        deser_field_opt(source, "latch", |v| self.latch = v)?;
+        deser_field_opt(source, "instrument_index", |v| self.instrument_index = v)?;
+        deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
+        Ok(())
+    }
+
+    fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>> {
+        if !self.json_updates.is_empty() {
+            let mut new_updates = Default::default();
+            mem::swap(&mut new_updates, &mut self.json_updates);
+            Some(new_updates)
+        } else {
+            None
+        }
+    }
+
+    fn clone_node(&self) -> ControlPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(notes: &[u8]) -> Vec<Note> {
+        notes
+            .iter()
+            .map(|&note| Note {
+                note,
+                velocity: 100,
+            })
+            .collect()
+    }
+
+    fn step_notes(steps: Vec<Vec<Note>>) -> Vec<Vec<u8>> {
+        steps
+            .into_iter()
+            .map(|s| s.into_iter().map(|n| n.note).collect())
+            .collect()
+    }
+
+    #[test]
+    fn patterns() {
+        let held = notes(&[64, 60, 67]);
+        let steps = |pattern, num_octaves| step_notes(arpeggio_steps(&held, pattern, num_octaves));
+        assert_eq!(steps(Pattern::Up, 1), vec![vec![60], vec![64], vec![67]]);
+        assert_eq!(steps(Pattern::Down, 1), vec![vec![67], vec![64], vec![60]]);
+        assert_eq!(
+            steps(Pattern::AsPlayed, 1),
+            vec![vec![64], vec![60], vec![67]]
+        );
+        assert_eq!(
+            steps(Pattern::UpDown, 2),
+            vec![
+                vec![60],
+                vec![64],
+                vec![67],
+                vec![72],
+                vec![76],
+                vec![79],
+                vec![76],
+                vec![72],
+                vec![67],
+                vec![64]
+            ]
+        );
+        assert_eq!(
+            steps(Pattern::Chord, 2),
+            vec![vec![60, 64, 67], vec![72, 76, 79]]
+        );
+    }
+
+    #[test]
+    fn latch() {
+        let mut node = Node::default();
+        node.set_latch(true);
+        node.press(60, 100);
+        node.press(64, 100);
+        node.release(60);
+        node.release(64);
+        assert_eq!(node.notes.len(), 2);
+        node.press(62, 100);
+        assert_eq!(node.notes, notes(&[62]));
+        node.set_latch(false);
+        node.release(62);
+        assert!(node.notes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn legato_note_offs() {
+        use crate::control::{create_control_channel, node::Control};
+
+        let (sender, mut receiver) = create_control_channel(16);
+        let mut node = Node::default();
+        node.set_control_sender(sender);
+        node.set_instrument(Some(0));
+        node.set_gate(1.0);
+        node.press(60, 100);
+        node.beat_tick(0, 0).await;
+        node.beat_tick(0, 1).await;
+
+        let mut kinds = vec![];
+        while let Ok(msg) = receiver.try_recv() {
+            kinds.push(msg.midi_msg.kind);
+        }
+        let on = midi::MessageKind::NoteOn {
+            note: 60,
+            velocity: 100,
+        };
+        let off = midi::MessageKind::NoteOff {
+            note: 60,
+            velocity: 0,
+        };
+        assert_eq!(kinds, vec![on, off, on]);
+    }
+}
@@ -1,17 +1,22 @@
 use super::{ControlPtr, RequestKind, ResponseCallback, ResponseKind};
 use crate::{
     control::{voices::Voices, ControlMessage, CtrSender},
+    error::{Error, ErrorCode},
     json::{
-        deser_field, deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate,
+        self, deser_field, deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate,
         SerializationResult,
     },
     json_try, midi,
+    parameter::{self, Parameter, Parameters, Value},
     path::VirtualPaths,
     rhythm::Rhythm,
 };
 use axum::async_trait;
 use serde_json::json;
-use std::{fs, mem, path::Path};
+use std::{
+    fs, mem,
+    path::{Path, PathBuf},
+};
 
 const DEFAULT_NAME: &str = "Drum Machine";
 
@@ -58,7 +63,7 @@ impl Node {
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid voice or slot")
         }
     }
 
@@ -78,7 +83,7 @@ impl Node {
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid voice or slot")
         }
     }
 
@@ -97,7 +102,7 @@ impl Node {
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid voice or slot")
         }
     }
 
@@ -108,7 +113,7 @@ impl Node {
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid voice or slot")
         }
     }
 
@@ -123,7 +128,7 @@ impl Node {
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid voice or slot")
         }
     }
 
@@ -134,7 +139,7 @@ impl Node {
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid voice or slot")
         }
     }
 
@@ -149,7 +154,7 @@ impl Node {
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid voice or slot")
         }
     }
 
@@ -191,41 +196,48 @@ impl Node {
     }
 
     fn load_preset_from_file(&mut self, path: &Path) -> ResponseKind {
-        if let Some(vp) = &self.virtual_paths {
-            if let Some(path) = vp.translate(path) {
-                if let Ok(file) = fs::read_to_string(path) {
-                    if let Ok(source) = serde_json::from_str(&file) {
-                        if self.deserialize_preset(&source).is_ok() {
-                            json_try! {
-                                self.json_updates.push(("voices".into(), serialize(&self.voices)?))
-                            }
-                            return ResponseKind::Ok;
-                        }
-                    }
-                }
-            }
+        if let Err(reason) = self.read_preset(path) {
+            return ResponseKind::Failed { reason };
+        }
+        json_try! {
+            self.json_updates.push(("voices".into(), serialize(&self.voices)?))
         }
-        ResponseKind::Failed
+        ResponseKind::Ok
     }
 
     fn save_preset_to_file(&self, path: &Path) -> ResponseKind {
-        if let Some(vp) = &self.virtual_paths {
-            if let Some(path) = vp.translate(path) {
-                if let Ok(source) = self.serialize_preset() {
-                    if let Ok(source) = serde_json::to_string_pretty(&source) {
-                        if fs::write(path, source).is_ok() {
-                            return ResponseKind::Ok;
-                        }
-                    }
-                }
-            }
+        match self.write_preset(path) {
+            Ok(()) => ResponseKind::Ok,
+            Err(reason) => ResponseKind::Failed { reason },
         }
-        ResponseKind::Failed
+    }
+
+    fn read_preset(&mut self, path: &Path) -> Result<(), Error> {
+        let file = fs::read_to_string(self.translate_path(path)?)?;
+        let source = serde_json::from_str(&file).map_err(json::Error::from)?;
+        Ok(self.deserialize_preset(&source)?)
+    }
+
+    fn write_preset(&self, path: &Path) -> Result<(), Error> {
+        let to_error = |e: json::Error| Error::new(ErrorCode::Serialization, e.0);
+        let source = self.serialize_preset().map_err(to_error)?;
+        let source = serde_json::to_string_pretty(&source).map_err(|e| to_error(e.into()))?;
+        Ok(fs::write(self.translate_path(path)?, source)?)
+    }
+
+    fn translate_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        self.virtual_paths
+            .as_ref()
+            .and_then(|vp| vp.translate(path))
+            .ok_or_else(|| Error::invalid_path(path))
     }
 
     fn set_user_preset_enabled(&mut self, preset: usize, flag: bool) -> ResponseKind {
         if preset >= self.user_presets.len() {
-            ResponseKind::Failed
+            ResponseKind::failed(
+                ErrorCode::InvalidValue,
+                format!("Invalid user preset: {preset}"),
+            )
         } else {
             self.user_presets[preset] = flag;
             json_try! {
@@ -285,6 +297,27 @@ impl Clone for Node {
     }
 }
 
+impl Parameters for Node {
+    fn parameters(&self) -> Vec<Parameter> {
+        vec![Parameter::bool("enabled", true)]
+    }
+
+    fn parameter(&self, id: &str) -> Option<Value> {
+        match id {
+            "enabled" => Some(Value::Bool(self.enabled)),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: Value) -> parameter::SetResult {
+        let res = match id {
+            "enabled" => self.set_enabled(value.as_bool()),
+            _ => return Err(parameter::Error::UnknownParameter),
+        };
+        parameter::result(res)
+    }
+}
+
 #[async_trait]
 impl super::Control for Node {
     fn reset(&mut self) {}
@@ -350,6 +383,7 @@ impl super::Control for Node {
             RequestKind::SetVoiceVelocity(i, v) => cb(self.set_voice_velocity(i, v)),
             RequestKind::SetVoiceChannel(i, c) => cb(self.set_voice_channel(i, c)),
             RequestKind::SetSlot(vi, si, slot) => cb(self.set_slot(vi, si, slot)),
+            _ => cb(ResponseKind::Denied),
         }
     }
 
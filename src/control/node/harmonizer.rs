@@ -0,0 +1,590 @@
+use super::{ControlPtr, RequestKind, ResponseCallback, ResponseKind};
+use crate::{
+    control::{
+        harmony::{self, Scale, Voicing},
+        ControlMessage, CtrSender,
+    },
+    error::ErrorCode,
+    json::{
+        self, deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate,
+        SerializationResult,
+    },
+    json_try, midi,
+    parameter::{self, Parameter, Parameters, Unit, Value},
+    path::VirtualPaths,
+    rhythm::Rhythm,
+};
+use axum::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::HashMap, mem};
+
+const DEFAULT_NAME: &str = "Harmonizer";
+const MAX_NUM_VOICES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "HarmonizerMode")]
+pub enum Mode {
+    // every note plays the stored chord shifted to it
+    ChordMemory,
+    // every note is harmonized in the selected key and scale
+    Diatonic,
+}
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    mode: Mode,
+    targets: Vec<usize>,
+    // semitones relative to the played note
+    chord: Vec<i8>,
+    key: u8,
+    scale: Scale,
+    // scale degrees relative to the played note
+    degrees: Vec<i8>,
+    voicing: Voicing,
+    inversion: u8,
+    // velocity multiplier of each voice, the first one is the played note
+    velocity_scales: Vec<f32>,
+    sender: Option<CtrSender>,
+    // sent by update, which waits for room in the channel so that no note-off is dropped
+    outbox: Vec<ControlMessage>,
+    held: Vec<u8>,
+    // notes which were sent for each of the played notes
+    sounding: HashMap<(u8, u8), Vec<(usize, u8)>>,
+    user_presets: Vec<bool>,
+    json_updates: Vec<JsonFieldUpdate>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: String) -> ResponseKind {
+        self.name = name;
+        json_try! {
+            self.json_updates.push(("name".to_owned(), serialize(&self.name)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> ResponseKind {
+        self.enabled = flag;
+        json_try! {
+            self.json_updates.push(("enabled".to_owned(), serialize(flag)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_targets(&mut self, targets: Vec<usize>) -> ResponseKind {
+        self.targets = targets;
+        json_try! {
+            self.json_updates.push(("targets".into(), serialize(&self.targets)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_mode(&mut self, mode: Mode) -> ResponseKind {
+        self.mode = mode;
+        json_try! {
+            self.json_updates.push(("mode".into(), serialize(mode)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_chord(&mut self, chord: Vec<i8>) -> ResponseKind {
+        if !is_valid_chord(&chord) {
+            return ResponseKind::failed(ErrorCode::InvalidValue, invalid_chord_reason());
+        }
+        self.chord = chord;
+        json_try! {
+            self.json_updates.push(("chord".into(), serialize(&self.chord)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn learn_chord(&mut self) -> ResponseKind {
+        let Some(lowest) = self.held.iter().min().copied() else {
+            return ResponseKind::failed(ErrorCode::InvalidState, "No notes are held");
+        };
+        let mut chord: Vec<i8> = self
+            .held
+            .iter()
+            .map(|note| (*note - lowest) as i8)
+            .collect();
+        chord.sort();
+        self.set_chord(chord)
+    }
+
+    fn set_key(&mut self, key: u8) -> ResponseKind {
+        if key >= 12 {
+            return ResponseKind::failed(ErrorCode::InvalidValue, format!("Invalid key: {key}"));
+        }
+        self.key = key;
+        json_try! {
+            self.json_updates.push(("key".into(), serialize(key)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_scale(&mut self, scale: Scale) -> ResponseKind {
+        self.scale = scale;
+        json_try! {
+            self.json_updates.push(("scale".into(), serialize(scale)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_degrees(&mut self, degrees: Vec<i8>) -> ResponseKind {
+        if !are_valid_degrees(&degrees) {
+            return ResponseKind::failed(ErrorCode::InvalidValue, invalid_degrees_reason());
+        }
+        self.degrees = degrees;
+        json_try! {
+            self.json_updates.push(("degrees".into(), serialize(&self.degrees)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_voicing(&mut self, voicing: Voicing) -> ResponseKind {
+        self.voicing = voicing;
+        json_try! {
+            self.json_updates.push(("voicing".into(), serialize(voicing)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_inversion(&mut self, inversion: u8) -> ResponseKind {
+        self.inversion = inversion;
+        json_try! {
+            self.json_updates.push(("inversion".into(), serialize(inversion)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_voice_velocity_scale(&mut self, voice: usize, scale: f32) -> ResponseKind {
+        if voice >= MAX_NUM_VOICES || !scale.is_finite() || scale < 0.0 {
+            let reason = "Invalid voice or velocity scale";
+            return ResponseKind::failed(ErrorCode::InvalidValue, reason);
+        }
+        self.velocity_scales[voice] = scale;
+        json_try! {
+            self.json_updates.push(("velocity_scales".into(), serialize(&self.velocity_scales)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_user_preset_enabled(&mut self, preset: usize, flag: bool) -> ResponseKind {
+        if preset >= self.user_presets.len() {
+            ResponseKind::failed(
+                ErrorCode::InvalidValue,
+                format!("Invalid user preset: {preset}"),
+            )
+        } else {
+            self.user_presets[preset] = flag;
+            json_try! {
+                self.json_updates.push(("user_presets".into(), serialize(&self.user_presets)?))
+            }
+            ResponseKind::Ok
+        }
+    }
+
+    fn harmonize(&self, note: u8, velocity: u8) -> Vec<(u8, u8)> {
+        let note = note as i32;
+        let mut voices: Vec<(i32, usize)> = match self.mode {
+            Mode::ChordMemory => self
+                .chord
+                .iter()
+                .enumerate()
+                .map(|(i, interval)| (note + *interval as i32, i))
+                .collect(),
+            Mode::Diatonic => std::iter::once((note, 0))
+                .chain(self.degrees.iter().enumerate().map(|(i, degree)| {
+                    let transposed =
+                        harmony::diatonic_transpose(note, self.key, self.scale, *degree as i32);
+                    (transposed, i + 1)
+                }))
+                .collect(),
+        };
+        harmony::apply_voicing(&mut voices, self.voicing, self.inversion);
+
+        let mut result: Vec<(u8, u8)> = vec![];
+        for (note, voice) in voices {
+            if !(0..=127).contains(&note) || result.iter().any(|(n, _)| *n as i32 == note) {
+                continue;
+            }
+            let scale = self.velocity_scales.get(voice).copied().unwrap_or(1.0);
+            let velocity = (velocity as f32 * scale).round().clamp(0.0, 127.0) as u8;
+            if velocity > 0 {
+                result.push((note as u8, velocity));
+            }
+        }
+        result
+    }
+
+    fn send(&mut self, instrument_id: usize, midi_msg: midi::Message) {
+        if self.sender.is_some() {
+            self.outbox.push(ControlMessage {
+                instrument_id,
+                midi_msg,
+            });
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        self.note_off(channel, note);
+        let mut sent = vec![];
+        for (n, v) in self.harmonize(note, velocity) {
+            for instrument_id in self.targets.clone() {
+                self.send(
+                    instrument_id,
+                    midi::Message {
+                        kind: midi::MessageKind::NoteOn {
+                            note: n,
+                            velocity: v,
+                        },
+                        channel,
+                    },
+                );
+                sent.push((instrument_id, n));
+            }
+        }
+        self.sounding.insert((channel, note), sent);
+    }
+
+    fn note_off(&mut self, channel: u8, note: u8) {
+        if let Some(sent) = self.sounding.remove(&(channel, note)) {
+            for (instrument_id, n) in sent {
+                self.send(
+                    instrument_id,
+                    midi::Message {
+                        kind: midi::MessageKind::NoteOff {
+                            note: n,
+                            velocity: 0,
+                        },
+                        channel,
+                    },
+                );
+            }
+        }
+    }
+}
+
+fn is_valid_chord(chord: &[i8]) -> bool {
+    !chord.is_empty() && chord.len() <= MAX_NUM_VOICES
+}
+
+fn invalid_chord_reason() -> String {
+    format!("A chord has between 1 and {MAX_NUM_VOICES} notes")
+}
+
+// The played note takes one of the voices
+fn are_valid_degrees(degrees: &[i8]) -> bool {
+    degrees.len() < MAX_NUM_VOICES
+}
+
+fn invalid_degrees_reason() -> String {
+    format!("At most {} degrees can be added", MAX_NUM_VOICES - 1)
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            mode: Mode::Diatonic,
+            targets: vec![],
+            chord: vec![0, 4, 7],
+            key: 0,
+            scale: Scale::Major,
+            degrees: vec![2],
+            voicing: Voicing::Close,
+            inversion: 0,
+            velocity_scales: vec![1.0; MAX_NUM_VOICES],
+            sender: None,
+            outbox: vec![],
+            held: vec![],
+            sounding: HashMap::new(),
+            user_presets: vec![true; super::NUM_USER_PRESETS],
+            json_updates: Default::default(),
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            mode: self.mode,
+            targets: self.targets.clone(),
+            chord: self.chord.clone(),
+            key: self.key,
+            scale: self.scale,
+            degrees: self.degrees.clone(),
+            voicing: self.voicing,
+            inversion: self.inversion,
+            velocity_scales: self.velocity_scales.clone(),
+            sender: None,
+            outbox: vec![],
+            held: vec![],
+            sounding: HashMap::new(),
+            user_presets: self.user_presets.clone(),
+            json_updates: Default::default(),
+        }
+    }
+}
+
+impl Parameters for Node {
+    fn parameters(&self) -> Vec<Parameter> {
+        vec![
+            Parameter::bool("enabled", true),
+            Parameter::int("key", 0, 11, 0, Unit::Semitones),
+            Parameter::int("inversion", 0, MAX_NUM_VOICES as i32 - 1, 0, Unit::None),
+        ]
+    }
+
+    fn parameter(&self, id: &str) -> Option<Value> {
+        match id {
+            "enabled" => Some(Value::Bool(self.enabled)),
+            "key" => Some(Value::Int(self.key as i32)),
+            "inversion" => Some(Value::Int(self.inversion as i32)),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: Value) -> parameter::SetResult {
+        let res = match id {
+            "enabled" => self.set_enabled(value.as_bool()),
+            "key" => self.set_key(value.as_i32() as u8),
+            "inversion" => self.set_inversion(value.as_i32() as u8),
+            _ => return Err(parameter::Error::UnknownParameter),
+        };
+        parameter::result(res)
+    }
+}
+
+#[async_trait]
+impl super::Control for Node {
+    fn reset(&mut self) {}
+
+    async fn beat_tick(&mut self, _beat_num: u8, _div_num: u8) {}
+
+    async fn update(&mut self) {
+        let Some(sender) = self.sender.clone() else {
+            return;
+        };
+        for msg in mem::take(&mut self.outbox) {
+            _ = sender.send(msg).await;
+        }
+    }
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    fn set_rhythm(&mut self, _rhythm: Rhythm) {}
+
+    fn set_tempo_bpm(&mut self, _tempo_bpm: f32) {}
+
+    fn set_control_sender(&mut self, sender: CtrSender) {
+        self.sender = Some(sender);
+    }
+
+    fn set_user_preset(&mut self, preset: usize) {
+        if preset < self.user_presets.len() {
+            self.enabled = self.user_presets[preset];
+            json_try! {
+                self.json_updates.push(("enabled".into(), serialize(self.enabled)?))
+            }
+        }
+    }
+
+    fn receive_midi_message(&mut self, message: &midi::Message) {
+        match message.kind {
+            midi::MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                self.held.retain(|n| *n != note);
+                self.held.push(note);
+                if self.enabled {
+                    self.note_on(message.channel, note, velocity);
+                }
+            }
+            midi::MessageKind::NoteOn { note, .. } | midi::MessageKind::NoteOff { note, .. } => {
+                self.held.retain(|n| *n != note);
+                // released even when disabled, so that no note gets stuck
+                self.note_off(message.channel, note);
+            }
+            // the other messages already reach the instruments unchanged
+            _ => {}
+        }
+    }
+
+    fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
+        match kind {
+            RequestKind::SetName(name) => cb(self.set_name(name)),
+            RequestKind::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RequestKind::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
+            RequestKind::SetTargets(targets) => cb(self.set_targets(targets)),
+            RequestKind::SetHarmonyMode(mode) => cb(self.set_mode(mode)),
+            RequestKind::SetChord(chord) => cb(self.set_chord(chord)),
+            RequestKind::LearnChord => cb(self.learn_chord()),
+            RequestKind::SetKey(key) => cb(self.set_key(key)),
+            RequestKind::SetScale(scale) => cb(self.set_scale(scale)),
+            RequestKind::SetDegrees(degrees) => cb(self.set_degrees(degrees)),
+            RequestKind::SetVoicing(voicing) => cb(self.set_voicing(voicing)),
+            RequestKind::SetInversion(inversion) => cb(self.set_inversion(inversion)),
+            RequestKind::SetVoiceVelocityScale(v, s) => cb(self.set_voice_velocity_scale(v, s)),
+            _ => cb(ResponseKind::Denied),
+        }
+    }
+
+    fn render_node_moved(&mut self, id: usize, new_id: usize) {
+        for target in &mut self.targets {
+            if *target == id {
+                *target = new_id;
+            }
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "mode": serialize(self.mode)?,
+            "targets": serialize(&self.targets)?,
+            "chord": serialize(&self.chord)?,
+            "key": serialize(self.key)?,
+            "scale": serialize(self.scale)?,
+            "degrees": serialize(&self.degrees)?,
+            "voicing": serialize(self.voicing)?,
+            "inversion": serialize(self.inversion)?,
+            "velocity_scales": serialize(&self.velocity_scales)?,
+            "user_presets": serialize(&self.user_presets)?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        let mut chord = None;
+        deser_field_opt(source, "chord", |v: Vec<i8>| chord = Some(v))?;
+        let mut degrees = None;
+        deser_field_opt(source, "degrees", |v: Vec<i8>| degrees = Some(v))?;
+        if chord.as_ref().is_some_and(|chord| !is_valid_chord(chord)) {
+            return Err(json::Error(invalid_chord_reason()));
+        }
+        if degrees
+            .as_ref()
+            .is_some_and(|degrees| !are_valid_degrees(degrees))
+        {
+            return Err(json::Error(invalid_degrees_reason()));
+        }
+        if let Some(chord) = chord {
+            self.chord = chord;
+        }
+        if let Some(degrees) = degrees {
+            self.degrees = degrees;
+        }
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "mode", |v| self.mode = v)?;
+        deser_field_opt(source, "targets", |v| self.targets = v)?;
+        deser_field_opt(source, "key", |v: u8| self.key = v % 12)?;
+        deser_field_opt(source, "scale", |v| self.scale = v)?;
+        deser_field_opt(source, "voicing", |v| self.voicing = v)?;
+        deser_field_opt(source, "inversion", |v| self.inversion = v)?;
+        deser_field_opt(source, "velocity_scales", |v| self.velocity_scales = v)?;
+        deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
+        self.velocity_scales.resize(MAX_NUM_VOICES, 1.0);
+        Ok(())
+    }
+
+    fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>> {
+        if !self.json_updates.is_empty() {
+            let mut new_updates = Default::default();
+            mem::swap(&mut new_updates, &mut self.json_updates);
+            Some(new_updates)
+        } else {
+            None
+        }
+    }
+
+    fn clone_node(&self) -> ControlPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{create_control_channel, node::Control};
+
+    #[test]
+    fn chord_validation() {
+        let mut node = Node::default();
+        assert!(node.deserialize(&json!({ "chord": [] })).is_err());
+        assert!(node.deserialize(&json!({ "chord": vec![0; 9] })).is_err());
+        assert!(node.deserialize(&json!({ "chord": [0, 3, 7] })).is_ok());
+        assert_eq!(node.chord, vec![0, 3, 7]);
+    }
+
+    #[test]
+    fn degrees_validation() {
+        let mut node = Node::default();
+        let degrees = vec![2; MAX_NUM_VOICES];
+        assert!(node.deserialize(&json!({ "degrees": degrees })).is_err());
+        assert_eq!(node.degrees, vec![2]);
+        let degrees = vec![2; MAX_NUM_VOICES - 1];
+        assert!(node.deserialize(&json!({ "degrees": degrees })).is_ok());
+        assert_eq!(node.degrees.len(), MAX_NUM_VOICES - 1);
+    }
+
+    #[tokio::test]
+    async fn note_offs_wait_for_room() {
+        let (sender, mut receiver) = create_control_channel(1);
+        let mut node = Node::default();
+        node.set_control_sender(sender);
+        node.set_targets(vec![1]);
+        let note = |kind| midi::Message { kind, channel: 0 };
+        node.receive_midi_message(&note(midi::MessageKind::NoteOn {
+            note: 60,
+            velocity: 100,
+        }));
+        node.receive_midi_message(&note(midi::MessageKind::NoteOff {
+            note: 60,
+            velocity: 0,
+        }));
+
+        let receive = async {
+            let mut kinds = vec![];
+            for _ in 0..4 {
+                kinds.push(receiver.recv().await.unwrap().midi_msg.kind);
+            }
+            kinds
+        };
+        let ((), kinds) = tokio::join!(node.update(), receive);
+        let offs = kinds
+            .iter()
+            .filter(|kind| matches!(kind, midi::MessageKind::NoteOff { .. }))
+            .count();
+        assert_eq!(offs, 2);
+    }
+
+    #[tokio::test]
+    async fn forwards_only_notes() {
+        let (sender, mut receiver) = create_control_channel(16);
+        let mut node = Node::default();
+        node.set_control_sender(sender);
+        node.set_targets(vec![1]);
+        node.receive_midi_message(&midi::Message {
+            kind: midi::MessageKind::ProgramChange { program: 3 },
+            channel: 0,
+        });
+        node.update().await;
+        assert!(receiver.try_recv().is_err());
+        node.receive_midi_message(&midi::Message {
+            kind: midi::MessageKind::NoteOn {
+                note: 60,
+                velocity: 100,
+            },
+            channel: 0,
+        });
+        node.update().await;
+        assert_eq!(receiver.try_recv().map(|m| m.instrument_id), Ok(1));
+    }
+}
@@ -0,0 +1,489 @@
+use super::{ControlPtr, RequestKind, ResponseCallback, ResponseKind};
+use crate::{
+    control::{take::Take, ControlMessage, CtrSender},
+    error::{Error, ErrorCode},
+    json::{
+        deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate, SerializationResult,
+    },
+    json_try, midi,
+    parameter::{self, Parameter, Parameters, Unit, Value},
+    path::VirtualPaths,
+    rhythm::Rhythm,
+};
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{mem, path::Path, time::Instant};
+
+const DEFAULT_NAME: &str = "Looper";
+const DEFAULT_NUM_BARS: usize = 4;
+const DEFAULT_TEMPO_BPM: f32 = 90.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum State {
+    Stopped,
+    Recording,
+    Playing,
+    Overdubbing,
+}
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    state: State,
+    num_bars: usize,
+    instrument_index: Option<usize>,
+    take: Take,
+    sender: Option<CtrSender>,
+    virtual_paths: Option<VirtualPaths>,
+    rhythm: Rhythm,
+    tempo_bpm: f32,
+    bar: Option<usize>,
+    position: usize,
+    pass_length: usize,
+    last_tick: Instant,
+    sounding: Vec<midi::Message>,
+    user_presets: Vec<bool>,
+    json_updates: Vec<JsonFieldUpdate>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: String) -> ResponseKind {
+        self.name = name;
+        json_try! {
+            self.json_updates.push(("name".to_owned(), serialize(&self.name)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> ResponseKind {
+        self.enabled = flag;
+        if !flag {
+            self.release_sounding();
+        }
+        json_try! {
+            self.json_updates.push(("enabled".to_owned(), serialize(flag)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_instrument(&mut self, instrument_index: Option<usize>) -> ResponseKind {
+        self.release_sounding();
+        self.instrument_index = instrument_index;
+        json_try! {
+            self.json_updates.push(("instrument_index".into(), serialize(instrument_index)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_num_bars(&mut self, num_bars: usize) -> ResponseKind {
+        if num_bars == 0 {
+            return ResponseKind::failed(ErrorCode::InvalidValue, "A loop has at least one bar");
+        }
+        self.num_bars = num_bars;
+        self.update_num_slots();
+        json_try! {
+            self.json_updates.push(("num_bars".into(), serialize(num_bars)?))
+        }
+        self.push_take_update();
+        ResponseKind::Ok
+    }
+
+    fn start_recording(&mut self) -> ResponseKind {
+        self.release_sounding();
+        self.take.clear();
+        self.take.begin_layer();
+        self.pass_length = 0;
+        self.set_state(State::Recording);
+        self.push_take_update();
+        ResponseKind::Ok
+    }
+
+    fn start_overdub(&mut self) -> ResponseKind {
+        self.take.begin_layer();
+        self.pass_length = 0;
+        self.set_state(State::Overdubbing);
+        ResponseKind::Ok
+    }
+
+    fn start_playback(&mut self) -> ResponseKind {
+        self.take.commit_layer();
+        self.set_state(State::Playing);
+        self.push_take_update();
+        ResponseKind::Ok
+    }
+
+    fn stop(&mut self) -> ResponseKind {
+        self.take.commit_layer();
+        self.release_sounding();
+        self.set_state(State::Stopped);
+        self.push_take_update();
+        ResponseKind::Ok
+    }
+
+    fn undo_layer(&mut self) -> ResponseKind {
+        if self.take.is_recording() {
+            self.take.discard_layer();
+            if self.state == State::Overdubbing {
+                self.take.begin_layer();
+                self.pass_length = 0;
+            } else {
+                self.set_state(State::Stopped);
+            }
+        } else if !self.take.undo() {
+            return ResponseKind::failed(ErrorCode::InvalidState, "There is no layer to undo");
+        }
+        self.release_sounding();
+        self.push_take_update();
+        ResponseKind::Ok
+    }
+
+    fn clear_take(&mut self) -> ResponseKind {
+        self.take.clear();
+        self.release_sounding();
+        self.set_state(State::Stopped);
+        self.push_take_update();
+        ResponseKind::Ok
+    }
+
+    // The file is written on a blocking thread, cb is called once it is done
+    fn export_midi_file(&self, path: &Path, cb: ResponseCallback) {
+        let Some(file) = self
+            .virtual_paths
+            .as_ref()
+            .and_then(|vp| vp.translate(path))
+        else {
+            return cb(ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            });
+        };
+        let smf = self.take.to_smf(self.tempo_bpm, self.rhythm);
+        tokio::task::spawn_blocking(move || {
+            cb(match smf.save(file) {
+                Ok(()) => ResponseKind::Ok,
+                Err(e) => ResponseKind::Failed { reason: e.into() },
+            })
+        });
+    }
+
+    fn set_user_preset_enabled(&mut self, preset: usize, flag: bool) -> ResponseKind {
+        if preset >= self.user_presets.len() {
+            ResponseKind::failed(
+                ErrorCode::InvalidValue,
+                format!("Invalid user preset: {preset}"),
+            )
+        } else {
+            self.user_presets[preset] = flag;
+            json_try! {
+                self.json_updates.push(("user_presets".into(), serialize(&self.user_presets)?))
+            }
+            ResponseKind::Ok
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        json_try! {
+            self.json_updates.push(("state".into(), serialize(state)?))
+        }
+    }
+
+    fn push_take_update(&mut self) {
+        json_try! {
+            self.json_updates.push(("take".into(), serialize(&self.take)?))
+        }
+    }
+
+    fn update_num_slots(&mut self) {
+        self.take
+            .set_num_slots(self.num_bars * self.rhythm.num_slots());
+        self.bar = self.bar.map(|b| b.min(self.num_bars - 1));
+        self.position %= self.take.num_slots().max(1);
+    }
+
+    fn period(&self) -> f32 {
+        60.0 / (self.tempo_bpm * self.rhythm.num_divs as f32)
+    }
+
+    // Quantise the moment of the message to the nearest slot of the loop
+    fn current_slot(&self) -> usize {
+        let elapsed = self.last_tick.elapsed().as_secs_f32();
+        if elapsed >= self.period() / 2.0 {
+            self.position + 1
+        } else {
+            self.position
+        }
+    }
+
+    fn advance_position(&mut self, beat_num: u8, div_num: u8) {
+        let slot_in_bar = beat_num as usize * self.rhythm.num_divs as usize + div_num as usize;
+        if slot_in_bar == 0 {
+            self.bar = Some(self.bar.map(|b| (b + 1) % self.num_bars).unwrap_or(0));
+        }
+        self.position = self.bar.unwrap_or(0) * self.rhythm.num_slots() + slot_in_bar;
+        self.last_tick = Instant::now();
+    }
+
+    fn finish_pass_if_complete(&mut self) {
+        if !self.take.is_recording() {
+            return;
+        }
+        self.pass_length += 1;
+        if self.pass_length <= self.take.num_slots() {
+            return;
+        }
+        self.pass_length = 1;
+        match self.state {
+            State::Recording => {
+                self.take.commit_layer();
+                self.set_state(State::Playing);
+            }
+            State::Overdubbing => self.take.commit_pass(),
+            _ => {}
+        }
+        self.push_take_update();
+    }
+
+    async fn play_position(&mut self) {
+        let (Some(sender), Some(instrument_id)) = (self.sender.clone(), self.instrument_index)
+        else {
+            return;
+        };
+        let messages: Vec<midi::Message> = self
+            .take
+            .events_at(self.position)
+            .map(|e| e.message)
+            .collect();
+        for midi_msg in messages {
+            _ = sender
+                .send(ControlMessage {
+                    instrument_id,
+                    midi_msg,
+                })
+                .await;
+            self.track_sounding(midi_msg);
+        }
+    }
+
+    fn track_sounding(&mut self, msg: midi::Message) {
+        match msg.kind {
+            midi::MessageKind::NoteOn { velocity, .. } if velocity > 0 => self.sounding.push(msg),
+            midi::MessageKind::NoteOn { note, .. } | midi::MessageKind::NoteOff { note, .. } => {
+                self.sounding.retain(|m| {
+                    !(m.channel == msg.channel
+                        && matches!(m.kind, midi::MessageKind::NoteOn { note: n, .. } if n == note))
+                })
+            }
+            _ => {}
+        }
+    }
+
+    fn release_sounding(&mut self) {
+        let sounding = mem::take(&mut self.sounding);
+        if let (Some(sender), Some(instrument_id)) = (&self.sender, self.instrument_index) {
+            for msg in sounding {
+                if let midi::MessageKind::NoteOn { note, .. } = msg.kind {
+                    _ = sender.try_send(ControlMessage {
+                        instrument_id,
+                        midi_msg: midi::Message {
+                            kind: midi::MessageKind::NoteOff { note, velocity: 0 },
+                            channel: msg.channel,
+                        },
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        let rhythm = Rhythm::default();
+        let mut take = Take::default();
+        take.set_num_slots(DEFAULT_NUM_BARS * rhythm.num_slots());
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            state: State::Stopped,
+            num_bars: DEFAULT_NUM_BARS,
+            instrument_index: None,
+            take,
+            sender: None,
+            virtual_paths: None,
+            rhythm,
+            tempo_bpm: DEFAULT_TEMPO_BPM,
+            bar: None,
+            position: 0,
+            pass_length: 0,
+            last_tick: Instant::now(),
+            sounding: vec![],
+            user_presets: vec![true; super::NUM_USER_PRESETS],
+            json_updates: Default::default(),
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        let mut take = self.take.clone();
+        take.discard_layer();
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            state: State::Stopped,
+            num_bars: self.num_bars,
+            instrument_index: self.instrument_index,
+            take,
+            sender: None,
+            virtual_paths: None,
+            rhythm: self.rhythm,
+            tempo_bpm: self.tempo_bpm,
+            bar: None,
+            position: 0,
+            pass_length: 0,
+            last_tick: Instant::now(),
+            sounding: vec![],
+            user_presets: self.user_presets.clone(),
+            json_updates: Default::default(),
+        }
+    }
+}
+
+impl Parameters for Node {
+    fn parameters(&self) -> Vec<Parameter> {
+        vec![
+            Parameter::bool("enabled", true),
+            Parameter::int("num_bars", 1, 64, DEFAULT_NUM_BARS as i32, Unit::Bars),
+        ]
+    }
+
+    fn parameter(&self, id: &str) -> Option<Value> {
+        match id {
+            "enabled" => Some(Value::Bool(self.enabled)),
+            "num_bars" => Some(Value::Int(self.num_bars as i32)),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: Value) -> parameter::SetResult {
+        let res = match id {
+            "enabled" => self.set_enabled(value.as_bool()),
+            "num_bars" => self.set_num_bars(value.as_i32() as usize),
+            _ => return Err(parameter::Error::UnknownParameter),
+        };
+        parameter::result(res)
+    }
+}
+
+#[async_trait]
+impl super::Control for Node {
+    fn reset(&mut self) {
+        self.bar = None;
+        self.position = 0;
+        self.last_tick = Instant::now();
+    }
+
+    async fn beat_tick(&mut self, beat_num: u8, div_num: u8) {
+        self.advance_position(beat_num, div_num);
+        self.finish_pass_if_complete();
+        if self.enabled && self.state != State::Stopped {
+            self.play_position().await;
+        }
+    }
+
+    fn set_virtual_paths(&mut self, vp: VirtualPaths) {
+        self.virtual_paths = Some(vp);
+    }
+
+    fn set_rhythm(&mut self, rhythm: Rhythm) {
+        self.rhythm = rhythm;
+        self.update_num_slots();
+        self.push_take_update();
+    }
+
+    fn set_tempo_bpm(&mut self, tempo_bpm: f32) {
+        self.tempo_bpm = tempo_bpm;
+    }
+
+    fn set_control_sender(&mut self, sender: CtrSender) {
+        self.sender = Some(sender);
+    }
+
+    fn set_user_preset(&mut self, preset: usize) {
+        if preset < self.user_presets.len() {
+            self.enabled = self.user_presets[preset];
+            json_try! {
+                self.json_updates.push(("enabled".into(), serialize(self.enabled)?))
+            }
+        }
+    }
+
+    fn receive_midi_message(&mut self, message: &midi::Message) {
+        if self.enabled && self.take.is_recording() {
+            self.take.record(self.current_slot(), *message);
+        }
+    }
+
+    fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
+        match kind {
+            RequestKind::SetName(name) => cb(self.set_name(name)),
+            RequestKind::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RequestKind::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
+            RequestKind::SetInstrument(index) => cb(self.set_instrument(index)),
+            RequestKind::SetNumBars(num_bars) => cb(self.set_num_bars(num_bars)),
+            RequestKind::StartRecording => cb(self.start_recording()),
+            RequestKind::StartOverdub => cb(self.start_overdub()),
+            RequestKind::StartPlayback => cb(self.start_playback()),
+            RequestKind::Stop => cb(self.stop()),
+            RequestKind::UndoLayer => cb(self.undo_layer()),
+            RequestKind::ClearTake => cb(self.clear_take()),
+            RequestKind::ExportMidiFile(path) => self.export_midi_file(&path, cb),
+            _ => cb(ResponseKind::Denied),
+        }
+    }
+
+    fn render_node_moved(&mut self, id: usize, new_id: usize) {
+        if self.instrument_index == Some(id) {
+            self.instrument_index = Some(new_id);
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "state": serialize(self.state)?,
+            "num_bars": serialize(self.num_bars)?,
+            "instrument_index": serialize(self.instrument_index)?,
+            "take": serialize(&self.take)?,
+            "user_presets": serialize(&self.user_presets)?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "num_bars", |v| self.num_bars = v)?;
+        deser_field_opt(source, "instrument_index", |v| self.instrument_index = v)?;
+        deser_field_opt(source, "take", |v| self.take = v)?;
+        deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
+        self.num_bars = self.num_bars.max(1);
+        self.update_num_slots();
+        Ok(())
+    }
+
+    fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>> {
+        if !self.json_updates.is_empty() {
+            let mut new_updates = Default::default();
+            mem::swap(&mut new_updates, &mut self.json_updates);
+            Some(new_updates)
+        } else {
+            None
+        }
+    }
+
+    fn clone_node(&self) -> ControlPtr {
+        Box::new(self.clone())
+    }
+}
@@ -1,21 +1,31 @@
-use super::CtrSender;
+use super::{
+    harmony::{Scale, Voicing},
+    CtrSender,
+};
 use crate::{
-    json::{DeserializationResult, JsonFieldUpdate, SerializationResult},
+    error::{Error, ErrorCode},
+    json::{self, DeserializationResult, JsonFieldUpdate, SerializationResult},
     midi,
+    parameter::{self, Parameters},
     path::VirtualPaths,
     rhythm::Rhythm,
 };
 use async_trait::async_trait;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+pub mod arpeggiator;
 pub mod drum_machine;
+pub mod harmonizer;
+pub mod looper;
 
 pub type ResponseCallback = Box<dyn FnOnce(ResponseKind) + 'static + Send + Sync>;
 
 pub const NUM_USER_PRESETS: usize = 16;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "ControlNodeRequestKind")]
 pub enum RequestKind {
     SetName(String),
     SetEnabled(bool),
@@ -31,20 +41,60 @@ pub enum RequestKind {
     SetVoiceVelocity(usize, u8),
     SetVoiceChannel(usize, u8),
     SetSlot(usize, usize, bool),
+    SetInstrument(Option<usize>),
+    SetNumBars(usize),
+    StartRecording,
+    StartOverdub,
+    StartPlayback,
+    Stop,
+    UndoLayer,
+    ClearTake,
+    ExportMidiFile(PathBuf),
+    SetPattern(arpeggiator::Pattern),
+    SetNumOctaves(u8),
+    SetGate(f32),
+    SetLatch(bool),
+    SetTargets(Vec<usize>),
+    SetHarmonyMode(harmonizer::Mode),
+    SetChord(Vec<i8>),
+    LearnChord,
+    SetKey(u8),
+    SetScale(Scale),
+    SetDegrees(Vec<i8>),
+    SetVoicing(Voicing),
+    SetInversion(u8),
+    SetVoiceVelocityScale(usize, f32),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "ControlNodeResponseKind")]
 pub enum ResponseKind {
     InvalidId,
     Denied,
-    Failed,
+    Failed { reason: Error },
     Ok,
 }
 
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+impl parameter::Response for ResponseKind {
+    fn succeeded(&self) -> bool {
+        *self == Self::Ok
+    }
+}
+
 #[async_trait]
-pub trait Control: Sync + Send {
+pub trait Control: Parameters + Sync + Send {
     fn reset(&mut self);
     async fn beat_tick(&mut self, beat_num: u8, div_num: u8);
+    // Called on every update of the controller, also between the beat ticks
+    async fn update(&mut self) {}
     fn set_virtual_paths(&mut self, vp: VirtualPaths);
     fn set_rhythm(&mut self, rhythm: Rhythm);
     fn set_tempo_bpm(&mut self, tempo_bpm: f32);
@@ -55,6 +105,14 @@ pub trait Control: Sync + Send {
     fn render_node_moved(&mut self, id: usize, new_id: usize);
     fn serialize(&self) -> SerializationResult; //TODO: return serde_json::Value instead
     fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult;
+    // Deserializes a partial state, returns the updates of the fields it holds
+    fn apply_state(
+        &mut self,
+        state: &serde_json::Value,
+    ) -> Result<Vec<JsonFieldUpdate>, json::Error> {
+        self.deserialize(state)?;
+        Ok(json::field_updates(state, &self.serialize()?))
+    }
     fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>>;
     fn clone_node(&self) -> ControlPtr;
 }
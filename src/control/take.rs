@@ -0,0 +1,395 @@
+use crate::{midi, rhythm::Rhythm};
+use midly::num::{u14, u15, u24, u28, u4, u7};
+use serde::{Deserialize, Serialize};
+use std::mem;
+
+const TICKS_PER_BEAT: u16 = 480;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub slot: usize,
+    pub message: midi::Message,
+}
+
+// Recorded loop, every recording session is stored as a separate layer, so that
+// the sessions can be undone one by one. A note-off ending the loop is stored at
+// slot `num_slots` and played back before the events of slot 0.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Take {
+    num_slots: usize,
+    layers: Vec<Vec<Event>>,
+    #[serde(skip)]
+    recording: Option<Vec<Event>>,
+    // the last layer holds the finished passes of the session being recorded
+    #[serde(skip)]
+    layer_open: bool,
+}
+
+impl Take {
+    pub fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn set_num_slots(&mut self, num_slots: usize) {
+        self.num_slots = num_slots;
+        for layer in &mut self.layers {
+            layer.retain(|e| in_loop(e, num_slots));
+        }
+        if let Some(layer) = &mut self.recording {
+            layer.retain(|e| in_loop(e, num_slots));
+        }
+    }
+
+    pub fn begin_layer(&mut self) {
+        self.commit_layer();
+        self.recording = Some(vec![]);
+    }
+
+    // Make the recorded pass audible, it stays in the layer of the session. Notes
+    // still held are ended with the loop.
+    pub fn commit_pass(&mut self) {
+        let Some(mut pass) = self.recording.as_mut().map(mem::take) else {
+            return;
+        };
+        end_open_notes(&mut pass, self.num_slots);
+        if self.layer_open {
+            if let Some(layer) = self.layers.last_mut() {
+                layer.extend(pass);
+            }
+        } else if !pass.is_empty() {
+            self.layers.push(pass);
+            self.layer_open = true;
+        }
+    }
+
+    pub fn commit_layer(&mut self) {
+        self.commit_pass();
+        self.recording = None;
+        self.layer_open = false;
+    }
+
+    // Drops the whole session, including its finished passes
+    pub fn discard_layer(&mut self) {
+        if self.layer_open {
+            self.layers.pop();
+        }
+        self.recording = None;
+        self.layer_open = false;
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.layer_open = false;
+        self.layers.pop().is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+        self.recording = None;
+        self.layer_open = false;
+    }
+
+    pub fn record(&mut self, slot: usize, message: midi::Message) {
+        if self.num_slots == 0 {
+            return;
+        }
+        let num_slots = self.num_slots;
+        if let Some(layer) = &mut self.recording {
+            let mut slot = slot % num_slots;
+            // do not let the note end in the same slot it has started in, a note
+            // started in the last slot ends with the loop
+            if let Some(note) = note_off_number(&message.kind) {
+                if started_in_slot(layer, slot, note, message.channel) {
+                    slot += 1;
+                }
+            }
+            layer.push(Event { slot, message });
+        }
+    }
+
+    // Committed events at the given slot, the pass being recorded is not played back
+    pub fn events_at(&self, slot: usize) -> impl Iterator<Item = &Event> {
+        let num_slots = self.num_slots;
+        let events = self.layers.iter().flat_map(|layer| layer.iter());
+        events
+            .clone()
+            .filter(move |e| slot == 0 && e.slot == num_slots)
+            .chain(events.filter(move |e| e.slot == slot))
+    }
+
+    pub fn to_smf(&self, tempo_bpm: f32, rhythm: Rhythm) -> midly::Smf<'static> {
+        let ticks_per_slot = TICKS_PER_BEAT as usize / rhythm.num_divs.max(1) as usize;
+        let mut events: Vec<&Event> = self.layers.iter().flatten().collect();
+        events.sort_by_key(|e| e.slot);
+
+        let mut track = vec![
+            midly::TrackEvent {
+                delta: u28::new(0),
+                kind: midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(u24::new(
+                    (60_000_000.0 / tempo_bpm) as u32,
+                ))),
+            },
+            midly::TrackEvent {
+                delta: u28::new(0),
+                kind: midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(
+                    rhythm.num_beats,
+                    2,
+                    24,
+                    8,
+                )),
+            },
+        ];
+
+        let mut last_tick = 0;
+        for event in events {
+            let tick = event.slot * ticks_per_slot;
+            track.push(midly::TrackEvent {
+                delta: u28::new((tick - last_tick) as u32),
+                kind: to_midly_event(&event.message),
+            });
+            last_tick = tick;
+        }
+
+        let end_tick = self.num_slots * ticks_per_slot;
+        track.push(midly::TrackEvent {
+            delta: u28::new(end_tick.saturating_sub(last_tick) as u32),
+            kind: midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        });
+
+        let mut smf = midly::Smf::new(midly::Header::new(
+            midly::Format::SingleTrack,
+            midly::Timing::Metrical(u15::new(TICKS_PER_BEAT)),
+        ));
+        smf.tracks.push(track);
+        smf
+    }
+}
+
+fn in_loop(event: &Event, num_slots: usize) -> bool {
+    event.slot < num_slots
+        || event.slot == num_slots && note_off_number(&event.message.kind).is_some()
+}
+
+fn note_off_number(kind: &midi::MessageKind) -> Option<u8> {
+    match *kind {
+        midi::MessageKind::NoteOff { note, .. } => Some(note),
+        midi::MessageKind::NoteOn { note, velocity: 0 } => Some(note),
+        _ => None,
+    }
+}
+
+fn end_open_notes(layer: &mut Vec<Event>, num_slots: usize) {
+    let mut open: Vec<(u8, u8)> = vec![];
+    for event in layer.iter() {
+        let channel = event.message.channel;
+        match (event.message.kind, note_off_number(&event.message.kind)) {
+            (_, Some(note)) => open.retain(|&n| n != (channel, note)),
+            (midi::MessageKind::NoteOn { note, .. }, None) if !open.contains(&(channel, note)) => {
+                open.push((channel, note))
+            }
+            _ => {}
+        }
+    }
+    layer.extend(open.into_iter().map(|(channel, note)| Event {
+        slot: num_slots,
+        message: midi::Message {
+            kind: midi::MessageKind::NoteOff { note, velocity: 0 },
+            channel,
+        },
+    }));
+}
+
+fn started_in_slot(layer: &[Event], slot: usize, note: u8, channel: u8) -> bool {
+    layer
+        .iter()
+        .rev()
+        .find(|e| {
+            e.message.channel == channel
+                && match e.message.kind {
+                    midi::MessageKind::NoteOn { note: n, .. } => n == note,
+                    midi::MessageKind::NoteOff { note: n, .. } => n == note,
+                    _ => false,
+                }
+        })
+        .map(|e| e.slot == slot && note_off_number(&e.message.kind).is_none())
+        .unwrap_or(false)
+}
+
+fn to_midly_event(message: &midi::Message) -> midly::TrackEventKind<'static> {
+    use midi::MessageKind as Kind;
+    let channel = u4::from_int_lossy(message.channel);
+    let message = match message.kind {
+        Kind::NoteOff { note, velocity } => midly::MidiMessage::NoteOff {
+            key: u7::from_int_lossy(note),
+            vel: u7::from_int_lossy(velocity),
+        },
+        Kind::NoteOn { note, velocity } => midly::MidiMessage::NoteOn {
+            key: u7::from_int_lossy(note),
+            vel: u7::from_int_lossy(velocity),
+        },
+        Kind::PolyphonicAftertouch { note, pressure } => midly::MidiMessage::Aftertouch {
+            key: u7::from_int_lossy(note),
+            vel: u7::from_int_lossy(pressure),
+        },
+        Kind::ControlChange { kind, value } => midly::MidiMessage::Controller {
+            controller: u7::from_int_lossy(kind.as_number()),
+            value: u7::from_int_lossy(value),
+        },
+        Kind::ProgramChange { program } => midly::MidiMessage::ProgramChange {
+            program: u7::from_int_lossy(program),
+        },
+        Kind::ChannelAftertouch { pressure } => midly::MidiMessage::ChannelAftertouch {
+            vel: u7::from_int_lossy(pressure),
+        },
+        Kind::PitchWheel { value } => midly::MidiMessage::PitchBend {
+            bend: midly::PitchBend(u14::from_int_lossy(value)),
+        },
+    };
+    midly::TrackEventKind::Midi { channel, message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> midi::Message {
+        midi::Message {
+            kind: midi::MessageKind::NoteOn {
+                note,
+                velocity: 100,
+            },
+            channel: 0,
+        }
+    }
+
+    fn note_off(note: u8) -> midi::Message {
+        midi::Message {
+            kind: midi::MessageKind::NoteOff { note, velocity: 0 },
+            channel: 0,
+        }
+    }
+
+    #[test]
+    fn record_and_undo() {
+        let mut take = Take::default();
+        take.set_num_slots(16);
+        take.begin_layer();
+        take.record(3, note_on(60));
+        take.record(5, note_off(60));
+        assert_eq!(take.events_at(3).count(), 0);
+        take.commit_layer();
+        assert_eq!(take.events_at(3).count(), 1);
+
+        take.begin_layer();
+        take.record(19, note_on(62));
+        take.commit_layer();
+        assert_eq!(take.num_layers(), 2);
+        assert_eq!(take.events_at(3).count(), 2);
+
+        assert!(take.undo());
+        assert_eq!(take.events_at(3).count(), 1);
+        take.clear();
+        assert!(take.is_empty());
+    }
+
+    #[test]
+    fn note_off_moved_after_note_on() {
+        let mut take = Take::default();
+        take.set_num_slots(4);
+        take.begin_layer();
+        take.record(3, note_on(60));
+        take.record(3, note_off(60));
+        take.commit_layer();
+        assert_eq!(take.events_at(0).count(), 1);
+        assert_eq!(
+            take.events_at(0).next().map(|e| e.message),
+            Some(note_off(60))
+        );
+
+        let smf = take.to_smf(120.0, Rhythm::default());
+        let kinds: Vec<_> = smf.tracks[0][2..4].iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![to_midly_event(&note_on(60)), to_midly_event(&note_off(60))]
+        );
+    }
+
+    #[test]
+    fn overdub_session() {
+        let mut take = Take::default();
+        take.set_num_slots(4);
+        take.begin_layer();
+        take.record(0, note_on(60));
+        take.record(3, note_off(60));
+        take.commit_pass();
+        assert_eq!(take.events_at(0).count(), 1);
+        take.record(1, note_on(62));
+        take.record(3, note_off(62));
+        take.commit_pass();
+        take.commit_layer();
+        assert_eq!(take.num_layers(), 1);
+        assert_eq!(take.events_at(1).count(), 1);
+
+        take.begin_layer();
+        take.record(2, note_on(64));
+        take.record(3, note_off(64));
+        take.commit_pass();
+        take.discard_layer();
+        assert_eq!(take.num_layers(), 1);
+        assert_eq!(take.events_at(2).count(), 0);
+    }
+
+    #[test]
+    fn held_notes_end_with_the_loop() {
+        let mut take = Take::default();
+        take.set_num_slots(4);
+        take.begin_layer();
+        take.record(0, note_on(60));
+        take.record(1, note_on(62));
+        take.record(2, note_off(62));
+        let other_channel = midi::Message {
+            channel: 1,
+            ..note_on(60)
+        };
+        take.record(2, other_channel);
+        take.commit_pass();
+        let ends: Vec<_> = take.events_at(0).filter(|e| e.slot == 4).collect();
+        assert_eq!(ends.len(), 2);
+        assert!(ends.iter().any(|e| e.message == note_off(60)));
+        assert!(ends
+            .iter()
+            .any(|e| e.message.channel == 1 && note_off_number(&e.message.kind) == Some(60)));
+
+        take.record(1, note_on(64));
+        take.commit_layer();
+        assert_eq!(take.events_at(0).filter(|e| e.slot == 4).count(), 3);
+    }
+
+    #[test]
+    fn smf_export() {
+        let mut take = Take::default();
+        take.set_num_slots(16);
+        take.begin_layer();
+        take.record(0, note_on(60));
+        take.record(4, note_off(60));
+        take.commit_layer();
+        let smf = take.to_smf(120.0, Rhythm::default());
+        let mut data = vec![];
+        smf.write_std(&mut data).unwrap();
+        let parsed = midly::Smf::parse(&data).unwrap();
+        assert_eq!(parsed.tracks.len(), 1);
+        assert_eq!(parsed.tracks[0].len(), 5);
+        assert_eq!(parsed.tracks[0][3].delta.as_int(), 480);
+    }
+}
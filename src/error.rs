@@ -0,0 +1,99 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum ErrorCode {
+    InvalidValue,
+    InvalidPath,
+    NotFound,
+    Io,
+    Serialization,
+    Deserialization,
+    // nothing is loaded which the request could act on
+    NotLoaded,
+    LoadFailed,
+    MidiConnect,
+    InvalidSlot,
+    // the service handling the request did not answer
+    Unavailable,
+    Unsupported,
+    // the request does not apply in the current state
+    InvalidState,
+    // another client holds the lock of the edited node
+    Locked,
+}
+
+// Reason of a failed request, sent to the client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_path(path: &std::path::Path) -> Self {
+        Self::new(
+            ErrorCode::InvalidPath,
+            format!("Invalid path: {}", path.display()),
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        let code = match e.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            _ => ErrorCode::Io,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+impl From<crate::json::Error> for Error {
+    fn from(e: crate::json::Error) -> Self {
+        Self::new(ErrorCode::Deserialization, e.0)
+    }
+}
+
+impl From<crate::midi::ReaderError> for Error {
+    fn from(e: crate::midi::ReaderError) -> Self {
+        let code = match e {
+            crate::midi::ReaderError::ConnectError => ErrorCode::MidiConnect,
+            crate::midi::ReaderError::InvalidSlot(_) => ErrorCode::InvalidSlot,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let e: Error = std::io::Error::from(std::io::ErrorKind::NotFound).into();
+        assert_eq!(e.code, ErrorCode::NotFound);
+        let e: Error = crate::midi::ReaderError::InvalidSlot(3).into();
+        assert_eq!(e, Error::new(ErrorCode::InvalidSlot, "Invalid slot: 3"));
+        assert_eq!(
+            serde_json::to_value(Error::new(ErrorCode::InvalidValue, "Out of range")).unwrap(),
+            serde_json::json!({"code": "InvalidValue", "message": "Out of range"})
+        );
+    }
+}
@@ -0,0 +1,459 @@
+use crate::{
+    error::{Error, ErrorCode},
+    path::VirtualPaths,
+};
+use futures::{stream, Stream, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+// Size of the chunks a download is streamed in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+// Most an upload request may carry, larger files are sent in several requests
+pub const MAX_UPLOAD_CHUNK: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DirEntry {
+    pub is_dir: bool,
+    // relative to the listed directory
+    pub path: PathBuf,
+    // in bytes, zero for directories
+    pub size: u64,
+    // seconds since the unix epoch
+    pub modified: Option<u64>,
+}
+
+// File operations on virtual paths, nothing outside of the roots can be reached
+#[derive(Clone, Default)]
+pub struct Files {
+    virtual_paths: VirtualPaths,
+}
+
+impl Files {
+    pub fn new(virtual_paths: VirtualPaths) -> Self {
+        Self { virtual_paths }
+    }
+
+    fn translate(&self, path: &Path) -> Result<PathBuf, Error> {
+        self.virtual_paths
+            .translate(path)
+            .ok_or_else(|| Error::invalid_path(path))
+    }
+
+    // Translates the path and makes sure no symlink leads out of the roots.
+    // Parts that do not exist yet are checked through their closest existing parent.
+    async fn resolve(&self, path: &Path) -> Result<PathBuf, Error> {
+        let translated = self.translate(path)?;
+        let mut existing = translated.as_path();
+        while fs::symlink_metadata(existing).await.is_err() {
+            existing = existing.parent().ok_or_else(|| Error::invalid_path(path))?;
+        }
+        if self.is_inside_roots(existing).await {
+            Ok(translated)
+        } else {
+            Err(Error::invalid_path(path))
+        }
+    }
+
+    // Whether the existing path, with all symlinks followed, lies within one of the roots
+    async fn is_inside_roots(&self, real: &Path) -> bool {
+        let Ok(real) = fs::canonicalize(real).await else {
+            return false;
+        };
+        for root in self.virtual_paths.roots() {
+            if let Ok(root) = fs::canonicalize(root).await {
+                if real.starts_with(root) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Sorted by path, symlinks are followed as long as they stay within the roots
+    pub async fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        let dir = self.resolve(path).await?;
+        let mut entries = fs::read_dir(&dir).await?;
+        let mut result = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            // Broken symlinks and the ones leading out are left out
+            if !self.is_inside_roots(&entry.path()).await {
+                continue;
+            }
+            let Ok(metadata) = fs::metadata(entry.path()).await else {
+                continue;
+            };
+            result.push(DirEntry {
+                is_dir: metadata.is_dir(),
+                path: entry.file_name().into(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_secs()),
+            });
+        }
+        result.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(result)
+    }
+
+    pub async fn make_dir(&self, path: &Path) -> Result<(), Error> {
+        fs::create_dir_all(self.resolve(path).await?).await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, path: &Path) -> Result<(), Error> {
+        let translated = self.resolve(path).await?;
+        if self.is_root(&translated) {
+            return Err(Error::new(
+                ErrorCode::InvalidPath,
+                format!("Can not delete the root {}", path.display()),
+            ));
+        }
+        let metadata = fs::symlink_metadata(&translated)
+            .await
+            .map_err(|_| not_found(path))?;
+        if metadata.is_dir() {
+            fs::remove_dir_all(translated).await?;
+        } else {
+            fs::remove_file(translated).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn rename(&self, path: &Path, new_path: &Path) -> Result<(), Error> {
+        let from = self.resolve(path).await?;
+        let to = self.resolve(new_path).await?;
+        if self.is_root(&from) {
+            return Err(Error::new(
+                ErrorCode::InvalidPath,
+                format!("Can not rename the root {}", path.display()),
+            ));
+        }
+        fs::rename(from, to).await?;
+        Ok(())
+    }
+
+    // Directories are copied with all their content, existing files are never
+    // overwritten
+    pub async fn copy(&self, path: &Path, new_path: &Path) -> Result<(), Error> {
+        let from = self.resolve(path).await?;
+        let to = self.resolve(new_path).await?;
+        if to.starts_with(&from) {
+            return Err(Error::new(
+                ErrorCode::InvalidPath,
+                format!("Can not copy {} into itself", path.display()),
+            ));
+        }
+        if fs::try_exists(&to).await? {
+            return Err(Error::new(
+                ErrorCode::InvalidState,
+                format!("{} already exists", new_path.display()),
+            ));
+        }
+        fs::symlink_metadata(&from)
+            .await
+            .map_err(|_| not_found(path))?;
+
+        let mut pending = vec![(from, to)];
+        while let Some((from, to)) = pending.pop() {
+            // Otherwise a link would bring a file from outside into the roots
+            if !self.is_inside_roots(&from).await {
+                return Err(Error::new(
+                    ErrorCode::InvalidPath,
+                    format!("{} leads out of the roots", from.display()),
+                ));
+            }
+            if fs::symlink_metadata(&from).await?.is_dir() {
+                fs::create_dir(&to).await?;
+                let mut entries = fs::read_dir(&from).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    pending.push((entry.path(), to.join(entry.file_name())));
+                }
+            } else {
+                fs::copy(&from, &to).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the size of the whole file and its content from the offset on
+    pub async fn download(
+        &self,
+        path: &Path,
+        offset: u64,
+    ) -> Result<(u64, impl Stream<Item = std::io::Result<Vec<u8>>>), Error> {
+        let translated = self.resolve(path).await?;
+        let mut file = fs::File::open(&translated)
+            .await
+            .map_err(|_| not_found(path))?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Err(Error::new(
+                ErrorCode::InvalidPath,
+                format!("{} is not a file", path.display()),
+            ));
+        }
+        if offset > metadata.len() {
+            return Err(Error::new(
+                ErrorCode::InvalidValue,
+                format!("Offset {offset} is past the end of the file"),
+            ));
+        }
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok((metadata.len(), chunks(file)))
+    }
+
+    // The chunks are collected in a part file next to the target, which is
+    // renamed over it once the total size is reached, so the target is never
+    // seen half written. The first chunk starts a new part file, the following
+    // ones have to continue where it ends, so an interrupted upload can be
+    // resumed. Without a total size the request is the whole file.
+    // Returns the size of the part file after the chunk.
+    pub async fn upload<S, B, E>(
+        &self,
+        path: &Path,
+        offset: u64,
+        total: Option<u64>,
+        chunk: S,
+    ) -> Result<u64, Error>
+    where
+        S: Stream<Item = Result<B, E>>,
+        B: AsRef<[u8]>,
+        E: fmt::Display,
+    {
+        let translated = self.resolve(path).await?;
+        let Some(name) = translated.file_name() else {
+            return Err(Error::invalid_path(path));
+        };
+        let mut part_name = std::ffi::OsString::from(".");
+        part_name.push(name);
+        part_name.push(".part");
+        let part = translated.with_file_name(part_name);
+        let mut file = if offset == 0 {
+            if let Some(parent) = translated.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::File::create(&part).await?
+        } else {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(&part)
+                .await
+                .map_err(|_| not_found(path))?;
+            let size = file.metadata().await?.len();
+            if size != offset {
+                return Err(Error::new(
+                    ErrorCode::InvalidState,
+                    format!("The upload has to continue at offset {size}"),
+                ));
+            }
+            file.seek(SeekFrom::End(0)).await?;
+            file
+        };
+
+        let mut size = offset;
+        let mut chunk = std::pin::pin!(chunk);
+        while let Some(data) = chunk.next().await {
+            let data =
+                data.map_err(|e| Error::new(ErrorCode::Io, format!("Upload interrupted: {e}")))?;
+            size += data.as_ref().len() as u64;
+            if size - offset > MAX_UPLOAD_CHUNK || total.is_some_and(|total| size > total) {
+                drop(file);
+                _ = fs::remove_file(&part).await;
+                return Err(Error::new(
+                    ErrorCode::InvalidValue,
+                    format!("The upload exceeds its size or {MAX_UPLOAD_CHUNK} bytes per request"),
+                ));
+            }
+            file.write_all(data.as_ref()).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        if total.is_none_or(|total| size == total) {
+            fs::rename(&part, &translated).await?;
+        }
+        Ok(size)
+    }
+
+    fn is_root(&self, path: &Path) -> bool {
+        self.virtual_paths.is_root(path)
+    }
+}
+
+fn chunks(file: fs::File) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+    stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(len) => {
+                buf.truncate(len);
+                Some((Ok(buf), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(
+        ErrorCode::NotFound,
+        format!("No such file: {}", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("ami-{name}-{}", std::process::id()));
+            _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn files(root: &TempDir) -> Files {
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert("samples:".into(), root.0.clone());
+        Files::new(virtual_paths)
+    }
+
+    fn chunk(data: &[u8]) -> impl Stream<Item = Result<Vec<u8>, String>> {
+        stream::iter(vec![Ok(data.to_vec())])
+    }
+
+    #[tokio::test]
+    async fn copy_recursive() {
+        let root = TempDir::new("copy");
+        let files = files(&root);
+        std::fs::create_dir_all(root.0.join("kit/snares")).unwrap();
+        std::fs::write(root.0.join("kit/kick.wav"), b"kick").unwrap();
+        std::fs::write(root.0.join("kit/snares/snare.wav"), b"snare").unwrap();
+
+        files
+            .copy(Path::new("samples:/kit"), Path::new("samples:/copy"))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(root.0.join("copy/snares/snare.wav")).unwrap(),
+            b"snare"
+        );
+        let entries = files.read_dir(Path::new("samples:/copy")).await.unwrap();
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|e| (e.is_dir, e.path.clone(), e.size))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (false, PathBuf::from("kick.wav"), 4),
+                (true, PathBuf::from("snares"), 0)
+            ]
+        );
+
+        let into_itself = files
+            .copy(
+                Path::new("samples:/kit"),
+                Path::new("samples:/kit/snares/kit"),
+            )
+            .await;
+        assert_eq!(into_itself.unwrap_err().code, ErrorCode::InvalidPath);
+        let existing = files
+            .copy(Path::new("samples:/kit"), Path::new("samples:/copy"))
+            .await;
+        assert_eq!(existing.unwrap_err().code, ErrorCode::InvalidState);
+    }
+
+    #[tokio::test]
+    async fn sandbox() {
+        let root = TempDir::new("sandbox");
+        let files = files(&root);
+        let escape = Path::new("samples:/../../etc");
+        assert_eq!(
+            files.read_dir(escape).await.unwrap_err().code,
+            ErrorCode::InvalidPath
+        );
+        #[cfg(unix)]
+        {
+            let outside = TempDir::new("outside");
+            std::fs::write(outside.0.join("secret"), b"secret").unwrap();
+            std::os::unix::fs::symlink(&outside.0, root.0.join("link")).unwrap();
+            for path in ["samples:/link", "samples:/link/secret", "samples:/link/new"] {
+                let path = Path::new(path);
+                assert_eq!(
+                    files.resolve(path).await.unwrap_err().code,
+                    ErrorCode::InvalidPath
+                );
+            }
+            let upload = files
+                .upload(Path::new("samples:/link/new"), 0, None, chunk(b"x"))
+                .await;
+            assert_eq!(upload.unwrap_err().code, ErrorCode::InvalidPath);
+            assert!(!outside.0.join("new").exists());
+            assert!(files
+                .read_dir(Path::new("samples:"))
+                .await
+                .unwrap()
+                .is_empty());
+        }
+        assert_eq!(
+            files.delete(Path::new("samples:")).await.unwrap_err().code,
+            ErrorCode::InvalidPath
+        );
+    }
+
+    #[tokio::test]
+    async fn resumable_upload() {
+        let root = TempDir::new("upload");
+        let files = files(&root);
+        let path = Path::new("samples:/sf2/piano.sf2");
+
+        assert_eq!(files.upload(path, 0, None, chunk(b"old")).await.unwrap(), 3);
+        assert_eq!(
+            files.upload(path, 0, Some(6), chunk(b"abc")).await.unwrap(),
+            3
+        );
+        assert_eq!(
+            files
+                .upload(path, 2, Some(6), chunk(b"x"))
+                .await
+                .unwrap_err()
+                .code,
+            ErrorCode::InvalidState
+        );
+        // The old content stays until the upload is complete
+        assert_eq!(std::fs::read(root.0.join("sf2/piano.sf2")).unwrap(), b"old");
+        assert_eq!(
+            files.upload(path, 3, Some(6), chunk(b"def")).await.unwrap(),
+            6
+        );
+        assert!(!root.0.join("sf2/.piano.sf2.part").exists());
+
+        let (size, content) = files.download(path, 2).await.unwrap();
+        let content: Vec<u8> = content.map(|chunk| chunk.unwrap()).concat().await;
+        assert_eq!(size, 6);
+        assert_eq!(content, b"cdef");
+    }
+}
@@ -1,7 +1,13 @@
 use serde::{de::DeserializeOwned, Serialize};
 
 #[derive(Debug, PartialEq)]
-pub struct Error;
+pub struct Error(pub String);
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self(e.to_string())
+    }
+}
 
 pub type SerializationResult = Result<serde_json::Value, Error>;
 pub type DeserializationResult = Result<(), Error>;
@@ -9,7 +15,7 @@ pub type DeserializationResult = Result<(), Error>;
 pub type JsonFieldUpdate = (String, serde_json::Value);
 
 pub fn serialize<T: Serialize>(value: T) -> SerializationResult {
-    serde_json::to_value(value).map_err(|_| Error)
+    Ok(serde_json::to_value(value)?)
 }
 
 pub fn expect_serialize<T: Serialize>(value: T) -> serde_json::Value {
@@ -31,11 +37,12 @@ pub fn deser_field<T: DeserializeOwned>(
     callback: impl FnOnce(T),
 ) -> Result<(), Error> {
     if let Some(val) = source.get(field_name) {
-        let val: T = serde_json::from_value::<T>(val.clone()).map_err(|_| Error)?;
+        let val: T = serde_json::from_value::<T>(val.clone())
+            .map_err(|e| Error(format!("Invalid field {field_name}: {e}")))?;
         callback(val);
         Ok(())
     } else {
-        Err(Error)
+        Err(Error(format!("Missing field: {field_name}")))
     }
 }
 
@@ -45,12 +52,29 @@ pub fn deser_field_opt<T: DeserializeOwned>(
     callback: impl FnOnce(T),
 ) -> Result<(), Error> {
     if let Some(val) = source.get(field_name) {
-        let val: T = serde_json::from_value(val.clone()).map_err(|_| Error)?;
+        let val: T = serde_json::from_value(val.clone())
+            .map_err(|e| Error(format!("Invalid field {field_name}: {e}")))?;
         callback(val);
     }
     Ok(())
 }
 
+// Takes the current value of every field present in `source`
+pub fn field_updates(
+    source: &serde_json::Value,
+    serialized: &serde_json::Value,
+) -> Vec<JsonFieldUpdate> {
+    source
+        .as_object()
+        .map(|fields| {
+            fields
+                .keys()
+                .filter_map(|k| serialized.get(k).map(|v| (k.clone(), v.clone())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 #[macro_export]
 macro_rules! json_try {
     ($($stmt:stmt)*) => {
@@ -0,0 +1,597 @@
+pub mod sfz;
+pub mod soundfont;
+
+use crate::{
+    error::{Error, ErrorCode},
+    path::VirtualPaths,
+    webserver::{Clients, ServerMessageKind},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+// Bumped when the indexed fields change, older index files are scanned again
+const INDEX_VERSION: u32 = 2;
+const DEFAULT_LIMIT: usize = 100;
+// Changes often come in bursts, like the chunks of an upload
+const RESCAN_DELAY: Duration = Duration::from_secs(2);
+const ROOT: &str = "samples:";
+
+// Drum kits, the categories below are those of the melodic General MIDI programs
+const DRUMS: &str = "Drums";
+
+// Categories of the General MIDI programs, 8 programs each
+const GM_CATEGORIES: [&str; 16] = [
+    "Piano",
+    "Chromatic Percussion",
+    "Organ",
+    "Guitar",
+    "Bass",
+    "Strings",
+    "Ensemble",
+    "Brass",
+    "Reed",
+    "Pipe",
+    "Synth Lead",
+    "Synth Pad",
+    "Synth Effects",
+    "Ethnic",
+    "Percussive",
+    "Sound Effects",
+];
+
+// The first matching keyword wins, so e.g. "bassoon" has to come before "bass".
+// Only the General MIDI categories and drums are used, as GM files are categorized.
+const KEYWORD_CATEGORIES: [(&str, &str); 32] = [
+    ("piano", "Piano"),
+    ("rhodes", "Piano"),
+    ("wurli", "Piano"),
+    ("grand", "Piano"),
+    ("organ", "Organ"),
+    ("guitar", "Guitar"),
+    ("bassoon", "Reed"),
+    ("contrabass", "Strings"),
+    ("bass", "Bass"),
+    ("violin", "Strings"),
+    ("viola", "Strings"),
+    ("cello", "Strings"),
+    ("string", "Strings"),
+    ("trumpet", "Brass"),
+    ("trombone", "Brass"),
+    ("tuba", "Brass"),
+    ("horn", "Brass"),
+    ("brass", "Brass"),
+    ("sax", "Reed"),
+    ("clarinet", "Reed"),
+    ("oboe", "Reed"),
+    ("flute", "Pipe"),
+    ("choir", "Ensemble"),
+    ("vox", "Ensemble"),
+    ("pad", "Synth Pad"),
+    ("lead", "Synth Lead"),
+    ("drum", DRUMS),
+    ("kit", DRUMS),
+    ("perc", DRUMS),
+    ("bell", "Chromatic Percussion"),
+    ("marimba", "Chromatic Percussion"),
+    ("vibra", "Chromatic Percussion"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum FileKind {
+    Sf2,
+    Sf3,
+    Sfz,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LibraryPreset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    // Lowest and highest key played by the preset
+    pub key_range: Option<(u8, u8)>,
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LibraryFile {
+    // virtual path, e.g. samples:/gm/FluidR3.sf2
+    pub path: PathBuf,
+    pub kind: FileKind,
+    pub name: String,
+    // in bytes
+    pub size: u64,
+    // seconds since the unix epoch
+    pub modified: Option<u64>,
+    // Directory names and the categories of the presets
+    pub tags: Vec<String>,
+    // SFZ files have a single preset
+    pub presets: Vec<LibraryPreset>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SearchResult {
+    pub path: PathBuf,
+    pub kind: FileKind,
+    pub preset: LibraryPreset,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "LibraryRequestKind")]
+pub enum RequestKind {
+    // Presets whose name, category, file path or file tags contain every word of the query
+    Search {
+        query: String,
+        category: Option<String>,
+        kind: Option<FileKind>,
+        limit: Option<usize>,
+    },
+    GetFile(PathBuf),
+    Rescan,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "LibraryResponseKind")]
+pub enum ResponseKind {
+    Ok,
+    Results(Vec<SearchResult>),
+    File(LibraryFile),
+    Failed { reason: Error },
+}
+
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "LibraryUpdateKind")]
+pub enum UpdateKind {
+    Scanning,
+    Indexed { files: usize, presets: usize },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    version: u32,
+    files: Vec<LibraryFile>,
+}
+
+// Index of the instruments below samples:, kept up to date in the background
+// and cached on disk
+#[derive(Clone)]
+pub struct Library {
+    root: Option<PathBuf>,
+    index_path: PathBuf,
+    index: Arc<Mutex<Index>>,
+    rescan: Arc<Notify>,
+    clients: Clients,
+}
+
+impl Library {
+    pub fn new(virtual_paths: &VirtualPaths, index_path: PathBuf, clients: Clients) -> Self {
+        Self {
+            root: virtual_paths.translate(Path::new(ROOT)),
+            index_path,
+            index: Default::default(),
+            rescan: Default::default(),
+            clients,
+        }
+    }
+
+    pub async fn run(mut self) {
+        self.load_index().await;
+        loop {
+            self.scan().await;
+            self.rescan.notified().await;
+            tokio::time::sleep(RESCAN_DELAY).await;
+        }
+    }
+
+    // Requested after files changed, the scan runs in the background
+    pub fn rescan(&self) {
+        self.rescan.notify_one();
+    }
+
+    pub fn process_request(&self, kind: RequestKind) -> ResponseKind {
+        match kind {
+            RequestKind::Search {
+                query,
+                category,
+                kind,
+                limit,
+            } => {
+                let index = self.index.lock().unwrap();
+                let results = search(
+                    &index.files,
+                    &query,
+                    category.as_deref(),
+                    kind,
+                    limit.unwrap_or(DEFAULT_LIMIT),
+                );
+                ResponseKind::Results(results)
+            }
+            RequestKind::GetFile(path) => {
+                let index = self.index.lock().unwrap();
+                match index.files.iter().find(|file| file.path == path) {
+                    Some(file) => ResponseKind::File(file.clone()),
+                    None => ResponseKind::failed(
+                        ErrorCode::NotFound,
+                        format!("{} is not in the library", path.display()),
+                    ),
+                }
+            }
+            RequestKind::Rescan => {
+                self.rescan();
+                ResponseKind::Ok
+            }
+        }
+    }
+
+    async fn load_index(&self) {
+        let Ok(content) = tokio::fs::read_to_string(&self.index_path).await else {
+            return;
+        };
+        match serde_json::from_str::<Index>(&content) {
+            Ok(index) if index.version == INDEX_VERSION => *self.index.lock().unwrap() = index,
+            Ok(_) => info!("Library index is outdated, scanning again"),
+            Err(e) => warn!("Failed to read the library index: {e}"),
+        }
+    }
+
+    async fn scan(&mut self) {
+        let Some(root) = self.root.clone() else {
+            return;
+        };
+        self.clients
+            .broadcast(ServerMessageKind::LibraryUpdate(UpdateKind::Scanning));
+
+        let previous: HashMap<PathBuf, LibraryFile> = self
+            .index
+            .lock()
+            .unwrap()
+            .files
+            .iter()
+            .map(|file| (file.path.clone(), file.clone()))
+            .collect();
+        let files = match tokio::task::spawn_blocking(move || scan_dir(&root, &previous)).await {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Library scan failed: {e}");
+                return;
+            }
+        };
+
+        let update = UpdateKind::Indexed {
+            files: files.len(),
+            presets: files.iter().map(|file| file.presets.len()).sum(),
+        };
+        let index = Index {
+            version: INDEX_VERSION,
+            files,
+        };
+        match serde_json::to_string(&index) {
+            Ok(content) => {
+                if let Err(e) = tokio::fs::write(&self.index_path, content).await {
+                    warn!("Failed to write the library index: {e}");
+                }
+            }
+            Err(e) => warn!("Failed to serialize the library index: {e}"),
+        }
+        *self.index.lock().unwrap() = index;
+        self.clients
+            .broadcast(ServerMessageKind::LibraryUpdate(update));
+    }
+}
+
+// Files with an unchanged size and modification time are taken from the
+// previous scan. Hidden entries and symlinked directories are skipped.
+fn scan_dir(root: &Path, previous: &HashMap<PathBuf, LibraryFile>) -> Vec<LibraryFile> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let real_path = entry.path();
+            if file_type.is_dir() {
+                pending.push(real_path);
+                continue;
+            }
+            let Some(kind) = file_kind(&real_path) else {
+                continue;
+            };
+            let Ok(metadata) = fs::metadata(&real_path) else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let relative = real_path.strip_prefix(root).unwrap_or(&real_path);
+            let path = Path::new(ROOT).join(relative);
+            let size = metadata.len();
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs());
+
+            match previous.get(&path) {
+                Some(file) if file.size == size && file.modified == modified => {
+                    files.push(file.clone());
+                }
+                _ => match index_file(&real_path, relative, kind) {
+                    Ok((name, presets)) => files.push(LibraryFile {
+                        tags: tags(relative, &presets),
+                        path,
+                        kind,
+                        name,
+                        size,
+                        modified,
+                        presets,
+                    }),
+                    Err(e) => warn!("Failed to index {}: {e}", path.display()),
+                },
+            }
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+fn file_kind(path: &Path) -> Option<FileKind> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "sf2" => Some(FileKind::Sf2),
+        "sf3" => Some(FileKind::Sf3),
+        "sfz" => Some(FileKind::Sfz),
+        _ => None,
+    }
+}
+
+fn index_file(
+    real_path: &Path,
+    relative: &Path,
+    kind: FileKind,
+) -> Result<(String, Vec<LibraryPreset>), Box<dyn std::error::Error>> {
+    let stem = relative
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match kind {
+        FileKind::Sf2 | FileKind::Sf3 => {
+            let mut reader = BufReader::new(fs::File::open(real_path)?);
+            let header = soundfont::read_header(&mut reader)?;
+            // Only full General MIDI sets are categorized by program number
+            let gm = header
+                .presets
+                .iter()
+                .filter(|preset| preset.bank == 0)
+                .count()
+                >= 100;
+            let presets = header
+                .presets
+                .into_iter()
+                .map(|preset| LibraryPreset {
+                    category: category(&preset.name, preset.bank, preset.program, gm),
+                    name: preset.name,
+                    bank: preset.bank,
+                    program: preset.program,
+                    key_range: preset.key_range,
+                })
+                .collect();
+            Ok((header.name.unwrap_or(stem), presets))
+        }
+        FileKind::Sfz => {
+            let content = fs::read(real_path)?;
+            let summary = sfz::summarize(&String::from_utf8_lossy(&content));
+            let preset = LibraryPreset {
+                category: category(&relative.to_string_lossy(), 0, 0, false),
+                name: stem.clone(),
+                bank: 0,
+                program: 0,
+                key_range: summary.key_range,
+            };
+            Ok((stem, vec![preset]))
+        }
+    }
+}
+
+fn category(name: &str, bank: u16, program: u16, gm: bool) -> Option<String> {
+    if bank == 128 {
+        return Some(DRUMS.to_owned());
+    }
+    if gm && bank == 0 {
+        return GM_CATEGORIES
+            .get(program as usize / 8)
+            .map(|category| category.to_string());
+    }
+    let name = name.to_lowercase();
+    KEYWORD_CATEGORIES
+        .iter()
+        .find(|(keyword, _)| name.contains(keyword))
+        .map(|(_, category)| category.to_string())
+}
+
+fn tags(relative: &Path, presets: &[LibraryPreset]) -> Vec<String> {
+    let dirs = relative
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.iter())
+        .map(|dir| dir.to_string_lossy().into_owned());
+    let categories = presets.iter().filter_map(|preset| preset.category.clone());
+    let mut tags: Vec<String> = Vec::new();
+    for tag in dirs.chain(categories) {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+fn search(
+    files: &[LibraryFile],
+    query: &str,
+    category: Option<&str>,
+    kind: Option<FileKind>,
+    limit: usize,
+) -> Vec<SearchResult> {
+    let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    let mut results = Vec::new();
+    for file in files
+        .iter()
+        .filter(|file| kind.is_none() || Some(file.kind) == kind)
+    {
+        let file_text = format!(
+            "{} {} {}",
+            file.name,
+            file.path.display(),
+            file.tags.join(" ")
+        )
+        .to_lowercase();
+        for preset in &file.presets {
+            if category.is_some_and(|category| {
+                !preset
+                    .category
+                    .as_deref()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(category))
+            }) {
+                continue;
+            }
+            let text = format!(
+                "{} {} {file_text}",
+                preset.name,
+                preset.category.as_deref().unwrap_or_default()
+            )
+            .to_lowercase();
+            if words.iter().all(|word| text.contains(word)) {
+                results.push(SearchResult {
+                    path: file.path.clone(),
+                    kind: file.kind,
+                    preset: preset.clone(),
+                });
+                if results.len() >= limit {
+                    return results;
+                }
+            }
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(name: &str, bank: u16, program: u16) -> LibraryPreset {
+        LibraryPreset {
+            name: name.to_owned(),
+            bank,
+            program,
+            key_range: None,
+            category: category(name, bank, program, false),
+        }
+    }
+
+    fn file(path: &str, kind: FileKind, presets: Vec<LibraryPreset>) -> LibraryFile {
+        let relative = Path::new(path).strip_prefix(ROOT).unwrap();
+        LibraryFile {
+            path: path.into(),
+            kind,
+            name: relative.file_stem().unwrap().to_string_lossy().into_owned(),
+            size: 0,
+            modified: None,
+            tags: tags(relative, &presets),
+            presets,
+        }
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(category("Bassoon", 0, 70, false).as_deref(), Some("Reed"));
+        assert_eq!(
+            category("Finger Bass", 0, 33, false).as_deref(),
+            Some("Bass")
+        );
+        assert_eq!(
+            category("Rhodes Mk1", 0, 4, false).as_deref(),
+            Some("Piano")
+        );
+        assert_eq!(
+            category("Choir Aahs", 0, 52, false).as_deref(),
+            Some("Ensemble")
+        );
+        assert_eq!(
+            category("Standard", 128, 0, false).as_deref(),
+            Some("Drums")
+        );
+        assert_eq!(category("Yamaha", 0, 1, true).as_deref(), Some("Piano"));
+        assert_eq!(category("Something", 0, 40, false), None);
+        assert!(KEYWORD_CATEGORIES
+            .iter()
+            .all(|(_, c)| *c == DRUMS || GM_CATEGORIES.contains(c)));
+    }
+
+    #[test]
+    fn search_presets() {
+        let files = vec![
+            file(
+                "samples:/gm/Fluid.sf2",
+                FileKind::Sf2,
+                vec![
+                    preset("Grand Piano", 0, 0),
+                    preset("Slap Bass", 0, 36),
+                    preset("Standard", 128, 0),
+                ],
+            ),
+            file(
+                "samples:/pianos/Salamander.sfz",
+                FileKind::Sfz,
+                vec![preset("Salamander Grand Piano", 0, 0)],
+            ),
+        ];
+        assert_eq!(files[1].tags, vec!["pianos", "Piano"]);
+
+        let names = |results: Vec<SearchResult>| -> Vec<String> {
+            results.into_iter().map(|r| r.preset.name).collect()
+        };
+        assert_eq!(
+            names(search(&files, "grand piano", None, None, 10)),
+            vec!["Grand Piano", "Salamander Grand Piano"]
+        );
+        assert_eq!(
+            names(search(&files, "piano", None, Some(FileKind::Sfz), 10)),
+            vec!["Salamander Grand Piano"]
+        );
+        assert_eq!(
+            names(search(&files, "fluid", Some("drums"), None, 10)),
+            vec!["Standard"]
+        );
+        // the file is tagged with the categories of its presets
+        assert_eq!(
+            names(search(&files, "gm drums", None, None, 10)),
+            vec!["Grand Piano", "Slap Bass", "Standard"]
+        );
+        assert_eq!(search(&files, "", None, None, 2).len(), 2);
+    }
+}
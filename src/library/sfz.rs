@@ -0,0 +1,159 @@
+// Headers of the sections, opcodes of outer sections are inherited by the
+// inner ones
+const SECTIONS: [&str; 4] = ["global", "master", "group", "region"];
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Summary {
+    pub regions: usize,
+    // Lowest and highest key played by any of the regions
+    pub key_range: Option<(u8, u8)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Keys {
+    lo: Option<u8>,
+    hi: Option<u8>,
+}
+
+impl Keys {
+    fn set(&mut self, opcode: &str, value: &str) {
+        let Some(key) = parse_key(value) else {
+            return;
+        };
+        match opcode {
+            "lokey" => self.lo = Some(key),
+            "hikey" => self.hi = Some(key),
+            "key" => {
+                self.lo = Some(key);
+                self.hi = Some(key);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Only the key ranges of the regions are read, sample files and `#include`s
+// are not followed
+pub fn summarize(content: &str) -> Summary {
+    let mut summary = Summary::default();
+    // Keys of the global, master, group and region sections
+    let mut levels = [Keys::default(); 4];
+    let mut level = None;
+
+    let mut regions = Vec::new();
+    for token in tokens(content) {
+        if let Some(header) = token.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+            if level == Some(3) {
+                regions.push(levels[3]);
+            }
+            level = SECTIONS.iter().position(|section| *section == header);
+            if let Some(level) = level {
+                // A section resets itself and everything nested in it
+                let inherited = if level == 0 {
+                    Keys::default()
+                } else {
+                    levels[level - 1]
+                };
+                levels[level..].fill(inherited);
+            }
+        } else if let (Some(level), Some((opcode, value))) = (level, token.split_once('=')) {
+            // Inner sections not opened yet inherit the value
+            for keys in &mut levels[level..] {
+                keys.set(opcode, value);
+            }
+        }
+    }
+    if level == Some(3) {
+        regions.push(levels[3]);
+    }
+
+    summary.regions = regions.len();
+    for keys in regions {
+        let (lo, hi) = (keys.lo.unwrap_or(0), keys.hi.unwrap_or(127));
+        if lo > hi {
+            continue;
+        }
+        summary.key_range = Some(match summary.key_range {
+            Some((min, max)) => (min.min(lo), max.max(hi)),
+            None => (lo, hi),
+        });
+    }
+    summary
+}
+
+// Headers and `opcode=value` pairs, values with spaces like sample paths
+// are cut but never needed here
+fn tokens(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or_default())
+        .flat_map(|line| line.split_inclusive('>'))
+        .flat_map(|part| match part.find('<') {
+            Some(start) => [&part[..start], &part[start..]],
+            None => [part, ""],
+        })
+        .flat_map(str::split_whitespace)
+}
+
+// Numbers or note names like c4 or f#3, where c4 is 60
+fn parse_key(value: &str) -> Option<u8> {
+    if let Ok(key) = value.parse::<i32>() {
+        return u8::try_from(key).ok().filter(|key| *key < 128);
+    }
+    let value = value.to_lowercase();
+    let mut chars = value.chars();
+    let base = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (offset, octave) = match rest.strip_prefix('#') {
+        Some(octave) => (1, octave),
+        None => match rest.strip_prefix('b') {
+            Some(octave) if !octave.is_empty() => (-1, octave),
+            _ => (0, rest),
+        },
+    };
+    let key = (octave.parse::<i32>().ok()? + 1) * 12 + base + offset;
+    u8::try_from(key).ok().filter(|key| *key < 128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_names() {
+        assert_eq!(parse_key("60"), Some(60));
+        assert_eq!(parse_key("c4"), Some(60));
+        assert_eq!(parse_key("C#4"), Some(61));
+        assert_eq!(parse_key("eb-1"), Some(3));
+        assert_eq!(parse_key("a0"), Some(21));
+        assert_eq!(parse_key("g9"), Some(127));
+        assert_eq!(parse_key("h4"), None);
+        assert_eq!(parse_key("200"), None);
+    }
+
+    #[test]
+    fn inherited_keys() {
+        let sfz = "
+            // a comment <region> lokey=0
+            <global> hikey=c5
+            <group>lokey=48
+            <region> sample=a.wav
+            <region> sample=b.wav lokey=36 // below the group
+            <group> key=100
+            <region>sample=c.wav<region> sample=d.wav hikey=99
+        ";
+        let summary = summarize(sfz);
+        assert_eq!(summary.regions, 4);
+        assert_eq!(summary.key_range, Some((36, 100)));
+        assert_eq!(summarize("<control> default_path=x/").regions, 0);
+    }
+}
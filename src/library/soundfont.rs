@@ -0,0 +1,422 @@
+use std::{
+    fmt,
+    io::{Read, Seek, SeekFrom},
+};
+
+// Generators which are needed for the key ranges of the presets
+const GEN_INSTRUMENT: u16 = 41;
+const GEN_KEY_RANGE: u16 = 43;
+const GEN_SAMPLE_ID: u16 = 53;
+
+const PHDR_LEN: usize = 38;
+const INST_LEN: usize = 22;
+const BAG_LEN: usize = 4;
+const GEN_LEN: usize = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    // Not a RIFF file of the sfbk form
+    NotASoundFont,
+    MissingChunk(&'static str),
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Failed to read SoundFont: {e}"),
+            Error::NotASoundFont => write!(f, "Not a SoundFont"),
+            Error::MissingChunk(chunk) => write!(f, "SoundFont without {chunk} chunk"),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresetHeader {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    // Lowest and highest key played by any of the zones
+    pub key_range: Option<(u8, u8)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Header {
+    pub name: Option<String>,
+    // 3 for SF3 files with compressed samples
+    pub major_version: u16,
+    pub presets: Vec<PresetHeader>,
+}
+
+// Hydra chunks of the pdta list
+#[derive(Default)]
+struct Hydra {
+    phdr: Vec<u8>,
+    pbag: Vec<u8>,
+    pgen: Vec<u8>,
+    inst: Vec<u8>,
+    ibag: Vec<u8>,
+    igen: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Zone {
+    key_range: Option<(u8, u8)>,
+    // Instrument of a preset zone or sample of an instrument zone, global
+    // zones have none
+    link: Option<u16>,
+}
+
+// Reads the names, presets and key ranges, the sample data is skipped
+pub fn read_header<R: Read + Seek>(reader: &mut R) -> Result<Header, Error> {
+    let (id, _) = read_chunk_header(reader)?;
+    if &id != b"RIFF" || &read_id(reader)? != b"sfbk" {
+        return Err(Error::NotASoundFont);
+    }
+
+    let mut header = Header::default();
+    let mut hydra = None;
+    while let Ok((id, len)) = read_chunk_header(reader) {
+        let end = reader.stream_position()? + padded(len);
+        if &id == b"LIST" {
+            match &read_id(reader)? {
+                b"INFO" => read_info(reader, end, &mut header)?,
+                b"pdta" => hydra = Some(read_hydra(reader, end)?),
+                _ => {}
+            }
+        }
+        reader.seek(SeekFrom::Start(end))?;
+    }
+
+    let hydra = hydra.ok_or(Error::MissingChunk("pdta"))?;
+    if hydra.phdr.is_empty() {
+        return Err(Error::MissingChunk("phdr"));
+    }
+    header.presets = presets(&hydra);
+    Ok(header)
+}
+
+fn read_info<R: Read + Seek>(reader: &mut R, end: u64, header: &mut Header) -> Result<(), Error> {
+    while reader.stream_position()? + 8 <= end {
+        let (id, len) = read_chunk_header(reader)?;
+        let next = reader.stream_position()? + padded(len);
+        match &id {
+            b"ifil" if len >= 2 => header.major_version = read_u16(reader)?,
+            b"INAM" => {
+                let name = read_string(&read_bytes(reader, len)?);
+                header.name = Some(name).filter(|name| !name.is_empty());
+            }
+            _ => {}
+        }
+        reader.seek(SeekFrom::Start(next))?;
+    }
+    Ok(())
+}
+
+fn read_hydra<R: Read + Seek>(reader: &mut R, end: u64) -> Result<Hydra, Error> {
+    let mut hydra = Hydra::default();
+    while reader.stream_position()? + 8 <= end {
+        let (id, len) = read_chunk_header(reader)?;
+        let next = reader.stream_position()? + padded(len);
+        let chunk = match &id {
+            b"phdr" => &mut hydra.phdr,
+            b"pbag" => &mut hydra.pbag,
+            b"pgen" => &mut hydra.pgen,
+            b"inst" => &mut hydra.inst,
+            b"ibag" => &mut hydra.ibag,
+            b"igen" => &mut hydra.igen,
+            _ => {
+                reader.seek(SeekFrom::Start(next))?;
+                continue;
+            }
+        };
+        *chunk = read_bytes(reader, len)?;
+        reader.seek(SeekFrom::Start(next))?;
+    }
+    Ok(hydra)
+}
+
+fn presets(hydra: &Hydra) -> Vec<PresetHeader> {
+    // The last record only terminates the list
+    let records: Vec<&[u8]> = hydra.phdr.chunks_exact(PHDR_LEN).collect();
+    records
+        .windows(2)
+        .map(|pair| {
+            let (record, next) = (pair[0], pair[1]);
+            let zones = zones(
+                &hydra.pbag,
+                &hydra.pgen,
+                u16_at(record, 24),
+                u16_at(next, 24),
+                GEN_INSTRUMENT,
+            );
+            PresetHeader {
+                name: read_string(&record[..20]),
+                program: u16_at(record, 20),
+                bank: u16_at(record, 22),
+                key_range: preset_key_range(hydra, &zones),
+            }
+        })
+        .collect()
+}
+
+fn preset_key_range(hydra: &Hydra, preset_zones: &[Zone]) -> Option<(u8, u8)> {
+    let mut range: Option<(u8, u8)> = None;
+    for preset_zone in preset_zones {
+        let Some(instrument) = preset_zone.link else {
+            continue;
+        };
+        let offset = instrument as usize * INST_LEN;
+        let (Some(record), Some(next)) = (
+            hydra.inst.get(offset..offset + INST_LEN),
+            hydra.inst.get(offset + INST_LEN..offset + 2 * INST_LEN),
+        ) else {
+            continue;
+        };
+        let instrument_zones = zones(
+            &hydra.ibag,
+            &hydra.igen,
+            u16_at(record, 20),
+            u16_at(next, 20),
+            GEN_SAMPLE_ID,
+        );
+        for zone in instrument_zones.iter().filter(|zone| zone.link.is_some()) {
+            let zone_range = intersect(
+                zone.key_range.unwrap_or((0, 127)),
+                preset_zone.key_range.unwrap_or((0, 127)),
+            );
+            if let Some((lo, hi)) = zone_range {
+                range = Some(match range {
+                    Some((min, max)) => (min.min(lo), max.max(hi)),
+                    None => (lo, hi),
+                });
+            }
+        }
+    }
+    range
+}
+
+fn zones(bags: &[u8], gens: &[u8], first: u16, end: u16, link_gen: u16) -> Vec<Zone> {
+    let bag = |index: u16| {
+        bags.get(index as usize * BAG_LEN..)
+            .map(|bag| u16_at(bag, 0))
+    };
+    (first..end)
+        .filter_map(|index| {
+            let (gen_first, gen_end) = (bag(index)?, bag(index + 1)?);
+            let mut zone = Zone {
+                key_range: None,
+                link: None,
+            };
+            for gen in gen_first..gen_end {
+                let offset = gen as usize * GEN_LEN;
+                let record = gens.get(offset..offset + GEN_LEN)?;
+                match u16_at(record, 0) {
+                    GEN_KEY_RANGE => zone.key_range = Some((record[2], record[3])),
+                    oper if oper == link_gen => zone.link = Some(u16_at(record, 2)),
+                    _ => {}
+                }
+            }
+            Some(zone)
+        })
+        .collect()
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> Option<(u8, u8)> {
+    let (lo, hi) = (a.0.max(b.0), a.1.min(b.1));
+    (lo <= hi).then_some((lo, hi))
+}
+
+fn read_chunk_header<R: Read>(reader: &mut R) -> Result<([u8; 4], u32), Error> {
+    let id = read_id(reader)?;
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    Ok((id, u32::from_le_bytes(len)))
+}
+
+fn read_id<R: Read>(reader: &mut R) -> Result<[u8; 4], Error> {
+    let mut id = [0; 4];
+    reader.read_exact(&mut id)?;
+    Ok(id)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, Error> {
+    let mut value = [0; 2];
+    reader.read_exact(&mut value)?;
+    Ok(u16::from_le_bytes(value))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: u32) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Fixed length and zero terminated
+fn read_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_owned()
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    bytes
+        .get(offset..offset + 2)
+        .map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+// Chunks are padded to an even length
+fn padded(len: u32) -> u64 {
+    len as u64 + (len as u64 & 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        for chunk in chunks {
+            data.extend(chunk);
+        }
+        chunk(b"LIST", &data)
+    }
+
+    fn name(name: &str, len: usize) -> Vec<u8> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    fn phdr(name_: &str, program: u16, bank: u16, bag: u16) -> Vec<u8> {
+        let mut record = name(name_, 20);
+        for value in [program, bank, bag] {
+            record.extend(value.to_le_bytes());
+        }
+        record.extend([0; 12]);
+        record
+    }
+
+    fn inst(name_: &str, bag: u16) -> Vec<u8> {
+        let mut record = name(name_, 20);
+        record.extend(bag.to_le_bytes());
+        record
+    }
+
+    fn pairs(values: &[(u16, [u8; 2])]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|(a, b)| [a.to_le_bytes(), *b].concat())
+            .collect()
+    }
+
+    // Two presets, the piano limited to the upper half of its instrument
+    fn sound_font() -> Vec<u8> {
+        let info = list(
+            b"INFO",
+            &[
+                chunk(b"ifil", &[2, 0, 1, 0]),
+                chunk(b"INAM", b"Test Font\0"),
+            ],
+        );
+        let sdta = list(b"sdta", &[chunk(b"smpl", &[0; 64])]);
+        let pdta = list(
+            b"pdta",
+            &[
+                chunk(
+                    b"phdr",
+                    &[
+                        phdr("Piano", 0, 0, 0),
+                        phdr("Kit", 0, 128, 1),
+                        phdr("EOP", 0, 0, 2),
+                    ]
+                    .concat(),
+                ),
+                // bags: gen index, mod index
+                chunk(b"pbag", &pairs(&[(0, [0, 0]), (2, [0, 0]), (3, [0, 0])])),
+                chunk(
+                    b"pgen",
+                    &pairs(&[
+                        (GEN_KEY_RANGE, [60, 127]),
+                        (GEN_INSTRUMENT, [0, 0]),
+                        (GEN_INSTRUMENT, [1, 0]),
+                    ]),
+                ),
+                chunk(
+                    b"inst",
+                    &[inst("Piano", 0), inst("Kit", 2), inst("EOI", 3)].concat(),
+                ),
+                chunk(
+                    b"ibag",
+                    &pairs(&[(0, [0, 0]), (1, [0, 0]), (3, [0, 0]), (5, [0, 0])]),
+                ),
+                chunk(
+                    b"igen",
+                    &pairs(&[
+                        // global zone of the piano
+                        (GEN_KEY_RANGE, [0, 127]),
+                        (GEN_KEY_RANGE, [21, 108]),
+                        (GEN_SAMPLE_ID, [0, 0]),
+                        (GEN_KEY_RANGE, [35, 35]),
+                        (GEN_SAMPLE_ID, [0, 0]),
+                    ]),
+                ),
+            ],
+        );
+        let mut data = b"sfbk".to_vec();
+        data.extend([info, sdta, pdta].concat());
+        chunk(b"RIFF", &data)
+    }
+
+    #[test]
+    fn header() {
+        let header = read_header(&mut Cursor::new(sound_font())).unwrap();
+        assert_eq!(header.name.as_deref(), Some("Test Font"));
+        assert_eq!(header.major_version, 2);
+        assert_eq!(
+            header.presets,
+            vec![
+                PresetHeader {
+                    name: "Piano".into(),
+                    bank: 0,
+                    program: 0,
+                    key_range: Some((60, 108)),
+                },
+                PresetHeader {
+                    name: "Kit".into(),
+                    bank: 128,
+                    program: 0,
+                    key_range: Some((35, 35)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn not_a_sound_font() {
+        let wave = chunk(b"RIFF", b"WAVEfmt ");
+        assert!(matches!(
+            read_header(&mut Cursor::new(wave)),
+            Err(Error::NotASoundFont)
+        ));
+        assert!(read_header(&mut Cursor::new(b"RI".to_vec())).is_err());
+    }
+}
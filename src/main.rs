@@ -1,18 +1,21 @@
-use audio::output::{BufferTx, DefaultOutputDeviceParams};
+use audio::output::DefaultOutputDeviceParams;
+use axum::http::HeaderValue;
 use clap::Parser;
 use control::{
     controller::{self, Controller},
-    node::drum_machine,
+    node::{arpeggiator, drum_machine, harmonizer, looper},
 };
+use error::{Error, ErrorCode};
 use midi::MidiReader;
 use render::{
     node::{fluidlite_synth, oxi_synth, rusty_synth, sfizz_synth},
+    realtime,
     renderer::{self, Renderer},
 };
-use ringbuf::traits::Producer;
 use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
-    sync::{atomic::AtomicUsize, Arc},
+    sync::Arc,
     time::Duration,
 };
 use tokio::sync::Mutex;
@@ -22,11 +25,18 @@ use webserver::{Clients, ServerMessageKind};
 
 pub mod audio;
 pub mod control;
+pub mod error;
+pub mod files;
 pub mod json;
+pub mod library;
+pub mod mapping;
 pub mod midi;
+pub mod parameter;
 pub mod path;
 pub mod render;
 pub mod rhythm;
+pub mod scene;
+pub mod setlist;
 pub mod synth;
 mod webserver;
 
@@ -34,9 +44,6 @@ const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
 
 #[derive(Parser, Debug)]
 #[command(about = "Simple software for adding two integers numbers.")]
-
-//TODO: implement node container which will provice basic functionality like midi filters and
-//  velocity mapping OR make macros to implement this functionality automatically
 pub struct Args {
     // #[clap(index=1)]
     // a: i32,
@@ -48,6 +55,40 @@ pub struct Args {
 
     #[arg(short, long, help = "Path to beats directory")]
     beats: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = audio::Backend::Cpal, help = "Audio backend")]
+    backend: audio::Backend,
+
+    #[arg(long, default_value_t = 2, help = "Number of output channels")]
+    channels: usize,
+
+    #[arg(long, help = "Connect the JACK outputs to the physical playback ports")]
+    jack_autoconnect: bool,
+
+    #[arg(
+        long,
+        help = "Cache file of the library index [default: <samples>/.ami-library.json]"
+    )]
+    library_index: Option<PathBuf>,
+
+    #[arg(long, help = "JSON file with the admin and performer tokens")]
+    auth_config: Option<PathBuf>,
+
+    #[arg(long, help = "Token or PIN granting full access to the web interface")]
+    admin_token: Option<String>,
+
+    #[arg(long, help = "Token or PIN for playing without editing files or nodes")]
+    performer_token: Option<String>,
+
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED), help = "Address of the web interface")]
+    bind: IpAddr,
+
+    #[arg(
+        long,
+        value_parser = HeaderValue::from_str,
+        help = "Origin allowed to call the API from another site, can be repeated"
+    )]
+    allow_origin: Vec<HeaderValue>,
 }
 
 #[tokio::main]
@@ -68,10 +109,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("| Beats directory: {:?}", args.beats);
 
     let (midi_tx, midi_rx) = midi::create_channel(2048);
+    // MIDI played by the control nodes, kept apart from the inputs
+    let (midi_out_tx, _) = midi::create_channel(2048);
     let (rnd_req_tx, rnd_req_rx) = renderer::create_request_channel(32);
     let (ctr_req_tx, ctr_req_rx) = controller::create_request_channel(32);
     let (ctr_tx, ctr_rx) = control::create_control_channel(32);
+    let (scn_req_tx, scn_req_rx) = scene::create_request_channel(32);
+    let (stl_req_tx, stl_req_rx) = setlist::create_request_channel(32);
+    let (map_req_tx, map_req_rx) = mapping::create_request_channel(32);
 
+    let library_index = args
+        .library_index
+        .unwrap_or_else(|| args.samples.join(".ami-library.json"));
     let mut virtual_paths = crate::path::VirtualPaths::default();
     virtual_paths.insert("samples:".into(), args.samples);
     virtual_paths.insert("beats:".into(), args.beats);
@@ -102,7 +151,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tokio::spawn(run_midi_logger(midi_rx, clients.clone()));
     tokio::spawn(run_midi_port_watchdog(clients.clone()));
 
-    let mut cache = webserver::Cache::default();
+    let mut cache = webserver::Cache::new(clients.clone());
 
     #[cfg(not(target_os = "windows"))]
     let sample_rate = 44100;
@@ -112,17 +161,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let buffer_size = 2048;
 
-    let audio_output = audio::output::connect_to_default_output_device(DefaultOutputDeviceParams {
-        sample_rate,
-        buffer_size,
-        num_channels: 2,
-    })
-    .expect("Failed to connect to output device");
+    let audio_output = match args.backend {
+        audio::Backend::Cpal => {
+            let audio_output =
+                audio::output::connect_to_default_output_device(DefaultOutputDeviceParams {
+                    sample_rate,
+                    buffer_size,
+                    num_channels: args.channels,
+                })
+                .expect("Failed to connect to output device");
+            let num_channels = audio_output.buf_tx.num_channels();
+            let (rt_tx, rt_core) = realtime::create(num_channels);
+            realtime::spawn(rt_core, audio_output.buf_tx);
+            audio::Output {
+                stream: audio::Stream::Cpal(audio_output.stream),
+                sample_rate: audio_output.sample_rate,
+                num_channels,
+                rt_tx,
+            }
+        }
+        audio::Backend::Jack => connect_jack_output(
+            midi_tx.clone(),
+            midi_out_tx.subscribe(),
+            args.channels,
+            args.jack_autoconnect,
+        )?,
+    };
+    let num_channels = audio_output.num_channels;
 
     let mut renderer = Renderer::new(
         midi_tx.subscribe(),
         rnd_req_rx,
         ctr_rx,
+        audio_output.rt_tx,
         virtual_paths.clone(),
         clients.clone(),
         cache.clone(),
@@ -131,135 +202,230 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     renderer.register_node_kind("OxiSynth", || Box::<oxi_synth::Node>::default());
     renderer.register_node_kind("FluidliteSynth", || Box::<fluidlite_synth::Node>::default());
     renderer.register_node_kind("SfizzSynth", || Box::<sfizz_synth::Node>::default());
+    renderer.register_preview_kind("sf2", "OxiSynth");
+    renderer.register_preview_kind("sf3", "OxiSynth");
+    renderer.register_preview_kind("sfz", "SfizzSynth");
     renderer.set_sample_rate(audio_output.sample_rate);
+    renderer.set_num_outputs(render::num_outputs(num_channels));
+    renderer.set_midi_output(midi_out_tx);
+    cache.set_renderer(renderer.serialize()).await;
 
-    let req_num_samples = audio_output.required_num_samples;
-    let lbuf_tx = audio_output.lbuf_tx;
-    let rbuf_tx = audio_output.rbuf_tx;
-    tokio::spawn(run_renderer(renderer, req_num_samples, (lbuf_tx, rbuf_tx)));
+    let _audio_stream = audio_output.stream;
+    tokio::spawn(run_renderer(renderer));
 
     let mut controller = Controller::new(
         midi_tx.subscribe(),
         ctr_req_rx,
         ctr_tx,
+        rnd_req_tx.clone(),
         virtual_paths.clone(),
         clients.clone(),
         cache.clone(),
     );
     controller.register_node_kind("DrumMachine", || Box::<drum_machine::Node>::default());
+    controller.register_node_kind("Looper", || Box::<looper::Node>::default());
+    controller.register_node_kind("Arpeggiator", || Box::<arpeggiator::Node>::default());
+    controller.register_node_kind("Harmonizer", || Box::<harmonizer::Node>::default());
     cache.set_controller(controller.serialize().await).await;
 
     tokio::spawn(run_controller(controller));
 
+    let scene_manager = scene::SceneManager::new(
+        midi_tx.subscribe(),
+        scn_req_rx,
+        ctr_req_tx.clone(),
+        virtual_paths.clone(),
+        clients.clone(),
+        cache.clone(),
+    );
+    cache.set_scenes(scene_manager.serialize()).await;
+    tokio::spawn(scene_manager.run());
+
+    let setlist_manager = setlist::SetlistManager::new(
+        midi_tx.subscribe(),
+        stl_req_rx,
+        rnd_req_tx.clone(),
+        ctr_req_tx.clone(),
+        virtual_paths.clone(),
+        clients.clone(),
+        cache.clone(),
+    );
+    cache.set_setlist(setlist_manager.serialize()).await;
+    tokio::spawn(setlist_manager.run());
+
+    let mapping_manager = mapping::MappingManager::new(
+        midi_tx.subscribe(),
+        map_req_rx,
+        rnd_req_tx.clone(),
+        ctr_req_tx.clone(),
+        scn_req_tx.clone(),
+        virtual_paths.clone(),
+        clients.clone(),
+        cache.clone(),
+    );
+    cache.set_mappings(mapping_manager.serialize()).await;
+    tokio::spawn(mapping_manager.run());
+
+    let mut auth_config = match &args.auth_config {
+        Some(path) => webserver::auth::Config::load(path)?,
+        None => Default::default(),
+    };
+    if args.admin_token.is_some() {
+        auth_config.admin_token = args.admin_token;
+    }
+    if args.performer_token.is_some() {
+        auth_config.performer_token = args.performer_token;
+    }
+    if !auth_config.is_enabled() {
+        tracing::warn!(
+            "| Authentication is disabled, every client has full access (--bind 127.0.0.1 keeps other machines out)"
+        );
+    } else if auth_config.admin_token.is_none() {
+        tracing::warn!(
+            "| No admin token, files and nodes can not be edited from the web interface"
+        );
+    }
+
+    let library = library::Library::new(&virtual_paths, library_index, clients.clone());
+    tokio::spawn(library.clone().run());
+
+    let files = files::Files::new(virtual_paths);
     let shared_state = webserver::SharedState {
         clients: Clients::clone(&clients),
         midi_reader: Arc::clone(&midi_reader),
         cache: cache.clone(),
+        auth: webserver::auth::Auth::new(auth_config),
+        files: files.clone(),
+        library: library.clone(),
     };
 
-    webserver::run(3000, shared_state, move |addr, req| {
+    let addr = SocketAddr::new(args.bind, 3000);
+    webserver::run(addr, args.allow_origin, shared_state, move |origin, req| {
         let midi_reader = Arc::clone(&midi_reader);
         let mut clients = Clients::clone(&clients);
         let rnd_req_tx = rnd_req_tx.clone();
         let ctr_req_tx = ctr_req_tx.clone();
-        let vp = virtual_paths.clone();
+        let scn_req_tx = scn_req_tx.clone();
+        let stl_req_tx = stl_req_tx.clone();
+        let map_req_tx = map_req_tx.clone();
+        let files = files.clone();
+        let library = library.clone();
         async move {
             use webserver::ClientMessageKind;
             use webserver::ServerMessageKind;
             match req {
                 ClientMessageKind::Ping => ServerMessageKind::Pong,
                 ClientMessageKind::Report(report) => {
-                    info!("Report from [{addr}]: {report}");
+                    info!("Report from [{}]: {report}", origin.addr);
                     ServerMessageKind::Ack
                 }
                 ClientMessageKind::ConnectMidiInput(slot, name) => {
                     let mut midi_reader = midi_reader.lock().await;
-                    if let Ok(()) = midi_reader.connect_input(slot, &name) {
-                        clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
-                            midi_reader.connected_input_names(),
-                        ));
-                        ServerMessageKind::Ack
-                    } else {
-                        ServerMessageKind::Nak
+                    match midi_reader.connect_input(slot, &name) {
+                        Ok(()) => {
+                            clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
+                                midi_reader.connected_input_names(),
+                            ));
+                            ServerMessageKind::Ack
+                        }
+                        Err(e) => ServerMessageKind::Error(e.into()),
                     }
                 }
                 ClientMessageKind::DisconnectMidiInput(slot) => {
                     let mut midi_reader = midi_reader.lock().await;
-                    if let Ok(()) = midi_reader.disconnect_input(slot) {
-                        clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
-                            midi_reader.connected_input_names(),
-                        ));
-                        ServerMessageKind::Ack
-                    } else {
-                        ServerMessageKind::Nak
+                    match midi_reader.disconnect_input(slot) {
+                        Ok(()) => {
+                            clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
+                                midi_reader.connected_input_names(),
+                            ));
+                            ServerMessageKind::Ack
+                        }
+                        Err(e) => ServerMessageKind::Error(e.into()),
                     }
                 }
                 ClientMessageKind::RendererRequest(req) => {
-                    let res = send_renderer_request(&rnd_req_tx, req).await;
+                    let res = renderer::send_request_from(&rnd_req_tx, origin.client, req).await;
                     if let Some(res) = res {
                         ServerMessageKind::RendererResponse(res)
                     } else {
-                        ServerMessageKind::Nak
+                        ServerMessageKind::Error(Error::new(
+                            ErrorCode::Unavailable,
+                            "Renderer is not running",
+                        ))
                     }
                 }
                 ClientMessageKind::ControllerRequest(req) => {
-                    let res = send_controller_request(&ctr_req_tx, req).await;
+                    let res = controller::send_request_from(&ctr_req_tx, origin.client, req).await;
                     if let Some(res) = res {
                         ServerMessageKind::ControllerResponse(res)
                     } else {
-                        ServerMessageKind::Nak
+                        ServerMessageKind::Error(Error::new(
+                            ErrorCode::Unavailable,
+                            "Controller is not running",
+                        ))
                     }
                 }
-                ClientMessageKind::ReadDir(path) => {
-                    if let Some(path) = vp.translate(&path) {
-                        if let Ok(dir) = std::fs::read_dir(&path) {
-                            //TODO: use tokio instead
-                            let entries = dir
-                                .into_iter()
-                                .flatten()
-                                .map(|x| {
-                                    (
-                                        x.path().is_dir(),
-                                        crate::path::remove_prefix(x.path().as_path(), &path),
-                                    )
-                                })
-                                .collect();
-                            return ServerMessageKind::DirInfo(Some(entries));
-                        }
+                ClientMessageKind::SceneRequest(req) => {
+                    let res = scene::send_request_from(&scn_req_tx, origin.client, req).await;
+                    if let Some(res) = res {
+                        ServerMessageKind::SceneResponse(res)
+                    } else {
+                        ServerMessageKind::Error(Error::new(
+                            ErrorCode::Unavailable,
+                            "Scene is not running",
+                        ))
                     }
-                    ServerMessageKind::DirInfo(None)
                 }
-                ClientMessageKind::MakeDir(path) => {
-                    if let Some(path) = vp.translate(&path) {
-                        if tokio::fs::create_dir_all(&path).await.is_ok() {
-                            return ServerMessageKind::Ack;
-                        }
+                ClientMessageKind::SetlistRequest(req) => {
+                    let res = setlist::send_request_from(&stl_req_tx, origin.client, req).await;
+                    if let Some(res) = res {
+                        ServerMessageKind::SetlistResponse(res)
+                    } else {
+                        ServerMessageKind::Error(Error::new(
+                            ErrorCode::Unavailable,
+                            "Setlist is not running",
+                        ))
                     }
-                    ServerMessageKind::Nak
                 }
-                ClientMessageKind::DeleteFile(path) => {
-                    if let Some(path) = vp.translate(&path) {
-                        if path.is_dir() {
-                            if tokio::fs::remove_dir_all(path).await.is_ok() {
-                                return ServerMessageKind::Ack;
-                            }
-                        } else if path.is_file() && tokio::fs::remove_file(path).await.is_ok() {
-                            return ServerMessageKind::Ack;
-                        }
+                ClientMessageKind::MappingRequest(req) => {
+                    let res = mapping::send_request_from(&map_req_tx, origin.client, req).await;
+                    if let Some(res) = res {
+                        ServerMessageKind::MappingResponse(res)
+                    } else {
+                        ServerMessageKind::Error(Error::new(
+                            ErrorCode::Unavailable,
+                            "Mapping is not running",
+                        ))
                     }
-                    ServerMessageKind::Nak
+                }
+                ClientMessageKind::LibraryRequest(req) => {
+                    ServerMessageKind::LibraryResponse(library.process_request(req))
+                }
+                ClientMessageKind::Subscribe(_)
+                | ClientMessageKind::GetChanges { .. }
+                | ClientMessageKind::SetName(_)
+                | ClientMessageKind::Lock(_)
+                | ClientMessageKind::Unlock(_) => ServerMessageKind::Error(Error::new(
+                    ErrorCode::Unsupported,
+                    "Only available on websocket connections",
+                )),
+                ClientMessageKind::ReadDir(path) => match files.read_dir(&path).await {
+                    Ok(entries) => ServerMessageKind::DirInfo(entries),
+                    Err(e) => ServerMessageKind::Error(e),
+                },
+                ClientMessageKind::MakeDir(path) => match files.make_dir(&path).await {
+                    Ok(()) => ServerMessageKind::Ack,
+                    Err(e) => ServerMessageKind::Error(e),
+                },
+                ClientMessageKind::DeleteFile(path) => {
+                    file_op_result(files.delete(&path).await, &library)
                 }
                 ClientMessageKind::RenameFile(path, new_path) => {
-                    if let (Some(path), Some(new_path)) =
-                        (vp.translate(&path), vp.translate(&new_path))
-                    {
-                        if tokio::fs::rename(&path, &new_path).await.is_ok() {
-                            return ServerMessageKind::Ack;
-                        }
-                    }
-                    ServerMessageKind::Nak
+                    file_op_result(files.rename(&path, &new_path).await, &library)
+                }
+                ClientMessageKind::CopyFile(path, new_path) => {
+                    file_op_result(files.copy(&path, &new_path).await, &library)
                 }
-                ClientMessageKind::CopyFile(path, new_path) => ServerMessageKind::Nak,
             }
         }
     })
@@ -268,6 +434,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// The library is scanned again after files changed
+fn file_op_result(result: Result<(), Error>, library: &library::Library) -> ServerMessageKind {
+    match result {
+        Ok(()) => {
+            library.rescan();
+            ServerMessageKind::Ack
+        }
+        Err(e) => ServerMessageKind::Error(e),
+    }
+}
+
 async fn run_midi_logger(mut midi_rx: midi::Receiver, mut clients: Clients) {
     while let Ok(message) = midi_rx.recv().await {
         // tracing::trace!("MSG");
@@ -275,69 +452,60 @@ async fn run_midi_logger(mut midi_rx: midi::Receiver, mut clients: Clients) {
     }
 }
 
+// New clients are sent the ports on connect, so only changes are broadcast
 async fn run_midi_port_watchdog(mut clients: Clients) {
+    let mut ports = Vec::new();
     loop {
-        clients.broadcast(ServerMessageKind::AvailableMidiInputs(
-            MidiReader::get_available_ports(),
-        ));
+        let available = MidiReader::get_available_ports();
+        if available != ports {
+            clients.broadcast(ServerMessageKind::AvailableMidiInputs(available.clone()));
+            ports = available;
+        }
         tokio::time::sleep(Duration::from_millis(1000)).await;
     }
 }
 
-async fn run_renderer(
-    mut renderer: Renderer,
-    req_num_samples: Arc<AtomicUsize>,
-    (mut lbuf_tx, mut rbuf_tx): (BufferTx, BufferTx),
-) {
-    let mut lbuf = vec![];
-    let mut rbuf = vec![];
-    let mut counter = 0;
-
+// Requests and MIDI are handled here, the render thread only renders
+async fn run_renderer(mut renderer: Renderer) {
     loop {
-        counter += 1;
-        if counter >= 10 {
-            counter = 0;
-            renderer.update().await;
-        }
-
-        let curr_buf_size = req_num_samples.load(std::sync::atomic::Ordering::Relaxed);
-
-        if curr_buf_size > 0 {
-            if lbuf.len() < curr_buf_size {
-                lbuf.resize(curr_buf_size, 0.0);
-                rbuf.resize(curr_buf_size, 0.0);
-            }
-
-            let lbuf_slice = &mut lbuf[..curr_buf_size];
-            let rbuf_slice = &mut rbuf[..curr_buf_size];
-
-            renderer.render(lbuf_slice, rbuf_slice);
-
-            lbuf_tx.push_slice(lbuf_slice);
-            rbuf_tx.push_slice(rbuf_slice);
-
-            req_num_samples.store(0, std::sync::atomic::Ordering::Relaxed);
-        }
-
-        tokio::time::sleep(Duration::from_micros(10)).await;
+        renderer.update().await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
     }
 }
 
-async fn send_renderer_request(
-    req_tx: &renderer::Requester,
-    req: renderer::RequestKind,
-) -> Option<renderer::ResponseKind> {
-    let (res_tx, res_rx) = renderer::create_response_channel();
-
-    if let Ok(()) = req_tx.send((req, res_tx)).await {
-        if let Ok(response_kind) = res_rx.await {
-            Some(response_kind)
-        } else {
-            None
-        }
-    } else {
-        None
-    }
+#[cfg(feature = "jack")]
+fn connect_jack_output(
+    midi_tx: midi::Sender,
+    midi_out_rx: midi::Receiver,
+    num_channels: usize,
+    autoconnect: bool,
+) -> Result<audio::Output, Box<dyn std::error::Error>> {
+    let client = audio::jack::open(audio::jack::ClientParams {
+        client_name: "AMI",
+        num_channels,
+        autoconnect,
+    })?;
+    let (rt_tx, rt_core) = realtime::create(client.num_channels());
+
+    let client = client.activate(rt_core, midi_tx, midi_out_rx)?;
+    info!("| JACK client: {}", client.client.as_client().name());
+
+    Ok(audio::Output {
+        stream: audio::Stream::Jack(client.client),
+        sample_rate: client.sample_rate,
+        num_channels: client.num_channels,
+        rt_tx,
+    })
+}
+
+#[cfg(not(feature = "jack"))]
+fn connect_jack_output(
+    _midi_tx: midi::Sender,
+    _midi_out_rx: midi::Receiver,
+    _num_channels: usize,
+    _autoconnect: bool,
+) -> Result<audio::Output, Box<dyn std::error::Error>> {
+    Err("AMI was built without the `jack` feature".into())
 }
 
 async fn run_controller(mut controller: Controller) {
@@ -347,23 +515,6 @@ async fn run_controller(mut controller: Controller) {
     }
 }
 
-async fn send_controller_request(
-    req_tx: &controller::Requester,
-    req: controller::RequestKind,
-) -> Option<controller::ResponseKind> {
-    let (res_tx, res_rx) = controller::create_response_channel();
-
-    if let Ok(()) = req_tx.send((req, res_tx)).await {
-        if let Ok(response_kind) = res_rx.await {
-            Some(response_kind)
-        } else {
-            None
-        }
-    } else {
-        None
-    }
-}
-
 async fn play_midi_file(path: &Path, midi_tx: midi::Sender) {
     let data = std::fs::read(path).unwrap();
     let smf = midly::Smf::parse(&data).unwrap();
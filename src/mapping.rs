@@ -0,0 +1,591 @@
+use crate::{
+    control::controller,
+    error::{Error, ErrorCode},
+    json::expect_serialize,
+    midi, parameter,
+    path::VirtualPaths,
+    render::renderer,
+    scene,
+    webserver::{cache::NodeUid, presence::ClientId, Cache, Clients, ServerMessageKind},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tokio::sync::{broadcast::error::RecvError, mpsc, oneshot};
+use tracing::error;
+
+pub type Requester = mpsc::Sender<(RequestKind, Option<ClientId>, Responder)>;
+pub type RequestListener = mpsc::Receiver<(RequestKind, Option<ClientId>, Responder)>;
+pub type Responder = oneshot::Sender<ResponseKind>;
+pub type ResponseListener = oneshot::Receiver<ResponseKind>;
+
+pub fn create_request_channel(buffer: usize) -> (Requester, RequestListener) {
+    mpsc::channel(buffer)
+}
+
+pub fn create_response_channel() -> (Responder, ResponseListener) {
+    oneshot::channel()
+}
+
+pub async fn send_request(req_tx: &Requester, req: RequestKind) -> Option<ResponseKind> {
+    send_request_from(req_tx, None, req).await
+}
+
+// The changes made by the request are attributed to the client
+pub async fn send_request_from(
+    req_tx: &Requester,
+    author: Option<ClientId>,
+    req: RequestKind,
+) -> Option<ResponseKind> {
+    let (res_tx, res_rx) = create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, author, res_tx)).await {
+        res_rx.await.ok()
+    } else {
+        None
+    }
+}
+
+// Values from this threshold up count as a pressed button
+const BUTTON_THRESHOLD: u8 = 64;
+
+// Node parameters are the ones the nodes list, see parameter::Parameters. The
+// node is kept by its uid, so the mapping stays with it when nodes are moved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "MappingParameter")]
+pub enum Parameter {
+    RenderNode { uid: NodeUid, id: String },
+    ControlNode { uid: NodeUid, id: String },
+    ControllerEnabled,
+    ControllerTempoBpm,
+    SceneRecall,
+}
+
+// No channel matches messages from every channel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Source {
+    ControlChange { channel: Option<u8>, cc: u8 },
+    Note { channel: Option<u8>, note: u8 },
+    ProgramChange { channel: Option<u8> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Curve {
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "MappingMode")]
+pub enum Mode {
+    Absolute,
+    Toggle,
+    Momentary,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Mapping {
+    pub source: Source,
+    pub parameter: Parameter,
+    pub min: f32,
+    pub max: f32,
+    pub curve: Curve,
+    pub mode: Mode,
+    #[serde(skip)]
+    pressed: bool,
+    #[serde(skip)]
+    toggled: bool,
+    // Declaration of a node parameter, looked up when the mapping is first used
+    #[serde(skip)]
+    kind: Option<parameter::Kind>,
+}
+
+impl Mapping {
+    pub fn new(source: Source, parameter: Parameter, kind: Option<parameter::Kind>) -> Self {
+        let (min, max) = match kind {
+            Some(kind) => kind.range(),
+            None => parameter.default_range(),
+        };
+        Self {
+            source,
+            parameter,
+            min,
+            max,
+            curve: Curve::Linear,
+            mode: Mode::Absolute,
+            pressed: false,
+            toggled: false,
+            kind,
+        }
+    }
+
+    // Returns the new parameter value if the message should change it
+    fn process(&mut self, value: u8, pressed: bool) -> Option<f32> {
+        let was_pressed = self.pressed;
+        self.pressed = pressed;
+        match self.mode {
+            Mode::Absolute => Some(scale(value, self.min, self.max, self.curve)),
+            Mode::Toggle if pressed && !was_pressed => {
+                self.toggled = !self.toggled;
+                Some(if self.toggled { self.max } else { self.min })
+            }
+            Mode::Toggle => None,
+            Mode::Momentary if pressed != was_pressed => {
+                Some(if pressed { self.max } else { self.min })
+            }
+            Mode::Momentary => None,
+        }
+    }
+}
+
+impl Parameter {
+    fn default_range(&self) -> (f32, f32) {
+        match self {
+            Self::ControllerTempoBpm => (40.0, 240.0),
+            Self::SceneRecall => (0.0, 127.0),
+            _ => (0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "MappingRequestKind")]
+pub enum RequestKind {
+    Learn(Parameter),
+    CancelLearn,
+    AddMapping(Mapping),
+    SetMapping(usize, Mapping),
+    RemoveMapping(usize),
+    ClearMappings,
+    Load(PathBuf),
+    Save(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "MappingResponseKind")]
+pub enum ResponseKind {
+    InvalidId,
+    Failed { reason: Error },
+    Ok,
+}
+
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "MappingUpdateKind")]
+pub enum UpdateKind {
+    Mappings(Vec<Mapping>),
+    Learning(Option<Parameter>),
+}
+
+pub struct MappingManager {
+    mappings: Vec<Mapping>,
+    learning: Option<Parameter>,
+    midi_rx: midi::Receiver,
+    req_rx: RequestListener,
+    rnd_req_tx: renderer::Requester,
+    ctr_req_tx: controller::Requester,
+    scn_req_tx: scene::Requester,
+    virtual_paths: VirtualPaths,
+    clients: Clients,
+    cache: Cache,
+}
+
+impl MappingManager {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        midi_rx: midi::Receiver,
+        req_rx: RequestListener,
+        rnd_req_tx: renderer::Requester,
+        ctr_req_tx: controller::Requester,
+        scn_req_tx: scene::Requester,
+        virtual_paths: VirtualPaths,
+        clients: Clients,
+        cache: Cache,
+    ) -> Self {
+        Self {
+            mappings: Default::default(),
+            learning: None,
+            midi_rx,
+            req_rx,
+            rnd_req_tx,
+            ctr_req_tx,
+            scn_req_tx,
+            virtual_paths,
+            clients,
+            cache,
+        }
+    }
+
+    pub fn serialize(&self) -> serde_json::Value {
+        json!({
+            "mappings": expect_serialize(&self.mappings),
+            "learning": expect_serialize(&self.learning),
+        })
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                req = self.req_rx.recv() => match req {
+                    Some((kind, author, responder)) => {
+                        self.cache.set_author(author);
+                        self.process_request(kind, responder).await;
+                        self.cache.set_author(None);
+                    }
+                    None => break,
+                },
+                msg = self.midi_rx.recv() => match msg {
+                    Ok(msg) => self.process_midi_message(&msg).await,
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+
+    async fn process_request(&mut self, kind: RequestKind, responder: Responder) {
+        match kind {
+            RequestKind::Learn(parameter) => {
+                respond(responder, ResponseKind::Ok);
+                self.set_learning(Some(parameter)).await;
+            }
+            RequestKind::CancelLearn => {
+                respond(responder, ResponseKind::Ok);
+                self.set_learning(None).await;
+            }
+            RequestKind::AddMapping(mapping) => {
+                respond(responder, ResponseKind::Ok);
+                self.mappings.push(mapping);
+                self.update_mappings().await;
+            }
+            RequestKind::SetMapping(id, mapping) => {
+                if id < self.mappings.len() {
+                    respond(responder, ResponseKind::Ok);
+                    self.mappings[id] = mapping;
+                    self.update_mappings().await;
+                } else {
+                    respond(responder, ResponseKind::InvalidId);
+                }
+            }
+            RequestKind::RemoveMapping(id) => {
+                if id < self.mappings.len() {
+                    respond(responder, ResponseKind::Ok);
+                    self.mappings.remove(id);
+                    self.update_mappings().await;
+                } else {
+                    respond(responder, ResponseKind::InvalidId);
+                }
+            }
+            RequestKind::ClearMappings => {
+                respond(responder, ResponseKind::Ok);
+                self.mappings.clear();
+                self.update_mappings().await;
+            }
+            RequestKind::Load(path) => {
+                let res = self.load(&path).await;
+                respond(responder, res);
+            }
+            RequestKind::Save(path) => {
+                let res = self.save(&path).await;
+                respond(responder, res);
+            }
+        }
+    }
+
+    async fn process_midi_message(&mut self, message: &midi::Message) {
+        let Some((source, value, pressed)) = message_source(message) else {
+            return;
+        };
+
+        if let Some(parameter) = self.learning.clone() {
+            // Note offs would otherwise be learned right after their note ons
+            if pressed {
+                let kind = self.parameter_kind(&parameter).await;
+                self.mappings.push(Mapping::new(source, parameter, kind));
+                self.set_learning(None).await;
+                self.update_mappings().await;
+            }
+            return;
+        }
+
+        let mut changes = vec![];
+        for (id, mapping) in self.mappings.iter_mut().enumerate() {
+            if source_matches(&mapping.source, &source) {
+                if let Some(value) = mapping.process(value, pressed) {
+                    changes.push((id, value));
+                }
+            }
+        }
+        for (id, value) in changes {
+            self.set_parameter(id, value).await;
+        }
+    }
+
+    async fn set_parameter(&mut self, mapping: usize, value: f32) {
+        match self.mappings[mapping].parameter.clone() {
+            Parameter::RenderNode { uid, id } => {
+                let Some(kind) = self.mapping_kind(mapping).await else {
+                    return;
+                };
+                if let Some(index) = self.cache.render_node_index(uid).await {
+                    let req = renderer::RequestKind::SetParameter(index, id, kind.value(value));
+                    renderer::send_request(&self.rnd_req_tx, req).await;
+                }
+            }
+            Parameter::ControlNode { uid, id } => {
+                let Some(kind) = self.mapping_kind(mapping).await else {
+                    return;
+                };
+                if let Some(index) = self.cache.control_node_index(uid).await {
+                    let req = controller::RequestKind::SetParameter(index, id, kind.value(value));
+                    controller::send_request(&self.ctr_req_tx, req).await;
+                }
+            }
+            Parameter::ControllerEnabled => {
+                let req = controller::RequestKind::SetEnabled(value >= 0.5);
+                controller::send_request(&self.ctr_req_tx, req).await;
+            }
+            Parameter::ControllerTempoBpm => {
+                let req = controller::RequestKind::SetTempoBpm(value);
+                controller::send_request(&self.ctr_req_tx, req).await;
+            }
+            Parameter::SceneRecall => {
+                let req = scene::RequestKind::Recall(value.round() as usize);
+                scene::send_request(&self.scn_req_tx, req).await;
+            }
+        }
+    }
+
+    async fn mapping_kind(&mut self, mapping: usize) -> Option<parameter::Kind> {
+        if self.mappings[mapping].kind.is_none() {
+            let parameter = self.mappings[mapping].parameter.clone();
+            self.mappings[mapping].kind = self.parameter_kind(&parameter).await;
+        }
+        self.mappings[mapping].kind
+    }
+
+    // Declaration of a node parameter as the node lists it
+    async fn parameter_kind(&self, parameter: &Parameter) -> Option<parameter::Kind> {
+        let (parameters, id) = match parameter {
+            Parameter::RenderNode { uid, id } => {
+                let index = self.cache.render_node_index(*uid).await?;
+                let req = renderer::RequestKind::ListParameters(index);
+                match renderer::send_request(&self.rnd_req_tx, req).await? {
+                    renderer::ResponseKind::Parameters(parameters) => (parameters, id),
+                    _ => return None,
+                }
+            }
+            Parameter::ControlNode { uid, id } => {
+                let index = self.cache.control_node_index(*uid).await?;
+                let req = controller::RequestKind::ListParameters(index);
+                match controller::send_request(&self.ctr_req_tx, req).await? {
+                    controller::ResponseKind::Parameters(parameters) => (parameters, id),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        parameters.into_iter().find(|p| p.id == *id).map(|p| p.kind)
+    }
+
+    async fn load(&mut self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let content = match tokio::fs::read_to_string(file).await {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::Failed { reason: e.into() },
+        };
+        let mappings = match serde_json::from_str(&content) {
+            Ok(mappings) => mappings,
+            Err(e) => return ResponseKind::failed(ErrorCode::Deserialization, e.to_string()),
+        };
+        self.mappings = mappings;
+        self.update_mappings().await;
+        ResponseKind::Ok
+    }
+
+    async fn save(&self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let content = match serde_json::to_string_pretty(&self.mappings) {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::failed(ErrorCode::Serialization, e.to_string()),
+        };
+        match tokio::fs::write(file, content).await {
+            Ok(()) => ResponseKind::Ok,
+            Err(e) => ResponseKind::Failed { reason: e.into() },
+        }
+    }
+
+    async fn set_learning(&mut self, learning: Option<Parameter>) {
+        self.learning = learning.clone();
+        self.update(UpdateKind::Learning(learning)).await;
+    }
+
+    async fn update_mappings(&mut self) {
+        self.update(UpdateKind::Mappings(self.mappings.clone()))
+            .await;
+    }
+
+    async fn update(&mut self, kind: UpdateKind) {
+        self.cache.set_mappings(self.serialize()).await;
+        self.clients
+            .broadcast(ServerMessageKind::MappingUpdate(kind));
+    }
+}
+
+// Source of the message together with its value and whether it counts as pressed
+fn message_source(message: &midi::Message) -> Option<(Source, u8, bool)> {
+    use midi::MessageKind as Kind;
+    let channel = Some(message.channel);
+    match message.kind {
+        Kind::ControlChange { kind, value } => {
+            let cc = kind.as_number();
+            Some((
+                Source::ControlChange { channel, cc },
+                value,
+                value >= BUTTON_THRESHOLD,
+            ))
+        }
+        Kind::NoteOn { note, velocity } => {
+            Some((Source::Note { channel, note }, velocity, velocity > 0))
+        }
+        Kind::NoteOff { note, .. } => Some((Source::Note { channel, note }, 0, false)),
+        Kind::ProgramChange { program } => Some((Source::ProgramChange { channel }, program, true)),
+        _ => None,
+    }
+}
+
+fn source_matches(mapped: &Source, incoming: &Source) -> bool {
+    let channel_matches =
+        |mapped: &Option<u8>, incoming: &Option<u8>| mapped.is_none() || mapped == incoming;
+    match (mapped, incoming) {
+        (
+            Source::ControlChange { channel, cc },
+            Source::ControlChange {
+                channel: in_channel,
+                cc: in_cc,
+            },
+        ) => cc == in_cc && channel_matches(channel, in_channel),
+        (
+            Source::Note { channel, note },
+            Source::Note {
+                channel: in_channel,
+                note: in_note,
+            },
+        ) => note == in_note && channel_matches(channel, in_channel),
+        (
+            Source::ProgramChange { channel },
+            Source::ProgramChange {
+                channel: in_channel,
+            },
+        ) => channel_matches(channel, in_channel),
+        _ => false,
+    }
+}
+
+fn scale(value: u8, min: f32, max: f32, curve: Curve) -> f32 {
+    let x = value.min(127) as f32 / 127.0;
+    let y = match curve {
+        Curve::Linear => x,
+        Curve::Exponential => x * x,
+        Curve::Logarithmic => x.sqrt(),
+    };
+    min + (max - min) * y
+}
+
+fn respond(responder: Responder, response_kind: ResponseKind) {
+    if let Err(e) = responder.send(response_kind) {
+        error!("Failed to send a response: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale() {
+        assert_eq!(super::scale(0, 40.0, 240.0, Curve::Linear), 40.0);
+        assert_eq!(super::scale(127, 40.0, 240.0, Curve::Linear), 240.0);
+        assert_eq!(super::scale(127, 1.0, 0.0, Curve::Exponential), 0.0);
+        assert!(super::scale(64, 0.0, 1.0, Curve::Exponential) < 0.5);
+        assert!(super::scale(64, 0.0, 1.0, Curve::Logarithmic) > 0.5);
+    }
+
+    #[test]
+    fn modes() {
+        let source = Source::ControlChange {
+            channel: None,
+            cc: 64,
+        };
+        let mut mapping = Mapping::new(source, Parameter::ControllerEnabled, None);
+
+        mapping.mode = Mode::Toggle;
+        assert_eq!(mapping.process(127, true), Some(1.0));
+        assert_eq!(mapping.process(127, true), None);
+        assert_eq!(mapping.process(0, false), None);
+        assert_eq!(mapping.process(127, true), Some(0.0));
+
+        mapping.mode = Mode::Momentary;
+        assert_eq!(mapping.process(0, false), Some(0.0));
+        assert_eq!(mapping.process(127, true), Some(1.0));
+        assert_eq!(mapping.process(0, false), Some(0.0));
+    }
+
+    #[test]
+    fn ranges() {
+        let source = Source::ProgramChange { channel: None };
+        let mapping = Mapping::new(source, Parameter::ControllerTempoBpm, None);
+        assert_eq!((mapping.min, mapping.max), (40.0, 240.0));
+        let parameter = Parameter::RenderNode {
+            uid: 1,
+            id: "transposition".into(),
+        };
+        let kind = parameter::Kind::Int { min: -48, max: 48 };
+        let mapping = Mapping::new(source, parameter, Some(kind));
+        assert_eq!((mapping.min, mapping.max), (-48.0, 48.0));
+        assert_eq!(mapping.kind, Some(kind));
+    }
+
+    #[test]
+    fn sources() {
+        let any = Source::Note {
+            channel: None,
+            note: 60,
+        };
+        let first = Source::Note {
+            channel: Some(0),
+            note: 60,
+        };
+        let second = Source::Note {
+            channel: Some(1),
+            note: 60,
+        };
+        assert!(source_matches(&any, &second));
+        assert!(source_matches(&first, &first));
+        assert!(!source_matches(&first, &second));
+        assert!(!source_matches(
+            &any,
+            &Source::ControlChange {
+                channel: Some(0),
+                cc: 60
+            }
+        ));
+    }
+}
@@ -2,9 +2,10 @@
 // https://www.songstuff.com/recording/article/midi_message_format/
 // https://www.midi.org/specifications-old/item/table-3-control-change-messages-data-bytes-2
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize, JsonSchema)]
 pub enum MessageKind {
     NoteOff { note: u8, velocity: u8 },
     NoteOn { note: u8, velocity: u8 },
@@ -29,7 +30,7 @@ impl MessageKind {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize, JsonSchema)]
 pub enum ControlChangeKind {
     BankSelectMsb,
     ModulationWheelMsb,
@@ -430,7 +431,7 @@ impl ControlChangeKind {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize, JsonSchema)]
 pub struct Message {
     pub kind: MessageKind,
     pub channel: u8,
@@ -445,6 +446,24 @@ impl Message {
         }
     }
 
+    // Raw bytes of the message and how many of them are used
+    pub fn encode(&self) -> ([u8; 3], usize) {
+        let status = self.kind.as_number() | (self.channel & 0x0F);
+        match self.kind {
+            MessageKind::NoteOff { note, velocity } | MessageKind::NoteOn { note, velocity } => {
+                ([status, note, velocity], 3)
+            }
+            MessageKind::PolyphonicAftertouch { note, pressure } => ([status, note, pressure], 3),
+            MessageKind::ControlChange { kind, value } => ([status, kind.as_number(), value], 3),
+            MessageKind::ProgramChange { program } => ([status, program, 0], 2),
+            MessageKind::ChannelAftertouch { pressure } => ([status, pressure, 0], 2),
+            MessageKind::PitchWheel { value } => (
+                [status, (value & 0x7F) as u8, ((value >> 7) & 0x7F) as u8],
+                3,
+            ),
+        }
+    }
+
     pub fn get_pitch_wheel_signed(value: u16) -> i16 {
         (value as i16) - 8192
     }
@@ -552,3 +571,33 @@ fn parse_pitch_wheel(bytes: &[u8]) -> Option<MessageKind> {
         Some(MessageKind::PitchWheel { value })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let kinds = [
+            MessageKind::NoteOn {
+                note: 60,
+                velocity: 100,
+            },
+            MessageKind::NoteOff {
+                note: 60,
+                velocity: 0,
+            },
+            MessageKind::ControlChange {
+                kind: ControlChangeKind::DamperPedal,
+                value: 127,
+            },
+            MessageKind::ProgramChange { program: 5 },
+            MessageKind::PitchWheel { value: 12000 },
+        ];
+        for kind in kinds {
+            let msg = Message { kind, channel: 9 };
+            let (bytes, len) = msg.encode();
+            assert_eq!(Message::decode(&bytes[..len]), Some(msg));
+        }
+    }
+}
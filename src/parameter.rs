@@ -0,0 +1,230 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Value {
+    Bool(bool),
+    Int(i32),
+    Float(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "ParameterKind")]
+pub enum Kind {
+    Bool,
+    Int { min: i32, max: i32 },
+    Float { min: f32, max: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Unit {
+    None,
+    Semitones,
+    Octaves,
+    Bars,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Parameter {
+    pub id: String,
+    pub kind: Kind,
+    pub default: Value,
+    pub unit: Unit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    UnknownParameter,
+    InvalidValue,
+    Failed,
+}
+
+pub type SetResult = Result<(), Error>;
+
+// Responses of the node requests which parameters are set with
+pub trait Response {
+    fn succeeded(&self) -> bool;
+}
+
+pub fn result(res: impl Response) -> SetResult {
+    if res.succeeded() {
+        Ok(())
+    } else {
+        Err(Error::Failed)
+    }
+}
+
+// Parameters which can be addressed generically by their id
+pub trait Parameters {
+    fn parameters(&self) -> Vec<Parameter>;
+    fn parameter(&self, id: &str) -> Option<Value>;
+    // Only called with values validated against the declaration of the parameter
+    fn set_parameter(&mut self, id: &str, value: Value) -> SetResult;
+}
+
+impl Parameter {
+    pub fn bool(id: &str, default: bool) -> Self {
+        Self {
+            id: id.into(),
+            kind: Kind::Bool,
+            default: Value::Bool(default),
+            unit: Unit::None,
+        }
+    }
+
+    pub fn int(id: &str, min: i32, max: i32, default: i32, unit: Unit) -> Self {
+        Self {
+            id: id.into(),
+            kind: Kind::Int { min, max },
+            default: Value::Int(default),
+            unit,
+        }
+    }
+
+    pub fn float(id: &str, min: f32, max: f32, default: f32, unit: Unit) -> Self {
+        Self {
+            id: id.into(),
+            kind: Kind::Float { min, max },
+            default: Value::Float(default),
+            unit,
+        }
+    }
+
+    // Integers are accepted for float parameters, everything else has to match exactly
+    pub fn validate(&self, value: Value) -> Option<Value> {
+        match (self.kind, value) {
+            (Kind::Bool, Value::Bool(_)) => Some(value),
+            (Kind::Int { min, max }, Value::Int(v)) if (min..=max).contains(&v) => Some(value),
+            (Kind::Float { min, max }, Value::Float(v)) if (min..=max).contains(&v) => Some(value),
+            (Kind::Float { min, max }, Value::Int(v)) if (min..=max).contains(&(v as f32)) => {
+                Some(Value::Float(v as f32))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Kind {
+    // The closest value of this kind, e.g. for a value coming from a MIDI controller
+    pub fn value(&self, value: f32) -> Value {
+        match *self {
+            Kind::Bool => Value::Bool(value >= 0.5),
+            Kind::Int { min, max } => Value::Int((value.round() as i32).clamp(min, max)),
+            Kind::Float { min, max } => Value::Float(value.clamp(min, max)),
+        }
+    }
+
+    pub fn range(&self) -> (f32, f32) {
+        match *self {
+            Kind::Bool => (0.0, 1.0),
+            Kind::Int { min, max } => (min as f32, max as f32),
+            Kind::Float { min, max } => (min, max),
+        }
+    }
+}
+
+impl Value {
+    pub fn as_bool(&self) -> bool {
+        match *self {
+            Self::Bool(v) => v,
+            Self::Int(v) => v != 0,
+            Self::Float(v) => v >= 0.5,
+        }
+    }
+
+    pub fn as_i32(&self) -> i32 {
+        match *self {
+            Self::Bool(v) => v as i32,
+            Self::Int(v) => v,
+            Self::Float(v) => v.round() as i32,
+        }
+    }
+
+    pub fn as_f32(&self) -> f32 {
+        match *self {
+            Self::Bool(v) => v as i32 as f32,
+            Self::Int(v) => v as f32,
+            Self::Float(v) => v,
+        }
+    }
+}
+
+// Validates the value against the declared parameters before setting it
+pub fn set<T: Parameters + ?Sized>(target: &mut T, id: &str, value: Value) -> SetResult {
+    let parameter = target
+        .parameters()
+        .into_iter()
+        .find(|p| p.id == id)
+        .ok_or(Error::UnknownParameter)?;
+    let value = parameter.validate(value).ok_or(Error::InvalidValue)?;
+    target.set_parameter(id, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let gain = Parameter::float("gain", 0.0, 4.0, 1.0, Unit::None);
+        assert_eq!(gain.validate(Value::Float(0.5)), Some(Value::Float(0.5)));
+        assert_eq!(gain.validate(Value::Int(2)), Some(Value::Float(2.0)));
+        assert_eq!(gain.validate(Value::Float(4.5)), None);
+        assert_eq!(gain.validate(Value::Float(f32::NAN)), None);
+        assert_eq!(gain.validate(Value::Bool(true)), None);
+
+        let key = Parameter::int("key", 0, 11, 0, Unit::Semitones);
+        assert_eq!(key.validate(Value::Int(11)), Some(Value::Int(11)));
+        assert_eq!(key.validate(Value::Int(12)), None);
+        assert_eq!(key.validate(Value::Float(1.0)), None);
+    }
+
+    #[test]
+    fn kind_value() {
+        let key = Kind::Int { min: 0, max: 11 };
+        assert_eq!(key.value(4.6), Value::Int(5));
+        assert_eq!(key.value(12.0), Value::Int(11));
+        assert_eq!(Kind::Bool.value(0.5), Value::Bool(true));
+        assert_eq!(Kind::Bool.value(0.2), Value::Bool(false));
+        let gain = Kind::Float { min: 0.0, max: 4.0 };
+        assert_eq!(gain.value(-1.0), Value::Float(0.0));
+        assert_eq!(gain.range(), (0.0, 4.0));
+    }
+
+    struct Node {
+        gain: f32,
+    }
+
+    impl Parameters for Node {
+        fn parameters(&self) -> Vec<Parameter> {
+            vec![Parameter::float("gain", 0.0, 4.0, 1.0, Unit::None)]
+        }
+
+        fn parameter(&self, id: &str) -> Option<Value> {
+            (id == "gain").then_some(Value::Float(self.gain))
+        }
+
+        fn set_parameter(&mut self, id: &str, value: Value) -> SetResult {
+            match id {
+                "gain" => self.gain = value.as_f32(),
+                _ => return Err(Error::UnknownParameter),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn set() {
+        let mut node = Node { gain: 1.0 };
+        assert_eq!(super::set(&mut node, "gain", Value::Int(2)), Ok(()));
+        assert_eq!(node.parameter("gain"), Some(Value::Float(2.0)));
+        assert_eq!(
+            super::set(&mut node, "gain", Value::Float(-1.0)),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(
+            super::set(&mut node, "level", Value::Float(1.0)),
+            Err(Error::UnknownParameter)
+        );
+    }
+}
@@ -16,16 +16,32 @@ impl VirtualPaths {
     // Translate virtual path to real one, if the resulting path is outside the base,
     // or the virtual path was not found, then return None
     pub fn translate(&self, path: &Path) -> Option<PathBuf> {
-        let path = path.to_str().expect("Path is not a valid UTF-8");
+        let path = path.to_str()?;
         let path = &PathBuf::from(&path.replace('/', std::path::MAIN_SEPARATOR_STR));
         for (vp, rp) in self.paths.iter() {
             if let Some(p) = remap_prefix(path, vp, rp) {
-                return Some(p);
+                if !is_path_within_base(&p, rp) {
+                    return None;
+                }
+                return normalize_path(&p);
             }
         }
         None
     }
 
+    // Whether the real path is one of the roots
+    pub fn is_root(&self, path: &Path) -> bool {
+        let path = normalize_path(path);
+        self.paths
+            .values()
+            .any(|rp| path.is_some() && normalize_path(rp) == path)
+    }
+
+    // Real paths the virtual ones are mapped to
+    pub fn roots(&self) -> impl Iterator<Item = &Path> {
+        self.paths.values().map(PathBuf::as_path)
+    }
+
     pub fn translate_back(&self, path: &Path) -> Option<PathBuf> {
         let path = path.to_str().expect("Path is not a valid UTF-8");
         let path = &PathBuf::from(&path.replace(std::path::MAIN_SEPARATOR, "/"));
@@ -104,6 +120,11 @@ mod tests {
             Some(PathBuf::from("/projects"))
         );
         assert_eq!(vp.translate(Path::new("dsd")), None);
+        assert_eq!(vp.translate(Path::new("samples:/../../etc")), None);
+        assert_eq!(
+            vp.translate(Path::new("samples:/kit/../piano.sf2")),
+            Some(PathBuf::from("/samples/piano.sf2"))
+        );
         assert_eq!(
             vp.translate_back(Path::new("/projects")),
             Some(PathBuf::from("projects:"))
@@ -122,10 +143,7 @@ mod tests {
 
     #[test]
     fn remove_prefix() {
-        let x = super::remove_prefix(
-            Path::new("sample:/test/1/2/3"),
-            Path::new("sample:/test"),
-        );
+        let x = super::remove_prefix(Path::new("sample:/test/1/2/3"), Path::new("sample:/test"));
         assert_eq!(x, PathBuf::from("1/2/3"));
     }
 }
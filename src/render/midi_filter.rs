@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::midi;
@@ -6,7 +7,8 @@ const NUM_CHANNELS: usize = 16;
 const NUM_NOTES: usize = 128;
 const NUM_CONTROL_COMMANDS: usize = 128;
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "MidiFilterUpdateKind")]
 pub enum UpdateKind {
     Enabled(bool),
     Channel(usize, bool),
@@ -1,12 +1,20 @@
-use node::RenderPtr;
+use node::VoicePtr;
 
 pub mod midi_filter;
 pub mod node;
 pub mod preset_map;
+pub mod preview;
+pub mod realtime;
 pub mod velocity_map;
+pub mod zone;
 pub mod renderer;
 
 pub const MAX_BUFFER_SIZE: usize = 192000;
+// Stereo outputs a node can be routed to, the first one is the main output
+pub const MAX_OUTPUTS: usize = 8;
+pub const MAX_CHANNELS: usize = 2 * MAX_OUTPUTS;
+// In semitones, for the transposition of a node and the global one
+pub const MAX_TRANSPOSITION: i8 = 48;
 
 pub fn amplify_buffer(buffer: &mut [f32], gain: f32) {
     if gain != 1.0 {
@@ -18,12 +26,40 @@ pub fn clear_buffer(buffer: &mut [f32]) {
     buffer.fill(0.0);
 }
 
-pub fn render_nodes_to_bufs(nodes: &mut [RenderPtr], lbuf: &mut [f32], rbuf: &mut [f32]) {
+pub fn render_nodes_to_bufs(nodes: &mut [VoicePtr], lbuf: &mut [f32], rbuf: &mut [f32]) {
     nodes
         .iter_mut()
         .for_each(|child| child.render_additive(lbuf, rbuf));
 }
 
+pub enum OutputChannels<'a> {
+    Stereo(&'a mut [f32], &'a mut [f32]),
+    // the last output of an odd number of channels
+    Mono(&'a mut [f32]),
+}
+
+// Channels of an output, outputs the device doesn't have fall back to the main output
+pub fn output_channels<'a>(
+    channels: &'a mut [&mut [f32]],
+    output: usize,
+) -> Option<OutputChannels<'a>> {
+    let output = if 2 * output < channels.len() {
+        output
+    } else {
+        0
+    };
+    match channels.get_mut(2 * output..)? {
+        [left, right, ..] => Some(OutputChannels::Stereo(left, right)),
+        [mono] => Some(OutputChannels::Mono(mono)),
+        _ => None,
+    }
+}
+
+// Number of outputs of a device, the last channel of an odd count is a mono output
+pub fn num_outputs(num_channels: usize) -> usize {
+    num_channels.div_ceil(2)
+}
+
 pub fn add_buf_to_buf(buffer: &mut [f32], tmp_buffer: &[f32]) {
     let len = usize::min(buffer.len(), tmp_buffer.len());
     for i in 0..len {
@@ -40,4 +76,32 @@ mod tests {
         super::amplify_buffer(&mut buffer, gain);
         assert_eq!(buffer, [1.0 * gain, 0.0 * gain, 3.2 * gain])
     }
+
+    #[test]
+    fn output_channels() {
+        use super::OutputChannels;
+
+        let mut bufs = [[0.0; 2]; 5];
+        let mut channels: Vec<&mut [f32]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        let Some(OutputChannels::Stereo(lbuf, rbuf)) = super::output_channels(&mut channels, 1)
+        else {
+            panic!("output 1 is stereo");
+        };
+        lbuf[0] = 1.0;
+        rbuf[0] = 2.0;
+        // the fifth channel has no partner, so output 2 is mono
+        let Some(OutputChannels::Mono(buf)) = super::output_channels(&mut channels, 2) else {
+            panic!("output 2 is mono");
+        };
+        buf[0] = 3.0;
+        // output 3 doesn't exist, it goes to the main output
+        let Some(OutputChannels::Stereo(lbuf, _)) = super::output_channels(&mut channels, 3) else {
+            panic!("output 0 is stereo");
+        };
+        lbuf[0] = 4.0;
+        assert_eq!(bufs[0][0], 4.0);
+        assert_eq!((bufs[2][0], bufs[3][0], bufs[4][0]), (1.0, 2.0, 3.0));
+        assert!(super::output_channels(&mut [], 0).is_none());
+        assert_eq!((super::num_outputs(1), super::num_outputs(5)), (1, 3));
+    }
 }
@@ -0,0 +1,893 @@
+use super::{
+    Render, RenderPtr, RequestKind, ResponseCallback, ResponseKind, Voice, VoiceCommand, VoicePtr,
+    VoiceTask,
+};
+use crate::{
+    error::ErrorCode,
+    json::{
+        self, deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate,
+        SerializationResult,
+    },
+    json_try,
+    midi::{self, ControlChangeKind},
+    parameter::{self, Parameter, Parameters, Value},
+    path::VirtualPaths,
+    render::{
+        self,
+        midi_filter::{self, MidiFilterUser},
+        velocity_map,
+        zone::{self, ZoneUser},
+    },
+};
+use serde_json::json;
+use std::{
+    any::Any,
+    marker::PhantomData,
+    mem,
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+};
+
+// The synth of an engine, owned by the render thread. None of it may allocate.
+pub trait EngineVoice: Default + Send + 'static {
+    // Overwrites the buffers, returns false when there was nothing to render
+    fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) -> bool;
+    fn reset(&mut self);
+    fn note_on(&mut self, note: u8, velocity: u8);
+    fn note_off(&mut self, note: u8, velocity: u8);
+    fn polyphonic_aftertouch(&mut self, _note: u8, _pressure: u8) {}
+    fn control_change(&mut self, kind: ControlChangeKind, value: u8);
+    fn program_change(&mut self, _program: u8) {}
+    fn channel_aftertouch(&mut self, _pressure: u8) {}
+    fn pitch_wheel(&mut self, value: u16);
+}
+
+// Bare synth engine, the container takes care of everything in front of and behind it.
+// The engine keeps the state and hands its voice the changes through voice tasks.
+pub trait Engine: Parameters + Clone + Default + Send + 'static {
+    const DEFAULT_NAME: &'static str;
+    type Voice: EngineVoice;
+
+    // Background work such as finishing file loads
+    fn update(&mut self) {}
+    fn set_block_size(&mut self, _block_size: usize) {}
+    fn set_virtual_paths(&mut self, vp: VirtualPaths);
+    fn set_sample_rate(&mut self, sample_rate: u32);
+    fn set_master_tuning(&mut self, _a4_hz: f32) {}
+    // Sees the MIDI messages on their way to the voice, e.g. to keep the controllers
+    fn observe_midi(&mut self, _kind: &midi::MessageKind) {}
+    // Only receives the requests the container does not handle itself
+    fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback);
+    fn serialize(&self) -> SerializationResult;
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult;
+    // Called after deserializing a partial state to push it into the engine,
+    // sets the parameters again whose field is part of the state. The reverb_
+    // parameters belong to the "reverb" field.
+    fn apply_state(&mut self, state: &serde_json::Value) {
+        for parameter in self.parameters() {
+            let field = parameter.id.split('_').next().unwrap_or_default();
+            if state.get(field).is_none() {
+                continue;
+            }
+            if let Some(value) = self.parameter(&parameter.id) {
+                _ = self.set_parameter(&parameter.id, value);
+            }
+        }
+    }
+    // Updates of the controller state after a MIDI message sent through a request
+    fn midi_state_updates(&self) -> Vec<JsonFieldUpdate> {
+        Vec::new()
+    }
+    fn take_json_updates(&mut self) -> Vec<JsonFieldUpdate>;
+    fn take_voice_tasks(&mut self) -> Vec<VoiceTask>;
+}
+
+// Voice tasks an engine collects until the container sends them
+pub struct VoiceTasks<V> {
+    tasks: Vec<VoiceTask>,
+    voice: PhantomData<fn(&mut V)>,
+}
+
+impl<V: EngineVoice> VoiceTasks<V> {
+    pub fn run(&mut self, mut f: impl FnMut(&mut V) + Send + 'static) {
+        self.tasks.push(Box::new(move |voice: &mut dyn Any| {
+            if let Some(voice) = voice.downcast_mut::<V>() {
+                f(voice);
+            }
+        }));
+    }
+
+    // Puts the value into the voice, what it replaces stays in the task and is
+    // handed back with it, so the render thread neither allocates nor frees
+    pub fn swap<T: Send + 'static>(&mut self, mut value: T, field: fn(&mut V) -> &mut T) {
+        self.tasks.push(Box::new(move |voice: &mut dyn Any| {
+            if let Some(voice) = voice.downcast_mut::<V>() {
+                mem::swap(field(voice), &mut value);
+            }
+        }));
+    }
+
+    pub fn take(&mut self) -> Vec<VoiceTask> {
+        mem::take(&mut self.tasks)
+    }
+}
+
+impl<V> Default for VoiceTasks<V> {
+    fn default() -> Self {
+        Self {
+            tasks: Vec::new(),
+            voice: PhantomData,
+        }
+    }
+}
+
+pub type LoadHandle<T> = JoinHandle<Result<T, String>>;
+
+// Engines loading their file on a thread can load another file ahead of time,
+// its handle takes over when that file is loaded
+pub trait Preload {
+    type Loaded;
+
+    fn last_file(&mut self) -> &mut Option<PathBuf>;
+    fn load_handle(&mut self) -> &mut Option<LoadHandle<Self::Loaded>>;
+    fn preloaded_file(&mut self) -> &mut Option<(PathBuf, LoadHandle<Self::Loaded>)>;
+    // Starts loading the last file into the load handle
+    fn load_non_blocking(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Runs the regular loader for another file and keeps its result aside
+    fn preload_file(&mut self, path: &Path) -> ResponseKind {
+        let last_file = self.last_file().replace(path.to_owned());
+        let loading = self.load_handle().take();
+        let res = self.load_non_blocking();
+        let preloaded = mem::replace(self.load_handle(), loading);
+        *self.last_file() = last_file;
+        match (res, preloaded) {
+            (Ok(()), Some(handle)) => {
+                *self.preloaded_file() = Some((path.to_owned(), handle));
+                ResponseKind::Ok
+            }
+            (Err(e), _) => ResponseKind::failed(ErrorCode::LoadFailed, e.to_string()),
+            (Ok(()), None) => ResponseKind::failed(ErrorCode::LoadFailed, "Nothing to preload"),
+        }
+    }
+
+    fn take_preloaded_file(&mut self, path: &Path) -> Option<LoadHandle<Self::Loaded>> {
+        match self.preloaded_file().take() {
+            Some((file, handle)) if file == path => Some(handle),
+            _ => None,
+        }
+    }
+}
+
+pub struct Container<E: Engine> {
+    name: String,
+    enabled: bool,
+    midi_filter: midi_filter::MidiFilter,
+    zones: zone::Zones,
+    gain: f32,
+    pan: f32,
+    output: usize,
+    transposition: i8,
+    global_transposition: i8,
+    velocity_mapping: velocity_map::Kind,
+    ignore_global_transposition: bool,
+    // the notes sent for each held key, the note-offs match them when the
+    // transposition changed in between
+    held: Vec<(u8, Vec<u8>)>,
+    block_size: usize,
+    user_presets: Vec<bool>,
+    engine: E,
+    json_updates: Vec<JsonFieldUpdate>,
+    voice_commands: Vec<VoiceCommand>,
+}
+
+// What the render thread keeps of a container
+pub struct ContainerVoice<V: EngineVoice> {
+    gain: f32,
+    pan: f32,
+    output: usize,
+    tmp_lbuf: Vec<f32>,
+    tmp_rbuf: Vec<f32>,
+    voice: V,
+}
+
+impl<E: Engine> Container<E> {
+    fn set_name(&mut self, name: &str) -> ResponseKind {
+        self.name = name.into();
+        json_try! {
+            self.json_updates.push(("name".to_owned(), serialize(name)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> ResponseKind {
+        self.enabled = flag;
+        json_try! {
+            self.json_updates.push(("enabled".to_owned(), serialize(flag)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_gain(&mut self, gain: f32) -> ResponseKind {
+        self.gain = gain;
+        self.send_mix();
+        json_try! {
+            self.json_updates.push(("gain".into(), serialize(gain)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_pan(&mut self, pan: f32) -> ResponseKind {
+        if !(-1.0..=1.0).contains(&pan) {
+            return ResponseKind::failed(ErrorCode::InvalidValue, "Pan must be between -1 and 1");
+        }
+        self.pan = pan;
+        self.send_mix();
+        json_try! {
+            self.json_updates.push(("pan".into(), serialize(pan)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_output(&mut self, output: usize) -> ResponseKind {
+        if output >= render::MAX_OUTPUTS {
+            return ResponseKind::failed(
+                ErrorCode::InvalidValue,
+                format!("There are {} outputs", render::MAX_OUTPUTS),
+            );
+        }
+        self.output = output;
+        self.send_mix();
+        json_try! {
+            self.json_updates.push(("output".into(), serialize(output)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_transposition(&mut self, transposition: i8) -> ResponseKind {
+        if transposition.abs() > render::MAX_TRANSPOSITION {
+            return ResponseKind::failed(ErrorCode::InvalidValue, "Transposition out of range");
+        }
+        self.transposition = transposition;
+        json_try! {
+            self.json_updates.push(("transposition".into(), serialize(transposition)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_velocity_mapping(&mut self, mapping: velocity_map::Kind) -> ResponseKind {
+        if !velocity_map::is_valid(&mapping) {
+            return ResponseKind::failed(ErrorCode::InvalidValue, "Invalid velocity mapping");
+        }
+        self.velocity_mapping = mapping;
+        json_try! {
+            self.json_updates.push(("velocity_mapping".into(), serialize(&self.velocity_mapping)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn set_ignore_global_transposition(&mut self, flag: bool) -> ResponseKind {
+        self.ignore_global_transposition = flag;
+        json_try! {
+            self.json_updates.push(("ignore_global_transposition".into(), serialize(flag)?))
+        }
+        ResponseKind::Ok
+    }
+
+    fn update_midi_filter(&mut self, kind: midi_filter::UpdateKind) -> ResponseKind {
+        if MidiFilterUser::process_update_request(self, kind).is_ok() {
+            json_try! {
+                self.json_updates.push(("midi_filter".into(), serialize(&self.midi_filter)?))
+            }
+            ResponseKind::Ok
+        } else {
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid MIDI filter update")
+        }
+    }
+
+    fn update_zones(&mut self, kind: zone::UpdateKind) -> ResponseKind {
+        if ZoneUser::process_update_request(self, kind).is_ok() {
+            json_try! {
+                self.json_updates.push(("zones".into(), serialize(self.zones.list())?))
+            }
+            ResponseKind::Ok
+        } else {
+            ResponseKind::failed(ErrorCode::InvalidValue, "Invalid zone update")
+        }
+    }
+
+    fn set_user_preset_enabled(&mut self, preset: usize, flag: bool) -> ResponseKind {
+        if preset >= self.user_presets.len() {
+            ResponseKind::failed(
+                ErrorCode::InvalidValue,
+                format!("Invalid user preset: {preset}"),
+            )
+        } else {
+            self.user_presets[preset] = flag;
+            json_try! {
+                self.json_updates.push(("user_presets".into(), serialize(&self.user_presets)?))
+            }
+            ResponseKind::Ok
+        }
+    }
+
+    fn process_midi_message_kind(&mut self, kind: &midi::MessageKind) {
+        use midi::MessageKind as Kind;
+        match *kind {
+            Kind::NoteOn { note, velocity } => self.note_on(note, velocity),
+            Kind::NoteOff { note, velocity } => self.note_off(note, velocity),
+            Kind::PolyphonicAftertouch { note, pressure } => {
+                self.polyphonic_aftertouch(note, pressure)
+            }
+            kind => self.send_midi(kind),
+        }
+    }
+
+    fn note_on(&mut self, key: u8, velocity: u8) {
+        let velocity = velocity_map::map(&self.velocity_mapping, velocity);
+        // a key struck again still ends the notes it has started before
+        let mut sent = self.release_key(key);
+        for (note, velocity) in self.zones.note_on(key, velocity) {
+            if let Some(note) = self.transpose_note(note) {
+                self.send_midi(midi::MessageKind::NoteOn { note, velocity });
+                if !sent.contains(&note) {
+                    sent.push(note);
+                }
+            }
+        }
+        self.held.push((key, sent));
+    }
+
+    fn note_off(&mut self, key: u8, velocity: u8) {
+        self.zones.note_off(key);
+        for note in self.release_key(key) {
+            self.send_midi(midi::MessageKind::NoteOff { note, velocity });
+        }
+    }
+
+    fn polyphonic_aftertouch(&mut self, key: u8, pressure: u8) {
+        let notes = self.held_notes(key).to_vec();
+        for note in notes {
+            self.send_midi(midi::MessageKind::PolyphonicAftertouch { note, pressure });
+        }
+    }
+
+    fn held_notes(&self, key: u8) -> &[u8] {
+        self.held
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, notes)| notes.as_slice())
+            .unwrap_or(&[])
+    }
+
+    fn release_key(&mut self, key: u8) -> Vec<u8> {
+        match self.held.iter().position(|(k, _)| *k == key) {
+            Some(index) => self.held.swap_remove(index).1,
+            None => vec![],
+        }
+    }
+
+    fn send_midi(&mut self, kind: midi::MessageKind) {
+        self.engine.observe_midi(&kind);
+        self.voice_commands.push(VoiceCommand::Midi(kind));
+    }
+
+    fn send_mix(&mut self) {
+        self.voice_commands.push(VoiceCommand::Mix {
+            gain: self.gain,
+            pan: self.pan,
+            output: self.output,
+        });
+    }
+
+    fn does_midi_msg_pass(&self, msg: &midi::Message) -> bool {
+        if let midi::MessageKind::NoteOn { .. } = msg.kind {
+            self.enabled
+        } else {
+            true
+        }
+    }
+
+    fn get_total_transposition(&self) -> i8 {
+        if self.ignore_global_transposition {
+            self.transposition
+        } else {
+            self.transposition.saturating_add(self.global_transposition)
+        }
+    }
+
+    // Notes transposed out of the MIDI range are dropped
+    fn transpose_note(&self, note: u8) -> Option<u8> {
+        let note = note as i16 + self.get_total_transposition() as i16;
+        (0..=127).contains(&note).then_some(note as u8)
+    }
+}
+
+impl<E: Engine> Default for Container<E> {
+    fn default() -> Self {
+        Self {
+            name: E::DEFAULT_NAME.into(),
+            enabled: true,
+            midi_filter: Default::default(),
+            zones: Default::default(),
+            gain: 1.0,
+            pan: 0.0,
+            output: 0,
+            transposition: 0,
+            global_transposition: 0,
+            velocity_mapping: velocity_map::Kind::Identity,
+            ignore_global_transposition: false,
+            held: vec![],
+            block_size: 0,
+            user_presets: vec![true; super::NUM_USER_PRESETS],
+            engine: Default::default(),
+            json_updates: Default::default(),
+            voice_commands: Default::default(),
+        }
+    }
+}
+
+impl<E: Engine> Clone for Container<E> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            midi_filter: self.midi_filter.clone(),
+            zones: self.zones.clone(),
+            gain: self.gain,
+            pan: self.pan,
+            output: self.output,
+            transposition: self.transposition,
+            global_transposition: self.global_transposition,
+            velocity_mapping: self.velocity_mapping.clone(),
+            ignore_global_transposition: self.ignore_global_transposition,
+            held: vec![],
+            block_size: self.block_size,
+            user_presets: self.user_presets.clone(),
+            engine: self.engine.clone(),
+            json_updates: Default::default(),
+            voice_commands: Default::default(),
+        }
+    }
+}
+
+impl<E: Engine> Render for Container<E> {
+    // The voice starts out empty, the engine fills it with its voice tasks
+    fn create_voice(&self) -> VoicePtr {
+        Box::new(ContainerVoice {
+            gain: self.gain,
+            pan: self.pan,
+            output: self.output,
+            tmp_lbuf: vec![0.0; self.block_size],
+            tmp_rbuf: vec![0.0; self.block_size],
+            voice: E::Voice::default(),
+        })
+    }
+
+    fn update(&mut self) {
+        self.engine.update();
+    }
+
+    fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size;
+        self.engine.set_block_size(block_size);
+    }
+
+    fn reset_rendering(&mut self) {
+        self.zones.release_all();
+        self.held.clear();
+        self.voice_commands.push(VoiceCommand::Reset);
+    }
+
+    fn set_virtual_paths(&mut self, vp: VirtualPaths) {
+        self.engine.set_virtual_paths(vp);
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.engine.set_sample_rate(sample_rate);
+    }
+
+    fn receive_midi_message(&mut self, message: &midi::Message) {
+        if self.midi_filter.does_pass(message) && self.does_midi_msg_pass(message) {
+            self.process_midi_message_kind(&message.kind);
+        }
+    }
+
+    fn set_global_transposition(&mut self, transposition: i8) {
+        self.global_transposition = clamp_transposition(transposition);
+    }
+
+    fn set_master_tuning(&mut self, a4_hz: f32) {
+        self.engine.set_master_tuning(a4_hz);
+    }
+
+    fn set_user_preset(&mut self, preset: usize) {
+        if preset < self.user_presets.len() {
+            self.enabled = self.user_presets[preset];
+            json_try! {
+                self.json_updates.push(("enabled".into(), serialize(self.enabled)?))
+            }
+        }
+    }
+
+    fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
+        type RK = RequestKind;
+        match kind {
+            RK::SetName(name) => cb(self.set_name(&name)),
+            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RK::SetGain(gain) => cb(self.set_gain(gain)),
+            RK::SetPan(pan) => cb(self.set_pan(pan)),
+            RK::SetOutput(output) => cb(self.set_output(output)),
+            RK::SetTransposition(tr) => cb(self.set_transposition(tr)),
+            RK::SetVelocityMapping(kind) => cb(self.set_velocity_mapping(kind)),
+            RK::SetIgnoreGlobalTransposition(flag) => {
+                cb(self.set_ignore_global_transposition(flag))
+            }
+            RK::MidiMessage(kind) => {
+                self.process_midi_message_kind(&kind);
+                self.json_updates.extend(self.engine.midi_state_updates());
+                cb(ResponseKind::Ok)
+            }
+            RK::UpdateMidiFilter(kind) => cb(self.update_midi_filter(kind)),
+            RK::UpdateZones(kind) => cb(self.update_zones(kind)),
+            RK::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
+            kind => self.engine.process_request(kind, cb),
+        };
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let mut result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "midi_filter": serialize(&self.midi_filter)?,
+            "zones": serialize(self.zones.list())?,
+            "gain": serialize(self.gain)?,
+            "pan": serialize(self.pan)?,
+            "output": serialize(self.output)?,
+            "transposition": serialize(self.transposition)?,
+            "global_transposition": serialize(self.global_transposition)?,
+            "velocity_mapping": serialize(&self.velocity_mapping)?,
+            "ignore_global_transposition": serialize(self.ignore_global_transposition)?,
+            "user_presets": serialize(&self.user_presets)?,
+        });
+        if let (Some(fields), serde_json::Value::Object(engine_fields)) =
+            (result.as_object_mut(), self.engine.serialize()?)
+        {
+            fields.extend(engine_fields);
+        }
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "midi_filter", |v| self.midi_filter = v)?;
+        deser_field_opt(source, "zones", |v| self.zones.set_list(v))?;
+        deser_field_opt(source, "gain", |v| self.gain = v)?;
+        deser_field_opt(source, "pan", |v: f32| self.pan = v.clamp(-1.0, 1.0))?;
+        deser_field_opt(source, "output", |v: usize| {
+            self.output = v.min(render::MAX_OUTPUTS - 1)
+        })?;
+        deser_field_opt(source, "transposition", |v| {
+            self.transposition = clamp_transposition(v)
+        })?;
+        deser_field_opt(source, "velocity_mapping", |v: velocity_map::Kind| {
+            if velocity_map::is_valid(&v) {
+                self.velocity_mapping = v
+            }
+        })?;
+        deser_field_opt(source, "global_transposition", |v| {
+            self.global_transposition = clamp_transposition(v)
+        })?;
+        deser_field_opt(source, "ignore_global_transposition", |v| {
+            self.ignore_global_transposition = v
+        })?;
+        deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
+        self.send_mix();
+        self.engine.deserialize(source)
+    }
+
+    fn apply_state(&mut self, state: &serde_json::Value) -> DeserializationResult {
+        self.deserialize(state)?;
+        self.engine.apply_state(state);
+        let updates = json::field_updates(state, &self.serialize()?);
+        self.json_updates.extend(updates);
+        Ok(())
+    }
+
+    fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>> {
+        self.json_updates.extend(self.engine.take_json_updates());
+        if !self.json_updates.is_empty() {
+            Some(mem::take(&mut self.json_updates))
+        } else {
+            None
+        }
+    }
+
+    fn take_voice_commands(&mut self) -> Vec<VoiceCommand> {
+        let tasks = self.engine.take_voice_tasks();
+        self.voice_commands
+            .extend(tasks.into_iter().map(VoiceCommand::Task));
+        mem::take(&mut self.voice_commands)
+    }
+
+    fn clone_node(&self) -> RenderPtr {
+        Box::new(self.clone())
+    }
+}
+
+impl<V: EngineVoice> ContainerVoice<V> {
+    fn process_midi_message_kind(&mut self, kind: midi::MessageKind) {
+        use midi::MessageKind as Kind;
+        match kind {
+            Kind::NoteOn { note, velocity } => self.voice.note_on(note, velocity),
+            Kind::NoteOff { note, velocity } => self.voice.note_off(note, velocity),
+            Kind::PolyphonicAftertouch { note, pressure } => {
+                self.voice.polyphonic_aftertouch(note, pressure)
+            }
+            Kind::ControlChange { kind, value } => self.voice.control_change(kind, value),
+            Kind::ProgramChange { program } => self.voice.program_change(program),
+            Kind::ChannelAftertouch { pressure } => self.voice.channel_aftertouch(pressure),
+            Kind::PitchWheel { value } => self.voice.pitch_wheel(value),
+        }
+    }
+}
+
+impl<V: EngineVoice> Voice for ContainerVoice<V> {
+    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
+        let len = lbuf.len().min(rbuf.len()).min(self.tmp_lbuf.len());
+        let tmp_lbuf = &mut self.tmp_lbuf[..len];
+        let tmp_rbuf = &mut self.tmp_rbuf[..len];
+        if self.voice.render(tmp_lbuf, tmp_rbuf) {
+            let (lgain, rgain) = pan_gains(self.gain, self.pan);
+            render::amplify_buffer(tmp_lbuf, lgain);
+            render::amplify_buffer(tmp_rbuf, rgain);
+            render::add_buf_to_buf(lbuf, tmp_lbuf);
+            render::add_buf_to_buf(rbuf, tmp_rbuf);
+        }
+    }
+
+    fn output(&self) -> usize {
+        self.output
+    }
+
+    fn process_command(&mut self, cmd: &mut VoiceCommand) {
+        match *cmd {
+            VoiceCommand::Midi(kind) => self.process_midi_message_kind(kind),
+            VoiceCommand::Mix { gain, pan, output } => {
+                self.gain = gain;
+                self.pan = pan;
+                self.output = output;
+            }
+            VoiceCommand::Reset => self.voice.reset(),
+            VoiceCommand::Task(ref mut task) => task(&mut self.voice),
+        }
+    }
+}
+
+impl<E: Engine> Parameters for Container<E> {
+    fn parameters(&self) -> Vec<Parameter> {
+        let mut parameters = super::common_parameters();
+        parameters.extend(self.engine.parameters());
+        parameters
+    }
+
+    fn parameter(&self, id: &str) -> Option<Value> {
+        match id {
+            "enabled" => Some(Value::Bool(self.enabled)),
+            "gain" => Some(Value::Float(self.gain)),
+            "pan" => Some(Value::Float(self.pan)),
+            "output" => Some(Value::Int(self.output as i32)),
+            "transposition" => Some(Value::Int(self.transposition as i32)),
+            "ignore_global_transposition" => Some(Value::Bool(self.ignore_global_transposition)),
+            _ => self.engine.parameter(id),
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: Value) -> parameter::SetResult {
+        let res = match id {
+            "enabled" => self.set_enabled(value.as_bool()),
+            "gain" => self.set_gain(value.as_f32()),
+            "pan" => self.set_pan(value.as_f32()),
+            "output" => self.set_output(value.as_i32().max(0) as usize),
+            "transposition" => self.set_transposition(value.as_i32() as i8),
+            "ignore_global_transposition" => self.set_ignore_global_transposition(value.as_bool()),
+            _ => return self.engine.set_parameter(id, value),
+        };
+        parameter::result(res)
+    }
+}
+
+impl<E: Engine> MidiFilterUser for Container<E> {
+    fn midi_filter_mut(&mut self) -> &mut midi_filter::MidiFilter {
+        &mut self.midi_filter
+    }
+}
+
+impl<E: Engine> ZoneUser for Container<E> {
+    fn zones_mut(&mut self) -> &mut zone::Zones {
+        &mut self.zones
+    }
+}
+
+fn clamp_transposition(transposition: i8) -> i8 {
+    transposition.clamp(-render::MAX_TRANSPOSITION, render::MAX_TRANSPOSITION)
+}
+
+// Balance law, the centre position leaves both channels untouched
+fn pan_gains(gain: f32, pan: f32) -> (f32, f32) {
+    (
+        gain * f32::min(1.0, 1.0 - pan),
+        gain * f32::min(1.0, 1.0 + pan),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pan_gains() {
+        assert_eq!(super::pan_gains(0.5, 0.0), (0.5, 0.5));
+        assert_eq!(super::pan_gains(1.0, -1.0), (1.0, 0.0));
+        assert_eq!(super::pan_gains(1.0, 0.5), (0.5, 1.0));
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        notes: Vec<(u8, u8)>,
+    }
+
+    #[derive(Default)]
+    struct Level;
+
+    impl EngineVoice for Level {
+        fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) -> bool {
+            lbuf.fill(1.0);
+            rbuf.fill(1.0);
+            true
+        }
+        fn reset(&mut self) {}
+        fn note_on(&mut self, _note: u8, _velocity: u8) {}
+        fn note_off(&mut self, _note: u8, _velocity: u8) {}
+        fn control_change(&mut self, _kind: ControlChangeKind, _value: u8) {}
+        fn pitch_wheel(&mut self, _value: u16) {}
+    }
+
+    impl Parameters for Recorder {
+        fn parameters(&self) -> Vec<Parameter> {
+            Vec::new()
+        }
+
+        fn parameter(&self, _id: &str) -> Option<Value> {
+            None
+        }
+
+        fn set_parameter(&mut self, _id: &str, _value: Value) -> parameter::SetResult {
+            Err(parameter::Error::UnknownParameter)
+        }
+    }
+
+    impl Engine for Recorder {
+        const DEFAULT_NAME: &'static str = "Recorder";
+        type Voice = Level;
+
+        fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+        fn set_sample_rate(&mut self, _sample_rate: u32) {}
+        fn observe_midi(&mut self, kind: &midi::MessageKind) {
+            if let midi::MessageKind::NoteOn { note, velocity } = *kind {
+                self.notes.push((note, velocity));
+            }
+        }
+        fn process_request(&mut self, _kind: RequestKind, cb: ResponseCallback) {
+            cb(ResponseKind::Denied)
+        }
+        fn serialize(&self) -> SerializationResult {
+            Ok(json!({ "notes": serialize(&self.notes)? }))
+        }
+        fn deserialize(&mut self, _source: &serde_json::Value) -> DeserializationResult {
+            Ok(())
+        }
+        fn take_json_updates(&mut self) -> Vec<JsonFieldUpdate> {
+            Vec::new()
+        }
+        fn take_voice_tasks(&mut self) -> Vec<VoiceTask> {
+            Vec::new()
+        }
+    }
+
+    fn note_on(note: u8) -> midi::Message {
+        midi::Message {
+            channel: 0,
+            kind: midi::MessageKind::NoteOn {
+                note,
+                velocity: 100,
+            },
+        }
+    }
+
+    #[test]
+    fn notes_pass_through_container() {
+        let mut node = Container::<Recorder>::default();
+        node.set_transposition(2);
+        node.set_global_transposition(-12);
+        node.receive_midi_message(&note_on(60));
+        node.set_enabled(false);
+        node.receive_midi_message(&note_on(62));
+        assert_eq!(node.engine.notes, vec![(50, 100)]);
+        let commands = node.take_voice_commands();
+        assert!(matches!(
+            commands[..],
+            [VoiceCommand::Midi(midi::MessageKind::NoteOn {
+                note: 50,
+                velocity: 100
+            })]
+        ));
+
+        let state = node.serialize().unwrap();
+        assert_eq!(state["transposition"], json!(2));
+        assert_eq!(state["notes"], json!([[50, 100]]));
+    }
+
+    #[test]
+    fn note_off_matches_transposed_note() {
+        let mut node = Container::<Recorder>::default();
+        let note_off = midi::Message {
+            channel: 0,
+            kind: midi::MessageKind::NoteOff {
+                note: 60,
+                velocity: 0,
+            },
+        };
+        node.set_transposition(12);
+        node.receive_midi_message(&note_on(60));
+        node.set_global_transposition(-5);
+        node.receive_midi_message(&note_off);
+        node.set_transposition(48);
+        node.set_global_transposition(48);
+        node.receive_midi_message(&note_on(60));
+        node.receive_midi_message(&note_off);
+        let notes: Vec<_> = node
+            .take_voice_commands()
+            .into_iter()
+            .filter_map(|cmd| match cmd {
+                VoiceCommand::Midi(kind) => Some(kind),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                midi::MessageKind::NoteOn {
+                    note: 72,
+                    velocity: 100
+                },
+                midi::MessageKind::NoteOff {
+                    note: 72,
+                    velocity: 0
+                },
+            ]
+        );
+        assert!(matches!(
+            node.set_transposition(49),
+            ResponseKind::Failed { .. }
+        ));
+    }
+
+    #[test]
+    fn gain_and_pan_are_applied() {
+        let mut node = Container::<Recorder>::default();
+        node.set_block_size(4);
+        let mut voice = node.create_voice();
+        node.set_gain(0.5);
+        node.set_pan(1.0);
+        for mut cmd in node.take_voice_commands() {
+            voice.process_command(&mut cmd);
+        }
+        let (mut lbuf, mut rbuf) = ([0.0; 4], [0.25; 4]);
+        voice.render_additive(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [0.0; 4]);
+        assert_eq!(rbuf, [0.75; 4]);
+        assert!(matches!(node.set_pan(1.5), ResponseKind::Failed { .. }));
+    }
+}
@@ -1,12 +1,16 @@
-use super::{midi_filter, velocity_map};
+use super::{midi_filter, velocity_map, zone, MAX_OUTPUTS, MAX_TRANSPOSITION};
 use crate::{
+    error::{Error, ErrorCode},
     json::{DeserializationResult, JsonFieldUpdate, SerializationResult},
     midi,
+    parameter::{self, Parameter, Parameters, Unit},
     path::VirtualPaths,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{any::Any, path::PathBuf};
 
+pub mod container;
 pub mod fluidlite_synth;
 pub mod oxi_synth;
 pub mod rusty_synth;
@@ -15,13 +19,21 @@ pub mod sfizz_synth;
 pub const NUM_USER_PRESETS: usize = 16;
 
 pub type ResponseCallback = Box<dyn FnOnce(ResponseKind) + 'static + Send + Sync>;
+// Change of the engine voice made by a node, runs once on the render thread.
+// It is handed back afterwards with whatever it swapped out, so that the
+// renderer frees them.
+pub type VoiceTask = Box<dyn FnMut(&mut dyn Any) + Send>;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "RenderNodeRequestKind")]
 pub enum RequestKind {
     SetName(String),
     SetEnabled(bool),
     LoadFile(PathBuf),
+    PreloadFile(PathBuf),
     SetGain(f32),
+    SetPan(f32),
+    SetOutput(usize),
     SetTransposition(i8),
     SetVelocityMapping(velocity_map::Kind),
     SetIgnoreGlobalTransposition(bool),
@@ -41,30 +53,117 @@ pub enum RequestKind {
     SetDrumMachineVoiceNote(usize, u8),
     SetDrumMachineSlot(usize, usize, u8),
     UpdateMidiFilter(midi_filter::UpdateKind),
+    UpdateZones(zone::UpdateKind),
     SetUserPresetEnabled(usize, bool),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "RenderNodeResponseKind")]
 pub enum ResponseKind {
     InvalidId,
     Denied,
-    Failed,
+    Failed { reason: Error },
     Ok,
 }
 
-pub trait Render: Sync + Send {
-    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]);
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+impl parameter::Response for ResponseKind {
+    fn succeeded(&self) -> bool {
+        *self == Self::Ok
+    }
+}
+
+// What the render thread does with a voice between two blocks, nothing of it allocates
+pub enum VoiceCommand {
+    // Already filtered, mapped and transposed by the node
+    Midi(midi::MessageKind),
+    Mix { gain: f32, pan: f32, output: usize },
+    Reset,
+    Task(VoiceTask),
+}
+
+// The renderer keeps the nodes and does all the work except rendering, the
+// voices they create are what lives on the render thread
+pub trait Render: Parameters + Send {
+    fn create_voice(&self) -> VoicePtr;
+    fn update(&mut self);
+    fn set_block_size(&mut self, block_size: usize);
     fn reset_rendering(&mut self);
     fn set_virtual_paths(&mut self, vp: VirtualPaths);
     fn set_sample_rate(&mut self, sample_rate: u32);
     fn receive_midi_message(&mut self, message: &midi::Message);
     fn set_global_transposition(&mut self, transposition: i8);
+    fn set_master_tuning(&mut self, a4_hz: f32);
     fn set_user_preset(&mut self, preset: usize);
     fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback);
     fn serialize(&self) -> SerializationResult; //TODO: return serde_json::Value instead
     fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult;
+    fn apply_state(&mut self, state: &serde_json::Value) -> DeserializationResult;
     fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>>;
+    // Changes the voice has to go through to keep up with the node
+    fn take_voice_commands(&mut self) -> Vec<VoiceCommand>;
     fn clone_node(&self) -> RenderPtr;
 }
 
 pub type RenderPtr = Box<dyn Render>;
+
+pub trait Voice: Send {
+    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]);
+    fn output(&self) -> usize;
+    // Tasks are only run, the command is handed back to the renderer to be dropped
+    fn process_command(&mut self, cmd: &mut VoiceCommand);
+}
+
+pub type VoicePtr = Box<dyn Voice>;
+
+pub fn common_parameters() -> Vec<Parameter> {
+    vec![
+        Parameter::bool("enabled", true),
+        Parameter::float("gain", 0.0, 4.0, 1.0, Unit::None),
+        Parameter::float("pan", -1.0, 1.0, 0.0, Unit::None),
+        Parameter::int("output", 0, MAX_OUTPUTS as i32 - 1, 0, Unit::None),
+        Parameter::int(
+            "transposition",
+            -(MAX_TRANSPOSITION as i32),
+            MAX_TRANSPOSITION as i32,
+            0,
+            Unit::Semitones,
+        ),
+        Parameter::bool("ignore_global_transposition", false),
+    ]
+}
+
+pub fn preset_parameters() -> Vec<Parameter> {
+    vec![
+        Parameter::int("bank", 0, 16383, 0, Unit::None),
+        Parameter::int("preset", 0, 127, 0, Unit::None),
+    ]
+}
+
+pub fn reverb_parameters() -> Vec<Parameter> {
+    vec![
+        Parameter::bool("reverb_active", false),
+        Parameter::float("reverb_room_size", 0.0, 1.0, 0.2, Unit::None),
+        Parameter::float("reverb_damping", 0.0, 1.0, 0.0, Unit::None),
+        Parameter::float("reverb_width", 0.0, 100.0, 0.5, Unit::None),
+        Parameter::float("reverb_level", 0.0, 1.0, 0.9, Unit::None),
+    ]
+}
+
+// Offset of the reference pitch from A4 = 440 Hz
+pub fn tuning_cents(a4_hz: f32) -> f32 {
+    1200.0 * (a4_hz / 440.0).log2()
+}
+
+// Pitches of all MIDI keys in cents, as taken by the SoundFont synths
+pub fn key_tuning(a4_hz: f32) -> [f64; 128] {
+    let offset = tuning_cents(a4_hz) as f64;
+    std::array::from_fn(|key| 100.0 * key as f64 + offset)
+}
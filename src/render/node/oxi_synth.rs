@@ -1,17 +1,19 @@
-use super::{Render, ResponseCallback};
+use super::{
+    container::{self, Container, LoadHandle, Preload, VoiceTasks},
+    ResponseCallback, VoiceTask,
+};
 use crate::{
+    error::ErrorCode,
     json::{
         deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate, SerializationResult,
     },
     json_try,
     midi::{self, ControlChangeKind},
+    parameter::{self, Parameter, Parameters, Value},
     path::VirtualPaths,
     render::{
-        self,
-        midi_filter::{self, MidiFilterUser},
         node::{RequestKind, ResponseKind},
         preset_map::{Preset, PresetMap},
-        velocity_map,
     },
 };
 use oxisynth::{SoundFont, Synth};
@@ -23,14 +25,15 @@ use std::{
     fs::File,
     mem,
     path::{Path, PathBuf},
-    thread::{self, JoinHandle},
+    thread,
 };
 
-const DEFAULT_NAME: &str = "Oxi Synth";
 const POLYPHONY: u16 = 64;
 
 type SoundFontLoadRes = (Synth, PresetMap, Option<u16>, Option<u8>);
-type SoundFontLoadHandle = JoinHandle<Result<SoundFontLoadRes, String>>;
+type SoundFontLoadHandle = LoadHandle<SoundFontLoadRes>;
+
+pub type Node = Container<Engine>;
 
 #[derive(Debug)]
 pub struct CouldNotInitSynth;
@@ -64,120 +67,68 @@ impl Default for ReverbParams {
     }
 }
 
-pub struct Node {
-    name: String,
-    enabled: bool,
-    midi_filter: midi_filter::MidiFilter,
-    synth: Option<Synth>,
+pub struct Engine {
     last_file: Option<PathBuf>,
     last_virtual_paths: Option<VirtualPaths>,
     last_sample_rate: Option<u32>,
+    tuning: Option<f32>,
     last_bank: Option<u16>,
     last_preset: Option<u8>,
     last_cc: HashMap<u8, u8>,
     last_pitch_wheel: u16,
     preset_map: Option<PresetMap>,
-    gain: f32,
-    transposition: i8,
-    global_transposition: i8,
-    velocity_mapping: velocity_map::Kind,
-    ignore_global_transposition: bool,
-    tmp_lbuf: Vec<f32>,
-    tmp_rbuf: Vec<f32>,
-    user_presets: Vec<bool>,
     sf_load_handle: Option<SoundFontLoadHandle>,
+    preloaded_file: Option<(PathBuf, SoundFontLoadHandle)>,
     sf_load_res_cb: Option<ResponseCallback>,
     reverb: ReverbParams,
     json_updates: Vec<JsonFieldUpdate>,
+    voice: VoiceTasks<Voice>,
 }
 
-impl Node {
-    fn set_name(&mut self, name: &str) -> ResponseKind {
-        self.name = name.into();
-        json_try! {
-            self.json_updates.push(("name".to_owned(), serialize(name)?))
-        }
-        ResponseKind::Ok
-    }
-
-    fn set_enabled(&mut self, flag: bool) -> ResponseKind {
-        self.enabled = flag;
-        json_try! {
-            self.json_updates.push(("enabled".to_owned(), serialize(flag)?))
-        }
-        ResponseKind::Ok
-    }
+#[derive(Default)]
+pub struct Voice {
+    synth: Option<Synth>,
+}
 
+impl Engine {
     fn load_file(&mut self, path: &Path, cb: ResponseCallback) {
         self.last_file = Some(path.to_owned());
-        if let Ok(()) = self.load_file_non_blocking() {
+        if let Some(handle) = self.take_preloaded_file(path) {
+            self.sf_load_handle = Some(handle);
             self.sf_load_res_cb = Some(cb);
+        } else if let Err(e) = self.load_file_non_blocking() {
+            cb(ResponseKind::failed(ErrorCode::LoadFailed, e.to_string()));
         } else {
-            cb(ResponseKind::Failed);
-        }
-    }
-
-    fn set_gain(&mut self, gain: f32) -> ResponseKind {
-        self.gain = gain;
-        json_try! {
-            self.json_updates.push(("gain".into(), serialize(gain)?))
-        }
-        ResponseKind::Ok
-    }
-
-    fn set_transposition(&mut self, transposition: i8) -> ResponseKind {
-        self.transposition = transposition;
-        json_try! {
-            self.json_updates.push(("transposition".into(), serialize(transposition)?))
-        }
-        ResponseKind::Ok
-    }
-
-    fn set_velocity_mapping(&mut self, mapping: velocity_map::Kind) -> ResponseKind {
-        self.velocity_mapping = mapping;
-        json_try! {
-            self.json_updates.push(("velocity_mapping".into(), serialize(mapping)?))
-        }
-        ResponseKind::Ok
-    }
-
-    fn set_ignore_global_transposition(&mut self, flag: bool) -> ResponseKind {
-        self.ignore_global_transposition = flag;
-        json_try! {
-            self.json_updates.push(("ignore_global_transposition".into(), serialize(flag)?))
+            self.sf_load_res_cb = Some(cb);
         }
-        ResponseKind::Ok
     }
 
     fn set_preset(&mut self, bank: u16, preset: u8) -> ResponseKind {
         self.last_bank = Some(bank);
         self.last_preset = Some(preset);
-        if let Some(synth) = &mut self.synth {
-            if synth.font_bank().count() != 0 {
-                _ = synth.bank_select(0, bank as u32);
-                _ = synth.send_event(oxisynth::MidiEvent::ProgramChange {
-                    channel: 0,
-                    program_id: preset,
-                });
-                json_try! {
-                    self.json_updates.push(("bank".into(), serialize(bank)?))
-                    self.json_updates.push(("preset".into(), serialize(preset)?))
+        if self.preset_map.is_some() {
+            self.voice.run(move |voice| {
+                if let Some(synth) = &mut voice.synth {
+                    select_preset(synth, bank, preset);
                 }
-                ResponseKind::Ok
-            } else {
-                ResponseKind::Failed
+            });
+            json_try! {
+                self.json_updates.push(("bank".into(), serialize(bank)?))
+                self.json_updates.push(("preset".into(), serialize(preset)?))
             }
+            ResponseKind::Ok
         } else {
-            ResponseKind::Failed
+            ResponseKind::failed(ErrorCode::NotLoaded, "No SoundFont loaded")
         }
     }
 
     fn set_reverb_active(&mut self, active: bool) -> ResponseKind {
         self.reverb.active = active;
-
-        if let Some(synth) = &mut self.synth {
-            synth.get_reverb_mut().set_active(active);
-        }
+        self.voice.run(move |voice| {
+            if let Some(synth) = &mut voice.synth {
+                synth.get_reverb_mut().set_active(active);
+            }
+        });
 
         json_try! {
             self.json_updates.push(("reverb".into(), serialize(self.reverb)?))
@@ -197,12 +148,13 @@ impl Node {
         self.reverb.damping = damping;
         self.reverb.width = width;
         self.reverb.level = level;
-
-        if let Some(synth) = &mut self.synth {
-            synth
-                .get_reverb_mut()
-                .set_reverb_params(room_size, damping, width, level);
-        }
+        self.voice.run(move |voice| {
+            if let Some(synth) = &mut voice.synth {
+                synth
+                    .get_reverb_mut()
+                    .set_reverb_params(room_size, damping, width, level);
+            }
+        });
 
         json_try! {
             self.json_updates.push(("reverb".into(), serialize(self.reverb)?))
@@ -211,115 +163,6 @@ impl Node {
         ResponseKind::Ok
     }
 
-    fn update_midi_filter(&mut self, kind: midi_filter::UpdateKind) -> ResponseKind {
-        if MidiFilterUser::process_update_request(self, kind).is_ok() {
-            json_try! {
-                self.json_updates.push(("midi_filter".into(), serialize(&self.midi_filter)?))
-            }
-            ResponseKind::Ok
-        } else {
-            ResponseKind::Failed
-        }
-    }
-
-    fn set_user_preset_enabled(&mut self, preset: usize, flag: bool) -> ResponseKind {
-        if preset >= self.user_presets.len() {
-            ResponseKind::Failed
-        } else {
-            self.user_presets[preset] = flag;
-            json_try! {
-                self.json_updates.push(("user_presets".into(), serialize(&self.user_presets)?))
-            }
-            ResponseKind::Ok
-        }
-    }
-
-    fn process_midi_message(&mut self, message: &midi::Message) {
-        self.process_midi_message_kind(&message.kind);
-    }
-
-    fn process_midi_message_kind(&mut self, kind: &midi::MessageKind) {
-        use midi::MessageKind as Kind;
-        match *kind {
-            Kind::NoteOn { note, velocity } => self.note_on(note, velocity),
-            Kind::NoteOff { note, .. } => self.note_off(note),
-            Kind::PolyphonicAftertouch { note, pressure } => {
-                self.polyphonic_aftertouch(note, pressure);
-            }
-            Kind::ControlChange { kind, value } => self.control_change(kind, value),
-            Kind::ProgramChange { program } => self.program_change(program),
-            Kind::ChannelAftertouch { pressure } => self.channel_aftertouch(pressure),
-            Kind::PitchWheel { value } => self.pitch_wheel(value),
-        }
-    }
-
-    fn note_on(&mut self, note: u8, velocity: u8) {
-        let note = self.transpose_note(note);
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::NoteOn {
-                channel: 0,
-                key: note,
-                vel: velocity,
-            });
-        }
-    }
-
-    fn note_off(&mut self, note: u8) {
-        let note = self.transpose_note(note);
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::NoteOff {
-                channel: 0,
-                key: note,
-            });
-        }
-    }
-
-    fn polyphonic_aftertouch(&mut self, note: u8, pressure: u8) {
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::PolyphonicKeyPressure {
-                channel: 0,
-                key: note,
-                value: pressure,
-            });
-        }
-    }
-
-    fn control_change(&mut self, kind: ControlChangeKind, value: u8) {
-        self.last_cc.insert(kind.as_number(), value);
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::ControlChange {
-                channel: 0,
-                ctrl: kind.as_number(),
-                value,
-            });
-        }
-    }
-
-    fn program_change(&mut self, program: u8) {
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::ProgramChange {
-                channel: 0,
-                program_id: program,
-            });
-        }
-    }
-
-    fn channel_aftertouch(&mut self, pressure: u8) {
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::ChannelPressure {
-                channel: 0,
-                value: pressure,
-            });
-        }
-    }
-
-    fn pitch_wheel(&mut self, value: u16) {
-        self.last_pitch_wheel = value;
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::PitchBend { channel: 0, value });
-        }
-    }
-
     fn load_file_non_blocking(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if let (Some(file), Some(vp)) = (&self.last_file, &self.last_virtual_paths) {
             if let Some(file) = vp.translate(file) {
@@ -435,37 +278,6 @@ impl Node {
     //     }
     // }
 
-    fn resize_buffers(&mut self, min_size: usize) {
-        if self.tmp_lbuf.len() < min_size {
-            self.tmp_lbuf.resize(min_size, 0.0);
-            self.tmp_rbuf.resize(min_size, 0.0);
-        }
-    }
-
-    fn does_midi_msg_pass(&self, msg: &midi::Message) -> bool {
-        if let midi::MessageKind::NoteOn { .. } = msg.kind {
-            self.enabled
-        } else {
-            true
-        }
-    }
-
-    fn get_total_transposition(&self) -> i8 {
-        if self.ignore_global_transposition {
-            self.transposition
-        } else {
-            self.transposition.saturating_add(self.global_transposition)
-        }
-    }
-
-    fn transpose_note(&self, note: u8) -> u8 {
-        (note as i16 + self.get_total_transposition() as i16) as u8
-    }
-
-    fn update(&mut self) {
-        self.handle_sf_load();
-    }
-
     fn sf_load_finished(&mut self) -> Option<SoundFontLoadHandle> {
         let finished = self
             .sf_load_handle
@@ -484,29 +296,31 @@ impl Node {
 
     fn handle_sf_load(&mut self) {
         if let Some(handle) = self.sf_load_finished() {
-            let res = handle.join();
-            if let Ok(Ok(res)) = res {
-                self.handle_sf_load_success(res);
-            } else {
-                self.call_sf_load_cb(ResponseKind::Failed);
+            match handle.join() {
+                Ok(Ok(res)) => self.handle_sf_load_success(res),
+                Ok(Err(message)) => {
+                    self.call_sf_load_cb(ResponseKind::failed(ErrorCode::LoadFailed, message))
+                }
+                Err(_) => {
+                    let reason = "The loader thread panicked";
+                    self.call_sf_load_cb(ResponseKind::failed(ErrorCode::LoadFailed, reason))
+                }
             }
         }
     }
 
     fn handle_sf_load_success(&mut self, res: SoundFontLoadRes) {
-        self.synth = Some(res.0);
+        let mut synth = res.0;
         self.preset_map = Some(res.1);
         self.last_bank = res.2;
         self.last_preset = res.3;
-        if let (Some(synth), Some(bank), Some(preset)) =
-            (&mut self.synth, self.last_bank, self.last_preset)
-        {
-            _ = synth.bank_select(0, bank as u32);
-            _ = synth.send_event(oxisynth::MidiEvent::ProgramChange {
-                channel: 0,
-                program_id: preset,
-            });
+        if let (Some(bank), Some(preset)) = (self.last_bank, self.last_preset) {
+            select_preset(&mut synth, bank, preset);
         }
+        if let Some(a4_hz) = self.tuning {
+            tune(&mut synth, a4_hz);
+        }
+        self.voice.swap(Some(synth), |voice| &mut voice.synth);
         json_try! {
             self.json_updates.push(("loaded_file".to_owned(), serialize(self.last_file.clone())?))
             self.json_updates.push(("preset_map".to_owned(), serialize(self.preset_map.clone())?))
@@ -525,144 +339,118 @@ impl Node {
     }
 }
 
-impl Default for Node {
+impl Default for Engine {
     fn default() -> Self {
         Self {
-            name: DEFAULT_NAME.into(),
-            enabled: true,
-            midi_filter: Default::default(),
-            synth: None,
             last_file: None,
             last_virtual_paths: None,
             last_sample_rate: None,
+            tuning: None,
             last_bank: None,
             last_preset: None,
             last_cc: HashMap::new(),
             last_pitch_wheel: 8192, // TODO: make sure this is the correct default value
             preset_map: None,
-            gain: 1.0,
-            transposition: 0,
-            global_transposition: 0,
-            velocity_mapping: velocity_map::Kind::Identity,
-            ignore_global_transposition: false,
-            tmp_lbuf: vec![],
-            tmp_rbuf: vec![],
-            user_presets: vec![true; super::NUM_USER_PRESETS],
             sf_load_handle: None,
+            preloaded_file: None,
             sf_load_res_cb: None,
             reverb: Default::default(),
             json_updates: Default::default(),
+            voice: Default::default(),
         }
     }
 }
 
-impl Clone for Node {
+impl Clone for Engine {
     fn clone(&self) -> Self {
         let mut res = Self {
-            name: self.name.clone(),
-            enabled: self.enabled,
-            midi_filter: self.midi_filter.clone(),
-            synth: None,
             last_file: self.last_file.clone(),
             last_virtual_paths: self.last_virtual_paths.clone(),
             last_sample_rate: self.last_sample_rate,
+            tuning: self.tuning,
             last_bank: self.last_bank,
             last_preset: self.last_preset,
             last_cc: self.last_cc.clone(),
             last_pitch_wheel: self.last_pitch_wheel,
             preset_map: None,
-            gain: self.gain,
-            transposition: self.transposition,
-            global_transposition: self.global_transposition,
-            velocity_mapping: self.velocity_mapping,
-            ignore_global_transposition: self.ignore_global_transposition,
-            tmp_lbuf: vec![0.0; self.tmp_lbuf.len()],
-            tmp_rbuf: vec![0.0; self.tmp_rbuf.len()],
-            user_presets: self.user_presets.clone(),
             sf_load_handle: None,
+            preloaded_file: None,
             sf_load_res_cb: None,
             reverb: self.reverb,
             json_updates: Default::default(),
+            voice: Default::default(),
         };
         _ = res.load_file_non_blocking();
         res
     }
 }
 
-impl Render for Node {
-    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
-        self.update();
-        let len = usize::min(lbuf.len(), rbuf.len());
-        self.resize_buffers(len);
-        let tmp_lbuf = &mut self.tmp_lbuf[..len];
-        let tmp_rbuf = &mut self.tmp_rbuf[..len];
-        if let Some(synth) = &mut self.synth {
-            synth.write_f32(len, tmp_lbuf, 0, 1, tmp_rbuf, 0, 1);
-        }
-        render::amplify_buffer(tmp_lbuf, self.gain);
-        render::amplify_buffer(tmp_rbuf, self.gain);
-        render::add_buf_to_buf(lbuf, tmp_lbuf);
-        render::add_buf_to_buf(rbuf, tmp_rbuf);
+impl Preload for Engine {
+    type Loaded = SoundFontLoadRes;
+
+    fn last_file(&mut self) -> &mut Option<PathBuf> {
+        &mut self.last_file
     }
 
-    fn reset_rendering(&mut self) {
-        if let Some(synth) = &mut self.synth {
-            _ = synth.send_event(oxisynth::MidiEvent::AllSoundOff { channel: 0 });
-        }
+    fn load_handle(&mut self) -> &mut Option<SoundFontLoadHandle> {
+        &mut self.sf_load_handle
     }
 
-    fn set_virtual_paths(&mut self, vp: VirtualPaths) {
-        self.last_virtual_paths = Some(vp);
+    fn preloaded_file(&mut self) -> &mut Option<(PathBuf, SoundFontLoadHandle)> {
+        &mut self.preloaded_file
     }
 
-    fn set_sample_rate(&mut self, sample_rate: u32) {
-        self.last_sample_rate = Some(sample_rate);
-        if let Some(synth) = &mut self.synth {
-            synth.set_sample_rate(sample_rate as f32);
-        }
+    fn load_non_blocking(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.load_file_non_blocking()
     }
+}
 
-    fn receive_midi_message(&mut self, message: &midi::Message) {
-        if self.midi_filter.does_pass(message) && self.does_midi_msg_pass(message) {
-            self.process_midi_message(message);
-        }
+impl container::Engine for Engine {
+    const DEFAULT_NAME: &'static str = "Oxi Synth";
+    type Voice = Voice;
+
+    fn update(&mut self) {
+        self.handle_sf_load();
     }
 
-    fn set_global_transposition(&mut self, transposition: i8) {
-        self.global_transposition = transposition;
+    fn set_virtual_paths(&mut self, vp: VirtualPaths) {
+        self.last_virtual_paths = Some(vp);
     }
 
-    fn set_user_preset(&mut self, preset: usize) {
-        if preset < self.user_presets.len() {
-            self.enabled = self.user_presets[preset];
-            json_try! {
-                self.json_updates.push(("enabled".into(), serialize(self.enabled)?))
+    fn set_master_tuning(&mut self, a4_hz: f32) {
+        self.tuning = Some(a4_hz);
+        self.voice.run(move |voice| {
+            if let Some(synth) = &mut voice.synth {
+                tune(synth, a4_hz);
+            }
+        });
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.last_sample_rate = Some(sample_rate);
+        self.voice.run(move |voice| {
+            if let Some(synth) = &mut voice.synth {
+                synth.set_sample_rate(sample_rate as f32);
             }
+        });
+    }
+
+    fn observe_midi(&mut self, kind: &midi::MessageKind) {
+        match *kind {
+            midi::MessageKind::ControlChange { kind, value } => {
+                self.last_cc.insert(kind.as_number(), value);
+            }
+            midi::MessageKind::PitchWheel { value } => self.last_pitch_wheel = value,
+            _ => {}
         }
     }
 
     fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
         type RK = RequestKind;
         match kind {
-            RK::SetName(name) => cb(self.set_name(&name)),
-            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
             RK::LoadFile(path) => self.load_file(&path, cb),
-            RK::SetGain(gain) => cb(self.set_gain(gain)),
-            RK::SetTransposition(tr) => cb(self.set_transposition(tr)),
-            RK::SetVelocityMapping(kind) => cb(self.set_velocity_mapping(kind)),
-            RK::SetIgnoreGlobalTransposition(flag) => {
-                cb(self.set_ignore_global_transposition(flag))
-            }
+            RK::PreloadFile(path) => cb(self.preload_file(&path)),
             RK::SetBankAndPreset(bank, preset) => cb(self.set_preset(bank, preset)),
-            RK::MidiMessage(kind) => {
-                self.process_midi_message_kind(&kind);
-                json_try! {
-                    //TODO: support indices and fields for optimization
-                    self.json_updates.push(("cc".into(), serialize(self.last_cc.clone())?))
-                    self.json_updates.push(("pitch_wheel".into(), serialize(self.last_pitch_wheel)?))
-                }
-                cb(ResponseKind::Ok)
-            }
             RK::SetSfReverbActive(active) => cb(self.set_reverb_active(active)),
             RK::SetSfReverbParams {
                 room_size,
@@ -670,74 +458,170 @@ impl Render for Node {
                 width,
                 level,
             } => cb(self.set_reverb_params(room_size, damping, width, level)),
-            RK::UpdateMidiFilter(kind) => cb(self.update_midi_filter(kind)),
-            RK::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
             _ => cb(ResponseKind::Denied),
         };
     }
 
     fn serialize(&self) -> SerializationResult {
         let result: serde_json::Value = json!({
-            "name": serialize(&self.name)?,
-            "enabled": serialize(self.enabled)?,
-            "midi_filter": serialize(&self.midi_filter)?,
-            "gain": serialize(self.gain)?,
-            "transposition": serialize(self.transposition)?,
-            "global_transposition": serialize(self.global_transposition)?,
-            "velocity_mapping": serialize(self.velocity_mapping)?,
-            "ignore_global_transposition": serialize(self.ignore_global_transposition)?,
             "loaded_file": serialize(&self.last_file)?,
             "preset_map": serialize(&self.preset_map)?,
             "bank": serialize(self.last_bank)?,
             "preset": serialize(self.last_preset)?,
             "cc": serialize(self.last_cc.clone())?,
             "pitch_wheel": serialize(self.last_pitch_wheel)?,
-            "user_presets": serialize(&self.user_presets)?,
             "reverb": serialize(self.reverb)?,
         });
         Ok(result)
     }
 
     fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
-        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
-        deser_field_opt(source, "name", |v| self.name = v)?;
-        deser_field_opt(source, "midi_filter", |v| self.midi_filter = v)?;
-        deser_field_opt(source, "gain", |v| self.gain = v)?;
-        deser_field_opt(source, "transposition", |v| self.transposition = v)?;
-        deser_field_opt(source, "global_transposition", |v| {
-            self.global_transposition = v
-        })?;
-        deser_field_opt(source, "ignore_global_transposition", |v| {
-            self.ignore_global_transposition = v
-        })?;
         deser_field_opt(source, "loaded_file", |v| self.last_file = v)?;
         deser_field_opt(source, "bank", |v| self.last_bank = v)?;
         deser_field_opt(source, "preset", |v| self.last_preset = v)?;
         deser_field_opt(source, "cc", |v| self.last_cc = v)?;
         deser_field_opt(source, "pitch_wheel", |v| self.last_pitch_wheel = v)?;
-        deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
         deser_field_opt(source, "reverb", |v| self.reverb = v)?;
         Ok(())
     }
 
-    fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>> {
-        if !self.json_updates.is_empty() {
-            let mut new_updates = Default::default();
-            mem::swap(&mut new_updates, &mut self.json_updates);
-            Some(new_updates)
+    fn midi_state_updates(&self) -> Vec<JsonFieldUpdate> {
+        let mut updates = Vec::new();
+        json_try! {
+            //TODO: support indices and fields for optimization
+            updates.push(("cc".into(), serialize(self.last_cc.clone())?))
+            updates.push(("pitch_wheel".into(), serialize(self.last_pitch_wheel)?))
+        }
+        updates
+    }
+
+    fn take_json_updates(&mut self) -> Vec<JsonFieldUpdate> {
+        mem::take(&mut self.json_updates)
+    }
+
+    fn take_voice_tasks(&mut self) -> Vec<VoiceTask> {
+        self.voice.take()
+    }
+}
+
+impl container::EngineVoice for Voice {
+    fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) -> bool {
+        if let Some(synth) = &mut self.synth {
+            synth.write_f32(lbuf.len(), lbuf, 0, 1, rbuf, 0, 1);
+            true
         } else {
-            None
+            false
         }
     }
 
-    fn clone_node(&self) -> super::RenderPtr {
-        Box::new(self.clone())
+    fn reset(&mut self) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::AllSoundOff { channel: 0 });
+        }
+    }
+
+    fn note_on(&mut self, note: u8, velocity: u8) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::NoteOn {
+                channel: 0,
+                key: note,
+                vel: velocity,
+            });
+        }
+    }
+
+    fn note_off(&mut self, note: u8, _velocity: u8) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::NoteOff {
+                channel: 0,
+                key: note,
+            });
+        }
+    }
+
+    fn polyphonic_aftertouch(&mut self, note: u8, pressure: u8) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::PolyphonicKeyPressure {
+                channel: 0,
+                key: note,
+                value: pressure,
+            });
+        }
+    }
+
+    fn control_change(&mut self, kind: ControlChangeKind, value: u8) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::ControlChange {
+                channel: 0,
+                ctrl: kind.as_number(),
+                value,
+            });
+        }
+    }
+
+    fn program_change(&mut self, program: u8) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::ProgramChange {
+                channel: 0,
+                program_id: program,
+            });
+        }
+    }
+
+    fn channel_aftertouch(&mut self, pressure: u8) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::ChannelPressure {
+                channel: 0,
+                value: pressure,
+            });
+        }
+    }
+
+    fn pitch_wheel(&mut self, value: u16) {
+        if let Some(synth) = &mut self.synth {
+            _ = synth.send_event(oxisynth::MidiEvent::PitchBend { channel: 0, value });
+        }
     }
 }
 
-impl MidiFilterUser for Node {
-    fn midi_filter_mut(&mut self) -> &mut midi_filter::MidiFilter {
-        &mut self.midi_filter
+impl Parameters for Engine {
+    fn parameters(&self) -> Vec<Parameter> {
+        let mut parameters = super::preset_parameters();
+        parameters.extend(super::reverb_parameters());
+        parameters
+    }
+
+    fn parameter(&self, id: &str) -> Option<Value> {
+        match id {
+            "bank" => self.last_bank.map(|bank| Value::Int(bank as i32)),
+            "preset" => self.last_preset.map(|preset| Value::Int(preset as i32)),
+            "reverb_active" => Some(Value::Bool(self.reverb.active)),
+            "reverb_room_size" => Some(Value::Float(self.reverb.room_size)),
+            "reverb_damping" => Some(Value::Float(self.reverb.damping)),
+            "reverb_width" => Some(Value::Float(self.reverb.width)),
+            "reverb_level" => Some(Value::Float(self.reverb.level)),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: Value) -> parameter::SetResult {
+        let res = match id {
+            "bank" => self.set_preset(value.as_i32() as u16, self.last_preset.unwrap_or(0)),
+            "preset" => self.set_preset(self.last_bank.unwrap_or(0), value.as_i32() as u8),
+            "reverb_active" => self.set_reverb_active(value.as_bool()),
+            "reverb_room_size" | "reverb_damping" | "reverb_width" | "reverb_level" => {
+                let mut reverb = self.reverb;
+                match id {
+                    "reverb_room_size" => reverb.room_size = value.as_f32(),
+                    "reverb_damping" => reverb.damping = value.as_f32(),
+                    "reverb_width" => reverb.width = value.as_f32(),
+                    _ => reverb.level = value.as_f32(),
+                }
+                self.set_reverb_params(reverb.room_size, reverb.damping, reverb.width, reverb.level)
+            }
+            _ => return Err(parameter::Error::UnknownParameter),
+        };
+        parameter::result(res)
     }
 }
 
@@ -758,3 +642,18 @@ fn get_preset_map(sf: &rustysynth::SoundFont) -> PresetMap {
 
     map
 }
+
+fn select_preset(synth: &mut Synth, bank: u16, preset: u8) {
+    _ = synth.bank_select(0, bank as u32);
+    _ = synth.send_event(oxisynth::MidiEvent::ProgramChange {
+        channel: 0,
+        program_id: preset,
+    });
+}
+
+// Tunes the channel the node plays on
+fn tune(synth: &mut Synth, a4_hz: f32) {
+    if let Ok(tuning) = oxisynth::Tuning::new_key_tuning(0, 0, &super::key_tuning(a4_hz)) {
+        _ = synth.channel_set_tuning(0, tuning);
+    }
+}
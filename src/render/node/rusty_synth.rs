@@ -1,17 +1,19 @@
-use super::{Render, ResponseCallback, ResponseKind};
+use super::{
+    container::{self, Container, LoadHandle, Preload, VoiceTasks},
+    ResponseCallback, ResponseKind, VoiceTask,
+};
 use crate::{
+    error::ErrorCode,
     json::{
         deser_field_opt, serialize, DeserializationResult, JsonFieldUpdate, SerializationResult,
     },
     json_try,
-    midi::{self, ControlChangeKind},
+    midi::ControlChangeKind,
+    parameter::{self, Parameter, Parameters, Value},
     path::VirtualPaths,
     render::{
-        self,
-        midi_filter::{self, MidiFilterUser},
         node::RequestKind,
         preset_map::{Preset, PresetMap},
-        velocity_map,
     },
 };
 use rustysynth::{SoundFont, Synthesizer, SynthesizerSettings};
@@ -22,13 +24,13 @@ use std::{
     mem,
     path::{Path, PathBuf},
     sync::Arc,
-    thread::{self, JoinHandle},
+    thread,
 };
 
-const DEFAULT_NAME: &str = "Rusty Synth";
-
 type SynthInitRes = (Synthesizer, PresetMap, Option<u16>, Option<u8>);
-type SynthInitResHandle = JoinHandle<Result<SynthInitRes, String>>;
+type SynthInitResHandle = LoadHandle<SynthInitRes>;
+
+pub type Node = Container<Engine>;
 
 #[derive(Debug)]
 pub struct CouldNotInitSynth;
@@ -41,166 +43,68 @@ impl Display for CouldNotInitSynth {
 
 impl std::error::Error for CouldNotInitSynth {}
 
-pub struct Node {
-    name: String,
-    enabled: bool,
-    midi_filter: midi_filter::MidiFilter,
-    synth: Option<Synthesizer>,
+#[derive(Default)]
+pub struct Engine {
     last_file: Option<PathBuf>,
     last_virtual_paths: Option<VirtualPaths>,
     last_sample_rate: Option<u32>,
+    tuning: Option<f32>,
     last_bank: Option<u16>,
     last_preset: Option<u8>,
     preset_map: Option<PresetMap>,
-    gain: f32,
-    transposition: i8,
-    global_transposition: i8,
-    velocity_mapping: velocity_map::Kind,
-    ignore_global_transposition: bool,
-    tmp_lbuf: Vec<f32>,
-    tmp_rbuf: Vec<f32>,
-    user_presets: Vec<bool>,
+    block_size: usize,
     synth_init_handle: Option<SynthInitResHandle>,
+    preloaded_file: Option<(PathBuf, SynthInitResHandle)>,
     synth_init_res_cb: Option<ResponseCallback>,
-    last_timestamp: u128,
     json_updates: Vec<JsonFieldUpdate>,
+    voice: VoiceTasks<Voice>,
 }
 
-impl Node {
-    fn set_name(&mut self, name: &str) -> ResponseKind {
-        self.name = name.into();
-        json_try! {
-            self.json_updates.push(("name".to_owned(), serialize(name)?))
-        }
-        ResponseKind::Ok
-    }
+#[derive(Default)]
+pub struct Voice {
+    synth: Option<Synthesizer>,
+    // Reapplied after a reset, which drops the tuning of the channels
+    tuning: Option<f32>,
+}
 
-    fn set_enabled(&mut self, flag: bool) -> ResponseKind {
-        self.enabled = flag;
-        json_try! {
-            self.json_updates.push(("enabled".to_owned(), serialize(flag)?))
+impl Voice {
+    fn tune(&mut self) {
+        if let (Some(synth), Some(a4_hz)) = (&mut self.synth, self.tuning) {
+            tune(synth, a4_hz);
         }
-        ResponseKind::Ok
     }
+}
 
+impl Engine {
     fn load_file(&mut self, path: &Path, cb: ResponseCallback) {
         self.last_file = Some(path.to_owned());
-        if let Ok(()) = self.init_synth_non_blocking() {
+        if let Some(handle) = self.take_preloaded_file(path) {
+            self.synth_init_handle = Some(handle);
             self.synth_init_res_cb = Some(cb);
+        } else if let Err(e) = self.init_synth_non_blocking() {
+            cb(ResponseKind::failed(ErrorCode::LoadFailed, e.to_string()));
         } else {
-            cb(ResponseKind::Failed);
-        }
-    }
-
-    fn set_gain(&mut self, gain: f32) -> ResponseKind {
-        self.gain = gain;
-        json_try! {
-            self.json_updates.push(("gain".into(), serialize(gain)?))
-        }
-        ResponseKind::Ok
-    }
-
-    fn set_transposition(&mut self, transposition: i8) -> ResponseKind {
-        self.transposition = transposition;
-        json_try! {
-            self.json_updates.push(("transposition".into(), serialize(transposition)?))
-        }
-        ResponseKind::Ok
-    }
-
-    fn set_velocity_mapping(&mut self, mapping: velocity_map::Kind) -> ResponseKind {
-        self.velocity_mapping = mapping;
-        json_try! {
-            self.json_updates.push(("velocity_mapping".into(), serialize(mapping)?))
-        }
-        ResponseKind::Ok
-    }
-
-    fn set_ignore_global_transposition(&mut self, flag: bool) -> ResponseKind {
-        self.ignore_global_transposition = flag;
-        json_try! {
-            self.json_updates.push(("ignore_global_transposition".into(), serialize(flag)?))
+            self.synth_init_res_cb = Some(cb);
         }
-        ResponseKind::Ok
     }
 
     fn set_preset(&mut self, bank: u16, preset: u8) -> ResponseKind {
         self.last_bank = Some(bank);
         self.last_preset = Some(preset);
-        if let Some(synth) = &mut self.synth {
-            synth.process_midi_message(0, 0xB0, 0x00, bank as i32);
-            synth.process_midi_message(0, 0xC0, preset as i32, 0x00);
+        if self.preset_map.is_some() {
+            self.voice.run(move |voice| {
+                if let Some(synth) = &mut voice.synth {
+                    synth.process_midi_message(0, 0xB0, 0x00, bank as i32);
+                    synth.process_midi_message(0, 0xC0, preset as i32, 0x00);
+                }
+            });
             json_try! {
                 self.json_updates.push(("bank".into(), serialize(bank)?))
                 self.json_updates.push(("preset".into(), serialize(preset)?))
             }
             ResponseKind::Ok
         } else {
-            ResponseKind::Failed
-        }
-    }
-
-    fn update_midi_filter(&mut self, kind: midi_filter::UpdateKind) -> ResponseKind {
-        if MidiFilterUser::process_update_request(self, kind).is_ok() {
-            json_try! {
-                self.json_updates.push(("midi_filter".into(), serialize(&self.midi_filter)?))
-            }
-            ResponseKind::Ok
-        } else {
-            ResponseKind::Failed
-        }
-    }
-
-    fn set_user_preset_enabled(&mut self, preset: usize, flag: bool) -> ResponseKind {
-        if preset >= self.user_presets.len() {
-            ResponseKind::Failed
-        } else {
-            self.user_presets[preset] = flag;
-            json_try! {
-                self.json_updates.push(("user_presets".into(), serialize(&self.user_presets)?))
-            }
-            ResponseKind::Ok
-        }
-    }
-
-    fn process_midi_message(&mut self, message: &midi::Message) {
-        use midi::MessageKind as Kind;
-        match message.kind {
-            Kind::NoteOn { note, velocity } => self.note_on(note, velocity),
-            Kind::NoteOff { note, .. } => self.note_off(note),
-            Kind::PolyphonicAftertouch { .. } => {}
-            Kind::ControlChange { kind, value } => self.control_change(kind, value),
-            Kind::ProgramChange { .. } => {}
-            Kind::ChannelAftertouch { .. } => {}
-            Kind::PitchWheel { value } => self.pitch_wheel(value),
-        }
-    }
-
-    fn note_on(&mut self, note: u8, velocity: u8) {
-        let note = self.transpose_note(note);
-        if let Some(s) = self.synth.as_mut() {
-            s.note_on(0, note as i32, velocity as i32)
-        }
-    }
-
-    fn note_off(&mut self, note: u8) {
-        let note = self.transpose_note(note);
-        if let Some(s) = self.synth.as_mut() {
-            s.note_off(0, note as i32)
-        }
-    }
-
-    fn control_change(&mut self, kind: ControlChangeKind, value: u8) {
-        if let Some(s) = self.synth.as_mut() {
-            s.process_midi_message(0, 0xB0, kind.as_number() as i32, value as i32)
-        }
-    }
-
-    fn pitch_wheel(&mut self, value: u16) {
-        let data1 = (value & 0x7F) | 0x80;
-        let data2 = (value >> 7) & 0x7F;
-        if let Some(s) = self.synth.as_mut() {
-            s.process_midi_message(0, 0xE0, data1 as i32, data2 as i32)
+            ResponseKind::failed(ErrorCode::NotLoaded, "No SoundFont loaded")
         }
     }
 
@@ -213,7 +117,7 @@ impl Node {
             if let Some(file) = vp.translate(file) {
                 let mut last_bank = self.last_bank;
                 let mut last_preset = self.last_preset;
-                let block_size = self.tmp_lbuf.len();
+                let block_size = self.block_size;
                 self.synth_init_handle =
                     Some(thread::spawn(move || -> Result<SynthInitRes, String> {
                         let mut sf2 = File::open(file).map_err(|e| e.to_string())?;
@@ -299,37 +203,6 @@ impl Node {
     //     }
     // }
 
-    fn resize_buffers(&mut self, min_size: usize) {
-        if self.tmp_lbuf.len() < min_size {
-            self.tmp_lbuf.resize(min_size, 0.0);
-            self.tmp_rbuf.resize(min_size, 0.0);
-        }
-    }
-
-    fn does_midi_msg_pass(&self, msg: &midi::Message) -> bool {
-        if let midi::MessageKind::NoteOn { .. } = msg.kind {
-            self.enabled
-        } else {
-            true
-        }
-    }
-
-    fn get_total_transposition(&self) -> i8 {
-        if self.ignore_global_transposition {
-            self.transposition
-        } else {
-            self.transposition.saturating_add(self.global_transposition)
-        }
-    }
-
-    fn transpose_note(&self, note: u8) -> u8 {
-        (note as i16 + self.get_total_transposition() as i16) as u8
-    }
-
-    fn update(&mut self) {
-        self.handle_synth_init();
-    }
-
     fn synth_init_finished(&mut self) -> Option<SynthInitResHandle> {
         let finished = self
             .synth_init_handle
@@ -348,17 +221,22 @@ impl Node {
 
     fn handle_synth_init(&mut self) {
         if let Some(handle) = self.synth_init_finished() {
-            let res = handle.join();
-            if let Ok(Ok(res)) = res {
-                self.handle_synth_init_success(res);
-            } else {
-                self.call_synth_init_cb(ResponseKind::Failed);
+            match handle.join() {
+                Ok(Ok(res)) => self.handle_synth_init_success(res),
+                Ok(Err(message)) => {
+                    self.call_synth_init_cb(ResponseKind::failed(ErrorCode::LoadFailed, message))
+                }
+                Err(_) => {
+                    let reason = "The loader thread panicked";
+                    self.call_synth_init_cb(ResponseKind::failed(ErrorCode::LoadFailed, reason))
+                }
             }
         }
     }
 
     fn handle_synth_init_success(&mut self, res: SynthInitRes) {
-        self.synth = Some(res.0);
+        self.voice.swap(Some(res.0), |voice| &mut voice.synth);
+        self.send_tuning();
         self.preset_map = Some(res.1);
         self.last_bank = res.2;
         self.last_preset = res.3;
@@ -371,6 +249,14 @@ impl Node {
         self.call_synth_init_cb(ResponseKind::Ok);
     }
 
+    fn send_tuning(&mut self) {
+        let tuning = self.tuning;
+        self.voice.run(move |voice| {
+            voice.tuning = tuning;
+            voice.tune();
+        });
+    }
+
     fn call_synth_init_cb(&mut self, res: ResponseKind) {
         let mut cb: Option<ResponseCallback> = None;
         mem::swap(&mut self.synth_init_res_cb, &mut cb);
@@ -380,95 +266,58 @@ impl Node {
     }
 }
 
-impl Default for Node {
-    fn default() -> Self {
-        Self {
-            name: DEFAULT_NAME.into(),
-            enabled: true,
-            midi_filter: Default::default(),
-            synth: None,
-            last_file: None,
-            last_virtual_paths: None,
-            last_sample_rate: None,
-            last_bank: None,
-            last_preset: None,
-            preset_map: None,
-            gain: 1.0,
-            transposition: 0,
-            global_transposition: 0,
-            velocity_mapping: velocity_map::Kind::Identity,
-            ignore_global_transposition: false,
-            tmp_lbuf: Default::default(),
-            tmp_rbuf: Default::default(),
-            user_presets: vec![true; super::NUM_USER_PRESETS],
-            synth_init_handle: None,
-            synth_init_res_cb: None,
-            last_timestamp: 0,
-            json_updates: Default::default(),
-        }
-    }
-}
-
-impl Clone for Node {
+impl Clone for Engine {
     fn clone(&self) -> Self {
         let mut res = Self {
-            name: self.name.clone(),
-            enabled: self.enabled,
-            midi_filter: self.midi_filter.clone(),
-            synth: None,
             last_file: self.last_file.clone(),
             last_virtual_paths: self.last_virtual_paths.clone(),
             last_sample_rate: self.last_sample_rate,
+            tuning: self.tuning,
             last_bank: self.last_bank,
             last_preset: self.last_preset,
             preset_map: None,
-            gain: self.gain,
-            transposition: self.transposition,
-            global_transposition: self.global_transposition,
-            velocity_mapping: self.velocity_mapping,
-            ignore_global_transposition: self.ignore_global_transposition,
-            tmp_lbuf: vec![0.0; self.tmp_lbuf.len()],
-            tmp_rbuf: vec![0.0; self.tmp_rbuf.len()],
-            user_presets: self.user_presets.clone(),
+            block_size: self.block_size,
             synth_init_handle: None,
+            preloaded_file: None,
             synth_init_res_cb: None,
-            last_timestamp: 0,
             json_updates: Default::default(),
+            voice: Default::default(),
         };
         _ = res.init_synth_non_blocking();
         res
     }
 }
 
-impl Render for Node {
-    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
-        self.update();
-        self.resize_buffers(usize::min(lbuf.len(), rbuf.len()));
-        if let Some(synth) = &mut self.synth {
-            self.last_timestamp += 1;
-            let tmp_lbuf = &mut self.tmp_lbuf[..lbuf.len()];
-            let tmp_rbuf = &mut self.tmp_rbuf[..rbuf.len()];
-            let start = std::time::Instant::now();
-            synth.render(tmp_lbuf, tmp_rbuf);
-            let duration = start.elapsed();
-            if duration.as_micros() > 2500 {
-                //FIXME: use fluidsynth instead (it's faster, maybe?)
-                synth.note_off_all(true);
-            }
-            // if self.last_timestamp % 100 == 0 {
-            //     tracing::trace!("{:?}", duration);
-            // }
-            render::amplify_buffer(tmp_lbuf, self.gain);
-            render::amplify_buffer(tmp_rbuf, self.gain);
-            render::add_buf_to_buf(lbuf, tmp_lbuf);
-            render::add_buf_to_buf(rbuf, tmp_rbuf);
-        }
+impl Preload for Engine {
+    type Loaded = SynthInitRes;
+
+    fn last_file(&mut self) -> &mut Option<PathBuf> {
+        &mut self.last_file
     }
 
-    fn reset_rendering(&mut self) {
-        if let Some(s) = self.synth.as_mut() {
-            s.reset()
-        }
+    fn load_handle(&mut self) -> &mut Option<SynthInitResHandle> {
+        &mut self.synth_init_handle
+    }
+
+    fn preloaded_file(&mut self) -> &mut Option<(PathBuf, SynthInitResHandle)> {
+        &mut self.preloaded_file
+    }
+
+    fn load_non_blocking(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.init_synth_non_blocking()
+    }
+}
+
+impl container::Engine for Engine {
+    const DEFAULT_NAME: &'static str = "Rusty Synth";
+    type Voice = Voice;
+
+    fn update(&mut self) {
+        self.handle_synth_init();
+    }
+
+    fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size;
     }
 
     fn set_virtual_paths(&mut self, vp: VirtualPaths) {
@@ -480,100 +329,117 @@ impl Render for Node {
         _ = self.init_synth_non_blocking();
     }
 
-    fn receive_midi_message(&mut self, message: &midi::Message) {
-        if self.midi_filter.does_pass(message) && self.does_midi_msg_pass(message) {
-            self.process_midi_message(message);
-        }
-    }
-
-    fn set_global_transposition(&mut self, transposition: i8) {
-        self.global_transposition = transposition;
-    }
-
-    fn set_user_preset(&mut self, preset: usize) {
-        if preset < self.user_presets.len() {
-            self.enabled = self.user_presets[preset];
-            json_try! {
-                self.json_updates.push(("enabled".into(), serialize(self.enabled)?))
-            }
-        }
+    fn set_master_tuning(&mut self, a4_hz: f32) {
+        self.tuning = Some(a4_hz);
+        self.send_tuning();
     }
 
     fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
         type RK = RequestKind;
         match kind {
-            RK::SetName(name) => cb(self.set_name(&name)),
-            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
             RK::LoadFile(path) => self.load_file(&path, cb),
-            RK::SetGain(gain) => cb(self.set_gain(gain)),
-            RK::SetTransposition(tr) => cb(self.set_transposition(tr)),
-            RK::SetVelocityMapping(kind) => cb(self.set_velocity_mapping(kind)),
-            RK::SetIgnoreGlobalTransposition(flag) => {
-                cb(self.set_ignore_global_transposition(flag))
-            }
+            RK::PreloadFile(path) => cb(self.preload_file(&path)),
             RK::SetBankAndPreset(bank, preset) => cb(self.set_preset(bank, preset)),
-            RK::UpdateMidiFilter(kind) => cb(self.update_midi_filter(kind)),
-            RK::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
             _ => cb(ResponseKind::Denied),
         };
     }
 
     fn serialize(&self) -> SerializationResult {
         let result: serde_json::Value = json!({
-            "name": serialize(&self.name)?,
-            "enabled": serialize(self.enabled)?,
-            "midi_filter": serialize(&self.midi_filter)?,
-            "gain": serialize(self.gain)?,
-            "transposition": serialize(self.transposition)?,
-            "global_transposition": serialize(self.global_transposition)?,
-            "velocity_mapping": serialize(self.velocity_mapping)?,
-            "ignore_global_transposition": serialize(self.ignore_global_transposition)?,
             "loaded_file": serialize(&self.last_file)?,
             "preset_map": serialize(&self.preset_map)?,
             "bank": serialize(self.last_bank)?,
             "preset": serialize(self.last_preset)?,
-            "user_presets": serialize(&self.user_presets)?,
         });
         Ok(result)
     }
 
     fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
-        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
-        deser_field_opt(source, "name", |v| self.name = v)?;
-        deser_field_opt(source, "midi_filter", |v| self.midi_filter = v)?;
-        deser_field_opt(source, "gain", |v| self.gain = v)?;
-        deser_field_opt(source, "transposition", |v| self.transposition = v)?;
-        deser_field_opt(source, "global_transposition", |v| {
-            self.global_transposition = v
-        })?;
-        deser_field_opt(source, "ignore_global_transposition", |v| {
-            self.ignore_global_transposition = v
-        })?;
         deser_field_opt(source, "loaded_file", |v| self.last_file = v)?;
         deser_field_opt(source, "bank", |v| self.last_bank = v)?;
         deser_field_opt(source, "preset", |v| self.last_preset = v)?;
-        deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
         Ok(())
     }
 
-    fn json_updates(&mut self) -> Option<Vec<JsonFieldUpdate>> {
-        if !self.json_updates.is_empty() {
-            let mut new_updates = Default::default();
-            mem::swap(&mut new_updates, &mut self.json_updates);
-            Some(new_updates)
+    fn take_json_updates(&mut self) -> Vec<JsonFieldUpdate> {
+        mem::take(&mut self.json_updates)
+    }
+
+    fn take_voice_tasks(&mut self) -> Vec<VoiceTask> {
+        self.voice.take()
+    }
+}
+
+impl container::EngineVoice for Voice {
+    fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) -> bool {
+        if let Some(synth) = &mut self.synth {
+            let start = std::time::Instant::now();
+            synth.render(lbuf, rbuf);
+            let duration = start.elapsed();
+            if duration.as_micros() > 2500 {
+                //FIXME: use fluidsynth instead (it's faster, maybe?)
+                synth.note_off_all(true);
+            }
+            true
         } else {
-            None
+            false
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(s) = self.synth.as_mut() {
+            s.reset()
+        }
+        self.tune();
+    }
+
+    fn note_on(&mut self, note: u8, velocity: u8) {
+        if let Some(s) = self.synth.as_mut() {
+            s.note_on(0, note as i32, velocity as i32)
+        }
+    }
+
+    fn note_off(&mut self, note: u8, _velocity: u8) {
+        if let Some(s) = self.synth.as_mut() {
+            s.note_off(0, note as i32)
         }
     }
 
-    fn clone_node(&self) -> super::RenderPtr {
-        Box::new(self.clone())
+    fn control_change(&mut self, kind: ControlChangeKind, value: u8) {
+        if let Some(s) = self.synth.as_mut() {
+            s.process_midi_message(0, 0xB0, kind.as_number() as i32, value as i32)
+        }
+    }
+
+    fn pitch_wheel(&mut self, value: u16) {
+        let data1 = (value & 0x7F) | 0x80;
+        let data2 = (value >> 7) & 0x7F;
+        if let Some(s) = self.synth.as_mut() {
+            s.process_midi_message(0, 0xE0, data1 as i32, data2 as i32)
+        }
     }
 }
 
-impl MidiFilterUser for Node {
-    fn midi_filter_mut(&mut self) -> &mut midi_filter::MidiFilter {
-        &mut self.midi_filter
+impl Parameters for Engine {
+    fn parameters(&self) -> Vec<Parameter> {
+        super::preset_parameters()
+    }
+
+    fn parameter(&self, id: &str) -> Option<Value> {
+        match id {
+            "bank" => self.last_bank.map(|bank| Value::Int(bank as i32)),
+            "preset" => self.last_preset.map(|preset| Value::Int(preset as i32)),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: Value) -> parameter::SetResult {
+        let res = match id {
+            "bank" => self.set_preset(value.as_i32() as u16, self.last_preset.unwrap_or(0)),
+            "preset" => self.set_preset(self.last_bank.unwrap_or(0), value.as_i32() as u8),
+            _ => return Err(parameter::Error::UnknownParameter),
+        };
+        parameter::result(res)
     }
 }
 
@@ -594,3 +460,19 @@ fn get_preset_map(sf: &SoundFont) -> PresetMap {
 
     map
 }
+
+// Tunes the channel the node plays on through the coarse and fine tuning RPNs
+fn tune(synth: &mut Synthesizer, a4_hz: f32) {
+    let cents = super::tuning_cents(a4_hz);
+    let coarse = (cents / 100.0).round();
+    let fine = ((cents / 100.0 - coarse) * 8192.0 + 8192.0).clamp(0.0, 16383.0) as i32;
+    for (rpn, msb, lsb) in [(1, fine >> 7, fine & 0x7F), (2, 64 + coarse as i32, 0)] {
+        synth.process_midi_message(0, 0xB0, 101, 0);
+        synth.process_midi_message(0, 0xB0, 100, rpn);
+        synth.process_midi_message(0, 0xB0, 6, msb);
+        synth.process_midi_message(0, 0xB0, 38, lsb);
+    }
+    // Data entry must not change the tuning afterwards
+    synth.process_midi_message(0, 0xB0, 101, 127);
+    synth.process_midi_message(0, 0xB0, 100, 127);
+}
@@ -0,0 +1,169 @@
+use crate::midi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+pub const MAX_PHRASE_NOTES: usize = 64;
+// Longest phrase in seconds
+pub const MAX_PHRASE_LENGTH: f32 = 30.0;
+
+// Note of the phrase played by the preview, times are in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PhraseNote {
+    pub note: u8,
+    pub velocity: u8,
+    pub start: f32,
+    pub duration: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PreviewParams {
+    pub file: PathBuf,
+    pub bank: u16,
+    pub preset: u8,
+    // Plays what comes in on the MIDI inputs instead of the phrase
+    pub follow_keyboard: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PreviewState {
+    pub params: PreviewParams,
+    pub loaded: bool,
+    pub gain: f32,
+}
+
+// MIDI messages of a phrase in the order they are due
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    events: Vec<(f32, midi::MessageKind)>,
+    next: usize,
+}
+
+// A rising arpeggio ending in a chord
+pub fn default_phrase() -> Vec<PhraseNote> {
+    let note = |note, start, duration| PhraseNote {
+        note,
+        velocity: 96,
+        start,
+        duration,
+    };
+    vec![
+        note(60, 0.0, 0.3),
+        note(64, 0.3, 0.3),
+        note(67, 0.6, 0.3),
+        note(72, 0.9, 0.3),
+        note(48, 1.3, 1.5),
+        note(60, 1.3, 1.5),
+        note(64, 1.3, 1.5),
+        note(67, 1.3, 1.5),
+    ]
+}
+
+pub fn is_valid_phrase(phrase: &[PhraseNote]) -> bool {
+    phrase.len() <= MAX_PHRASE_NOTES
+        && phrase.iter().all(|note| {
+            note.note < 128
+                && (1..128).contains(&note.velocity)
+                && note.start >= 0.0
+                && note.duration > 0.0
+                && note.start + note.duration <= MAX_PHRASE_LENGTH
+        })
+}
+
+impl Schedule {
+    pub fn new(phrase: &[PhraseNote]) -> Self {
+        let mut events = Vec::with_capacity(2 * phrase.len());
+        for note in phrase {
+            let (key, velocity) = (note.note, note.velocity);
+            events.push((
+                note.start,
+                midi::MessageKind::NoteOn {
+                    note: key,
+                    velocity,
+                },
+            ));
+            events.push((
+                note.start + note.duration,
+                midi::MessageKind::NoteOff {
+                    note: key,
+                    velocity: 0,
+                },
+            ));
+        }
+        // A note ending where the same note starts again is released first
+        events.sort_by(|(a, a_kind), (b, b_kind)| {
+            let is_on = |kind: &midi::MessageKind| matches!(kind, midi::MessageKind::NoteOn { .. });
+            a.total_cmp(b).then(is_on(a_kind).cmp(&is_on(b_kind)))
+        });
+        Self { events, next: 0 }
+    }
+
+    // Messages which became due up to the time since the phrase started
+    pub fn advance(&mut self, elapsed: f32) -> &[(f32, midi::MessageKind)] {
+        let start = self.next;
+        while self
+            .events
+            .get(self.next)
+            .is_some_and(|(time, _)| *time <= elapsed)
+        {
+            self.next += 1;
+        }
+        &self.events[start..self.next]
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(events: &[(f32, midi::MessageKind)]) -> Vec<(bool, u8)> {
+        events
+            .iter()
+            .map(|(_, kind)| match *kind {
+                midi::MessageKind::NoteOn { note, .. } => (true, note),
+                midi::MessageKind::NoteOff { note, .. } => (false, note),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn schedule() {
+        let phrase = [
+            PhraseNote {
+                note: 60,
+                velocity: 100,
+                start: 0.5,
+                duration: 0.5,
+            },
+            PhraseNote {
+                note: 60,
+                velocity: 100,
+                start: 0.0,
+                duration: 0.5,
+            },
+        ];
+        let mut schedule = Schedule::new(&phrase);
+        assert_eq!(notes(schedule.advance(0.0)), vec![(true, 60)]);
+        assert!(schedule.advance(0.25).is_empty());
+        assert_eq!(notes(schedule.advance(0.6)), vec![(false, 60), (true, 60)]);
+        assert!(!schedule.is_finished());
+        assert_eq!(notes(schedule.advance(2.0)), vec![(false, 60)]);
+        assert!(schedule.is_finished());
+    }
+
+    #[test]
+    fn phrase_validation() {
+        assert!(is_valid_phrase(&default_phrase()));
+        let mut phrase = default_phrase();
+        phrase[0].velocity = 0;
+        assert!(!is_valid_phrase(&phrase));
+        phrase[0].velocity = 1;
+        phrase[0].duration = MAX_PHRASE_LENGTH + 1.0;
+        assert!(!is_valid_phrase(&phrase));
+    }
+}
@@ -0,0 +1,548 @@
+use super::{
+    node::{VoiceCommand, VoicePtr},
+    output_channels, OutputChannels, MAX_CHANNELS,
+};
+use crate::audio::output::BufferTx;
+use ringbuf::{
+    traits::{Consumer, Producer, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+use std::{
+    collections::VecDeque,
+    mem,
+    thread::{self, JoinHandle},
+};
+use tracing::error;
+
+// Frames rendered at once, nodes never get larger buffers
+pub const BLOCK_SIZE: usize = 64;
+
+const NUM_COMMANDS: usize = 1024;
+const NODE_CAPACITY: usize = 64;
+
+// One voice per node of the renderer, in the same order
+pub type Voices = Vec<VoicePtr>;
+// Changes of the voice list that the render thread does between two blocks.
+// They run once and keep what they swap out.
+type Task = Box<dyn FnMut(&mut Voices) + Send>;
+// The preview voice is kept apart from the voices of the nodes
+type PreviewTask = Box<dyn FnMut(&mut Option<VoicePtr>) + Send>;
+
+pub enum Command {
+    Voice(usize, VoiceCommand),
+    Task(Task),
+    PreviewVoice(VoiceCommand),
+    PreviewTask(PreviewTask),
+}
+
+pub struct Sender {
+    cmd_tx: HeapProd<Command>,
+    // Commands that did not fit into the queue, sent again by flush
+    backlog: VecDeque<Command>,
+    garbage_rx: HeapCons<Command>,
+    num_voices: usize,
+    voice_capacity: usize,
+}
+
+// The part of the renderer owned by the render thread. Commands holding
+// memory are handed back after they ran, the render thread neither
+// allocates nor frees.
+pub struct Core {
+    voices: Voices,
+    // Rendered to the main output after the voices
+    preview: Option<VoicePtr>,
+    cmd_rx: HeapCons<Command>,
+    garbage_tx: HeapProd<Command>,
+    // Garbage the renderer had no room for yet, no more commands are taken until it is handed back
+    pending_garbage: Option<Command>,
+    channels: Vec<Vec<f32>>,
+    // a voice routed to a mono output is rendered here and folded down
+    fold_bufs: [Vec<f32>; 2],
+    // Frames of the last block already filled into the outputs
+    played: usize,
+}
+
+pub fn create(num_channels: usize) -> (Sender, Core) {
+    let (cmd_tx, cmd_rx) = HeapRb::new(NUM_COMMANDS).split();
+    let (garbage_tx, garbage_rx) = HeapRb::new(NUM_COMMANDS).split();
+    let core = Core {
+        voices: Vec::with_capacity(NODE_CAPACITY),
+        preview: None,
+        cmd_rx,
+        garbage_tx,
+        pending_garbage: None,
+        channels: vec![vec![0.0; BLOCK_SIZE]; num_channels.min(MAX_CHANNELS)],
+        fold_bufs: [vec![0.0; BLOCK_SIZE], vec![0.0; BLOCK_SIZE]],
+        played: BLOCK_SIZE,
+    };
+    let sender = Sender {
+        cmd_tx,
+        backlog: VecDeque::new(),
+        garbage_rx,
+        num_voices: 0,
+        voice_capacity: NODE_CAPACITY,
+    };
+    (sender, core)
+}
+
+// Keeps the output buffer filled, the audio callbacks only copy from it
+pub fn spawn(mut core: Core, mut buf_tx: BufferTx) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("render".into())
+        .spawn(move || loop {
+            core.process_commands();
+            if buf_tx.has_room(BLOCK_SIZE) {
+                buf_tx.push_block(core.render());
+            } else {
+                buf_tx.wait_for_room();
+            }
+        })
+        .expect("Failed to spawn the render thread")
+}
+
+impl Sender {
+    // Never waits for the render thread, what does not fit is kept in order
+    // until the next flush
+    pub fn send(&mut self, cmd: Command) {
+        if !self.backlog.is_empty() {
+            self.backlog.push_back(cmd);
+        } else if let Err(cmd) = self.cmd_tx.try_push(cmd) {
+            error!("The render queue is full, the render thread is falling behind");
+            self.backlog.push_back(cmd);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        while let Some(cmd) = self.backlog.pop_front() {
+            if let Err(cmd) = self.cmd_tx.try_push(cmd) {
+                self.backlog.push_front(cmd);
+                break;
+            }
+        }
+    }
+
+    fn run(&mut self, task: impl FnMut(&mut Voices) + Send + 'static) {
+        self.send(Command::Task(Box::new(task)));
+    }
+
+    pub fn add_voice(&mut self, voice: VoicePtr) {
+        // A full voice list is replaced by a larger one allocated here
+        if self.num_voices == self.voice_capacity {
+            self.voice_capacity *= 2;
+            let mut larger = Vec::with_capacity(self.voice_capacity);
+            self.run(move |voices| {
+                larger.append(voices);
+                mem::swap(voices, &mut larger);
+            });
+        }
+        self.num_voices += 1;
+        let mut voice = Some(voice);
+        self.run(move |voices| {
+            if let Some(voice) = voice.take() {
+                voices.push(voice);
+            }
+        });
+    }
+
+    pub fn remove_voice(&mut self, id: usize) {
+        self.num_voices = self.num_voices.saturating_sub(1);
+        let mut removed = None;
+        self.run(move |voices| {
+            if id < voices.len() {
+                removed.replace(voices.remove(id));
+            }
+        });
+    }
+
+    pub fn move_voice(&mut self, id: usize, new_id: usize) {
+        self.run(move |voices| {
+            if id < voices.len() && new_id < voices.len() {
+                let voice = voices.remove(id);
+                voices.insert(new_id, voice);
+            }
+        });
+    }
+
+    pub fn set_preview_voice(&mut self, voice: Option<VoicePtr>) {
+        let mut slot = voice;
+        self.send(Command::PreviewTask(Box::new(move |preview| {
+            mem::swap(preview, &mut slot)
+        })));
+    }
+
+    // Drops what the render thread handed back
+    pub fn collect_garbage(&mut self) {
+        self.garbage_rx.clear();
+    }
+}
+
+impl Command {
+    fn holds_memory(&self) -> bool {
+        matches!(
+            self,
+            Command::Task(_)
+                | Command::PreviewTask(_)
+                | Command::Voice(_, VoiceCommand::Task(_))
+                | Command::PreviewVoice(VoiceCommand::Task(_))
+        )
+    }
+}
+
+impl Core {
+    // Called between blocks, neither allocates nor frees
+    pub fn process_commands(&mut self) {
+        if let Some(garbage) = self.pending_garbage.take() {
+            if !self.hand_back(garbage) {
+                return;
+            }
+        }
+        while let Some(mut cmd) = self.cmd_rx.try_pop() {
+            match &mut cmd {
+                Command::Voice(id, cmd) => {
+                    if let Some(voice) = self.voices.get_mut(*id) {
+                        voice.process_command(cmd);
+                    }
+                }
+                Command::Task(task) => task(&mut self.voices),
+                Command::PreviewVoice(cmd) => {
+                    if let Some(preview) = &mut self.preview {
+                        preview.process_command(cmd);
+                    }
+                }
+                Command::PreviewTask(task) => task(&mut self.preview),
+            }
+            if cmd.holds_memory() && !self.hand_back(cmd) {
+                return;
+            }
+        }
+    }
+
+    // Keeps the command for the next block when the renderer has not collected yet
+    fn hand_back(&mut self, cmd: Command) -> bool {
+        match self.garbage_tx.try_push(cmd) {
+            Ok(()) => true,
+            Err(cmd) => {
+                self.pending_garbage = Some(cmd);
+                false
+            }
+        }
+    }
+
+    // Renders one block into the preallocated channels without allocating
+    pub fn render(&mut self) -> &[Vec<f32>] {
+        let num_channels = self.channels.len();
+        let mut channels: [&mut [f32]; MAX_CHANNELS] = Default::default();
+        for (channel, buf) in channels.iter_mut().zip(&mut self.channels) {
+            buf.fill(0.0);
+            *channel = buf;
+        }
+        let channels = &mut channels[..num_channels];
+        for voice in &mut self.voices {
+            let output = voice.output();
+            render_voice(
+                voice,
+                output_channels(channels, output),
+                &mut self.fold_bufs,
+            );
+        }
+        if let Some(preview) = &mut self.preview {
+            render_voice(preview, output_channels(channels, 0), &mut self.fold_bufs);
+        }
+        &self.channels
+    }
+
+    // For backends that render in their own callback. Outputs of any length
+    // are filled from whole blocks, the rest of a block is played next time.
+    pub fn fill(&mut self, outs: &mut [&mut [f32]]) {
+        let len = outs.first().map_or(0, |out| out.len());
+        let mut pos = 0;
+        while pos < len {
+            if self.played == BLOCK_SIZE {
+                self.process_commands();
+                self.render();
+                self.played = 0;
+            }
+            let num_frames = (BLOCK_SIZE - self.played).min(len - pos);
+            for (channel, out) in outs.iter_mut().enumerate() {
+                let out = &mut out[pos..pos + num_frames];
+                match self.channels.get(channel) {
+                    Some(buf) => out.copy_from_slice(&buf[self.played..self.played + num_frames]),
+                    None => out.fill(0.0),
+                }
+            }
+            pos += num_frames;
+            self.played += num_frames;
+        }
+    }
+}
+
+fn render_voice(
+    voice: &mut VoicePtr,
+    output: Option<OutputChannels>,
+    fold_bufs: &mut [Vec<f32>; 2],
+) {
+    match output {
+        Some(OutputChannels::Stereo(lbuf, rbuf)) => voice.render_additive(lbuf, rbuf),
+        Some(OutputChannels::Mono(buf)) => {
+            let [lbuf, rbuf] = fold_bufs;
+            lbuf.fill(0.0);
+            rbuf.fill(0.0);
+            voice.render_additive(lbuf, rbuf);
+            for (x, (l, r)) in buf.iter_mut().zip(lbuf.iter().zip(rbuf.iter())) {
+                *x += 0.5 * (l + r);
+            }
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        json::{DeserializationResult, JsonFieldUpdate, SerializationResult},
+        midi::{self, ControlChangeKind},
+        parameter::{self, Parameter, Parameters, Value},
+        path::VirtualPaths,
+        render::node::{
+            container::{Container, Engine, EngineVoice},
+            RenderPtr, RequestKind, ResponseCallback, ResponseKind, VoiceTask,
+        },
+    };
+    use ringbuf::traits::Observer;
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+        static DEALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            _ = DEALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // Allocations and deallocations of this thread so far
+    fn memory_ops() -> (usize, usize) {
+        (
+            ALLOCATIONS.with(|n| n.get()),
+            DEALLOCATIONS.with(|n| n.get()),
+        )
+    }
+
+    #[derive(Clone, Default)]
+    struct Tone;
+
+    #[derive(Default)]
+    struct ToneVoice {
+        sounding: bool,
+    }
+
+    impl Parameters for Tone {
+        fn parameters(&self) -> Vec<Parameter> {
+            Vec::new()
+        }
+
+        fn parameter(&self, _id: &str) -> Option<Value> {
+            None
+        }
+
+        fn set_parameter(&mut self, _id: &str, _value: Value) -> parameter::SetResult {
+            Err(parameter::Error::UnknownParameter)
+        }
+    }
+
+    impl Engine for Tone {
+        const DEFAULT_NAME: &'static str = "Tone";
+        type Voice = ToneVoice;
+
+        fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+        fn set_sample_rate(&mut self, _sample_rate: u32) {}
+        fn process_request(&mut self, _kind: RequestKind, cb: ResponseCallback) {
+            cb(ResponseKind::Denied)
+        }
+        fn serialize(&self) -> SerializationResult {
+            Ok(serde_json::json!({}))
+        }
+        fn deserialize(&mut self, _source: &serde_json::Value) -> DeserializationResult {
+            Ok(())
+        }
+        fn take_json_updates(&mut self) -> Vec<JsonFieldUpdate> {
+            Vec::new()
+        }
+        fn take_voice_tasks(&mut self) -> Vec<VoiceTask> {
+            Vec::new()
+        }
+    }
+
+    impl EngineVoice for ToneVoice {
+        fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) -> bool {
+            let value = if self.sounding { 0.5 } else { 0.0 };
+            lbuf.fill(value);
+            rbuf.fill(value);
+            true
+        }
+        fn reset(&mut self) {}
+        fn note_on(&mut self, _note: u8, _velocity: u8) {
+            self.sounding = true;
+        }
+        fn note_off(&mut self, _note: u8, _velocity: u8) {
+            self.sounding = false;
+        }
+        fn control_change(&mut self, _kind: ControlChangeKind, _value: u8) {}
+        fn pitch_wheel(&mut self, _value: u16) {}
+    }
+
+    fn tone(output: usize) -> RenderPtr {
+        let mut node: RenderPtr = Box::<Container<Tone>>::default();
+        node.set_block_size(BLOCK_SIZE);
+        node.process_request(RequestKind::SetOutput(output), Box::new(|_| {}));
+        node
+    }
+
+    fn note_on(node: &mut RenderPtr) {
+        node.receive_midi_message(&midi::Message {
+            channel: 0,
+            kind: midi::MessageKind::NoteOn {
+                note: 60,
+                velocity: 100,
+            },
+        });
+    }
+
+    #[test]
+    fn render_thread_neither_allocates_nor_frees() {
+        let (mut sender, mut core) = create(4);
+        // One more than fits into the voice list the core starts with
+        let mut nodes: Vec<_> = (0..=NODE_CAPACITY).map(|id| tone(id % 2)).collect();
+        for node in &nodes {
+            sender.add_voice(node.create_voice());
+        }
+        note_on(&mut nodes[1]);
+        for (id, node) in nodes.iter_mut().enumerate() {
+            for cmd in node.take_voice_commands() {
+                sender.send(Command::Voice(id, cmd));
+            }
+        }
+        sender.remove_voice(0);
+        sender.move_voice(0, 1);
+
+        let before = memory_ops();
+        core.process_commands();
+        let num_voices = core.voices.len();
+        let channels = core.render();
+        let after = memory_ops();
+        assert_eq!(after, before);
+        assert_eq!(num_voices, NODE_CAPACITY);
+        assert_eq!((channels[0][0], channels[1][0]), (0.0, 0.0));
+        assert_eq!((channels[2][0], channels[3][BLOCK_SIZE - 1]), (0.5, 0.5));
+        assert!(sender.garbage_rx.occupied_len() > 0);
+    }
+
+    #[test]
+    fn preview_is_apart_from_voices() {
+        let (mut sender, mut core) = create(4);
+        // The preview always plays on the main output
+        let mut preview = tone(1);
+        sender.set_preview_voice(Some(preview.create_voice()));
+        _ = preview.take_voice_commands();
+        core.process_commands();
+        assert!(core.voices.is_empty());
+        assert_eq!(core.render()[0][0], 0.0);
+
+        note_on(&mut preview);
+        for cmd in preview.take_voice_commands() {
+            sender.send(Command::PreviewVoice(cmd));
+        }
+        core.process_commands();
+        let channels = core.render();
+        assert_eq!((channels[0][0], channels[2][0]), (0.5, 0.0));
+
+        // The replaced voice is handed back with the task
+        sender.collect_garbage();
+        sender.set_preview_voice(None);
+        let before = memory_ops();
+        core.process_commands();
+        assert_eq!(memory_ops(), before);
+        assert!(core.preview.is_none());
+        assert_eq!(sender.garbage_rx.occupied_len(), 1);
+    }
+
+    #[test]
+    fn full_garbage_is_kept() {
+        let (mut sender, mut core) = create(2);
+        for _ in 0..NUM_COMMANDS {
+            sender.move_voice(0, 0);
+        }
+        core.process_commands();
+        assert!(sender.garbage_rx.is_full());
+
+        // Nothing is freed, the commands after the one that did not fit wait
+        sender.set_preview_voice(None);
+        sender.move_voice(0, 0);
+        let before = memory_ops();
+        core.process_commands();
+        assert_eq!(memory_ops(), before);
+        assert!(core.pending_garbage.is_some());
+        assert_eq!(core.cmd_rx.occupied_len(), 1);
+        core.process_commands();
+        assert_eq!(core.cmd_rx.occupied_len(), 1);
+
+        sender.collect_garbage();
+        core.process_commands();
+        assert!(core.pending_garbage.is_none());
+        assert!(core.cmd_rx.is_empty());
+        assert_eq!(sender.garbage_rx.occupied_len(), 2);
+    }
+
+    #[test]
+    fn fill_continues_the_block() {
+        let (mut sender, mut core) = create(2);
+        let mut node = tone(0);
+        sender.add_voice(node.create_voice());
+        let mut bufs = [[0.0; 40]; 2];
+        let mut outs = bufs.each_mut().map(|buf| &mut buf[..]);
+        core.fill(&mut outs);
+        assert_eq!(core.played, 40);
+
+        // Commands are taken with the next block only
+        note_on(&mut node);
+        for cmd in node.take_voice_commands() {
+            sender.send(Command::Voice(0, cmd));
+        }
+        let before = memory_ops();
+        core.fill(&mut outs);
+        assert_eq!(memory_ops(), before);
+        assert_eq!(core.played, 16);
+        assert_eq!((bufs[0][23], bufs[0][24], bufs[1][39]), (0.0, 0.5, 0.5));
+    }
+
+    #[test]
+    fn full_queue_keeps_the_commands() {
+        let (mut sender, mut core) = create(2);
+        for _ in 0..NUM_COMMANDS + 2 {
+            sender.send(Command::Voice(0, VoiceCommand::Reset));
+        }
+        assert_eq!(sender.backlog.len(), 2);
+        sender.flush();
+        assert_eq!(sender.backlog.len(), 2);
+        core.process_commands();
+        sender.flush();
+        assert!(sender.backlog.is_empty());
+    }
+}
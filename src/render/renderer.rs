@@ -1,20 +1,41 @@
-use crate::render::node;
+use crate::render::{
+    node,
+    preview::{self, PhraseNote, PreviewParams, PreviewState, Schedule},
+    realtime::{self, Command},
+    MAX_TRANSPOSITION,
+};
 use crate::{
     control,
-    json::JsonFieldUpdate,
+    error::{Error, ErrorCode},
+    json::{self, deser_field_opt, expect_serialize, DeserializationResult},
     midi,
+    parameter::{self, Parameter},
     path::VirtualPaths,
-    webserver::{Cache, Clients, ServerMessageKind},
+    webserver::{
+        cache::{self, CachedNode, NodeUid},
+        presence::{ClientId, Node},
+        Cache, Clients, ServerMessageKind,
+    },
 };
-use node::RenderPtr;
+use node::{RenderPtr, VoicePtr};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tracing::error;
 
-pub type Requester = mpsc::Sender<(RequestKind, Responder)>;
-pub type RequestListener = mpsc::Receiver<(RequestKind, Responder)>;
+pub type Requester = mpsc::Sender<(RequestKind, Option<ClientId>, Responder)>;
+pub type RequestListener = mpsc::Receiver<(RequestKind, Option<ClientId>, Responder)>;
 pub type Responder = oneshot::Sender<ResponseKind>;
 pub type ResponseListener = oneshot::Receiver<ResponseKind>;
 
@@ -26,7 +47,27 @@ pub fn create_response_channel() -> (Responder, ResponseListener) {
     oneshot::channel()
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub async fn send_request(req_tx: &Requester, req: RequestKind) -> Option<ResponseKind> {
+    send_request_from(req_tx, None, req).await
+}
+
+// The changes made by the request are attributed to the client
+pub async fn send_request_from(
+    req_tx: &Requester,
+    author: Option<ClientId>,
+    req: RequestKind,
+) -> Option<ResponseKind> {
+    let (res_tx, res_rx) = create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, author, res_tx)).await {
+        res_rx.await.ok()
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "RendererRequestKind")]
 pub enum RequestKind {
     SetUserPreset(usize),
     NodeRequest { id: usize, kind: node::RequestKind },
@@ -34,76 +75,138 @@ pub enum RequestKind {
     RemoveNode { id: usize },
     CloneNode { id: usize },
     MoveNode { id: usize, new_id: usize },
+    SetGlobalTransposition(i8),
+    SetMasterTuning { a4_hz: f32, fine_tune_cents: f32 },
+    // The renderer part of a scene, see scene::Scene: global transposition
+    // and master tuning as serialized by the renderer, (uid, state) pairs
+    ApplyScene(serde_json::Value, Vec<(NodeUid, serde_json::Value)>),
+    // The renderer part of a session: the renderer state and the nodes which
+    // replace all current ones, they keep their uids
+    LoadNodes(serde_json::Value, Vec<CachedNode>),
+    ListParameters(usize),
+    GetParameter(usize, String),
+    SetParameter(usize, String, parameter::Value),
+    // Answered once the file is loaded, replaces the running preview
+    StartPreview(PreviewParams),
+    // Plays the phrase of the running preview from the start
+    ReplayPreview,
+    StopPreview,
+    SetPreviewGain(f32),
+    SetPreviewPhrase(Vec<PhraseNote>),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "RendererResponseKind")]
 pub enum ResponseKind {
     InvalidNodeKind,
     InvalidId,
     Denied,
-    Failed,
+    Failed { reason: Error },
     Ok,
     NodeResponse { id: usize, kind: node::ResponseKind },
+    InvalidParameter,
+    InvalidValue,
+    Parameters(Vec<Parameter>),
+    ParameterValue(parameter::Value),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+// The renderer state reaches the clients as cache deltas, only what is
+// not kept in the cache is sent as an update
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "RendererUpdateKind")]
 pub enum UpdateKind {
-    AddNode {
-        id: usize,
-        kind: String,
-        instance: serde_json::Value,
-    },
-    RemoveNode {
-        id: usize,
-    },
-    CloneNode {
-        id: usize,
-    },
-    MoveNode {
-        id: usize,
-        new_id: usize,
-    },
-    NodeUpdates {
-        id: usize,
-        updates: Vec<JsonFieldUpdate>,
-    },
+    Preview(Option<PreviewState>),
 }
 
+const DEFAULT_A4_HZ: f32 = 440.0;
+const MIN_A4_HZ: f32 = 400.0;
+const MAX_A4_HZ: f32 = 480.0;
+const MAX_FINE_TUNE_CENTS: f32 = 100.0;
+const MAX_PREVIEW_GAIN: f32 = 4.0;
+
 pub type NodeKindConstructor = Box<dyn Fn() -> RenderPtr + 'static + Sync + Send>;
 
 pub struct Renderer {
     registered_node_kinds: HashMap<String, NodeKindConstructor>,
+    // only the voices of the nodes live on the render thread
     nodes: Vec<(String, RenderPtr)>,
+    rt: realtime::Sender,
     midi_rx: midi::Receiver,
     req_rx: RequestListener,
     dm_ctr_rx: control::CtrReceiver,
+    // receives what the control nodes play, never the raw inputs
+    midi_out_tx: Option<midi::Sender>,
     sample_rate: Option<u32>,
+    num_outputs: usize,
     global_transposition: i8,
+    a4_hz: f32,
+    fine_tune_cents: f32,
     virtual_paths: VirtualPaths,
     clients: Clients,
     cache: Cache,
+    // clients whose requests edited a node since the last json updates
+    node_authors: HashMap<usize, ClientId>,
+    // node kinds playing the previews, by file extension
+    preview_kinds: HashMap<String, String>,
+    preview: Option<Preview>,
+    preview_gain: f32,
+    preview_phrase: Vec<PhraseNote>,
+}
+
+// The preview voice is not part of the session, it is neither in the node
+// list nor in the cache
+struct Preview {
+    node: RenderPtr,
+    state: PreviewState,
+    // One of the LOAD_ states, set by the node once its loader thread finished
+    load_state: Arc<AtomicU8>,
+    started: Option<Instant>,
+    schedule: Schedule,
 }
 
+const LOADING: u8 = 0;
+const LOADED: u8 = 1;
+const LOAD_FAILED: u8 = 2;
+
 impl Renderer {
     pub fn new(
         midi_rx: midi::Receiver,
         req_rx: RequestListener,
         dm_ctr_rx: control::CtrReceiver,
+        rt: realtime::Sender,
         virtual_paths: VirtualPaths,
         clients: Clients,
         cache: Cache,
     ) -> Self {
         Self {
             registered_node_kinds: Default::default(),
-            nodes: Default::default(),
+            nodes: Vec::new(),
+            rt,
             midi_rx,
             req_rx,
             dm_ctr_rx,
+            midi_out_tx: None,
             sample_rate: None,
+            num_outputs: 1,
             global_transposition: 0,
+            a4_hz: DEFAULT_A4_HZ,
+            fine_tune_cents: 0.0,
             virtual_paths,
             clients,
             cache,
+            node_authors: HashMap::new(),
+            preview_kinds: HashMap::new(),
+            preview: None,
+            preview_gain: 1.0,
+            preview_phrase: preview::default_phrase(),
         }
     }
 
@@ -115,11 +218,29 @@ impl Renderer {
             .insert(name.to_owned(), Box::new(constructor));
     }
 
+    // Files with the extension are previewed with a node of the registered kind
+    pub fn register_preview_kind(&mut self, extension: &str, kind: &str) {
+        self.preview_kinds
+            .insert(extension.to_lowercase(), kind.to_owned());
+    }
+
     pub fn set_sample_rate(&mut self, sample_rate: u32) {
         self.sample_rate = Some(sample_rate);
         for (_, node) in &mut self.nodes {
             node.set_sample_rate(sample_rate);
         }
+        if let Some(preview) = &mut self.preview {
+            preview.node.set_sample_rate(sample_rate);
+        }
+    }
+
+    // Number of stereo outputs the audio device provides
+    pub fn set_num_outputs(&mut self, num_outputs: usize) {
+        self.num_outputs = num_outputs;
+    }
+
+    pub fn set_midi_output(&mut self, midi_out_tx: midi::Sender) {
+        self.midi_out_tx = Some(midi_out_tx);
     }
 
     pub fn set_global_transposition(&mut self, transposition: i8) {
@@ -129,67 +250,149 @@ impl Renderer {
         }
     }
 
+    pub fn set_master_tuning(&mut self, a4_hz: f32, fine_tune_cents: f32) {
+        self.a4_hz = a4_hz;
+        self.fine_tune_cents = fine_tune_cents;
+        let tuning = self.tuning_frequency();
+        for (_, node) in &mut self.nodes {
+            node.set_master_tuning(tuning);
+        }
+    }
+
+    pub fn serialize(&self) -> serde_json::Value {
+        json!({
+            "global_transposition": expect_serialize(self.global_transposition),
+            "num_outputs": expect_serialize(self.num_outputs),
+            "a4_hz": expect_serialize(self.a4_hz),
+            "fine_tune_cents": expect_serialize(self.fine_tune_cents),
+        })
+    }
+
+    // Restores the global transposition and the master tuning, the number of
+    // outputs is up to the audio device
+    pub async fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        let mut transposition = self.global_transposition;
+        let (mut a4_hz, mut fine_tune_cents) = (self.a4_hz, self.fine_tune_cents);
+        deser_field_opt(source, "global_transposition", |v| transposition = v)?;
+        deser_field_opt(source, "a4_hz", |v| a4_hz = v)?;
+        deser_field_opt(source, "fine_tune_cents", |v| fine_tune_cents = v)?;
+        if !is_valid_tuning(a4_hz, fine_tune_cents) {
+            return Err(json::Error("Master tuning out of range".into()));
+        }
+        if !is_valid_transposition(transposition) {
+            return Err(json::Error("Global transposition out of range".into()));
+        }
+        if transposition != self.global_transposition {
+            self.process_set_global_transposition(transposition).await;
+        }
+        if (a4_hz, fine_tune_cents) != (self.a4_hz, self.fine_tune_cents) {
+            self.process_set_master_tuning(a4_hz, fine_tune_cents).await;
+        }
+        Ok(())
+    }
+
     pub async fn update(&mut self) {
+        self.rt.collect_garbage();
         self.receive_requests().await;
         self.receive_midi_messages();
         self.receive_drum_machine_messages();
+        self.update_preview();
         self.process_json_updates().await;
+        self.flush_voices();
     }
 
-    pub fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
-        self.render_audio(lbuf, rbuf);
+    // Hands the voices what changed since the last flush. Has to be called
+    // before the voice list changes, the commands address voices by index.
+    fn flush_voices(&mut self) {
+        for (id, (_, node)) in self.nodes.iter_mut().enumerate() {
+            for cmd in node.take_voice_commands() {
+                self.rt.send(Command::Voice(id, cmd));
+            }
+        }
+        if let Some(preview) = &mut self.preview {
+            for cmd in preview.node.take_voice_commands() {
+                self.rt.send(Command::PreviewVoice(cmd));
+            }
+        }
+        self.rt.flush();
     }
 
-    pub fn add_node(&mut self, kind: String, mut node: RenderPtr) {
+    // Brings a new node in line with the renderer before it is handed to the render thread
+    fn prepare_node(&self, node: &mut RenderPtr) {
         if let Some(sample_rate) = self.sample_rate {
             node.set_sample_rate(sample_rate);
         }
+        node.set_block_size(realtime::BLOCK_SIZE);
         node.set_virtual_paths(self.virtual_paths.clone());
         node.set_global_transposition(self.global_transposition);
+        node.set_master_tuning(self.tuning_frequency());
+    }
+
+    fn add_node(&mut self, kind: String, mut node: RenderPtr) {
+        self.prepare_node(&mut node);
+        self.rt.add_voice(node.create_voice());
         self.nodes.push((kind, node));
     }
 
+    fn set_preview_voice(&mut self, voice: Option<VoicePtr>) {
+        self.rt.set_preview_voice(voice);
+    }
+
     pub async fn receive_requests(&mut self) {
-        while let Ok((kind, responder)) = self.req_rx.try_recv() {
+        while let Ok((kind, author, responder)) = self.req_rx.try_recv() {
+            if let Some(author) = author {
+                for id in edited_nodes(&kind) {
+                    self.node_authors.insert(id, author);
+                }
+            }
+            self.cache.set_author(author);
             self.process_request(kind, responder).await;
         }
+        self.cache.set_author(None);
     }
 
     fn receive_midi_messages(&mut self) {
+        let follow_keyboard = self
+            .preview
+            .as_ref()
+            .is_some_and(|preview| preview.state.params.follow_keyboard);
         while let Ok(msg) = self.midi_rx.try_recv() {
             for (_, node) in &mut self.nodes {
-                node.receive_midi_message(&msg)
+                node.receive_midi_message(&msg);
+            }
+            if let Some(preview) = self.preview.as_mut().filter(|_| follow_keyboard) {
+                preview.node.receive_midi_message(&msg);
             }
         }
     }
 
     fn receive_drum_machine_messages(&mut self) {
         while let Ok(msg) = self.dm_ctr_rx.try_recv() {
-            let node_id = msg.instrument_id;
-            if node_id < self.nodes.len() {
-                let node = &mut self.nodes[node_id].1;
+            if let Some(midi_out_tx) = &self.midi_out_tx {
+                if midi_out_tx.receiver_count() > 0 {
+                    _ = midi_out_tx.send(msg.midi_msg);
+                }
+            }
+            if let Some((_, node)) = self.nodes.get_mut(msg.instrument_id) {
                 node.receive_midi_message(&msg.midi_msg);
             }
         }
     }
 
     async fn process_json_updates(&mut self) {
-        for (id, node) in self.nodes.iter_mut().enumerate() {
-            if let Some(updates) = node.1.json_updates() {
-                self.cache.render_node_updates(id, &updates).await;
-                self.clients.broadcast(ServerMessageKind::RendererUpdate(
-                    UpdateKind::NodeUpdates { id, updates },
-                ));
+        let mut node_updates = Vec::new();
+        for (id, (_, node)) in self.nodes.iter_mut().enumerate() {
+            node.update();
+            if let Some(updates) = node.json_updates() {
+                node_updates.push((id, updates));
             }
         }
-    }
-
-    fn render_audio(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
-        lbuf.fill(0.0);
-        rbuf.fill(0.0);
-        for (_, node) in &mut self.nodes {
-            node.render_additive(lbuf, rbuf)
+        for (id, updates) in node_updates {
+            self.cache.set_author(self.node_authors.get(&id).copied());
+            self.cache.render_node_updates(id, &updates).await;
         }
+        self.cache.set_author(None);
+        self.node_authors.clear();
     }
 
     async fn process_request(&mut self, kind: RequestKind, responder: Responder) {
@@ -199,7 +402,11 @@ impl Renderer {
                     self.set_user_preset(preset);
                     respond(responder, ResponseKind::Ok);
                 } else {
-                    respond(responder, ResponseKind::Failed);
+                    let reason = format!("Invalid user preset: {preset}");
+                    respond(
+                        responder,
+                        ResponseKind::failed(ErrorCode::InvalidValue, reason),
+                    );
                 }
             }
             RequestKind::NodeRequest { id, kind } => self.process_node_request(responder, id, kind),
@@ -209,21 +416,372 @@ impl Renderer {
             RequestKind::MoveNode { id, new_id } => {
                 self.process_move_node(responder, id, new_id).await
             }
+            RequestKind::SetGlobalTransposition(transposition) => {
+                if is_valid_transposition(transposition) {
+                    respond(responder, ResponseKind::Ok);
+                    self.process_set_global_transposition(transposition).await;
+                } else {
+                    let reason = "Global transposition out of range";
+                    respond(
+                        responder,
+                        ResponseKind::failed(ErrorCode::InvalidValue, reason),
+                    );
+                }
+            }
+            RequestKind::SetMasterTuning {
+                a4_hz,
+                fine_tune_cents,
+            } => {
+                if is_valid_tuning(a4_hz, fine_tune_cents) {
+                    respond(responder, ResponseKind::Ok);
+                    self.process_set_master_tuning(a4_hz, fine_tune_cents).await;
+                } else {
+                    let reason = "Master tuning out of range";
+                    respond(
+                        responder,
+                        ResponseKind::failed(ErrorCode::InvalidValue, reason),
+                    );
+                }
+            }
+            RequestKind::ApplyScene(state, nodes) => {
+                self.process_apply_scene(responder, state, nodes).await
+            }
+            RequestKind::LoadNodes(state, nodes) => {
+                self.process_load_nodes(responder, state, nodes).await
+            }
+            RequestKind::ListParameters(id) => self.process_list_parameters(responder, id),
+            RequestKind::GetParameter(id, parameter) => {
+                self.process_get_parameter(responder, id, parameter)
+            }
+            RequestKind::SetParameter(id, parameter, value) => {
+                self.process_set_parameter(responder, id, parameter, value)
+            }
+            RequestKind::StartPreview(params) => self.process_start_preview(responder, params),
+            RequestKind::ReplayPreview => self.process_replay_preview(responder),
+            RequestKind::StopPreview => {
+                self.stop_preview();
+                respond(responder, ResponseKind::Ok);
+            }
+            RequestKind::SetPreviewGain(gain) => self.process_set_preview_gain(responder, gain),
+            RequestKind::SetPreviewPhrase(phrase) => {
+                if preview::is_valid_phrase(&phrase) {
+                    self.preview_phrase = phrase;
+                    respond(responder, ResponseKind::Ok);
+                } else {
+                    respond(responder, ResponseKind::InvalidValue);
+                }
+            }
         }
     }
 
+    fn process_start_preview(&mut self, responder: Responder, params: PreviewParams) {
+        if self.virtual_paths.translate(&params.file).is_none() {
+            let reason = Error::invalid_path(&params.file);
+            respond(responder, ResponseKind::Failed { reason });
+            return;
+        }
+        let Some(mut node) = self.create_preview_node(&params.file) else {
+            let reason = format!("Can not preview {}", params.file.display());
+            respond(
+                responder,
+                ResponseKind::failed(ErrorCode::Unsupported, reason),
+            );
+            return;
+        };
+        let load_state = Arc::new(AtomicU8::new(LOADING));
+        let on_loaded = Arc::clone(&load_state);
+        node.process_request(node::RequestKind::SetGain(self.preview_gain), ignore());
+        // Kept by the node when the file has the preset, answered with NotLoaded before
+        let (bank, preset) = (params.bank, params.preset);
+        node.process_request(node::RequestKind::SetBankAndPreset(bank, preset), ignore());
+        node.process_request(
+            node::RequestKind::LoadFile(params.file.clone()),
+            Box::new(move |res| {
+                let res = match res {
+                    node::ResponseKind::Ok => ResponseKind::Ok,
+                    node::ResponseKind::Failed { reason } => ResponseKind::Failed { reason },
+                    _ => ResponseKind::failed(ErrorCode::LoadFailed, "Failed to load the preview"),
+                };
+                let state = if res == ResponseKind::Ok {
+                    LOADED
+                } else {
+                    LOAD_FAILED
+                };
+                on_loaded.store(state, Ordering::Release);
+                respond(responder, res);
+            }),
+        );
+
+        self.set_preview_voice(Some(node.create_voice()));
+        let state = PreviewState {
+            params,
+            loaded: false,
+            gain: self.preview_gain,
+        };
+        self.preview = Some(Preview {
+            node,
+            state: state.clone(),
+            load_state,
+            started: None,
+            schedule: Schedule::new(&self.preview_phrase),
+        });
+        self.broadcast_update(UpdateKind::Preview(Some(state)));
+    }
+
+    fn create_preview_node(&self, file: &Path) -> Option<RenderPtr> {
+        let extension = file.extension()?.to_str()?.to_lowercase();
+        let kind = self.preview_kinds.get(&extension)?;
+        let mut node = self.registered_node_kinds.get(kind)?();
+        self.prepare_node(&mut node);
+        Some(node)
+    }
+
+    fn process_replay_preview(&mut self, responder: Responder) {
+        let Some(preview) = &mut self.preview else {
+            let reason = "No preview is running";
+            respond(
+                responder,
+                ResponseKind::failed(ErrorCode::NotLoaded, reason),
+            );
+            return;
+        };
+        // Notes still held by the last run are released first
+        for (_, kind) in preview.schedule.advance(f32::INFINITY) {
+            if let midi::MessageKind::NoteOff { .. } = kind {
+                preview.node.receive_midi_message(&preview_message(*kind));
+            }
+        }
+        preview.schedule = Schedule::new(&self.preview_phrase);
+        if preview.state.loaded {
+            preview.started = Some(Instant::now());
+        }
+        respond(responder, ResponseKind::Ok);
+    }
+
+    fn stop_preview(&mut self) {
+        if self.preview.take().is_some() {
+            self.set_preview_voice(None);
+            self.broadcast_update(UpdateKind::Preview(None));
+        }
+    }
+
+    fn process_set_preview_gain(&mut self, responder: Responder, gain: f32) {
+        if !(0.0..=MAX_PREVIEW_GAIN).contains(&gain) {
+            respond(responder, ResponseKind::InvalidValue);
+            return;
+        }
+        self.preview_gain = gain;
+        respond(responder, ResponseKind::Ok);
+        if let Some(preview) = &mut self.preview {
+            preview
+                .node
+                .process_request(node::RequestKind::SetGain(gain), ignore());
+            preview.state.gain = gain;
+            let state = preview.state.clone();
+            self.broadcast_update(UpdateKind::Preview(Some(state)));
+        }
+    }
+
+    // Finishes the loading and plays the phrase once the file is loaded, a
+    // preview that failed to load is dropped
+    fn update_preview(&mut self) {
+        let Some(preview) = &mut self.preview else {
+            return;
+        };
+        if !preview.state.loaded {
+            // The updates of the preview are not kept anywhere
+            preview.node.update();
+            _ = preview.node.json_updates();
+            match preview.load_state.load(Ordering::Acquire) {
+                LOADED => {
+                    preview.state.loaded = true;
+                    preview.started = Some(Instant::now());
+                    let update = UpdateKind::Preview(Some(preview.state.clone()));
+                    self.clients
+                        .broadcast(ServerMessageKind::RendererUpdate(update));
+                }
+                LOAD_FAILED => {
+                    self.stop_preview();
+                    return;
+                }
+                _ => return,
+            }
+        }
+        if preview.state.params.follow_keyboard {
+            return;
+        }
+        if let Some(started) = preview.started {
+            for (_, kind) in preview.schedule.advance(started.elapsed().as_secs_f32()) {
+                preview.node.receive_midi_message(&preview_message(*kind));
+            }
+        }
+    }
+
+    async fn process_apply_scene(
+        &mut self,
+        responder: Responder,
+        state: serde_json::Value,
+        nodes: Vec<(NodeUid, serde_json::Value)>,
+    ) {
+        // Nodes removed since the scene was captured are skipped
+        let mut states = Vec::new();
+        for (uid, state) in nodes {
+            if let Some(id) = self.cache.render_node_index(uid).await {
+                states.push((id, state));
+            }
+        }
+        // Nothing is applied when a node is locked by another client
+        if let Some(author) = self.cache.author() {
+            let nodes: Vec<_> = states.iter().map(|(id, _)| Node::RenderNode(*id)).collect();
+            if let Err(reason) = self.clients.check_nodes(Some(author), &nodes) {
+                respond(responder, ResponseKind::Failed { reason });
+                return;
+            }
+        }
+        if let Err(e) = self.deserialize(&state).await {
+            respond(responder, ResponseKind::Failed { reason: e.into() });
+            return;
+        }
+        let mut result = ResponseKind::Ok;
+        for (id, state) in states {
+            if let Some(author) = self.cache.author() {
+                self.node_authors.insert(id, author);
+            }
+            if let Some((_, node)) = self.nodes.get_mut(id) {
+                if let Err(e) = node.apply_state(&state) {
+                    result = ResponseKind::Failed { reason: e.into() };
+                }
+            }
+        }
+        respond(responder, result);
+    }
+
+    async fn process_load_nodes(
+        &mut self,
+        responder: Responder,
+        state: serde_json::Value,
+        nodes: Vec<CachedNode>,
+    ) {
+        if !cache::has_unique_uids(&nodes) {
+            let reason = "The nodes do not have unique uids";
+            respond(
+                responder,
+                ResponseKind::failed(ErrorCode::Deserialization, reason),
+            );
+            return;
+        }
+        // The current nodes stay when any of the new ones can not be created
+        let mut loaded = Vec::new();
+        for cached in nodes {
+            let Some(constructor) = self.registered_node_kinds.get(&cached.kind) else {
+                respond(responder, ResponseKind::InvalidNodeKind);
+                return;
+            };
+            let mut node = constructor();
+            if let Err(e) = node.deserialize(&cached.instance) {
+                respond(responder, ResponseKind::Failed { reason: e.into() });
+                return;
+            }
+            loaded.push((cached, node));
+        }
+
+        self.flush_voices();
+        while let Some(id) = self.nodes.len().checked_sub(1) {
+            self.nodes.remove(id);
+            self.rt.remove_voice(id);
+            self.cache.remove_render_node(id).await;
+        }
+        for (cached, node) in loaded {
+            let file = cached.instance.get("loaded_file").cloned();
+            let value = node.serialize().unwrap_or(cached.instance);
+            self.add_node(cached.kind.clone(), node);
+            self.cache
+                .restore_render_node(&cached.kind, &value, cached.uid)
+                .await;
+            let file = file.and_then(|file| serde_json::from_value(file).ok());
+            if let (Some(file), Some((_, node))) = (file, self.nodes.last_mut()) {
+                // Loads in the background, the node announces the file when it is done
+                node.process_request(node::RequestKind::LoadFile(file), Box::new(|_| {}));
+            }
+        }
+        match self.deserialize(&state).await {
+            Ok(()) => respond(responder, ResponseKind::Ok),
+            Err(e) => respond(responder, ResponseKind::Failed { reason: e.into() }),
+        }
+    }
+
+    async fn process_set_global_transposition(&mut self, transposition: i8) {
+        self.set_global_transposition(transposition);
+        self.cache
+            .set_renderer_global_transposition(transposition)
+            .await;
+    }
+
+    async fn process_set_master_tuning(&mut self, a4_hz: f32, fine_tune_cents: f32) {
+        self.set_master_tuning(a4_hz, fine_tune_cents);
+        self.cache
+            .set_renderer_master_tuning(a4_hz, fine_tune_cents)
+            .await;
+    }
+
+    // Reference frequency of A4 with the fine tune applied
+    fn tuning_frequency(&self) -> f32 {
+        self.a4_hz * 2f32.powf(self.fine_tune_cents / 1200.0)
+    }
+
     fn set_user_preset(&mut self, preset: usize) {
         for (_, node) in &mut self.nodes {
             node.set_user_preset(preset);
         }
     }
 
-    fn process_node_request(&mut self, responder: Responder, id: usize, kind: node::RequestKind) {
-        if id >= self.nodes.len() {
-            respond(responder, ResponseKind::InvalidId);
+    fn process_list_parameters(&mut self, responder: Responder, id: usize) {
+        if let Some((_, node)) = self.nodes.get(id) {
+            respond(responder, ResponseKind::Parameters(node.parameters()));
         } else {
+            respond(responder, ResponseKind::InvalidId);
+        }
+    }
+
+    fn process_get_parameter(&mut self, responder: Responder, id: usize, parameter: String) {
+        match self.nodes.get(id) {
+            Some((_, node)) => match node.parameter(&parameter) {
+                Some(value) => respond(responder, ResponseKind::ParameterValue(value)),
+                None => respond(responder, ResponseKind::InvalidParameter),
+            },
+            None => respond(responder, ResponseKind::InvalidId),
+        }
+    }
+
+    fn process_set_parameter(
+        &mut self,
+        responder: Responder,
+        id: usize,
+        parameter: String,
+        value: parameter::Value,
+    ) {
+        let Some((_, node)) = self.nodes.get_mut(id) else {
+            respond(responder, ResponseKind::InvalidId);
+            return;
+        };
+        // Changes are announced through the regular node updates
+        let res = match parameter::set(node.as_mut(), &parameter, value) {
+            Ok(()) => ResponseKind::Ok,
+            Err(parameter::Error::UnknownParameter) => ResponseKind::InvalidParameter,
+            Err(parameter::Error::InvalidValue) => ResponseKind::InvalidValue,
+            Err(parameter::Error::Failed) => {
+                ResponseKind::failed(ErrorCode::InvalidValue, "The node rejected the value")
+            }
+        };
+        respond(responder, res);
+    }
+
+    fn process_node_request(&mut self, responder: Responder, id: usize, kind: node::RequestKind) {
+        if let Some((_, node)) = self.nodes.get_mut(id) {
             let cb = move |kind| respond(responder, ResponseKind::NodeResponse { id, kind });
-            self.nodes[id].1.process_request(kind, Box::new(cb));
+            node.process_request(kind, Box::new(cb));
+        } else {
+            respond(responder, ResponseKind::InvalidId);
         }
     }
 
@@ -234,17 +792,16 @@ impl Renderer {
         }
 
         let node: RenderPtr = self.registered_node_kinds[&kind]();
-        if let Ok(value) = node.serialize() {
-            self.add_node(kind.clone(), node);
-            self.cache.add_render_node(&kind, &value).await;
-            respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::AddNode {
-                id: self.nodes.len() - 1,
-                kind,
-                instance: value,
-            });
-        } else {
-            respond(responder, ResponseKind::Failed);
+        match node.serialize() {
+            Ok(value) => {
+                self.add_node(kind.clone(), node);
+                self.cache.add_render_node(&kind, &value).await;
+                respond(responder, ResponseKind::Ok);
+            }
+            Err(e) => respond(
+                responder,
+                ResponseKind::failed(ErrorCode::Serialization, e.0),
+            ),
         }
     }
 
@@ -252,34 +809,38 @@ impl Renderer {
         if id >= self.nodes.len() {
             respond(responder, ResponseKind::InvalidId);
         } else {
+            self.flush_voices();
             self.nodes.remove(id);
+            self.rt.remove_voice(id);
             self.cache.remove_render_node(id).await;
             respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::RemoveNode { id });
         }
     }
 
     async fn process_clone_node(&mut self, responder: Responder, id: usize) {
-        if id >= self.nodes.len() {
-            respond(responder, ResponseKind::InvalidId);
-        } else {
-            let node = &self.nodes[id];
-            self.add_node(node.0.clone(), node.1.clone_node());
+        let clone = self
+            .nodes
+            .get(id)
+            .map(|(kind, node)| (kind.clone(), node.clone_node()));
+        if let Some((kind, node)) = clone {
+            self.add_node(kind, node);
             self.cache.clone_render_node(id).await;
             respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::CloneNode { id });
+        } else {
+            respond(responder, ResponseKind::InvalidId);
         }
     }
 
     async fn process_move_node(&mut self, responder: Responder, id: usize, new_id: usize) {
-        if id >= self.nodes.len() || new_id >= self.nodes.len() {
-            respond(responder, ResponseKind::InvalidId);
-        } else {
+        if id < self.nodes.len() && new_id < self.nodes.len() {
+            self.flush_voices();
             let node = self.nodes.remove(id);
             self.nodes.insert(new_id, node);
+            self.rt.move_voice(id, new_id);
             self.cache.move_render_node(id, new_id).await;
             respond(responder, ResponseKind::Ok);
-            self.broadcast_update(UpdateKind::MoveNode { id, new_id });
+        } else {
+            respond(responder, ResponseKind::InvalidId);
         }
     }
 
@@ -289,8 +850,261 @@ impl Renderer {
     }
 }
 
+// Nodes whose json updates are caused by the request
+pub fn edited_nodes(kind: &RequestKind) -> Vec<usize> {
+    match kind {
+        RequestKind::NodeRequest { id, .. } | RequestKind::SetParameter(id, ..) => vec![*id],
+        _ => Vec::new(),
+    }
+}
+
+fn is_valid_tuning(a4_hz: f32, fine_tune_cents: f32) -> bool {
+    (MIN_A4_HZ..=MAX_A4_HZ).contains(&a4_hz) && fine_tune_cents.abs() <= MAX_FINE_TUNE_CENTS
+}
+
+fn is_valid_transposition(transposition: i8) -> bool {
+    (-MAX_TRANSPOSITION..=MAX_TRANSPOSITION).contains(&transposition)
+}
+
+fn preview_message(kind: midi::MessageKind) -> midi::Message {
+    midi::Message { kind, channel: 0 }
+}
+
+fn ignore() -> node::ResponseCallback {
+    Box::new(|_| {})
+}
+
 fn respond(responder: Responder, response_kind: ResponseKind) {
     if let Err(e) = responder.send(response_kind) {
         error!("Failed to send a response: {e:?}");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        json::{JsonFieldUpdate, SerializationResult},
+        midi::ControlChangeKind,
+        parameter::{Parameters, Value},
+        render::node::{
+            container::{self, Container},
+            ResponseCallback, VoiceTask,
+        },
+    };
+    use std::path::PathBuf;
+
+    // Answers the file loads on its next update, files called missing fail
+    #[derive(Default)]
+    struct Probe {
+        loading: Option<(PathBuf, ResponseCallback)>,
+    }
+
+    #[derive(Default)]
+    struct ProbeVoice {
+        sounding: bool,
+    }
+
+    impl Clone for Probe {
+        fn clone(&self) -> Self {
+            Self::default()
+        }
+    }
+
+    impl Parameters for Probe {
+        fn parameters(&self) -> Vec<Parameter> {
+            Vec::new()
+        }
+
+        fn parameter(&self, _id: &str) -> Option<Value> {
+            None
+        }
+
+        fn set_parameter(&mut self, _id: &str, _value: Value) -> parameter::SetResult {
+            Err(parameter::Error::UnknownParameter)
+        }
+    }
+
+    impl container::Engine for Probe {
+        const DEFAULT_NAME: &'static str = "Probe";
+        type Voice = ProbeVoice;
+
+        fn update(&mut self) {
+            if let Some((file, cb)) = self.loading.take() {
+                if file.ends_with("missing.sf2") {
+                    cb(node::ResponseKind::failed(
+                        ErrorCode::LoadFailed,
+                        "Not found",
+                    ));
+                } else {
+                    cb(node::ResponseKind::Ok);
+                }
+            }
+        }
+        fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+        fn set_sample_rate(&mut self, _sample_rate: u32) {}
+        fn process_request(&mut self, kind: node::RequestKind, cb: ResponseCallback) {
+            match kind {
+                node::RequestKind::LoadFile(file) => self.loading = Some((file, cb)),
+                _ => cb(node::ResponseKind::Denied),
+            }
+        }
+        fn serialize(&self) -> SerializationResult {
+            Ok(json!({}))
+        }
+        fn deserialize(&mut self, _source: &serde_json::Value) -> DeserializationResult {
+            Ok(())
+        }
+        fn take_json_updates(&mut self) -> Vec<JsonFieldUpdate> {
+            Vec::new()
+        }
+        fn take_voice_tasks(&mut self) -> Vec<VoiceTask> {
+            Vec::new()
+        }
+    }
+
+    impl container::EngineVoice for ProbeVoice {
+        fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) -> bool {
+            let value = if self.sounding { 0.5 } else { 0.0 };
+            lbuf.fill(value);
+            rbuf.fill(value);
+            true
+        }
+        fn reset(&mut self) {}
+        fn note_on(&mut self, _note: u8, _velocity: u8) {
+            self.sounding = true;
+        }
+        fn note_off(&mut self, _note: u8, _velocity: u8) {
+            self.sounding = false;
+        }
+        fn control_change(&mut self, _kind: ControlChangeKind, _value: u8) {}
+        fn pitch_wheel(&mut self, _value: u16) {}
+    }
+
+    fn start(file: &str) -> RequestKind {
+        RequestKind::StartPreview(PreviewParams {
+            file: PathBuf::from(file),
+            bank: 0,
+            preset: 0,
+            follow_keyboard: false,
+        })
+    }
+
+    #[tokio::test]
+    async fn preview() {
+        let clients = Clients::new(16);
+        let cache = Cache::new(clients.clone());
+        let (_midi_tx, midi_rx) = midi::create_channel(16);
+        let (req_tx, req_rx) = create_request_channel(4);
+        let (_ctr_tx, ctr_rx) = control::create_control_channel(4);
+        let (rt, mut core) = realtime::create(2);
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert("samples:".into(), std::env::temp_dir());
+        let mut renderer =
+            Renderer::new(midi_rx, req_rx, ctr_rx, rt, virtual_paths, clients, cache);
+        renderer.register_node_kind("Probe", || Box::<Container<Probe>>::default());
+        renderer.register_preview_kind("sf2", "Probe");
+        let request = |kind| {
+            let (res_tx, res_rx) = create_response_channel();
+            req_tx.try_send((kind, None, res_tx)).unwrap();
+            res_rx
+        };
+
+        // The phrase starts with a note as soon as the file is loaded
+        let mut res_rx = request(start("samples:/piano.sf2"));
+        renderer.update().await;
+        assert_eq!(res_rx.try_recv().ok(), Some(ResponseKind::Ok));
+        assert!(renderer.preview.as_ref().unwrap().state.loaded);
+        core.process_commands();
+        assert_eq!(core.render()[0][0], 0.5);
+
+        let mut res_rx = request(RequestKind::StopPreview);
+        renderer.update().await;
+        assert_eq!(res_rx.try_recv().ok(), Some(ResponseKind::Ok));
+        assert!(renderer.preview.is_none());
+        core.process_commands();
+        assert_eq!(core.render()[0][0], 0.0);
+
+        let mut res_rx = request(start("samples:/missing.sf2"));
+        renderer.update().await;
+        assert!(matches!(res_rx.try_recv(), Ok(ResponseKind::Failed { .. })));
+        assert!(renderer.preview.is_none());
+
+        let mut res_rx = request(start("piano.sf2"));
+        renderer.update().await;
+        assert!(matches!(res_rx.try_recv(), Ok(ResponseKind::Failed { .. })));
+    }
+
+    #[tokio::test]
+    async fn apply_scene() {
+        let mut clients = Clients::new(16);
+        let cache = Cache::new(clients.clone());
+        let (_midi_tx, midi_rx) = midi::create_channel(16);
+        let (req_tx, req_rx) = create_request_channel(4);
+        let (_ctr_tx, ctr_rx) = control::create_control_channel(4);
+        let (rt, _core) = realtime::create(2);
+        let mut renderer = Renderer::new(
+            midi_rx,
+            req_rx,
+            ctr_rx,
+            rt,
+            VirtualPaths::default(),
+            clients.clone(),
+            cache.clone(),
+        );
+        renderer.register_node_kind("Probe", || Box::<Container<Probe>>::default());
+        let request = |kind, author| {
+            let (res_tx, res_rx) = create_response_channel();
+            req_tx.try_send((kind, author, res_tx)).unwrap();
+            res_rx
+        };
+        for _ in 0..2 {
+            request(
+                RequestKind::AddNode {
+                    kind: "Probe".into(),
+                },
+                None,
+            );
+        }
+        renderer.update().await;
+        let uid = cache.to_json().await["render_nodes"][0]["uid"]
+            .as_u64()
+            .unwrap();
+        request(RequestKind::RemoveNode { id: 1 }, None);
+        request(RequestKind::CloneNode { id: 0 }, None);
+        request(RequestKind::MoveNode { id: 1, new_id: 0 }, None);
+        renderer.update().await;
+
+        // The state follows the node to its new index, removed nodes are skipped
+        let scene = |gain: f32| {
+            RequestKind::ApplyScene(
+                json!({ "global_transposition": 2 }),
+                vec![(uid, json!({ "gain": gain })), (uid + 1, json!({}))],
+            )
+        };
+        let mut res_rx = request(scene(0.25), None);
+        renderer.update().await;
+        assert_eq!(res_rx.try_recv().ok(), Some(ResponseKind::Ok));
+        let state = cache.to_json().await;
+        assert_eq!(state["render_nodes"][1]["instance"]["gain"], 0.25);
+        assert_eq!(state["render_nodes"][0]["instance"]["gain"], 1.0);
+        assert_eq!(state["renderer"]["global_transposition"], 2);
+
+        // A node locked by another client leaves the whole scene out
+        clients.lock(2, Node::RenderNode(1)).await.unwrap();
+        let mut res_rx = request(scene(0.5), Some(1));
+        renderer.update().await;
+        assert!(matches!(res_rx.try_recv(), Ok(ResponseKind::Failed { .. })));
+        assert_eq!(renderer.global_transposition, 2);
+        let mut res_rx = request(scene(0.5), Some(2));
+        renderer.update().await;
+        assert_eq!(res_rx.try_recv().ok(), Some(ResponseKind::Ok));
+        let state = cache.to_json().await;
+        assert_eq!(state["render_nodes"][1]["instance"]["gain"], 0.5);
+
+        let mut res_rx = request(RequestKind::SetGlobalTransposition(49), None);
+        renderer.update().await;
+        assert!(matches!(res_rx.try_recv(), Ok(ResponseKind::Failed { .. })));
+        assert_eq!(renderer.global_transposition, 2);
+    }
+}
@@ -1,24 +1,59 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+const TABLE_SIZE: usize = 128;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "VelocityMapKind")]
 pub enum Kind {
     Identity,
     Linear { min: u8, max: u8 },
+    Exponential,
+    Logarithmic,
+    SCurve,
+    // output velocity for every input velocity
+    Table(Vec<u8>),
+    Fixed(u8),
 }
 
-pub fn map(kind: Kind, velocity: u8) -> u8 {
+pub fn is_valid(kind: &Kind) -> bool {
     match kind {
-        Kind::Identity => velocity,
-        Kind::Linear { min, max } => map_linear(velocity, min, max),
+        Kind::Linear { min, max } => min <= max && *max <= 127,
+        Kind::Table(table) => table.len() == TABLE_SIZE && table.iter().all(|v| *v <= 127),
+        Kind::Fixed(velocity) => (1..=127).contains(velocity),
+        _ => true,
+    }
+}
+
+pub fn map(kind: &Kind, velocity: u8) -> u8 {
+    // zero velocity means note off and it has to stay that way
+    if velocity == 0 {
+        return 0;
     }
+    let result = match kind {
+        Kind::Identity => velocity,
+        Kind::Linear { min, max } => map_linear(velocity, *min, *max),
+        Kind::Exponential => map_curve(velocity, |x| (3.0 * x).exp_m1() / 3f32.exp_m1()),
+        Kind::Logarithmic => map_curve(velocity, |x| (19.0 * x).ln_1p() / 19f32.ln_1p()),
+        Kind::SCurve => map_curve(velocity, |x| x * x * (3.0 - 2.0 * x)),
+        Kind::Table(table) => table.get(velocity as usize).copied().unwrap_or(velocity),
+        Kind::Fixed(fixed) => *fixed,
+    };
+    result.clamp(1, 127)
 }
 
 fn map_linear(velocity: u8, min: u8, max: u8) -> u8 {
     (velocity as f32 / 127.0 * (max - min) as f32).round() as u8 + min
 }
 
+fn map_curve(velocity: u8, curve: impl Fn(f32) -> f32) -> u8 {
+    (curve(velocity as f32 / 127.0) * 127.0).round() as u8
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn map_linear() {
         assert_eq!(super::map_linear(0, 0, 1), 0);
@@ -28,4 +63,28 @@ mod tests {
         assert_eq!(super::map_linear(90, 0, 3), 2);
         assert_eq!(super::map_linear(90, 1, 3), 2);
     }
+
+    #[test]
+    fn map_curves() {
+        for kind in [Kind::Exponential, Kind::Logarithmic, Kind::SCurve] {
+            assert_eq!(map(&kind, 0), 0);
+            assert!(map(&kind, 1) >= 1);
+            assert_eq!(map(&kind, 127), 127);
+        }
+        assert!(map(&Kind::Exponential, 64) < 64);
+        assert!(map(&Kind::Logarithmic, 64) > 64);
+        assert!(map(&Kind::SCurve, 32) < 32);
+        assert!(map(&Kind::SCurve, 96) > 96);
+    }
+
+    #[test]
+    fn map_table_and_fixed() {
+        let table = Kind::Table((0..128).map(|v| 127 - v as u8).collect());
+        assert!(is_valid(&table));
+        assert_eq!(map(&table, 27), 100);
+        assert_eq!(map(&table, 127), 1);
+        assert!(!is_valid(&Kind::Table(vec![0; 12])));
+        assert_eq!(map(&Kind::Fixed(90), 12), 90);
+        assert_eq!(map(&Kind::Fixed(90), 0), 0);
+    }
 }
@@ -0,0 +1,263 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const MAX_NUM_ZONES: usize = 16;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "ZoneUpdateKind")]
+pub enum UpdateKind {
+    Add(Zone),
+    Remove(usize),
+    Clear,
+    SetKeyRange(usize, u8, u8),
+    SetVelocityRange(usize, u8, u8),
+    SetTransposition(usize, i8),
+    SetCrossfade(usize, u8),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct Zone {
+    pub key_min: u8,
+    pub key_max: u8,
+    pub velocity_min: u8,
+    pub velocity_max: u8,
+    pub transposition: i8,
+    // number of keys at both ends of the key range over which the velocity fades out
+    pub crossfade: u8,
+}
+
+impl Zone {
+    fn is_valid(&self) -> bool {
+        self.key_min <= self.key_max
+            && self.key_max <= 127
+            && self.velocity_min <= self.velocity_max
+            && self.velocity_max <= 127
+    }
+
+    fn contains_key(&self, note: u8) -> bool {
+        (self.key_min..=self.key_max).contains(&note)
+    }
+
+    fn contains(&self, note: u8, velocity: u8) -> bool {
+        self.contains_key(note) && (self.velocity_min..=self.velocity_max).contains(&velocity)
+    }
+
+    fn transpose(&self, note: u8) -> Option<u8> {
+        let note = note as i16 + self.transposition as i16;
+        (0..=127).contains(&note).then_some(note as u8)
+    }
+
+    fn crossfade_gain(&self, note: u8) -> f32 {
+        if self.crossfade == 0 {
+            return 1.0;
+        }
+        // ends of the whole keyboard are never faded
+        let from_min = if self.key_min == 0 {
+            u8::MAX
+        } else {
+            note - self.key_min
+        };
+        let from_max = if self.key_max == 127 {
+            u8::MAX
+        } else {
+            self.key_max - note
+        };
+        let distance = from_min.min(from_max);
+        if distance >= self.crossfade {
+            1.0
+        } else {
+            (distance as f32 + 1.0) / (self.crossfade as f32 + 1.0)
+        }
+    }
+}
+
+impl Default for Zone {
+    fn default() -> Self {
+        Self {
+            key_min: 0,
+            key_max: 127,
+            velocity_min: 0,
+            velocity_max: 127,
+            transposition: 0,
+            crossfade: 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Zones {
+    list: Vec<Zone>,
+    // notes which were sent for each of the keys being held
+    active: Vec<(u8, Vec<u8>)>,
+}
+
+impl Zones {
+    pub fn list(&self) -> &Vec<Zone> {
+        &self.list
+    }
+
+    pub fn set_list(&mut self, list: Vec<Zone>) {
+        self.list = list
+            .into_iter()
+            .filter(Zone::is_valid)
+            .take(MAX_NUM_ZONES)
+            .collect();
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: u8) -> Vec<(u8, u8)> {
+        let mut result = vec![];
+        for zone in self.list.iter().filter(|z| z.contains(note, velocity)) {
+            if let Some(n) = zone.transpose(note) {
+                let v = (velocity as f32 * zone.crossfade_gain(note)).round() as u8;
+                result.push((n, v.max(1)));
+            }
+        }
+        self.active.retain(|(n, _)| *n != note);
+        self.active
+            .push((note, result.iter().map(|(n, _)| *n).collect()));
+        result
+    }
+
+    pub fn note_off(&mut self, note: u8) -> Vec<u8> {
+        if let Some(index) = self.active.iter().position(|(n, _)| *n == note) {
+            self.active.swap_remove(index).1
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.active.clear();
+    }
+}
+
+impl Default for Zones {
+    fn default() -> Self {
+        Self {
+            list: vec![Zone::default()],
+            active: vec![],
+        }
+    }
+}
+
+impl Clone for Zones {
+    fn clone(&self) -> Self {
+        Self {
+            list: self.list.clone(),
+            active: vec![],
+        }
+    }
+}
+
+pub struct InvalidUpdateRequest;
+pub type UpdateResult = Result<(), InvalidUpdateRequest>;
+
+pub trait ZoneUser {
+    fn zones_mut(&mut self) -> &mut Zones;
+
+    fn process_update_request(&mut self, kind: UpdateKind) -> UpdateResult {
+        let zones = &mut self.zones_mut().list;
+        match kind {
+            UpdateKind::Add(zone) => {
+                if !zone.is_valid() || zones.len() >= MAX_NUM_ZONES {
+                    return Err(InvalidUpdateRequest);
+                }
+                zones.push(zone);
+            }
+            UpdateKind::Remove(index) => {
+                if index >= zones.len() {
+                    return Err(InvalidUpdateRequest);
+                }
+                zones.remove(index);
+            }
+            UpdateKind::Clear => zones.clear(),
+            UpdateKind::SetKeyRange(i, min, max) => ur_update(zones, i, |z| {
+                z.key_min = min;
+                z.key_max = max;
+            })?,
+            UpdateKind::SetVelocityRange(i, min, max) => ur_update(zones, i, |z| {
+                z.velocity_min = min;
+                z.velocity_max = max;
+            })?,
+            UpdateKind::SetTransposition(i, tr) => ur_update(zones, i, |z| z.transposition = tr)?,
+            UpdateKind::SetCrossfade(i, cf) => ur_update(zones, i, |z| z.crossfade = cf)?,
+        }
+        Ok(())
+    }
+}
+
+fn ur_update(zones: &mut [Zone], index: usize, f: impl FnOnce(&mut Zone)) -> UpdateResult {
+    if let Some(zone) = zones.get_mut(index) {
+        let mut updated = *zone;
+        f(&mut updated);
+        if updated.is_valid() {
+            *zone = updated;
+            return Ok(());
+        }
+    }
+    Err(InvalidUpdateRequest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split() -> Zones {
+        let mut zones = Zones::default();
+        zones.set_list(vec![
+            Zone {
+                key_max: 59,
+                transposition: 12,
+                ..Default::default()
+            },
+            Zone {
+                key_min: 56,
+                velocity_min: 64,
+                crossfade: 4,
+                ..Default::default()
+            },
+        ]);
+        zones
+    }
+
+    #[test]
+    fn split_and_layer() {
+        let mut zones = split();
+        assert_eq!(zones.note_on(40, 100), vec![(52, 100)]);
+        assert!(zones.note_on(70, 30).is_empty());
+        assert_eq!(zones.note_on(70, 100), vec![(70, 100)]);
+        // both zones overlap here, the upper one fades in
+        assert_eq!(zones.note_on(57, 100), vec![(69, 100), (57, 40)]);
+        assert_eq!(zones.note_off(57), vec![69, 57]);
+        assert!(zones.note_off(57).is_empty());
+    }
+
+    #[test]
+    fn note_off_after_zone_change() {
+        let mut zones = split();
+        zones.note_on(40, 100);
+        zones.set_list(vec![]);
+        assert_eq!(zones.note_off(40), vec![52]);
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        struct User(Zones);
+        impl ZoneUser for User {
+            fn zones_mut(&mut self) -> &mut Zones {
+                &mut self.0
+            }
+        }
+        let mut user = User(Zones::default());
+        assert!(user
+            .process_update_request(UpdateKind::SetKeyRange(0, 60, 50))
+            .is_err());
+        assert!(user
+            .process_update_request(UpdateKind::SetKeyRange(1, 0, 50))
+            .is_err());
+        assert!(user
+            .process_update_request(UpdateKind::SetKeyRange(0, 0, 50))
+            .is_ok());
+        assert_eq!(user.0.list()[0].key_max, 50);
+    }
+}
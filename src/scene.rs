@@ -0,0 +1,703 @@
+use crate::{
+    control::controller,
+    error::{Error, ErrorCode},
+    json::expect_serialize,
+    midi,
+    path::VirtualPaths,
+    webserver::{cache::NodeUid, presence::ClientId, Cache, Clients, ServerMessageKind},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tokio::sync::{broadcast::error::RecvError, mpsc, oneshot};
+use tracing::error;
+
+pub type Requester = mpsc::Sender<(RequestKind, Option<ClientId>, Responder)>;
+pub type RequestListener = mpsc::Receiver<(RequestKind, Option<ClientId>, Responder)>;
+pub type Responder = oneshot::Sender<ResponseKind>;
+pub type ResponseListener = oneshot::Receiver<ResponseKind>;
+
+pub fn create_request_channel(buffer: usize) -> (Requester, RequestListener) {
+    mpsc::channel(buffer)
+}
+
+pub fn create_response_channel() -> (Responder, ResponseListener) {
+    oneshot::channel()
+}
+
+pub async fn send_request(req_tx: &Requester, req: RequestKind) -> Option<ResponseKind> {
+    send_request_from(req_tx, None, req).await
+}
+
+// The changes made by the request are attributed to the client
+pub async fn send_request_from(
+    req_tx: &Requester,
+    author: Option<ClientId>,
+    req: RequestKind,
+) -> Option<ResponseKind> {
+    let (res_tx, res_rx) = create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, author, res_tx)).await {
+        res_rx.await.ok()
+    } else {
+        None
+    }
+}
+
+pub const NUM_SCENES: usize = 128;
+
+// Fields which describe the setup or the live state rather than the sound
+const NON_SCENE_FIELDS: &[&str] = &[
+    "name",
+    "loaded_file",
+    "preset_map",
+    "user_presets",
+    "global_transposition",
+    "cc",
+    "pitch_wheel",
+    "take",
+    "state",
+];
+
+const NON_SCENE_CONTROLLER_FIELDS: &[&str] = &["enabled", "automation"];
+
+// Footswitch values from this threshold up count as pressed
+const FOOTSWITCH_THRESHOLD: u8 = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "SceneTarget")]
+pub enum Target {
+    RenderNode(NodeUid),
+    ControlNode(NodeUid),
+    Controller,
+}
+
+// The node states are keyed by the uids of the cache, so they stay with their
+// nodes when other nodes are moved or removed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Scene {
+    pub name: String,
+    pub render_nodes: Vec<(NodeUid, serde_json::Value)>,
+    pub control_nodes: Vec<(NodeUid, serde_json::Value)>,
+    pub controller: serde_json::Value,
+    // Only kept by the songs of a setlist
+    #[serde(default)]
+    pub renderer: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "SceneRequestKind")]
+pub enum RequestKind {
+    Load(PathBuf),
+    Save(PathBuf),
+    Capture {
+        scene: usize,
+        name: String,
+    },
+    Recall(usize),
+    Clear(usize),
+    SetSceneSafe {
+        target: Target,
+        field: String,
+        flag: bool,
+    },
+    SetProgramChangeRecall(bool),
+    SetFootswitch(Option<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "SceneResponseKind")]
+pub enum ResponseKind {
+    InvalidId,
+    Empty,
+    Failed { reason: Error },
+    Ok,
+}
+
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "SceneUpdateKind")]
+pub enum UpdateKind {
+    Scenes(Vec<Option<Scene>>),
+    Scene { id: usize, scene: Option<Scene> },
+    Current(Option<usize>),
+    SceneSafe(Vec<(Target, String)>),
+    ProgramChangeRecall(bool),
+    Footswitch(Option<u8>),
+}
+
+// Content of a scene file, everything but the current scene
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SceneBank {
+    scenes: Vec<Option<Scene>>,
+    scene_safe: Vec<(Target, String)>,
+    program_change_recall: bool,
+    footswitch: Option<u8>,
+}
+
+pub struct SceneManager {
+    scenes: Vec<Option<Scene>>,
+    current: Option<usize>,
+    scene_safe: Vec<(Target, String)>,
+    program_change_recall: bool,
+    footswitch: Option<u8>,
+    footswitch_pressed: bool,
+    midi_rx: midi::Receiver,
+    req_rx: RequestListener,
+    ctr_req_tx: controller::Requester,
+    virtual_paths: VirtualPaths,
+    clients: Clients,
+    cache: Cache,
+}
+
+impl SceneManager {
+    pub fn new(
+        midi_rx: midi::Receiver,
+        req_rx: RequestListener,
+        ctr_req_tx: controller::Requester,
+        virtual_paths: VirtualPaths,
+        clients: Clients,
+        cache: Cache,
+    ) -> Self {
+        Self {
+            scenes: vec![None; NUM_SCENES],
+            current: None,
+            scene_safe: Default::default(),
+            program_change_recall: true,
+            footswitch: None,
+            footswitch_pressed: false,
+            midi_rx,
+            req_rx,
+            ctr_req_tx,
+            virtual_paths,
+            clients,
+            cache,
+        }
+    }
+
+    pub fn serialize(&self) -> serde_json::Value {
+        json!({
+            "scenes": expect_serialize(&self.scenes),
+            "current": expect_serialize(self.current),
+            "scene_safe": expect_serialize(&self.scene_safe),
+            "program_change_recall": expect_serialize(self.program_change_recall),
+            "footswitch": expect_serialize(self.footswitch),
+        })
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                req = self.req_rx.recv() => match req {
+                    Some((kind, author, responder)) => {
+                        self.cache.set_author(author);
+                        self.process_request(kind, responder).await;
+                        self.cache.set_author(None);
+                    }
+                    None => break,
+                },
+                msg = self.midi_rx.recv() => match msg {
+                    Ok(msg) => self.process_midi_message(&msg).await,
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+
+    async fn process_request(&mut self, kind: RequestKind, responder: Responder) {
+        match kind {
+            RequestKind::Load(path) => {
+                let res = self.load(&path).await;
+                respond(responder, res);
+            }
+            RequestKind::Save(path) => {
+                let res = self.save(&path).await;
+                respond(responder, res);
+            }
+            RequestKind::Capture { scene, name } => {
+                let res = self.capture(scene, name).await;
+                respond(responder, res);
+            }
+            RequestKind::Recall(scene) => {
+                let res = self.recall(scene).await;
+                respond(responder, res);
+            }
+            RequestKind::Clear(scene) => {
+                let res = self.clear(scene).await;
+                respond(responder, res);
+            }
+            RequestKind::SetSceneSafe {
+                target,
+                field,
+                flag,
+            } => {
+                respond(responder, ResponseKind::Ok);
+                self.set_scene_safe(target, field, flag).await;
+            }
+            RequestKind::SetProgramChangeRecall(flag) => {
+                respond(responder, ResponseKind::Ok);
+                self.program_change_recall = flag;
+                self.update(UpdateKind::ProgramChangeRecall(flag)).await;
+            }
+            RequestKind::SetFootswitch(cc) => {
+                if cc.is_none_or(|cc| cc <= 127) {
+                    respond(responder, ResponseKind::Ok);
+                    self.footswitch = cc;
+                    self.footswitch_pressed = false;
+                    self.update(UpdateKind::Footswitch(cc)).await;
+                } else {
+                    let reason = "Invalid control change number";
+                    respond(
+                        responder,
+                        ResponseKind::failed(ErrorCode::InvalidValue, reason),
+                    );
+                }
+            }
+        }
+    }
+
+    async fn process_midi_message(&mut self, message: &midi::Message) {
+        use midi::MessageKind as Kind;
+        match message.kind {
+            Kind::ProgramChange { program }
+                if self.program_change_recall && self.has_scene(program as usize) =>
+            {
+                // The setlist takes the program changes while it selects its
+                // songs with them
+                let setlist_select = self.cache.get("/setlist/program_change_select").await;
+                if setlist_select != true {
+                    self.recall(program as usize).await;
+                }
+            }
+            Kind::ControlChange { kind, value } if Some(kind.as_number()) == self.footswitch => {
+                let pressed = value >= FOOTSWITCH_THRESHOLD;
+                if pressed && !self.footswitch_pressed {
+                    if let Some(next) = self.next_scene() {
+                        self.recall(next).await;
+                    }
+                }
+                self.footswitch_pressed = pressed;
+            }
+            _ => {}
+        }
+    }
+
+    fn has_scene(&self, id: usize) -> bool {
+        self.scenes.get(id).is_some_and(Option::is_some)
+    }
+
+    // First stored scene after the current one, wrapping around
+    fn next_scene(&self) -> Option<usize> {
+        let start = self.current.map_or(0, |c| c + 1);
+        (0..NUM_SCENES)
+            .map(|i| (start + i) % NUM_SCENES)
+            .find(|&i| self.scenes[i].is_some())
+    }
+
+    async fn load(&mut self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let content = match tokio::fs::read_to_string(file).await {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::Failed { reason: e.into() },
+        };
+        let bank: SceneBank = match serde_json::from_str(&content) {
+            Ok(bank) => bank,
+            Err(e) => return ResponseKind::failed(ErrorCode::Deserialization, e.to_string()),
+        };
+        if bank.footswitch.is_some_and(|cc| cc > 127) {
+            let reason = "Invalid control change number";
+            return ResponseKind::failed(ErrorCode::Deserialization, reason);
+        }
+
+        self.scenes = bank.scenes;
+        self.scenes.resize(NUM_SCENES, None);
+        self.scene_safe = bank.scene_safe;
+        self.program_change_recall = bank.program_change_recall;
+        self.footswitch = bank.footswitch;
+        self.footswitch_pressed = false;
+        self.current = None;
+        self.update(UpdateKind::Scenes(self.scenes.clone())).await;
+        self.update(UpdateKind::SceneSafe(self.scene_safe.clone()))
+            .await;
+        self.update(UpdateKind::ProgramChangeRecall(self.program_change_recall))
+            .await;
+        self.update(UpdateKind::Footswitch(self.footswitch)).await;
+        self.update(UpdateKind::Current(None)).await;
+        ResponseKind::Ok
+    }
+
+    async fn save(&self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let bank = SceneBank {
+            scenes: self.scenes.clone(),
+            scene_safe: self.scene_safe.clone(),
+            program_change_recall: self.program_change_recall,
+            footswitch: self.footswitch,
+        };
+        let content = match serde_json::to_string_pretty(&bank) {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::failed(ErrorCode::Serialization, e.to_string()),
+        };
+        match tokio::fs::write(file, content).await {
+            Ok(()) => ResponseKind::Ok,
+            Err(e) => ResponseKind::Failed { reason: e.into() },
+        }
+    }
+
+    async fn capture(&mut self, id: usize, name: String) -> ResponseKind {
+        if id >= NUM_SCENES {
+            return ResponseKind::InvalidId;
+        }
+
+        let cache = self.cache.to_json().await;
+        let scene = Scene {
+            name,
+            render_nodes: node_states(&cache["render_nodes"], |uid, state| {
+                self.scene_state(Target::RenderNode(uid), state)
+            }),
+            control_nodes: node_states(&cache["control_nodes"], |uid, state| {
+                self.scene_state(Target::ControlNode(uid), state)
+            }),
+            controller: self.scene_state(Target::Controller, &cache["controller"]),
+            renderer: serde_json::Value::Null,
+        };
+
+        self.scenes[id] = Some(scene.clone());
+        self.update(UpdateKind::Scene {
+            id,
+            scene: Some(scene),
+        })
+        .await;
+        ResponseKind::Ok
+    }
+
+    async fn recall(&mut self, id: usize) -> ResponseKind {
+        let Some(slot) = self.scenes.get(id) else {
+            return ResponseKind::InvalidId;
+        };
+        let Some(scene) = slot else {
+            return ResponseKind::Empty;
+        };
+
+        // Fields marked as scene safe after capturing stay untouched as well
+        let scene = Scene {
+            name: scene.name.clone(),
+            render_nodes: scene
+                .render_nodes
+                .iter()
+                .map(|(uid, state)| (*uid, self.scene_state(Target::RenderNode(*uid), state)))
+                .collect(),
+            control_nodes: scene
+                .control_nodes
+                .iter()
+                .map(|(uid, state)| (*uid, self.scene_state(Target::ControlNode(*uid), state)))
+                .collect(),
+            controller: self.scene_state(Target::Controller, &scene.controller),
+            renderer: serde_json::Value::Null,
+        };
+
+        let res = controller::send_request_from(
+            &self.ctr_req_tx,
+            self.cache.author(),
+            controller::RequestKind::ApplyScene(scene),
+        )
+        .await;
+        if let Err(reason) = apply_result(res) {
+            return ResponseKind::Failed { reason };
+        }
+
+        self.current = Some(id);
+        self.update(UpdateKind::Current(self.current)).await;
+        ResponseKind::Ok
+    }
+
+    async fn clear(&mut self, id: usize) -> ResponseKind {
+        if id >= NUM_SCENES {
+            return ResponseKind::InvalidId;
+        }
+        self.scenes[id] = None;
+        if self.current == Some(id) {
+            self.current = None;
+            self.update(UpdateKind::Current(None)).await;
+        }
+        self.update(UpdateKind::Scene { id, scene: None }).await;
+        ResponseKind::Ok
+    }
+
+    async fn set_scene_safe(&mut self, target: Target, field: String, flag: bool) {
+        let entry = (target, field);
+        let index = self.scene_safe.iter().position(|e| *e == entry);
+        match (index, flag) {
+            (None, true) => self.scene_safe.push(entry),
+            (Some(index), false) => {
+                self.scene_safe.remove(index);
+            }
+            _ => {}
+        }
+        self.update(UpdateKind::SceneSafe(self.scene_safe.clone()))
+            .await;
+    }
+
+    fn scene_state(&self, target: Target, state: &serde_json::Value) -> serde_json::Value {
+        let non_scene_fields = match target {
+            Target::Controller => NON_SCENE_CONTROLLER_FIELDS,
+            _ => NON_SCENE_FIELDS,
+        };
+        filter_fields(state, |field| {
+            non_scene_fields.contains(&field)
+                || self
+                    .scene_safe
+                    .iter()
+                    .any(|(t, f)| *t == target && f == field)
+        })
+    }
+
+    async fn update(&mut self, kind: UpdateKind) {
+        self.cache.set_scenes(self.serialize()).await;
+        self.clients.broadcast(ServerMessageKind::SceneUpdate(kind));
+    }
+}
+
+pub(crate) fn filter_fields(
+    state: &serde_json::Value,
+    excluded: impl Fn(&str) -> bool,
+) -> serde_json::Value {
+    match state.as_object() {
+        Some(fields) => fields
+            .iter()
+            .filter(|(k, _)| !excluded(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        None => json!({}),
+    }
+}
+
+// The (uid, state) pairs of the cached nodes
+pub(crate) fn node_states(
+    nodes: &serde_json::Value,
+    state: impl Fn(NodeUid, &serde_json::Value) -> serde_json::Value,
+) -> Vec<(NodeUid, serde_json::Value)> {
+    nodes
+        .as_array()
+        .map(|nodes| {
+            nodes
+                .iter()
+                .filter_map(|n| Some((n["uid"].as_u64()?, &n["instance"])))
+                .map(|(uid, instance)| (uid, state(uid, instance)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Outcome of the ApplyScene request
+pub fn apply_result(res: Option<controller::ResponseKind>) -> Result<(), Error> {
+    match res {
+        Some(controller::ResponseKind::Ok) => Ok(()),
+        Some(controller::ResponseKind::Failed { reason }) => Err(reason),
+        None => Err(Error::new(
+            ErrorCode::Unavailable,
+            "The controller did not respond",
+        )),
+        _ => Err(Error::new(
+            ErrorCode::InvalidState,
+            "The scene does not match the current nodes",
+        )),
+    }
+}
+
+fn respond(responder: Responder, response_kind: ResponseKind) {
+    if let Err(e) = responder.send(response_kind) {
+        error!("Failed to send a response: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        control::{self, controller::Controller, node::looper},
+        parameter::Value,
+        render::{
+            node::rusty_synth,
+            realtime,
+            renderer::{self, Renderer},
+        },
+    };
+    use std::time::Duration;
+
+    // Renderer and controller running as in main, the scene manager is driven by the test
+    fn start(dir: &Path) -> (SceneManager, renderer::Requester, controller::Requester) {
+        let clients = Clients::new(256);
+        let cache = Cache::new(clients.clone());
+        let (midi_tx, _) = midi::create_channel(16);
+        let (rnd_req_tx, rnd_req_rx) = renderer::create_request_channel(16);
+        let (ctr_req_tx, ctr_req_rx) = controller::create_request_channel(16);
+        let (_scn_req_tx, scn_req_rx) = create_request_channel(16);
+        let (ctr_tx, ctr_rx) = control::create_control_channel(16);
+        let (rt, _core) = realtime::create(2);
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert("samples:".into(), dir.to_owned());
+
+        let mut renderer = Renderer::new(
+            midi_tx.subscribe(),
+            rnd_req_rx,
+            ctr_rx,
+            rt,
+            virtual_paths.clone(),
+            clients.clone(),
+            cache.clone(),
+        );
+        renderer.register_node_kind("RustySynth", || Box::<rusty_synth::Node>::default());
+        tokio::spawn(async move {
+            loop {
+                renderer.update().await;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+        let mut controller = Controller::new(
+            midi_tx.subscribe(),
+            ctr_req_rx,
+            ctr_tx,
+            rnd_req_tx.clone(),
+            virtual_paths.clone(),
+            clients.clone(),
+            cache.clone(),
+        );
+        controller.register_node_kind("Looper", || Box::<looper::Node>::default());
+        tokio::spawn(async move {
+            loop {
+                controller.update().await;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+        let scenes = SceneManager::new(
+            midi_tx.subscribe(),
+            scn_req_rx,
+            ctr_req_tx.clone(),
+            virtual_paths,
+            clients,
+            cache,
+        );
+        (scenes, rnd_req_tx, ctr_req_tx)
+    }
+
+    async fn render_request(req_tx: &renderer::Requester, req: renderer::RequestKind) {
+        let res = renderer::send_request(req_tx, req).await;
+        assert_eq!(res, Some(renderer::ResponseKind::Ok));
+    }
+
+    async fn control_request(req_tx: &controller::Requester, req: controller::RequestKind) {
+        let res = controller::send_request(req_tx, req).await;
+        assert_eq!(res, Some(controller::ResponseKind::Ok));
+    }
+
+    async fn set_gain(req_tx: &renderer::Requester, id: usize, gain: f32) {
+        let req = renderer::RequestKind::SetParameter(id, "gain".into(), Value::Float(gain));
+        render_request(req_tx, req).await;
+        // The cache follows with the next json updates of the renderer
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    fn uids(nodes: &serde_json::Value) -> Vec<u64> {
+        super::node_states(nodes, |_, _| json!({}))
+            .into_iter()
+            .map(|(uid, _)| uid)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recall_after_restart() {
+        let dir = std::env::temp_dir().join(format!("ami-session-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let add_synth = || renderer::RequestKind::AddNode {
+            kind: "RustySynth".into(),
+        };
+
+        let (mut scenes, rnd_req_tx, ctr_req_tx) = start(&dir);
+        for _ in 0..3 {
+            render_request(&rnd_req_tx, add_synth()).await;
+        }
+        render_request(&rnd_req_tx, renderer::RequestKind::RemoveNode { id: 0 }).await;
+        let add_looper = controller::RequestKind::AddNode {
+            kind: "Looper".into(),
+        };
+        control_request(&ctr_req_tx, add_looper).await;
+        set_gain(&rnd_req_tx, 1, 0.25).await;
+        let cache = scenes.cache.to_json().await;
+        assert_eq!(uids(&cache["render_nodes"]), vec![2, 3]);
+        assert_eq!(uids(&cache["control_nodes"]), vec![4]);
+        assert_eq!(scenes.capture(0, "Verse".into()).await, ResponseKind::Ok);
+        assert_eq!(
+            scenes.save(Path::new("samples:/scenes.json")).await,
+            ResponseKind::Ok
+        );
+        let save = controller::RequestKind::SaveSession("samples:/session.json".into());
+        control_request(&ctr_req_tx, save).await;
+
+        // A node added before the session is loaded is replaced by it
+        let (mut scenes, rnd_req_tx, ctr_req_tx) = start(&dir);
+        render_request(&rnd_req_tx, add_synth()).await;
+        let load = controller::RequestKind::LoadSession("samples:/session.json".into());
+        control_request(&ctr_req_tx, load).await;
+        render_request(&rnd_req_tx, add_synth()).await;
+        let cache = scenes.cache.to_json().await;
+        assert_eq!(uids(&cache["render_nodes"]), vec![2, 3, 5]);
+        assert_eq!(uids(&cache["control_nodes"]), vec![4]);
+        assert_eq!(cache["render_nodes"][1]["instance"]["gain"], 0.25);
+
+        set_gain(&rnd_req_tx, 1, 1.0).await;
+        assert_eq!(
+            scenes.load(Path::new("samples:/scenes.json")).await,
+            ResponseKind::Ok
+        );
+        assert_eq!(scenes.recall(0).await, ResponseKind::Ok);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let cache = scenes.cache.to_json().await;
+        assert_eq!(cache["render_nodes"][1]["instance"]["gain"], 0.25);
+        assert_eq!(cache["render_nodes"][2]["instance"]["gain"], 1.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn filter_fields() {
+        let state = json!({ "gain": 0.5, "name": "Piano", "bank": 0 });
+        let filtered = super::filter_fields(&state, |f| NON_SCENE_FIELDS.contains(&f));
+        assert_eq!(filtered, json!({ "gain": 0.5, "bank": 0 }));
+        let filtered = super::filter_fields(&state, |f| f != "gain");
+        assert_eq!(filtered, json!({ "gain": 0.5 }));
+        assert_eq!(super::filter_fields(&json!(null), |_| false), json!({}));
+    }
+
+    #[test]
+    fn node_states() {
+        let nodes = json!([
+            { "kind": "OxiSynth", "uid": 4, "instance": { "gain": 0.5 } },
+            { "kind": "OxiSynth", "instance": { "gain": 1.0 } },
+            { "kind": "Looper", "uid": 2, "instance": { "name": "Loop" } },
+        ]);
+        let states = super::node_states(&nodes, |_, state| state.clone());
+        assert_eq!(
+            states,
+            vec![(4, json!({ "gain": 0.5 })), (2, json!({ "name": "Loop" }))]
+        );
+        assert!(super::node_states(&json!(null), |_, s| s.clone()).is_empty());
+    }
+}
@@ -0,0 +1,571 @@
+use crate::{
+    control::controller,
+    error::{Error, ErrorCode},
+    json::expect_serialize,
+    midi,
+    path::VirtualPaths,
+    render::{node, renderer},
+    scene::{self, Scene},
+    webserver::{cache::NodeUid, presence::ClientId, Cache, Clients, ServerMessageKind},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+};
+use tokio::sync::{broadcast::error::RecvError, mpsc, oneshot};
+use tracing::error;
+
+pub type Requester = mpsc::Sender<(RequestKind, Option<ClientId>, Responder)>;
+pub type RequestListener = mpsc::Receiver<(RequestKind, Option<ClientId>, Responder)>;
+pub type Responder = oneshot::Sender<ResponseKind>;
+pub type ResponseListener = oneshot::Receiver<ResponseKind>;
+
+pub fn create_request_channel(buffer: usize) -> (Requester, RequestListener) {
+    mpsc::channel(buffer)
+}
+
+pub fn create_response_channel() -> (Responder, ResponseListener) {
+    oneshot::channel()
+}
+
+pub async fn send_request(req_tx: &Requester, req: RequestKind) -> Option<ResponseKind> {
+    send_request_from(req_tx, None, req).await
+}
+
+// The changes made by the request are attributed to the client
+pub async fn send_request_from(
+    req_tx: &Requester,
+    author: Option<ClientId>,
+    req: RequestKind,
+) -> Option<ResponseKind> {
+    let (res_tx, res_rx) = create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, author, res_tx)).await {
+        res_rx.await.ok()
+    } else {
+        None
+    }
+}
+
+// Fields which only make sense for the running session
+const LIVE_FIELDS: &[&str] = &[
+    "preset_map",
+    "global_transposition",
+    "cc",
+    "pitch_wheel",
+    "take",
+    "state",
+];
+
+const LIVE_CONTROLLER_FIELDS: &[&str] = &["enabled"];
+
+// The renderer state a song brings along, e.g. the key the singer sings it in
+const SONG_RENDERER_FIELDS: &[&str] = &["global_transposition", "a4_hz", "fine_tune_cents"];
+
+// Values from this threshold up count as a pressed button
+const BUTTON_THRESHOLD: u8 = 64;
+
+// Every song is a complete state of the nodes, including the loaded files
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Setlist {
+    pub name: String,
+    pub songs: Vec<Scene>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "SetlistRequestKind")]
+pub enum RequestKind {
+    Load(PathBuf),
+    Save(PathBuf),
+    SetName(String),
+    AddSong(String),
+    UpdateSong(usize),
+    RemoveSong(usize),
+    MoveSong { id: usize, new_id: usize },
+    Next,
+    Previous,
+    Jump(usize),
+    SetProgramChangeSelect(bool),
+    SetNextCc(Option<u8>),
+    SetPreviousCc(Option<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "SetlistResponseKind")]
+pub enum ResponseKind {
+    InvalidId,
+    Failed { reason: Error },
+    Ok,
+}
+
+impl ResponseKind {
+    pub fn failed(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed {
+            reason: Error::new(code, message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "SetlistUpdateKind")]
+pub enum UpdateKind {
+    Setlist { name: String, songs: Vec<String> },
+    Current(Option<usize>),
+    ProgramChangeSelect(bool),
+    NextCc(Option<u8>),
+    PreviousCc(Option<u8>),
+}
+
+pub struct SetlistManager {
+    setlist: Setlist,
+    current: Option<usize>,
+    program_change_select: bool,
+    next_cc: Option<u8>,
+    previous_cc: Option<u8>,
+    pressed_ccs: Vec<u8>,
+    midi_rx: midi::Receiver,
+    req_rx: RequestListener,
+    rnd_req_tx: renderer::Requester,
+    ctr_req_tx: controller::Requester,
+    virtual_paths: VirtualPaths,
+    clients: Clients,
+    cache: Cache,
+}
+
+impl SetlistManager {
+    pub fn new(
+        midi_rx: midi::Receiver,
+        req_rx: RequestListener,
+        rnd_req_tx: renderer::Requester,
+        ctr_req_tx: controller::Requester,
+        virtual_paths: VirtualPaths,
+        clients: Clients,
+        cache: Cache,
+    ) -> Self {
+        Self {
+            setlist: Default::default(),
+            current: None,
+            program_change_select: false,
+            next_cc: None,
+            previous_cc: None,
+            pressed_ccs: Default::default(),
+            midi_rx,
+            req_rx,
+            rnd_req_tx,
+            ctr_req_tx,
+            virtual_paths,
+            clients,
+            cache,
+        }
+    }
+
+    pub fn serialize(&self) -> serde_json::Value {
+        json!({
+            "name": expect_serialize(&self.setlist.name),
+            "songs": expect_serialize(self.song_names()),
+            "current": expect_serialize(self.current),
+            "program_change_select": expect_serialize(self.program_change_select),
+            "next_cc": expect_serialize(self.next_cc),
+            "previous_cc": expect_serialize(self.previous_cc),
+        })
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                req = self.req_rx.recv() => match req {
+                    Some((kind, author, responder)) => {
+                        self.cache.set_author(author);
+                        self.process_request(kind, responder).await;
+                        self.cache.set_author(None);
+                    }
+                    None => break,
+                },
+                msg = self.midi_rx.recv() => match msg {
+                    Ok(msg) => self.process_midi_message(&msg).await,
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+
+    async fn process_request(&mut self, kind: RequestKind, responder: Responder) {
+        match kind {
+            RequestKind::Load(path) => {
+                let res = self.load(&path).await;
+                respond(responder, res);
+            }
+            RequestKind::Save(path) => {
+                let res = self.save(&path).await;
+                respond(responder, res);
+            }
+            RequestKind::SetName(name) => {
+                respond(responder, ResponseKind::Ok);
+                self.setlist.name = name;
+                self.update_setlist().await;
+            }
+            RequestKind::AddSong(name) => {
+                respond(responder, ResponseKind::Ok);
+                let song = self.capture_song(name).await;
+                self.setlist.songs.push(song);
+                self.update_setlist().await;
+            }
+            RequestKind::UpdateSong(id) => {
+                if id < self.setlist.songs.len() {
+                    respond(responder, ResponseKind::Ok);
+                    let name = self.setlist.songs[id].name.clone();
+                    self.setlist.songs[id] = self.capture_song(name).await;
+                    self.update_setlist().await;
+                } else {
+                    respond(responder, ResponseKind::InvalidId);
+                }
+            }
+            RequestKind::RemoveSong(id) => {
+                if id < self.setlist.songs.len() {
+                    respond(responder, ResponseKind::Ok);
+                    self.setlist.songs.remove(id);
+                    let current = self.current.and_then(|c| match c.cmp(&id) {
+                        Ordering::Less => Some(c),
+                        Ordering::Equal => None,
+                        Ordering::Greater => Some(c - 1),
+                    });
+                    self.set_current(current).await;
+                    self.update_setlist().await;
+                } else {
+                    respond(responder, ResponseKind::InvalidId);
+                }
+            }
+            RequestKind::MoveSong { id, new_id } => {
+                let len = self.setlist.songs.len();
+                if id < len && new_id < len {
+                    respond(responder, ResponseKind::Ok);
+                    let song = self.setlist.songs.remove(id);
+                    self.setlist.songs.insert(new_id, song);
+                    let current = self.current.map(|c| moved_index(c, id, new_id));
+                    self.set_current(current).await;
+                    self.update_setlist().await;
+                } else {
+                    respond(responder, ResponseKind::InvalidId);
+                }
+            }
+            RequestKind::Next => {
+                let res = self.step(1).await;
+                respond(responder, res);
+            }
+            RequestKind::Previous => {
+                let res = self.step(-1).await;
+                respond(responder, res);
+            }
+            RequestKind::Jump(id) => {
+                let res = self.jump(id).await;
+                respond(responder, res);
+            }
+            RequestKind::SetProgramChangeSelect(flag) => {
+                respond(responder, ResponseKind::Ok);
+                self.program_change_select = flag;
+                self.update(UpdateKind::ProgramChangeSelect(flag)).await;
+            }
+            RequestKind::SetNextCc(cc) => {
+                respond(responder, ResponseKind::Ok);
+                self.next_cc = cc;
+                self.update(UpdateKind::NextCc(cc)).await;
+            }
+            RequestKind::SetPreviousCc(cc) => {
+                respond(responder, ResponseKind::Ok);
+                self.previous_cc = cc;
+                self.update(UpdateKind::PreviousCc(cc)).await;
+            }
+        }
+    }
+
+    async fn process_midi_message(&mut self, message: &midi::Message) {
+        use midi::MessageKind as Kind;
+        match message.kind {
+            Kind::ProgramChange { program } if self.program_change_select => {
+                self.jump(program as usize).await;
+            }
+            Kind::ControlChange { kind, value } => {
+                let cc = kind.as_number();
+                if value < BUTTON_THRESHOLD {
+                    self.pressed_ccs.retain(|c| *c != cc);
+                } else if !self.pressed_ccs.contains(&cc) {
+                    self.pressed_ccs.push(cc);
+                    if Some(cc) == self.next_cc {
+                        self.step(1).await;
+                    } else if Some(cc) == self.previous_cc {
+                        self.step(-1).await;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn load(&mut self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let content = match tokio::fs::read_to_string(file).await {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::Failed { reason: e.into() },
+        };
+        let setlist = match serde_json::from_str(&content) {
+            Ok(setlist) => setlist,
+            Err(e) => return ResponseKind::failed(ErrorCode::Deserialization, e.to_string()),
+        };
+        self.setlist = setlist;
+        self.set_current(None).await;
+        self.update_setlist().await;
+        ResponseKind::Ok
+    }
+
+    async fn save(&self, path: &Path) -> ResponseKind {
+        let Some(file) = self.virtual_paths.translate(path) else {
+            return ResponseKind::Failed {
+                reason: Error::invalid_path(path),
+            };
+        };
+        let content = match serde_json::to_string_pretty(&self.setlist) {
+            Ok(content) => content,
+            Err(e) => return ResponseKind::failed(ErrorCode::Serialization, e.to_string()),
+        };
+        match tokio::fs::write(file, content).await {
+            Ok(()) => ResponseKind::Ok,
+            Err(e) => ResponseKind::Failed { reason: e.into() },
+        }
+    }
+
+    async fn capture_song(&self, name: String) -> Scene {
+        let cache = self.cache.to_json().await;
+        let song_state = |_, state: &serde_json::Value| {
+            scene::filter_fields(state, |f| LIVE_FIELDS.contains(&f))
+        };
+        Scene {
+            name,
+            render_nodes: scene::node_states(&cache["render_nodes"], song_state),
+            control_nodes: scene::node_states(&cache["control_nodes"], song_state),
+            controller: scene::filter_fields(&cache["controller"], |f| {
+                LIVE_CONTROLLER_FIELDS.contains(&f)
+            }),
+            renderer: scene::filter_fields(&cache["renderer"], |f| {
+                !SONG_RENDERER_FIELDS.contains(&f)
+            }),
+        }
+    }
+
+    async fn step(&mut self, offset: isize) -> ResponseKind {
+        let len = self.setlist.songs.len() as isize;
+        let id = match self.current {
+            Some(current) => current as isize + offset,
+            None => 0,
+        };
+        if (0..len).contains(&id) {
+            self.jump(id as usize).await
+        } else {
+            ResponseKind::InvalidId
+        }
+    }
+
+    async fn jump(&mut self, id: usize) -> ResponseKind {
+        let Some(song) = self.setlist.songs.get(id).cloned() else {
+            return ResponseKind::InvalidId;
+        };
+
+        let loaded = self.load_song_files(&song).await;
+        let scene = Scene {
+            render_nodes: song
+                .render_nodes
+                .iter()
+                .map(|(uid, state)| (*uid, scene::filter_fields(state, |f| f == "loaded_file")))
+                .collect(),
+            ..song.clone()
+        };
+        let res = controller::send_request_from(
+            &self.ctr_req_tx,
+            self.cache.author(),
+            controller::RequestKind::ApplyScene(scene),
+        )
+        .await;
+
+        self.set_current(Some(id)).await;
+        // The files of the next song load in the background, the manager keeps
+        // taking requests meanwhile
+        if let Some(next) = self.setlist.songs.get(id + 1).cloned() {
+            let rnd_req_tx = self.rnd_req_tx.clone();
+            tokio::spawn(preload_song_files(
+                rnd_req_tx,
+                self.cache.clone(),
+                song,
+                next,
+            ));
+        }
+
+        if !loaded {
+            let reason = "Not all files of the song could be loaded";
+            return ResponseKind::failed(ErrorCode::LoadFailed, reason);
+        }
+        match scene::apply_result(res) {
+            Ok(()) => ResponseKind::Ok,
+            Err(reason) => ResponseKind::Failed { reason },
+        }
+    }
+
+    // Loads the files which differ from the current ones and waits for all of them
+    async fn load_song_files(&mut self, song: &Scene) -> bool {
+        let cache = self.cache.to_json().await;
+        let mut loading = vec![];
+        for (uid, file) in song_files(song) {
+            let Some(id) = self.cache.render_node_index(uid).await else {
+                continue;
+            };
+            if cache["render_nodes"][id]["instance"]["loaded_file"] == expect_serialize(&file) {
+                continue;
+            }
+            let (res_tx, res_rx) = renderer::create_response_channel();
+            let req = renderer::RequestKind::NodeRequest {
+                id,
+                kind: node::RequestKind::LoadFile(file),
+            };
+            if self
+                .rnd_req_tx
+                .send((req, self.cache.author(), res_tx))
+                .await
+                .is_ok()
+            {
+                loading.push(res_rx);
+            }
+        }
+
+        let mut result = true;
+        for res_rx in loading {
+            result &= matches!(
+                res_rx.await,
+                Ok(renderer::ResponseKind::NodeResponse {
+                    kind: node::ResponseKind::Ok,
+                    ..
+                })
+            );
+        }
+        result
+    }
+
+    fn song_names(&self) -> Vec<String> {
+        self.setlist.songs.iter().map(|s| s.name.clone()).collect()
+    }
+
+    async fn set_current(&mut self, current: Option<usize>) {
+        if self.current != current {
+            self.current = current;
+            self.update(UpdateKind::Current(current)).await;
+        }
+    }
+
+    async fn update_setlist(&mut self) {
+        self.update(UpdateKind::Setlist {
+            name: self.setlist.name.clone(),
+            songs: self.song_names(),
+        })
+        .await;
+    }
+
+    async fn update(&mut self, kind: UpdateKind) {
+        self.cache.set_setlist(self.serialize()).await;
+        self.clients
+            .broadcast(ServerMessageKind::SetlistUpdate(kind));
+    }
+}
+
+async fn preload_song_files(
+    rnd_req_tx: renderer::Requester,
+    cache: Cache,
+    song: Scene,
+    next: Scene,
+) {
+    let files = song_files(&song);
+    for (uid, file) in song_files(&next) {
+        if files.contains(&(uid, file.clone())) {
+            continue;
+        }
+        let Some(id) = cache.render_node_index(uid).await else {
+            continue;
+        };
+        let req = renderer::RequestKind::NodeRequest {
+            id,
+            kind: node::RequestKind::PreloadFile(file),
+        };
+        renderer::send_request_from(&rnd_req_tx, cache.author(), req).await;
+    }
+}
+
+// Index of the entry at `index` after the entry at `id` moved to `new_id`
+fn moved_index(index: usize, id: usize, new_id: usize) -> usize {
+    if index == id {
+        new_id
+    } else if id < index && index <= new_id {
+        index - 1
+    } else if new_id <= index && index < id {
+        index + 1
+    } else {
+        index
+    }
+}
+
+fn song_files(song: &Scene) -> Vec<(NodeUid, PathBuf)> {
+    song.render_nodes
+        .iter()
+        .filter_map(|(uid, state)| {
+            serde_json::from_value::<PathBuf>(state.get("loaded_file")?.clone())
+                .ok()
+                .map(|file| (*uid, file))
+        })
+        .collect()
+}
+
+fn respond(responder: Responder, response_kind: ResponseKind) {
+    if let Err(e) = responder.send(response_kind) {
+        error!("Failed to send a response: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn song_files() {
+        let song = Scene {
+            render_nodes: vec![
+                (3, json!({ "loaded_file": "samples:/piano.sf2" })),
+                (5, json!({ "loaded_file": null })),
+                (1, json!({ "loaded_file": "samples:/strings.sf2" })),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            super::song_files(&song),
+            vec![
+                (3, PathBuf::from("samples:/piano.sf2")),
+                (1, PathBuf::from("samples:/strings.sf2"))
+            ]
+        );
+    }
+
+    #[test]
+    fn moved_index() {
+        let songs = ["a", "b", "c", "d"];
+        for (id, new_id) in [(0, 3), (3, 0), (1, 2), (2, 2)] {
+            let mut moved = songs.to_vec();
+            let song = moved.remove(id);
+            moved.insert(new_id, song);
+            for (index, song) in songs.iter().enumerate() {
+                assert_eq!(moved[super::moved_index(index, id, new_id)], *song);
+            }
+        }
+    }
+}
@@ -153,6 +153,16 @@ impl Synth {
         }
     }
 
+    pub fn set_tuning_frequency(&mut self, frequency: f32) {
+        unsafe {
+            bind::sfizz_set_tuning_frequency(self.c_synth, frequency);
+        }
+    }
+
+    pub fn tuning_frequency(&self) -> f32 {
+        unsafe { bind::sfizz_get_tuning_frequency(self.c_synth) }
+    }
+
     pub fn sample_rate(&self) -> Option<u32> {
         self.sample_rate
     }
@@ -204,6 +214,7 @@ impl Clone for Synth {
         if let Some(nf) = self.num_frames {
             new_obj.set_num_frames(nf)
         }
+        new_obj.set_tuning_frequency(self.tuning_frequency());
         //TODO: implement CC chache?
         new_obj
     }
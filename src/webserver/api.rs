@@ -0,0 +1,536 @@
+use super::{
+    auth::{self, Role},
+    dispatch, protocol, ClientMessageKind, Origin, ServerMessageKind, SharedState,
+};
+use crate::{
+    control::{controller, node as control_node},
+    error::{Error, ErrorCode},
+    library, mapping,
+    midi::MidiReader,
+    render::{node as render_node, renderer},
+    scene, setlist,
+};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf};
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesParams {
+    since: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PathParams {
+    path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MovePathParams {
+    path: PathBuf,
+    new_path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MidiInputParams {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferParams {
+    path: PathBuf,
+    // bytes already transferred by earlier requests
+    #[serde(default)]
+    offset: u64,
+    // of the whole file, uploads without it are sent in one request
+    size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResult {
+    size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MidiInputs {
+    available: Vec<String>,
+    connected: Vec<Option<String>>,
+}
+
+// Routes of the HTTP API, each request goes through the same handler as the websocket messages
+pub fn routes<F, Fut>() -> Router<(SharedState, F)>
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    Router::new()
+        .route("/protocol", get(get_protocol))
+        .route("/protocol.d.ts", get(get_protocol_typescript))
+        .route("/state", get(get_state::<F>))
+        .route("/state/changes", get(get_state_changes::<F>))
+        .route("/state/:section", get(get_state_section::<F>))
+        .route("/renderer", post(renderer_request::<F, Fut>))
+        .route("/controller", post(controller_request::<F, Fut>))
+        .route("/scenes", post(scene_request::<F, Fut>))
+        .route("/setlist", post(setlist_request::<F, Fut>))
+        .route("/mappings", post(mapping_request::<F, Fut>))
+        .route("/library", post(library_request::<F, Fut>))
+        .route("/midi/inputs", get(get_midi_inputs::<F>))
+        .route(
+            "/midi/inputs/:slot",
+            put(connect_midi_input::<F, Fut>).delete(disconnect_midi_input::<F, Fut>),
+        )
+        .route(
+            "/files",
+            get(read_dir::<F, Fut>).delete(delete_file::<F, Fut>),
+        )
+        .route("/files/dir", post(make_dir::<F, Fut>))
+        .route("/files/rename", post(rename_file::<F, Fut>))
+        .route("/files/copy", post(copy_file::<F, Fut>))
+        .route("/files/download", get(download_file::<F>))
+        .route("/files/upload", put(upload_file::<F>))
+}
+
+async fn get_protocol() -> Response {
+    Json(protocol::schema()).into_response()
+}
+
+async fn get_protocol_typescript() -> Response {
+    let content_type = [(header::CONTENT_TYPE, "application/typescript")];
+    (content_type, protocol::typescript()).into_response()
+}
+
+async fn get_state<F>(State((state, _)): State<(SharedState, F)>) -> Response {
+    Json(state.cache.to_json().await).into_response()
+}
+
+// Deltas after the revision, or a snapshot when they are not kept anymore
+async fn get_state_changes<F>(
+    Query(params): Query<ChangesParams>,
+    State((state, _)): State<(SharedState, F)>,
+) -> Response {
+    Json(state.cache.changes_since(params.since).await).into_response()
+}
+
+async fn get_state_section<F>(
+    Path(section): Path<String>,
+    State((state, _)): State<(SharedState, F)>,
+) -> Response {
+    match state.cache.to_json().await.get(&section) {
+        Some(value) => Json(value).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn get_midi_inputs<F>(State((state, _)): State<(SharedState, F)>) -> Response {
+    let connected = state.midi_reader.lock().await.connected_input_names();
+    Json(MidiInputs {
+        available: MidiReader::get_available_ports(),
+        connected,
+    })
+    .into_response()
+}
+
+async fn renderer_request<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(req): Json<renderer::RequestKind>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::RendererRequest(req);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn controller_request<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(req): Json<controller::RequestKind>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::ControllerRequest(req);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn scene_request<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(req): Json<scene::RequestKind>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::SceneRequest(req);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn setlist_request<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(req): Json<setlist::RequestKind>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::SetlistRequest(req);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn mapping_request<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(req): Json<mapping::RequestKind>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::MappingRequest(req);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn library_request<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(req): Json<library::RequestKind>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::LibraryRequest(req);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn connect_midi_input<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(slot): Path<usize>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(params): Json<MidiInputParams>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::ConnectMidiInput(slot, params.name);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn disconnect_midi_input<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(slot): Path<usize>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::DisconnectMidiInput(slot);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn read_dir<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Query(params): Query<PathParams>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::ReadDir(params.path);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn delete_file<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Query(params): Query<PathParams>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::DeleteFile(params.path);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn make_dir<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(params): Json<PathParams>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::MakeDir(params.path);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn rename_file<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(params): Json<MovePathParams>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::RenameFile(params.path, params.new_path);
+    handle(state, req_handler, addr, role, req).await
+}
+
+async fn copy_file<F, Fut>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(role): Extension<Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+    Json(params): Json<MovePathParams>,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let req = ClientMessageKind::CopyFile(params.path, params.new_path);
+    handle(state, req_handler, addr, role, req).await
+}
+
+// Streams the file from the offset on, so an interrupted download can be resumed
+async fn download_file<F>(
+    State((state, _)): State<(SharedState, F)>,
+    Query(params): Query<TransferParams>,
+) -> Response {
+    match state.files.download(&params.path, params.offset).await {
+        Ok((size, content)) => {
+            let name = params
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().replace('"', "_"))
+                .unwrap_or_default();
+            let headers = [
+                (header::CONTENT_TYPE, "application/octet-stream".to_owned()),
+                (header::CONTENT_LENGTH, (size - params.offset).to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{name}\""),
+                ),
+            ];
+            (headers, Body::from_stream(content)).into_response()
+        }
+        Err(e) => into_response(ServerMessageKind::Error(e)),
+    }
+}
+
+// Writes the body at the offset, large files are uploaded in several requests
+async fn upload_file<F>(
+    Extension(role): Extension<Role>,
+    State((state, _)): State<(SharedState, F)>,
+    Query(params): Query<TransferParams>,
+    body: Body,
+) -> Response {
+    if role < Role::Admin {
+        return into_response(ServerMessageKind::Denied);
+    }
+    let chunk = body.into_data_stream();
+    match state
+        .files
+        .upload(&params.path, params.offset, params.size, chunk)
+        .await
+    {
+        Ok(size) => {
+            state.library.rescan();
+            Json(UploadResult { size }).into_response()
+        }
+        Err(e) => into_response(ServerMessageKind::Error(e)),
+    }
+}
+
+async fn handle<F, Fut>(
+    mut state: SharedState,
+    mut req_handler: F,
+    addr: SocketAddr,
+    role: Role,
+    req: ClientMessageKind,
+) -> Response
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    if !auth::permits(role, &req) {
+        return into_response(ServerMessageKind::Denied);
+    }
+    let origin = Origin { addr, client: None };
+    into_response(dispatch(&mut state.clients, &mut req_handler, origin, req).await)
+}
+
+// Unwraps the websocket payload into its typed response with a matching status code
+fn into_response(payload: ServerMessageKind) -> Response {
+    let status = status(&payload);
+    match payload {
+        ServerMessageKind::RendererResponse(res) => (status, Json(res)).into_response(),
+        ServerMessageKind::ControllerResponse(res) => (status, Json(res)).into_response(),
+        ServerMessageKind::SceneResponse(res) => (status, Json(res)).into_response(),
+        ServerMessageKind::SetlistResponse(res) => (status, Json(res)).into_response(),
+        ServerMessageKind::MappingResponse(res) => (status, Json(res)).into_response(),
+        ServerMessageKind::LibraryResponse(res) => (status, Json(res)).into_response(),
+        ServerMessageKind::Error(e) => (status, Json(e)).into_response(),
+        ServerMessageKind::DirInfo(entries) => (status, Json(entries)).into_response(),
+        _ => status.into_response(),
+    }
+}
+
+fn status(payload: &ServerMessageKind) -> StatusCode {
+    match payload {
+        ServerMessageKind::Ack => StatusCode::NO_CONTENT,
+        ServerMessageKind::Error(e) => error_status(e),
+        ServerMessageKind::Denied => StatusCode::FORBIDDEN,
+        ServerMessageKind::RendererResponse(res) => renderer_status(res),
+        ServerMessageKind::ControllerResponse(res) => controller_status(res),
+        ServerMessageKind::SceneResponse(res) => match res {
+            scene::ResponseKind::InvalidId => StatusCode::NOT_FOUND,
+            scene::ResponseKind::Empty => StatusCode::CONFLICT,
+            scene::ResponseKind::Failed { reason } => error_status(reason),
+            scene::ResponseKind::Ok => StatusCode::OK,
+        },
+        ServerMessageKind::SetlistResponse(res) => match res {
+            setlist::ResponseKind::InvalidId => StatusCode::NOT_FOUND,
+            setlist::ResponseKind::Failed { reason } => error_status(reason),
+            setlist::ResponseKind::Ok => StatusCode::OK,
+        },
+        ServerMessageKind::MappingResponse(res) => match res {
+            mapping::ResponseKind::InvalidId => StatusCode::NOT_FOUND,
+            mapping::ResponseKind::Failed { reason } => error_status(reason),
+            mapping::ResponseKind::Ok => StatusCode::OK,
+        },
+        ServerMessageKind::LibraryResponse(library::ResponseKind::Failed { reason }) => {
+            error_status(reason)
+        }
+        _ => StatusCode::OK,
+    }
+}
+
+fn renderer_status(res: &renderer::ResponseKind) -> StatusCode {
+    use renderer::ResponseKind;
+    match res {
+        ResponseKind::InvalidNodeKind | ResponseKind::InvalidValue => StatusCode::BAD_REQUEST,
+        ResponseKind::InvalidId | ResponseKind::InvalidParameter => StatusCode::NOT_FOUND,
+        ResponseKind::Denied => StatusCode::FORBIDDEN,
+        ResponseKind::Failed { reason } => error_status(reason),
+        ResponseKind::NodeResponse { kind, .. } => match kind {
+            render_node::ResponseKind::InvalidId => StatusCode::NOT_FOUND,
+            render_node::ResponseKind::Denied => StatusCode::FORBIDDEN,
+            render_node::ResponseKind::Failed { reason } => error_status(reason),
+            render_node::ResponseKind::Ok => StatusCode::OK,
+        },
+        ResponseKind::Ok | ResponseKind::Parameters(_) | ResponseKind::ParameterValue(_) => {
+            StatusCode::OK
+        }
+    }
+}
+
+fn controller_status(res: &controller::ResponseKind) -> StatusCode {
+    use controller::ResponseKind;
+    match res {
+        ResponseKind::InvalidNodeKind | ResponseKind::InvalidValue => StatusCode::BAD_REQUEST,
+        ResponseKind::InvalidId | ResponseKind::InvalidParameter => StatusCode::NOT_FOUND,
+        ResponseKind::Denied => StatusCode::FORBIDDEN,
+        ResponseKind::Failed { reason } => error_status(reason),
+        ResponseKind::NodeResponse { kind, .. } => match kind {
+            control_node::ResponseKind::InvalidId => StatusCode::NOT_FOUND,
+            control_node::ResponseKind::Denied => StatusCode::FORBIDDEN,
+            control_node::ResponseKind::Failed { reason } => error_status(reason),
+            control_node::ResponseKind::Ok => StatusCode::OK,
+        },
+        ResponseKind::Ok | ResponseKind::Parameters(_) | ResponseKind::ParameterValue(_) => {
+            StatusCode::OK
+        }
+    }
+}
+
+fn error_status(error: &Error) -> StatusCode {
+    match error.code {
+        ErrorCode::NotFound | ErrorCode::InvalidSlot => StatusCode::NOT_FOUND,
+        ErrorCode::InvalidValue | ErrorCode::InvalidPath | ErrorCode::Deserialization => {
+            StatusCode::BAD_REQUEST
+        }
+        ErrorCode::NotLoaded | ErrorCode::InvalidState => StatusCode::CONFLICT,
+        ErrorCode::Locked => StatusCode::LOCKED,
+        ErrorCode::Unsupported => StatusCode::NOT_IMPLEMENTED,
+        ErrorCode::Unavailable | ErrorCode::MidiConnect => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Io | ErrorCode::Serialization | ErrorCode::LoadFailed => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(status(&ServerMessageKind::Ack), StatusCode::NO_CONTENT);
+        assert_eq!(
+            status(&ServerMessageKind::Error(Error::new(
+                ErrorCode::Locked,
+                "Render node 0 is locked by another client"
+            ))),
+            StatusCode::LOCKED
+        );
+        assert_eq!(
+            status(&ServerMessageKind::RendererResponse(
+                renderer::ResponseKind::NodeResponse {
+                    id: 0,
+                    kind: render_node::ResponseKind::Denied
+                }
+            )),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(&ServerMessageKind::SceneResponse(scene::ResponseKind::Ok)),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&ServerMessageKind::Error(Error::new(
+                ErrorCode::Unavailable,
+                "Renderer is not running"
+            ))),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status(&ServerMessageKind::ControllerResponse(
+                controller::ResponseKind::NodeResponse {
+                    id: 0,
+                    kind: control_node::ResponseKind::failed(ErrorCode::InvalidState, "No notes")
+                }
+            )),
+            StatusCode::CONFLICT
+        );
+    }
+}
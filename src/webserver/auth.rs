@@ -0,0 +1,462 @@
+use super::ClientMessageKind;
+use crate::{
+    control::{controller, node},
+    mapping,
+    render::renderer,
+    scene, setlist,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+const SESSION_COOKIE: &str = "ami_session";
+const SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+// Wrong tokens in a row before an address has to wait, PINs are short
+const MAX_FAILED_LOGINS: u32 = 5;
+const LOGIN_LOCKOUT: Duration = Duration::from_secs(60);
+
+const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta name="viewport" content="width=device-width, initial-scale=1"><title>AMI</title></head>
+<body>
+<form method="post" action="/api/login">
+<input type="password" name="token" placeholder="PIN" autofocus>
+<button type="submit">Log in</button>
+</form>
+</body>
+</html>
+"#;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+pub enum Role {
+    // Plays the set, can not change files or the node topology
+    Performer,
+    Admin,
+}
+
+// With only a performer token nobody can edit files or nodes from the web
+// interface, this is meant for locking down a finished setup
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Config {
+    pub admin_token: Option<String>,
+    pub performer_token: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReadFile(std::io::Error),
+    Parse(serde_json::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadFile(e) => write!(f, "Failed to read auth config: {e}"),
+            Error::Parse(e) => write!(f, "Invalid auth config: {e}"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    token: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    role: Role,
+    expires: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FailedLogins {
+    count: u32,
+    last: Instant,
+}
+
+// Thread safe, can be cloned
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    config: Arc<Config>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    failed_logins: Arc<Mutex<HashMap<IpAddr, FailedLogins>>>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(Error::ReadFile)?;
+        serde_json::from_str(&content).map_err(Error::Parse)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.admin_token.is_some() || self.performer_token.is_some()
+    }
+}
+
+impl Auth {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config: Arc::new(config),
+            sessions: Default::default(),
+            failed_logins: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_enabled()
+    }
+
+    pub fn role_for_token(&self, token: &str) -> Option<Role> {
+        let matches = |expected: &Option<String>| {
+            expected
+                .as_deref()
+                .is_some_and(|expected| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+        };
+        if matches(&self.config.admin_token) {
+            Some(Role::Admin)
+        } else if matches(&self.config.performer_token) {
+            Some(Role::Performer)
+        } else {
+            None
+        }
+    }
+
+    // Like role_for_token, but an address sending too many wrong tokens is
+    // turned away for a while, even with the right one
+    pub async fn check_token(&self, ip: IpAddr, token: &str) -> Result<Role, StatusCode> {
+        let mut failed_logins = self.failed_logins.lock().await;
+        let now = Instant::now();
+        failed_logins.retain(|_, failed| now.duration_since(failed.last) < LOGIN_LOCKOUT);
+        if failed_logins
+            .get(&ip)
+            .is_some_and(|failed| failed.count >= MAX_FAILED_LOGINS)
+        {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        match self.role_for_token(token) {
+            Some(role) => {
+                failed_logins.remove(&ip);
+                Ok(role)
+            }
+            None => {
+                let failed = failed_logins.entry(ip).or_insert(FailedLogins {
+                    count: 0,
+                    last: now,
+                });
+                failed.count += 1;
+                failed.last = now;
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+
+    // Accepts a bearer token (scripts) or a session cookie (web client)
+    pub async fn authenticate(&self, ip: IpAddr, headers: &HeaderMap) -> Result<Role, StatusCode> {
+        if !self.is_enabled() {
+            return Ok(Role::Admin);
+        }
+        if let Some(token) = bearer_token(headers) {
+            return self.check_token(ip, token).await;
+        }
+        let session = session_cookie(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+        let mut sessions = self.sessions.lock().await;
+        match sessions.get(session) {
+            Some(session) if session.expires > Instant::now() => Ok(session.role),
+            Some(_) => {
+                sessions.remove(session);
+                Err(StatusCode::UNAUTHORIZED)
+            }
+            None => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    async fn create_session(&self, role: Role) -> String {
+        let id: [u8; 16] = rand::thread_rng().gen();
+        let id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().await;
+        sessions.retain(|_, session| session.expires > now);
+        let expires = now + SESSION_TTL;
+        sessions.insert(id.clone(), Session { role, expires });
+        id
+    }
+
+    async fn remove_session(&self, headers: &HeaderMap) {
+        if let Some(session) = session_cookie(headers) {
+            self.sessions.lock().await.remove(session);
+        }
+    }
+}
+
+pub fn routes<S>(auth: Auth) -> Router<S> {
+    Router::new()
+        .route("/login", get(login_page))
+        .route("/api/login", post(login))
+        .route("/api/logout", post(logout))
+        .with_state(auth)
+}
+
+// Puts the role of the caller into the request extensions, unauthenticated
+// requests are refused or sent to the login page
+pub async fn require_role(State(auth): State<Auth>, mut req: Request, next: Next) -> Response {
+    let path = req.uri().path().to_owned();
+    if path == "/login" || path == "/api/login" {
+        return next.run(req).await;
+    }
+    let ip = match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip(),
+        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    match auth.authenticate(ip, req.headers()).await {
+        Ok(role) => {
+            req.extensions_mut().insert(role);
+            next.run(req).await
+        }
+        Err(StatusCode::UNAUTHORIZED) if path != "/ws" && !path.starts_with("/api/") => {
+            Redirect::to("/login").into_response()
+        }
+        Err(status) => status.into_response(),
+    }
+}
+
+pub fn permits(role: Role, req: &ClientMessageKind) -> bool {
+    role >= required_role(req)
+}
+
+pub fn required_role(req: &ClientMessageKind) -> Role {
+    let performer = match req {
+        ClientMessageKind::Ping
+        | ClientMessageKind::Report(_)
+        | ClientMessageKind::Subscribe(_)
+        | ClientMessageKind::GetChanges { .. }
+        | ClientMessageKind::SetName(_)
+        | ClientMessageKind::Lock(_)
+        | ClientMessageKind::Unlock(_)
+        | ClientMessageKind::ReadDir(_)
+        | ClientMessageKind::LibraryRequest(_) => true,
+        ClientMessageKind::RendererRequest(req) => !matches!(
+            req,
+            renderer::RequestKind::AddNode { .. }
+                | renderer::RequestKind::RemoveNode { .. }
+                | renderer::RequestKind::CloneNode { .. }
+                | renderer::RequestKind::MoveNode { .. }
+                | renderer::RequestKind::LoadNodes(..)
+        ),
+        ClientMessageKind::ControllerRequest(req) => !matches!(
+            req,
+            controller::RequestKind::AddNode { .. }
+                | controller::RequestKind::RemoveNode { .. }
+                | controller::RequestKind::CloneNode { .. }
+                | controller::RequestKind::MoveNode { .. }
+                | controller::RequestKind::SaveSession(_)
+                | controller::RequestKind::LoadSession(_)
+                // Node requests writing files
+                | controller::RequestKind::NodeRequest {
+                    kind: node::RequestKind::SavePreset(_) | node::RequestKind::ExportMidiFile(_),
+                    ..
+                }
+        ),
+        ClientMessageKind::SceneRequest(req) => matches!(req, scene::RequestKind::Recall(_)),
+        ClientMessageKind::SetlistRequest(req) => matches!(
+            req,
+            setlist::RequestKind::Next
+                | setlist::RequestKind::Previous
+                | setlist::RequestKind::Jump(_)
+        ),
+        ClientMessageKind::MappingRequest(mapping::RequestKind::CancelLearn) => true,
+        ClientMessageKind::MappingRequest(_)
+        | ClientMessageKind::ConnectMidiInput(..)
+        | ClientMessageKind::DisconnectMidiInput(_)
+        | ClientMessageKind::MakeDir(_)
+        | ClientMessageKind::DeleteFile(_)
+        | ClientMessageKind::RenameFile(..)
+        | ClientMessageKind::CopyFile(..) => false,
+    };
+    if performer {
+        Role::Performer
+    } else {
+        Role::Admin
+    }
+}
+
+async fn login_page() -> Html<&'static str> {
+    Html(LOGIN_PAGE)
+}
+
+async fn login(
+    State(auth): State<Auth>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(params): Form<LoginParams>,
+) -> Response {
+    let role = match auth.check_token(addr.ip(), &params.token).await {
+        Ok(role) => role,
+        Err(StatusCode::UNAUTHORIZED) => return Redirect::to("/login").into_response(),
+        Err(status) => return status.into_response(),
+    };
+    let session = auth.create_session(role).await;
+    let max_age = SESSION_TTL.as_secs();
+    let cookie =
+        format!("{SESSION_COOKIE}={session}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Strict");
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
+}
+
+async fn logout(State(auth): State<Auth>, headers: HeaderMap) -> Response {
+    auth.remove_session(&headers).await;
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; Max-Age=0");
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/login")).into_response()
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+// Token comparison that does not leak the position of the first mismatch
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use std::path::PathBuf;
+
+    fn auth() -> Auth {
+        Auth::new(Config {
+            admin_token: Some("1234".into()),
+            performer_token: Some("0000".into()),
+        })
+    }
+
+    #[test]
+    fn roles() {
+        let auth = auth();
+        assert_eq!(auth.role_for_token("1234"), Some(Role::Admin));
+        assert_eq!(auth.role_for_token("0000"), Some(Role::Performer));
+        assert_eq!(auth.role_for_token("123"), None);
+
+        let switch_preset =
+            ClientMessageKind::RendererRequest(renderer::RequestKind::SetUserPreset(1));
+        let delete = ClientMessageKind::DeleteFile(PathBuf::from("samples:"));
+        let add_node = ClientMessageKind::ControllerRequest(controller::RequestKind::AddNode {
+            kind: "Looper".into(),
+        });
+        assert!(permits(Role::Performer, &switch_preset));
+        assert!(!permits(Role::Performer, &delete));
+        assert!(!permits(Role::Performer, &add_node));
+        assert!(permits(Role::Admin, &delete));
+    }
+
+    fn control_node_request(kind: node::RequestKind) -> ClientMessageKind {
+        ClientMessageKind::ControllerRequest(controller::RequestKind::NodeRequest { id: 0, kind })
+    }
+
+    #[test]
+    fn save_preset_needs_admin() {
+        let save = control_node_request(node::RequestKind::SavePreset("samples:/p.json".into()));
+        assert!(!permits(Role::Performer, &save));
+        assert!(permits(Role::Admin, &save));
+        let load = control_node_request(node::RequestKind::LoadPreset("samples:/p.json".into()));
+        assert!(permits(Role::Performer, &load));
+    }
+
+    #[test]
+    fn export_midi_file_needs_admin() {
+        let export = control_node_request(node::RequestKind::ExportMidiFile("beats:/a.mid".into()));
+        assert!(!permits(Role::Performer, &export));
+        assert!(permits(Role::Admin, &export));
+        let stop = control_node_request(node::RequestKind::Stop);
+        assert!(permits(Role::Performer, &stop));
+    }
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn authenticate() {
+        let auth = auth();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            auth.authenticate(IP, &headers).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            Auth::default().authenticate(IP, &headers).await,
+            Ok(Role::Admin)
+        );
+
+        let session = auth.create_session(Role::Performer).await;
+        let cookie = format!("theme=dark; {SESSION_COOKIE}={session}");
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert_eq!(auth.authenticate(IP, &headers).await, Ok(Role::Performer));
+
+        auth.sessions
+            .lock()
+            .await
+            .get_mut(&session)
+            .unwrap()
+            .expires = Instant::now();
+        assert_eq!(
+            auth.authenticate(IP, &headers).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer 1234"),
+        );
+        assert_eq!(auth.authenticate(IP, &headers).await, Ok(Role::Admin));
+    }
+
+    #[tokio::test]
+    async fn throttle() {
+        let auth = auth();
+        for _ in 0..MAX_FAILED_LOGINS {
+            assert_eq!(
+                auth.check_token(IP, "9999").await,
+                Err(StatusCode::UNAUTHORIZED)
+            );
+        }
+        assert_eq!(
+            auth.check_token(IP, "1234").await,
+            Err(StatusCode::TOO_MANY_REQUESTS)
+        );
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 0, 2));
+        assert_eq!(auth.check_token(other, "1234").await, Ok(Role::Admin));
+    }
+}
@@ -0,0 +1,705 @@
+use super::{
+    presence::{ClientId, Node, Reorder},
+    Clients, ServerMessageKind,
+};
+use crate::{
+    control::automation::Automation,
+    json::{expect_serialize, JsonFieldUpdate},
+    rhythm::Rhythm,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::VecDeque, sync::Arc};
+use tokio::sync::Mutex;
+
+// Number of deltas kept for clients catching up, older clients get a snapshot
+const HISTORY_LEN: usize = 1024;
+
+// Change of a single value, paths are JSON pointers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Op {
+    // Replaces the value, a missing object field is created
+    Set {
+        path: String,
+        value: Value,
+    },
+    Insert {
+        path: String,
+        index: usize,
+        value: Value,
+    },
+    Remove {
+        path: String,
+        index: usize,
+    },
+    Move {
+        path: String,
+        id: usize,
+        new_id: usize,
+    },
+}
+
+// All ops of one mutation, revisions are consecutive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Delta {
+    pub revision: u64,
+    // client whose request caused the change, none for changes of the server
+    pub author: Option<ClientId>,
+    pub ops: Vec<Op>,
+}
+
+// Id of a node which stays the same when other nodes are moved or removed.
+// Sessions save it with the node, so it also stays the same across restarts.
+pub type NodeUid = u64;
+
+// A node as kept in the cache and in session files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CachedNode {
+    pub kind: String,
+    pub uid: NodeUid,
+    pub instance: Value,
+}
+
+#[derive(Debug)]
+struct Store {
+    state: Value,
+    revision: u64,
+    history: VecDeque<Delta>,
+    next_uid: NodeUid,
+}
+
+// Thread safe cache of the state shown by the clients, every mutation bumps
+// the revision and is broadcast as a delta
+#[derive(Clone)]
+pub struct Cache {
+    store: Arc<Mutex<Store>>,
+    clients: Clients,
+    // not shared, each manager attributes the changes of its own requests
+    author: Option<ClientId>,
+}
+
+impl Op {
+    // Returns false when the path or index does not exist, the state is left
+    // untouched then
+    pub fn apply(&self, state: &mut Value) -> bool {
+        match self {
+            Op::Set { path, value } => {
+                if let Some(target) = state.pointer_mut(path) {
+                    *target = value.clone();
+                    return true;
+                }
+                let Some((parent, field)) = path.rsplit_once('/') else {
+                    return false;
+                };
+                match state.pointer_mut(parent).and_then(Value::as_object_mut) {
+                    Some(object) => {
+                        object.insert(unescape(field), value.clone());
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Op::Insert { path, index, value } => match array_mut(state, path) {
+                Some(array) if *index <= array.len() => {
+                    array.insert(*index, value.clone());
+                    true
+                }
+                _ => false,
+            },
+            Op::Remove { path, index } => match array_mut(state, path) {
+                Some(array) if *index < array.len() => {
+                    array.remove(*index);
+                    true
+                }
+                _ => false,
+            },
+            Op::Move { path, id, new_id } => match array_mut(state, path) {
+                Some(array) if *id < array.len() && *new_id < array.len() => {
+                    let value = array.remove(*id);
+                    array.insert(*new_id, value);
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Store {
+    fn take_uid(&mut self) -> NodeUid {
+        self.next_uid += 1;
+        self.next_uid
+    }
+
+    // Later nodes never get the uid of a restored one
+    fn restore_uid(&mut self, uid: NodeUid) -> NodeUid {
+        self.next_uid = self.next_uid.max(uid);
+        uid
+    }
+
+    fn commit(&mut self, author: Option<ClientId>, ops: Vec<Op>) -> Option<Delta> {
+        let ops: Vec<Op> = ops
+            .into_iter()
+            .filter(|op| op.apply(&mut self.state))
+            .collect();
+        if ops.is_empty() {
+            return None;
+        }
+        self.revision += 1;
+        let delta = Delta {
+            revision: self.revision,
+            author,
+            ops,
+        };
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(delta.clone());
+        Some(delta)
+    }
+
+    fn changes_since(&self, revision: u64) -> Option<Vec<Delta>> {
+        if revision > self.revision {
+            return None;
+        }
+        let oldest = self
+            .history
+            .front()
+            .map_or(self.revision, |d| d.revision - 1);
+        if revision < oldest {
+            return None;
+        }
+        Some(
+            self.history
+                .iter()
+                .filter(|delta| delta.revision > revision)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl Cache {
+    pub fn new(clients: Clients) -> Self {
+        Self {
+            store: Arc::new(Mutex::new(Store {
+                state: json!({
+                    "render_nodes": [],
+                    "control_nodes": [],
+                    "renderer": {},
+                    "controller": {},
+                    "scenes": {},
+                    "setlist": {},
+                    "mappings": {}
+                }),
+                revision: 0,
+                history: VecDeque::new(),
+                next_uid: 0,
+            })),
+            clients,
+            author: None,
+        }
+    }
+
+    // Attributes the following changes to the client
+    pub fn set_author(&mut self, author: Option<ClientId>) {
+        self.author = author;
+    }
+
+    pub fn author(&self) -> Option<ClientId> {
+        self.author
+    }
+
+    pub async fn has_node(&self, node: Node) -> bool {
+        let (nodes, id) = match node {
+            Node::RenderNode(id) => ("render_nodes", id),
+            Node::ControlNode(id) => ("control_nodes", id),
+        };
+        let store = self.store.lock().await;
+        store.state[nodes].get(id).is_some()
+    }
+
+    pub async fn render_node_index(&self, uid: NodeUid) -> Option<usize> {
+        let store = self.store.lock().await;
+        node_index(&store.state, "render_nodes", uid)
+    }
+
+    pub async fn control_node_index(&self, uid: NodeUid) -> Option<usize> {
+        let store = self.store.lock().await;
+        node_index(&store.state, "control_nodes", uid)
+    }
+
+    // The value at the JSON pointer, null when it does not exist
+    pub async fn get(&self, path: &str) -> Value {
+        let store = self.store.lock().await;
+        store.state.pointer(path).cloned().unwrap_or_default()
+    }
+
+    pub async fn to_json(&self) -> Value {
+        let store = self.store.lock().await;
+        store.state.clone()
+    }
+
+    pub async fn snapshot(&self) -> ServerMessageKind {
+        let store = self.store.lock().await;
+        ServerMessageKind::Cache {
+            revision: store.revision,
+            state: store.state.clone(),
+        }
+    }
+
+    // A snapshot when the deltas are not kept anymore
+    pub async fn changes_since(&self, revision: u64) -> ServerMessageKind {
+        let changes = self.store.lock().await.changes_since(revision);
+        match changes {
+            Some(changes) => ServerMessageKind::Changes(changes),
+            None => self.snapshot().await,
+        }
+    }
+
+    async fn commit(&mut self, ops: Vec<Op>) {
+        self.commit_with(|_| ops).await;
+    }
+
+    // Same as commit_with, the ops get a fresh node uid unless one is restored
+    async fn commit_node_with(
+        &mut self,
+        restored: Option<NodeUid>,
+        ops: impl FnOnce(&Value, NodeUid) -> Vec<Op>,
+    ) {
+        let mut store = self.store.lock().await;
+        let uid = match restored {
+            Some(uid) => store.restore_uid(uid),
+            None => store.take_uid(),
+        };
+        let ops = ops(&store.state, uid);
+        if let Some(delta) = store.commit(self.author, ops) {
+            self.clients.broadcast(ServerMessageKind::Delta(delta));
+        }
+    }
+
+    // The ops are built and the delta is broadcast while the store is locked,
+    // so clients receive the deltas in revision order
+    async fn commit_with(&mut self, ops: impl FnOnce(&Value) -> Vec<Op>) {
+        let mut store = self.store.lock().await;
+        let ops = ops(&store.state);
+        if let Some(delta) = store.commit(self.author, ops) {
+            self.clients.broadcast(ServerMessageKind::Delta(delta));
+        }
+    }
+
+    pub async fn add_render_node(&mut self, kind: &str, value: &Value) {
+        self.commit_node_with(None, |state, uid| {
+            add_node(state, "render_nodes", kind, value, uid)
+        })
+        .await;
+    }
+
+    pub async fn restore_render_node(&mut self, kind: &str, value: &Value, uid: NodeUid) {
+        self.commit_node_with(Some(uid), |state, uid| {
+            add_node(state, "render_nodes", kind, value, uid)
+        })
+        .await;
+    }
+
+    pub async fn remove_render_node(&mut self, id: usize) {
+        self.commit(vec![Op::Remove {
+            path: "/render_nodes".into(),
+            index: id,
+        }])
+        .await;
+        self.clients.reorder(Reorder::Remove(Node::RenderNode(id)));
+    }
+
+    pub async fn clone_render_node(&mut self, id: usize) {
+        self.commit_node_with(None, |state, uid| {
+            clone_node(state, "render_nodes", id, uid)
+        })
+        .await;
+    }
+
+    pub async fn move_render_node(&mut self, id: usize, new_id: usize) {
+        self.commit(vec![Op::Move {
+            path: "/render_nodes".into(),
+            id,
+            new_id,
+        }])
+        .await;
+        self.clients
+            .reorder(Reorder::Move(Node::RenderNode(id), new_id));
+    }
+
+    pub async fn render_node_updates(&mut self, node_id: usize, updates: &[JsonFieldUpdate]) {
+        self.commit(node_updates("render_nodes", node_id, updates))
+            .await;
+    }
+
+    pub async fn set_renderer(&mut self, value: Value) {
+        self.commit(vec![set("/renderer", value)]).await;
+    }
+
+    pub async fn set_renderer_global_transposition(&mut self, transposition: i8) {
+        self.commit(vec![set(
+            "/renderer/global_transposition",
+            transposition.into(),
+        )])
+        .await;
+    }
+
+    pub async fn set_renderer_master_tuning(&mut self, a4_hz: f32, fine_tune_cents: f32) {
+        self.commit(vec![
+            set("/renderer/a4_hz", a4_hz.into()),
+            set("/renderer/fine_tune_cents", fine_tune_cents.into()),
+        ])
+        .await;
+    }
+
+    pub async fn set_controller(&mut self, value: Value) {
+        self.commit(vec![set("/controller", value)]).await;
+    }
+
+    pub async fn set_controller_enabled(&mut self, flag: bool) {
+        self.commit(vec![set("/controller/enabled", flag.into())])
+            .await;
+    }
+
+    pub async fn set_controller_tempo_bpm(&mut self, tempo_bpm: f32) {
+        self.commit(vec![set("/controller/tempo_bpm", tempo_bpm.into())])
+            .await;
+    }
+
+    pub async fn set_controller_rhythm(&mut self, rhythm: Rhythm) {
+        self.commit(vec![set("/controller/rhythm", expect_serialize(rhythm))])
+            .await;
+    }
+
+    pub async fn set_controller_automation(&mut self, automation: &Automation) {
+        self.commit(vec![set(
+            "/controller/automation",
+            expect_serialize(automation),
+        )])
+        .await;
+    }
+
+    pub async fn set_scenes(&mut self, value: Value) {
+        self.commit(vec![set("/scenes", value)]).await;
+    }
+
+    pub async fn set_setlist(&mut self, value: Value) {
+        self.commit(vec![set("/setlist", value)]).await;
+    }
+
+    pub async fn set_mappings(&mut self, value: Value) {
+        self.commit(vec![set("/mappings", value)]).await;
+    }
+
+    pub async fn add_control_node(&mut self, kind: &str, value: &Value) {
+        self.commit_node_with(None, |state, uid| {
+            add_node(state, "control_nodes", kind, value, uid)
+        })
+        .await;
+    }
+
+    pub async fn restore_control_node(&mut self, kind: &str, value: &Value, uid: NodeUid) {
+        self.commit_node_with(Some(uid), |state, uid| {
+            add_node(state, "control_nodes", kind, value, uid)
+        })
+        .await;
+    }
+
+    pub async fn remove_control_node(&mut self, id: usize) {
+        self.commit(vec![Op::Remove {
+            path: "/control_nodes".into(),
+            index: id,
+        }])
+        .await;
+        self.clients.reorder(Reorder::Remove(Node::ControlNode(id)));
+    }
+
+    pub async fn clone_control_node(&mut self, id: usize) {
+        self.commit_node_with(None, |state, uid| {
+            clone_node(state, "control_nodes", id, uid)
+        })
+        .await;
+    }
+
+    pub async fn move_control_node(&mut self, id: usize, new_id: usize) {
+        self.commit(vec![Op::Move {
+            path: "/control_nodes".into(),
+            id,
+            new_id,
+        }])
+        .await;
+        self.clients
+            .reorder(Reorder::Move(Node::ControlNode(id), new_id));
+    }
+
+    pub async fn control_node_updates(&mut self, node_id: usize, updates: &[JsonFieldUpdate]) {
+        self.commit(node_updates("control_nodes", node_id, updates))
+            .await;
+    }
+}
+
+fn set(path: &str, value: Value) -> Op {
+    Op::Set {
+        path: path.into(),
+        value,
+    }
+}
+
+fn add_node(state: &Value, nodes: &str, kind: &str, value: &Value, uid: NodeUid) -> Vec<Op> {
+    vec![Op::Insert {
+        path: format!("/{nodes}"),
+        index: state[nodes].as_array().map_or(0, Vec::len),
+        value: json!({
+            "kind": kind,
+            "uid": uid,
+            "instance": value,
+        }),
+    }]
+}
+
+// Clones are sent as the full node, so a delta never depends on a value the
+// client might not have
+fn clone_node(state: &Value, nodes: &str, id: usize, uid: NodeUid) -> Vec<Op> {
+    let Some(nodes_array) = state[nodes].as_array() else {
+        return vec![];
+    };
+    match nodes_array.get(id) {
+        Some(node) => {
+            let mut node = node.clone();
+            node["uid"] = uid.into();
+            vec![Op::Insert {
+                path: format!("/{nodes}"),
+                index: nodes_array.len(),
+                value: node,
+            }]
+        }
+        None => vec![],
+    }
+}
+
+pub fn has_unique_uids<'a>(nodes: impl IntoIterator<Item = &'a CachedNode>) -> bool {
+    let mut uids: Vec<NodeUid> = nodes.into_iter().map(|node| node.uid).collect();
+    let len = uids.len();
+    uids.sort_unstable();
+    uids.dedup();
+    uids.len() == len
+}
+
+fn node_index(state: &Value, nodes: &str, uid: NodeUid) -> Option<usize> {
+    state[nodes]
+        .as_array()?
+        .iter()
+        .position(|node| node["uid"] == uid)
+}
+
+fn node_updates(nodes: &str, node_id: usize, updates: &[JsonFieldUpdate]) -> Vec<Op> {
+    updates
+        .iter()
+        .map(|(field, value)| {
+            set(
+                &format!("/{nodes}/{node_id}/instance/{}", escape(field)),
+                value.clone(),
+            )
+        })
+        .collect()
+}
+
+fn array_mut<'a>(state: &'a mut Value, path: &str) -> Option<&'a mut Vec<Value>> {
+    state.pointer_mut(path).and_then(Value::as_array_mut)
+}
+
+fn escape(field: &str) -> String {
+    field.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape(field: &str) -> String {
+    field.replace("~1", "/").replace("~0", "~")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{
+        auth::Role, outbox::Drained, presence::DeviceKind, Client, ServerMessage,
+    };
+    use axum::extract::ws::Message;
+    use std::net::SocketAddr;
+
+    fn setup() -> (Cache, Client) {
+        let mut clients = Clients::new(4096);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+        let client = clients.connect(addr, None, Role::Admin, DeviceKind::Desktop);
+        (Cache::new(clients), client)
+    }
+
+    fn received_deltas(client: &Client) -> Vec<Delta> {
+        let Drained::Messages(msgs) = client.drain() else {
+            panic!("The client lagged");
+        };
+        msgs.into_iter()
+            .filter_map(|msg| match msg {
+                Message::Text(text) => serde_json::from_str::<ServerMessage>(&text).ok(),
+                _ => None,
+            })
+            .filter_map(|msg| match msg.payload {
+                ServerMessageKind::Delta(delta) => Some(delta),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn replay(mut state: Value, from: u64, deltas: &[Delta]) -> Value {
+        for (revision, delta) in (from + 1..).zip(deltas) {
+            assert_eq!(delta.revision, revision);
+            for op in &delta.ops {
+                assert!(op.apply(&mut state));
+            }
+        }
+        state
+    }
+
+    async fn mutate(cache: &mut Cache) {
+        let node = json!({ "name": "Piano", "gain": 1.0 });
+        cache
+            .set_renderer(json!({ "global_transposition": 0 }))
+            .await;
+        cache.add_render_node("OxiSynth", &node).await;
+        cache.add_render_node("RustySynth", &node).await;
+        cache.clone_render_node(0).await;
+        cache.clone_render_node(3).await;
+        cache.move_render_node(2, 0).await;
+        cache
+            .render_node_updates(1, &[("gain".into(), json!(0.5)), ("a/b".into(), json!(1))])
+            .await;
+        cache.remove_render_node(7).await;
+        cache.remove_render_node(0).await;
+        cache.set_renderer_global_transposition(-2).await;
+        cache.set_renderer_master_tuning(442.0, 3.0).await;
+        cache.set_controller(json!({ "enabled": false })).await;
+        cache.set_controller_enabled(true).await;
+        cache.set_controller_tempo_bpm(96.0).await;
+        cache.add_control_node("Looper", &node).await;
+        cache.add_control_node("Arpeggiator", &node).await;
+        cache.move_control_node(1, 0).await;
+        cache.clone_control_node(2).await;
+        cache
+            .control_node_updates(0, &[("name".into(), json!("Arp"))])
+            .await;
+        cache.set_scenes(json!({ "scenes": [] })).await;
+    }
+
+    #[tokio::test]
+    async fn deltas_reproduce_state() {
+        let (mut cache, client) = setup();
+        let initial = cache.to_json().await;
+        mutate(&mut cache).await;
+
+        let state = cache.to_json().await;
+        assert_eq!(replay(initial, 0, &received_deltas(&client)), state);
+        assert_eq!(state["render_nodes"].as_array().unwrap().len(), 2);
+        assert_eq!(state["control_nodes"][0]["kind"], "Arpeggiator");
+        assert_eq!(state["control_nodes"][0]["instance"]["name"], "Arp");
+        assert_eq!(state["render_nodes"][0]["instance"]["a/b"], 1);
+    }
+
+    #[tokio::test]
+    async fn node_uids() {
+        let (mut cache, _client) = setup();
+        let node = json!({ "name": "Piano" });
+        cache.add_render_node("OxiSynth", &node).await;
+        cache.add_render_node("RustySynth", &node).await;
+        cache.clone_render_node(0).await;
+        cache.add_control_node("Looper", &node).await;
+
+        let state = cache.to_json().await;
+        let uid = |nodes: &str, id: usize| state[nodes][id]["uid"].as_u64().unwrap();
+        assert_ne!(uid("render_nodes", 0), uid("render_nodes", 2));
+        assert_ne!(uid("render_nodes", 1), uid("control_nodes", 0));
+
+        cache.move_render_node(2, 0).await;
+        cache.remove_render_node(1).await;
+        let clone = uid("render_nodes", 2);
+        assert_eq!(cache.render_node_index(clone).await, Some(0));
+        assert_eq!(cache.render_node_index(uid("render_nodes", 0)).await, None);
+        assert_eq!(cache.render_node_index(uid("control_nodes", 0)).await, None);
+        assert_eq!(
+            cache.control_node_index(uid("control_nodes", 0)).await,
+            Some(0)
+        );
+
+        // New nodes never get the uid of a restored one
+        cache.restore_control_node("Looper", &node, 10).await;
+        cache.restore_render_node("OxiSynth", &node, 7).await;
+        cache.add_render_node("OxiSynth", &node).await;
+        let state = cache.to_json().await;
+        assert_eq!(state["control_nodes"][1]["uid"], 10);
+        assert_eq!(state["render_nodes"][2]["uid"], 7);
+        assert_eq!(state["render_nodes"][3]["uid"], 11);
+    }
+
+    #[tokio::test]
+    async fn changes_since() {
+        let (mut cache, _client) = setup();
+        cache.set_setlist(json!({ "name": "Gig" })).await;
+        let ServerMessageKind::Cache { revision, state } = cache.snapshot().await else {
+            panic!("Expected a snapshot");
+        };
+        mutate(&mut cache).await;
+
+        let ServerMessageKind::Changes(changes) = cache.changes_since(revision).await else {
+            panic!("Expected changes");
+        };
+        assert_eq!(replay(state, revision, &changes), cache.to_json().await);
+        assert!(matches!(
+            cache.changes_since(1000).await,
+            ServerMessageKind::Cache { .. }
+        ));
+
+        for tempo in 0..HISTORY_LEN {
+            cache.set_controller_tempo_bpm(tempo as f32).await;
+        }
+        assert!(matches!(
+            cache.changes_since(revision).await,
+            ServerMessageKind::Cache { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn attribution() {
+        let (mut cache, client) = setup();
+        let mut other = cache.clone();
+        cache.set_author(Some(1));
+        cache.set_controller_enabled(true).await;
+        other.set_controller_enabled(false).await;
+        cache.set_author(None);
+        cache.set_controller_tempo_bpm(120.0).await;
+
+        let authors: Vec<_> = received_deltas(&client)
+            .iter()
+            .map(|delta| delta.author)
+            .collect();
+        assert_eq!(authors, vec![Some(1), None, None]);
+    }
+
+    #[tokio::test]
+    async fn locks_follow_the_nodes() {
+        let (mut cache, client) = setup();
+        let node = json!({ "name": "Piano" });
+        for _ in 0..3 {
+            cache.add_render_node("OxiSynth", &node).await;
+        }
+        let mut clients = cache.clients.clone();
+        let id = client.info.id;
+        clients.lock(id, Node::RenderNode(2)).await.unwrap();
+
+        cache.move_render_node(2, 0).await;
+        assert!(clients.check_nodes(None, &[Node::RenderNode(0)]).is_err());
+        cache.remove_render_node(1).await;
+        assert!(clients.check_nodes(None, &[Node::RenderNode(0)]).is_err());
+        cache.remove_render_node(0).await;
+        assert!(clients.check_nodes(None, &[Node::RenderNode(0)]).is_ok());
+    }
+}
@@ -0,0 +1,653 @@
+use crate::{
+    control::controller,
+    error::{Error, ErrorCode},
+    files::{DirEntry, Files},
+    library::{self, Library},
+    mapping,
+    midi::{self, MidiReader},
+    render::renderer,
+    scene, setlist,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, Query, State, WebSocketUpgrade,
+    },
+    http::{header, HeaderValue, Method},
+    middleware,
+    response::IntoResponse,
+    routing::get,
+    Extension,
+};
+use axum_embed::ServeEmbed;
+use axum_extra::{headers, TypedHeader};
+pub use cache::Cache;
+use futures::{stream::SplitSink, Future, SinkExt, StreamExt};
+use outbox::{Drained, Outbox, Outgoing, Topic};
+use presence::{ClientId, ClientInfo, DeviceKind, Locks, Node, Reorder};
+use rust_embed::Embed;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{Mutex, Notify, RwLock};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info, warn};
+
+pub mod api;
+pub mod auth;
+pub mod cache;
+pub mod outbox;
+pub mod presence;
+pub mod protocol;
+
+#[derive(Embed, Clone)]
+#[folder = "client/build/"]
+struct WebClientAssets;
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectParams {
+    // Protocol version the client was built against
+    protocol: Option<u32>,
+    // Shown to the other clients, defaults to a numbered name
+    name: Option<String>,
+}
+
+// Where a request comes from, HTTP API requests have no client
+#[derive(Debug, Clone, Copy)]
+pub struct Origin {
+    pub addr: SocketAddr,
+    pub client: Option<ClientId>,
+}
+
+#[derive(Clone)]
+pub struct SharedState {
+    pub clients: Clients,
+    pub midi_reader: Arc<Mutex<MidiReader>>,
+    pub cache: Cache,
+    pub auth: auth::Auth,
+    pub files: Files,
+    pub library: Library,
+}
+
+// The web client is served from here, other origins (like a client dev
+// server) have to be allowed explicitly
+pub async fn run<F, Fut>(
+    addr: SocketAddr,
+    allowed_origins: Vec<HeaderValue>,
+    state: SharedState,
+    req_handler: F,
+) where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let cors = CorsLayer::new()
+        .allow_methods(vec![
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::HEAD,
+            Method::OPTIONS,
+        ])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        .allow_origin(AllowOrigin::list(allowed_origins));
+
+    let wc_assets = ServeEmbed::<WebClientAssets>::new();
+    let auth = state.auth.clone();
+
+    let app = axum::Router::new()
+        .fallback_service(wc_assets)
+        .route("/ws", get(ws_handler))
+        .nest("/api", api::routes())
+        .merge(auth::routes(auth.clone()))
+        .layer(middleware::from_fn_with_state(auth, auth::require_role))
+        .layer(cors)
+        // .layer(
+        //     TraceLayer::new_for_http()
+        //         .make_span_with(DefaultMakeSpan::default().include_headers(true)),
+        // )
+        .with_state((state, req_handler));
+    // .route("/", get(|| async { "Hello, World!" }))
+
+    info!("Starting server on http://{addr}/");
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+async fn ws_handler<F, Fut>(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<ConnectParams>,
+    Extension(role): Extension<auth::Role>,
+    State((state, req_handler)): State<(SharedState, F)>,
+) -> impl IntoResponse
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let device = user_agent.map_or(DeviceKind::Unknown, |TypedHeader(user_agent)| {
+        DeviceKind::from_user_agent(user_agent.as_str())
+    });
+    info!(
+        "New connection from {addr}. (clients connected: {})",
+        state.clients.len() + 1
+    );
+    if let Some(version) = params.protocol.filter(|v| !protocol::is_compatible(*v)) {
+        warn!("Client at {addr} speaks protocol version {version}, refusing it");
+        return ws.on_upgrade(move |socket| refuse_socket(socket, version));
+    }
+    let identity = (params.name, role, device);
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, identity, state, req_handler))
+}
+
+async fn refuse_socket(socket: WebSocket, version: u32) {
+    let (mut tx, _) = socket.split();
+    let reason = format!(
+        "Protocol version {version} is not supported, the server speaks version {}",
+        protocol::VERSION
+    );
+    send_broadcast(
+        &mut tx,
+        ServerMessageKind::Error(Error::new(ErrorCode::Unsupported, reason)),
+    )
+    .await;
+    tx.close().await.ok();
+}
+
+async fn handle_socket<F, Fut>(
+    socket: WebSocket,
+    addr: SocketAddr,
+    (name, role, device): (Option<String>, auth::Role, DeviceKind),
+    state: SharedState,
+    mut req_handler: F,
+) where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let (tx, mut rx) = socket.split();
+    let mut clients = state.clients;
+    let midi_reader = state.midi_reader;
+    let cache = state.cache;
+    let client = clients.connect(addr, name, role, device);
+    let id = client.info.id;
+    let origin = Origin {
+        addr,
+        client: Some(id),
+    };
+    let subscriber = client.clone();
+    let mut session_clients = clients.clone();
+    let changes_cache = cache.clone();
+    let resync_clients = clients.clone();
+    let resync_reader = Arc::clone(&midi_reader);
+    let tx = Arc::new(Mutex::new(tx));
+    let tx2 = Arc::clone(&tx);
+
+    send_broadcast(
+        &mut *tx.lock().await,
+        ServerMessageKind::Hello {
+            protocol_version: protocol::VERSION,
+            client_id: id,
+        },
+    )
+    .await;
+
+    send_broadcast(
+        &mut *tx.lock().await,
+        ServerMessageKind::ConnectedMidiInputs(midi_reader.lock().await.connected_input_names()),
+    )
+    .await;
+
+    send_broadcast(
+        &mut *tx.lock().await,
+        ServerMessageKind::AvailableMidiInputs(MidiReader::get_available_ports()),
+    )
+    .await;
+
+    send_broadcast(&mut *tx.lock().await, cache.snapshot().await).await;
+
+    tokio::select! {
+        _ = async move {
+            loop {
+                client.notified().await;
+                match client.drain() {
+                    Drained::Messages(msgs) => {
+                        let mut tx = tx.lock().await;
+                        for msg in msgs {
+                            send_raw_msg(&mut tx, msg).await;
+                        }
+                    }
+                    Drained::Resync => {
+                        warn!("Client at {addr} fell behind, sending a fresh cache");
+                        client.begin_resync();
+                        // The dropped presence and port updates are sent again as well
+                        let mut resync = vec![cache.snapshot().await];
+                        if client.is_subscribed(Topic::Presence) {
+                            resync.push(resync_clients.presence());
+                        }
+                        if client.is_subscribed(Topic::MidiPorts) {
+                            let connected = resync_reader.lock().await.connected_input_names();
+                            resync.push(ServerMessageKind::ConnectedMidiInputs(connected));
+                            resync.push(ServerMessageKind::AvailableMidiInputs(
+                                MidiReader::get_available_ports(),
+                            ));
+                        }
+                        let mut tx = tx.lock().await;
+                        for msg in resync {
+                            if !send_broadcast(&mut tx, msg).await {
+                                client.lag();
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        } => {},
+        _ = async move {
+            while let Some(Ok(msg)) = rx.next().await {
+                match msg {
+                    Message::Text(msg) => {
+                        if let Ok(msg) = serde_json::from_str::<ClientMessage>(&msg) {
+                            let payload = match msg.payload {
+                                req if !auth::permits(role, &req) => ServerMessageKind::Denied,
+                                ClientMessageKind::Subscribe(topics) => {
+                                    subscriber.subscribe(topics);
+                                    ServerMessageKind::Ack
+                                }
+                                ClientMessageKind::GetChanges { since } => {
+                                    changes_cache.changes_since(since).await
+                                }
+                                ClientMessageKind::SetName(name) => {
+                                    session_clients.rename(id, name);
+                                    ServerMessageKind::Ack
+                                }
+                                ClientMessageKind::Lock(node) if !changes_cache.has_node(node).await => {
+                                    ServerMessageKind::Error(Error::new(
+                                        ErrorCode::NotFound,
+                                        format!("No such node: {node:?}"),
+                                    ))
+                                }
+                                ClientMessageKind::Lock(node) => {
+                                    ack_or_error(session_clients.lock(id, node).await)
+                                }
+                                ClientMessageKind::Unlock(node) => {
+                                    ack_or_error(session_clients.unlock(id, node).await)
+                                }
+                                req => {
+                                    dispatch(&mut session_clients, &mut req_handler, origin, req)
+                                        .await
+                                }
+                            };
+                            send_msg(&mut *tx2.lock().await, ServerMessage {
+                                id: msg.id,
+                                response: true,
+                                payload,
+                            }).await;
+                        } else {
+                            warn!("Invalid message from {addr}: {msg}");
+                        }
+                    }
+                    Message::Close(_) => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        } => {},
+    };
+
+    clients.remove(id);
+    info!(
+        "Client at {addr} disconnected. (clients connected: {})",
+        clients.len()
+    );
+}
+
+// Runs a request through the handler unless it edits a node locked by another
+// client. The locks follow the nodes when the cache reorders them.
+pub async fn dispatch<F, Fut>(
+    clients: &mut Clients,
+    req_handler: &mut F,
+    origin: Origin,
+    req: ClientMessageKind,
+) -> ServerMessageKind
+where
+    F: FnMut(Origin, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    // Only requests which reorder the nodes have to wait for each other, the
+    // guard is dropped before the request is handled
+    {
+        let edits = Arc::clone(&clients.edits);
+        let (_reading, _writing) = match Reorder::of(&req) {
+            Some(_) => (None, Some(edits.write_owned().await)),
+            None => (Some(edits.read_owned().await), None),
+        };
+        if let Err(e) = clients.check_lock(origin.client, &req) {
+            return ServerMessageKind::Error(e);
+        }
+    }
+    req_handler(origin, req).await
+}
+
+fn ack_or_error(res: Result<(), Error>) -> ServerMessageKind {
+    match res {
+        Ok(()) => ServerMessageKind::Ack,
+        Err(e) => ServerMessageKind::Error(e),
+    }
+}
+
+// Can be cloned, all clones share the outbox
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub addr: SocketAddr,
+    pub info: ClientInfo,
+    outbox: Arc<std::sync::Mutex<Outbox>>,
+    notify: Arc<Notify>,
+}
+
+impl Client {
+    fn push(&self, outgoing: Outgoing) {
+        if self.outbox.lock().unwrap().push(outgoing) {
+            self.notify.notify_one();
+        }
+    }
+
+    fn subscribe(&self, topics: Vec<Topic>) {
+        self.outbox.lock().unwrap().subscribe(topics);
+    }
+
+    async fn notified(&self) {
+        self.notify.notified().await;
+    }
+
+    fn drain(&self) -> Drained {
+        self.outbox.lock().unwrap().drain()
+    }
+
+    fn is_subscribed(&self, topic: Topic) -> bool {
+        self.outbox.lock().unwrap().is_subscribed(topic)
+    }
+
+    fn begin_resync(&self) {
+        self.outbox.lock().unwrap().begin_resync()
+    }
+
+    fn lag(&self) {
+        self.outbox.lock().unwrap().lag()
+    }
+}
+
+// Returns whether the message was sent
+pub async fn send_raw_msg(tx: &mut SplitSink<WebSocket, Message>, msg: Message) -> bool {
+    if let Err(e) = tx.send(msg).await {
+        error!("Send error: {e}");
+        return false;
+    }
+    true
+}
+
+pub async fn send_msg(tx: &mut SplitSink<WebSocket, Message>, msg: ServerMessage) -> bool {
+    let msg = serde_json::to_string(&msg).expect("Failed to serialize server message");
+    let msg = Message::Text(msg);
+    send_raw_msg(tx, msg).await
+}
+
+pub async fn send_broadcast(
+    tx: &mut SplitSink<WebSocket, Message>,
+    msg: ServerMessageKind,
+) -> bool {
+    let msg = ServerMessage {
+        id: 0,
+        response: false,
+        payload: msg,
+    };
+    send_msg(tx, msg).await
+}
+
+#[derive(Debug, Clone)]
+pub struct Clients {
+    // thread safe struct of Clients, can be cloned
+    // locked from sync code, never held across an await
+    clients: Arc<std::sync::Mutex<Vec<Client>>>,
+    locks: Arc<std::sync::Mutex<Locks>>,
+    // Taken by dispatch and by lock changes, see there
+    edits: Arc<RwLock<()>>,
+    next_id: Arc<AtomicU64>,
+    outbox_capacity: usize,
+}
+
+impl Clients {
+    pub fn new(outbox_capacity: usize) -> Self {
+        Self {
+            clients: Default::default(),
+            locks: Default::default(),
+            edits: Default::default(),
+            next_id: Arc::new(AtomicU64::new(1)),
+            outbox_capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let clients = self.clients.lock().unwrap();
+        clients.len()
+    }
+
+    pub fn connect(
+        &mut self,
+        addr: SocketAddr,
+        name: Option<String>,
+        role: auth::Role,
+        device: DeviceKind,
+    ) -> Client {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let client = Client {
+            addr,
+            info: ClientInfo {
+                id,
+                name: name.unwrap_or_else(|| format!("Client {id}")),
+                role,
+                device,
+            },
+            outbox: Arc::new(std::sync::Mutex::new(Outbox::new(self.outbox_capacity))),
+            notify: Default::default(),
+        };
+        self.clients.lock().unwrap().push(client.clone());
+        self.broadcast_presence();
+        client
+    }
+
+    // The locks of the client are released
+    pub fn remove(&mut self, id: ClientId) {
+        self.clients.lock().unwrap().retain(|c| c.info.id != id);
+        self.locks.lock().unwrap().release_all(id);
+        self.broadcast_presence();
+    }
+
+    pub fn rename(&mut self, id: ClientId, name: String) {
+        if let Some(client) = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|c| c.info.id == id)
+        {
+            client.info.name = name;
+        }
+        self.broadcast_presence();
+    }
+
+    pub async fn lock(&mut self, id: ClientId, node: Node) -> Result<(), Error> {
+        let _edits = Arc::clone(&self.edits).write_owned().await;
+        self.locks.lock().unwrap().lock(node, id)?;
+        self.broadcast_presence();
+        Ok(())
+    }
+
+    pub async fn unlock(&mut self, id: ClientId, node: Node) -> Result<(), Error> {
+        let _edits = Arc::clone(&self.edits).write_owned().await;
+        self.locks.lock().unwrap().unlock(node, id)?;
+        self.broadcast_presence();
+        Ok(())
+    }
+
+    pub fn check_lock(
+        &self,
+        client: Option<ClientId>,
+        req: &ClientMessageKind,
+    ) -> Result<(), Error> {
+        self.locks.lock().unwrap().check(client, req)
+    }
+
+    pub fn check_nodes(&self, client: Option<ClientId>, nodes: &[Node]) -> Result<(), Error> {
+        self.locks.lock().unwrap().check_nodes(client, nodes)
+    }
+
+    // Called by the cache when the node order changed
+    pub fn reorder(&mut self, reorder: Reorder) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.is_empty() {
+            return;
+        }
+        locks.reorder(reorder);
+        drop(locks);
+        self.broadcast_presence();
+    }
+
+    pub fn presence(&self) -> ServerMessageKind {
+        let clients = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.info.clone())
+            .collect();
+        ServerMessageKind::Presence {
+            clients,
+            locks: self.locks.lock().unwrap().list(),
+        }
+    }
+
+    fn broadcast_presence(&mut self) {
+        let presence = self.presence();
+        self.broadcast(presence);
+    }
+
+    pub fn broadcast(&mut self, payload: ServerMessageKind) {
+        let clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return;
+        }
+
+        let msg = ServerMessage {
+            id: 0,
+            response: false,
+            payload,
+        };
+        let text = serde_json::to_string(&msg).expect("Failed to serialize server message");
+        let outgoing = Outgoing::new(&msg.payload, Message::Text(text));
+        for client in clients.iter() {
+            client.push(outgoing.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub enum ServerMessageKind {
+    Pong,
+    // First message on every connection
+    Hello {
+        protocol_version: u32,
+        client_id: ClientId,
+    },
+    Ack,
+    Error(Error),
+    // the role of the client does not allow the request
+    Denied,
+    Log(String),
+    MidiEvent(midi::Message),
+    AvailableMidiInputs(Vec<String>),
+    ConnectedMidiInputs(Vec<Option<String>>),
+    // Full state, deltas up to the revision are included
+    Cache {
+        revision: u64,
+        state: serde_json::Value,
+    },
+    Delta(cache::Delta),
+    Changes(Vec<cache::Delta>),
+    // Connected clients and the nodes they locked
+    Presence {
+        clients: Vec<ClientInfo>,
+        locks: Vec<(Node, ClientId)>,
+    },
+    RendererResponse(renderer::ResponseKind),
+    RendererUpdate(renderer::UpdateKind),
+    ControllerResponse(controller::ResponseKind),
+    ControllerUpdate(controller::UpdateKind),
+    SceneResponse(scene::ResponseKind),
+    SceneUpdate(scene::UpdateKind),
+    SetlistResponse(setlist::ResponseKind),
+    SetlistUpdate(setlist::UpdateKind),
+    MappingResponse(mapping::ResponseKind),
+    MappingUpdate(mapping::UpdateKind),
+    LibraryResponse(library::ResponseKind),
+    LibraryUpdate(library::UpdateKind),
+    DirInfo(Vec<DirEntry>),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ServerMessage {
+    id: usize,
+    response: bool,
+    payload: ServerMessageKind,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub enum ClientMessageKind {
+    Ping,
+    Report(String),
+    ConnectMidiInput(usize, String),
+    DisconnectMidiInput(usize),
+    RendererRequest(renderer::RequestKind),
+    ControllerRequest(controller::RequestKind),
+    SceneRequest(scene::RequestKind),
+    SetlistRequest(setlist::RequestKind),
+    MappingRequest(mapping::RequestKind),
+    LibraryRequest(library::RequestKind),
+    // Replaces the topics of the broadcasts sent to this connection
+    Subscribe(Vec<Topic>),
+    // Deltas after the revision, or a snapshot when they are not kept anymore
+    GetChanges { since: u64 },
+    // Name shown to the other clients
+    SetName(String),
+    // Keeps other clients from editing the node until it is unlocked or the
+    // connection closes
+    Lock(Node),
+    Unlock(Node),
+    ReadDir(PathBuf),
+    MakeDir(PathBuf),
+    DeleteFile(PathBuf),
+    RenameFile(PathBuf, PathBuf),
+    CopyFile(PathBuf, PathBuf),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ClientMessage {
+    id: usize,
+    request: bool,
+    payload: ClientMessageKind,
+}
@@ -0,0 +1,294 @@
+use super::ServerMessageKind;
+use crate::{control::controller, midi};
+use axum::extract::ws::Message;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum Topic {
+    // Every incoming MIDI message
+    Midi,
+    // Available and connected MIDI ports
+    MidiPorts,
+    // Beat position of the running controller
+    Beat,
+    // Updates of everything held in the cache
+    State,
+    // Connected clients and node locks
+    Presence,
+    Log,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 6] = [
+        Topic::Midi,
+        Topic::MidiPorts,
+        Topic::Beat,
+        Topic::State,
+        Topic::Presence,
+        Topic::Log,
+    ];
+
+    pub fn of(payload: &ServerMessageKind) -> Self {
+        match payload {
+            ServerMessageKind::MidiEvent(_) => Topic::Midi,
+            ServerMessageKind::AvailableMidiInputs(_)
+            | ServerMessageKind::ConnectedMidiInputs(_) => Topic::MidiPorts,
+            ServerMessageKind::ControllerUpdate(controller::UpdateKind::BeatState { .. }) => {
+                Topic::Beat
+            }
+            ServerMessageKind::Presence { .. } => Topic::Presence,
+            ServerMessageKind::Log(_) => Topic::Log,
+            _ => Topic::State,
+        }
+    }
+}
+
+// A queued message with the same key is dropped for the latest one, only
+// values where the latest state is all that matters are coalesced
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CoalesceKey {
+    AvailableMidiInputs,
+    BeatState,
+    ControlChange(u8, midi::ControlChangeKind),
+    PitchWheel(u8),
+    ChannelAftertouch(u8),
+    PolyphonicAftertouch(u8, u8),
+}
+
+impl CoalesceKey {
+    fn of(payload: &ServerMessageKind) -> Option<Self> {
+        match payload {
+            ServerMessageKind::AvailableMidiInputs(_) => Some(CoalesceKey::AvailableMidiInputs),
+            ServerMessageKind::ControllerUpdate(controller::UpdateKind::BeatState { .. }) => {
+                Some(CoalesceKey::BeatState)
+            }
+            ServerMessageKind::MidiEvent(message) => {
+                let channel = message.channel;
+                match message.kind {
+                    midi::MessageKind::ControlChange { kind, .. } => {
+                        Some(CoalesceKey::ControlChange(channel, kind))
+                    }
+                    midi::MessageKind::PitchWheel { .. } => Some(CoalesceKey::PitchWheel(channel)),
+                    midi::MessageKind::ChannelAftertouch { .. } => {
+                        Some(CoalesceKey::ChannelAftertouch(channel))
+                    }
+                    midi::MessageKind::PolyphonicAftertouch { note, .. } => {
+                        Some(CoalesceKey::PolyphonicAftertouch(channel, note))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+// A broadcast serialized once and shared by all outboxes
+#[derive(Debug, Clone)]
+pub struct Outgoing {
+    topic: Topic,
+    key: Option<CoalesceKey>,
+    msg: Message,
+}
+
+impl Outgoing {
+    pub fn new(payload: &ServerMessageKind, msg: Message) -> Self {
+        Self {
+            topic: Topic::of(payload),
+            key: CoalesceKey::of(payload),
+            msg,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Drained {
+    Messages(Vec<Message>),
+    // The client fell behind and has to be sent a fresh cache snapshot,
+    // returned until begin_resync is called
+    Resync,
+}
+
+// Bounded queue of the broadcasts a single client has not been sent yet
+#[derive(Debug)]
+pub struct Outbox {
+    queue: VecDeque<Outgoing>,
+    capacity: usize,
+    topics: Vec<Topic>,
+    lagged: bool,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            topics: Topic::ALL.to_vec(),
+            lagged: false,
+        }
+    }
+
+    pub fn subscribe(&mut self, topics: Vec<Topic>) {
+        self.queue
+            .retain(|outgoing| topics.contains(&outgoing.topic));
+        self.topics = topics;
+    }
+
+    pub fn is_subscribed(&self, topic: Topic) -> bool {
+        self.topics.contains(&topic)
+    }
+
+    // Returns whether the client has to be woken up
+    pub fn push(&mut self, outgoing: Outgoing) -> bool {
+        if !self.is_subscribed(outgoing.topic) {
+            return false;
+        }
+        if self.lagged {
+            // A resync is pending, the snapshot will have the change
+            return true;
+        }
+        // The latest value goes behind the messages sent before it
+        if let Some(key) = outgoing.key {
+            if let Some(pos) = self.queue.iter().position(|queued| queued.key == Some(key)) {
+                self.queue.remove(pos);
+            }
+        }
+        if self.queue.len() >= self.capacity {
+            // Missed MIDI events are not part of the state, they are dropped
+            // first, anything else needs a snapshot
+            let midi = self
+                .queue
+                .iter()
+                .position(|queued| queued.topic == Topic::Midi);
+            if let Some(pos) = midi {
+                self.queue.remove(pos);
+            } else if outgoing.topic == Topic::Midi {
+                return false;
+            } else {
+                self.lag();
+                return true;
+            }
+        }
+        self.queue.push_back(outgoing);
+        true
+    }
+
+    pub fn drain(&mut self) -> Drained {
+        if self.lagged {
+            Drained::Resync
+        } else {
+            Drained::Messages(self.queue.drain(..).map(|outgoing| outgoing.msg).collect())
+        }
+    }
+
+    // Called before the snapshot is taken, later messages are queued again
+    pub fn begin_resync(&mut self) {
+        self.lagged = false;
+    }
+
+    // Everything queued is superseded by the snapshot
+    pub fn lag(&mut self) {
+        self.queue.clear();
+        self.lagged = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outgoing(payload: ServerMessageKind) -> Outgoing {
+        let msg = Message::Text(serde_json::to_string(&payload).unwrap());
+        Outgoing::new(&payload, msg)
+    }
+
+    fn control_change(value: u8) -> ServerMessageKind {
+        ServerMessageKind::MidiEvent(midi::Message {
+            kind: midi::MessageKind::ControlChange {
+                kind: midi::ControlChangeKind::ModulationWheelMsb,
+                value,
+            },
+            channel: 0,
+        })
+    }
+
+    fn note_on(note: u8) -> ServerMessageKind {
+        ServerMessageKind::MidiEvent(midi::Message {
+            kind: midi::MessageKind::NoteOn {
+                note,
+                velocity: 100,
+            },
+            channel: 0,
+        })
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut outbox = Outbox::new(8);
+        outbox.push(outgoing(control_change(1)));
+        outbox.push(outgoing(note_on(60)));
+        outbox.push(outgoing(control_change(2)));
+        outbox.push(outgoing(note_on(60)));
+        assert_eq!(
+            outbox.drain(),
+            Drained::Messages(vec![
+                outgoing(note_on(60)).msg,
+                outgoing(control_change(2)).msg,
+                outgoing(note_on(60)).msg,
+            ])
+        );
+        assert_eq!(outbox.drain(), Drained::Messages(vec![]));
+    }
+
+    #[test]
+    fn topics() {
+        let mut outbox = Outbox::new(8);
+        outbox.push(outgoing(note_on(60)));
+        outbox.subscribe(vec![Topic::State]);
+        assert!(!outbox.is_subscribed(Topic::Presence));
+        assert!(!outbox.push(outgoing(note_on(62))));
+        assert!(outbox.push(outgoing(ServerMessageKind::Ack)));
+        assert_eq!(
+            outbox.drain(),
+            Drained::Messages(vec![outgoing(ServerMessageKind::Ack).msg])
+        );
+    }
+
+    #[test]
+    fn overflow_drops_midi() {
+        let mut outbox = Outbox::new(2);
+        for note in 0..3 {
+            assert!(outbox.push(outgoing(note_on(note))));
+        }
+        assert!(outbox.push(outgoing(ServerMessageKind::Ack)));
+        assert_eq!(
+            outbox.drain(),
+            Drained::Messages(vec![
+                outgoing(note_on(2)).msg,
+                outgoing(ServerMessageKind::Ack).msg,
+            ])
+        );
+    }
+
+    #[test]
+    fn resync_after_overflow() {
+        let mut outbox = Outbox::new(2);
+        for _ in 0..2 {
+            assert!(outbox.push(outgoing(ServerMessageKind::Ack)));
+        }
+        assert!(!outbox.push(outgoing(note_on(0))));
+        assert!(outbox.push(outgoing(ServerMessageKind::Ack)));
+        assert!(outbox.push(outgoing(note_on(1))));
+        assert_eq!(outbox.drain(), Drained::Resync);
+        // Until the snapshot is on its way
+        assert_eq!(outbox.drain(), Drained::Resync);
+        outbox.begin_resync();
+        outbox.push(outgoing(note_on(5)));
+        assert_eq!(
+            outbox.drain(),
+            Drained::Messages(vec![outgoing(note_on(5)).msg])
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use super::{auth::Role, ClientMessageKind};
+use crate::{
+    control::controller,
+    error::{Error, ErrorCode},
+    render::renderer,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub type ClientId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum DeviceKind {
+    Phone,
+    Tablet,
+    Desktop,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    pub name: String,
+    pub role: Role,
+    pub device: DeviceKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum Node {
+    RenderNode(usize),
+    ControlNode(usize),
+}
+
+// Change of the node order made by a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reorder {
+    Remove(Node),
+    Move(Node, usize),
+    // A session replaced all nodes
+    Replace,
+}
+
+// Nodes edited exclusively by one client, ids follow the nodes when they are
+// removed or moved
+#[derive(Debug, Default)]
+pub struct Locks {
+    locks: Vec<(Node, ClientId)>,
+}
+
+impl DeviceKind {
+    pub fn from_user_agent(user_agent: &str) -> Self {
+        let user_agent = user_agent.to_lowercase();
+        if user_agent.contains("ipad") || user_agent.contains("tablet") {
+            DeviceKind::Tablet
+        } else if user_agent.contains("mobile") || user_agent.contains("iphone") {
+            DeviceKind::Phone
+        } else if user_agent.contains("android") {
+            // Android tablets do not announce themselves as mobile
+            DeviceKind::Tablet
+        } else if user_agent.contains("windows")
+            || user_agent.contains("macintosh")
+            || user_agent.contains("linux")
+        {
+            DeviceKind::Desktop
+        } else {
+            DeviceKind::Unknown
+        }
+    }
+}
+
+impl Node {
+    // The nodes a request edits, adding nodes edits none
+    pub fn edited_by(req: &ClientMessageKind) -> Vec<Self> {
+        match req {
+            ClientMessageKind::RendererRequest(
+                renderer::RequestKind::RemoveNode { id }
+                | renderer::RequestKind::MoveNode { id, .. },
+            ) => vec![Node::RenderNode(*id)],
+            ClientMessageKind::RendererRequest(kind) => renderer::edited_nodes(kind)
+                .into_iter()
+                .map(Node::RenderNode)
+                .collect(),
+            ClientMessageKind::ControllerRequest(
+                controller::RequestKind::RemoveNode { id }
+                | controller::RequestKind::MoveNode { id, .. },
+            ) => vec![Node::ControlNode(*id)],
+            ClientMessageKind::ControllerRequest(kind) => controller::edited_nodes(kind)
+                .into_iter()
+                .map(Node::ControlNode)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn with_id(self, id: usize) -> Self {
+        match self {
+            Node::RenderNode(_) => Node::RenderNode(id),
+            Node::ControlNode(_) => Node::ControlNode(id),
+        }
+    }
+
+    fn id(self) -> usize {
+        match self {
+            Node::RenderNode(id) | Node::ControlNode(id) => id,
+        }
+    }
+
+    fn same_kind(self, other: Node) -> bool {
+        std::mem::discriminant(&self) == std::mem::discriminant(&other)
+    }
+}
+
+impl Locks {
+    pub fn lock(&mut self, node: Node, client: ClientId) -> Result<(), Error> {
+        match self.holder(node) {
+            Some(holder) if holder != client => Err(locked_error(node)),
+            Some(_) => Ok(()),
+            None => {
+                self.locks.push((node, client));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn unlock(&mut self, node: Node, client: ClientId) -> Result<(), Error> {
+        match self.holder(node) {
+            Some(holder) if holder != client => Err(locked_error(node)),
+            _ => {
+                self.locks.retain(|(locked, _)| *locked != node);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn release_all(&mut self, client: ClientId) {
+        self.locks.retain(|(_, holder)| *holder != client);
+    }
+
+    pub fn holder(&self, node: Node) -> Option<ClientId> {
+        self.locks
+            .iter()
+            .find(|(locked, _)| *locked == node)
+            .map(|(_, holder)| *holder)
+    }
+
+    pub fn list(&self) -> Vec<(Node, ClientId)> {
+        self.locks.clone()
+    }
+
+    // Requests without a client, e.g. from the HTTP API, can not edit any locked node
+    pub fn check(&self, client: Option<ClientId>, req: &ClientMessageKind) -> Result<(), Error> {
+        if Reorder::of(req) == Some(Reorder::Replace) {
+            let locked: Vec<Node> = self.locks.iter().map(|(node, _)| *node).collect();
+            return self.check_nodes(client, &locked);
+        }
+        self.check_nodes(client, &Node::edited_by(req))
+    }
+
+    pub fn check_nodes(&self, client: Option<ClientId>, nodes: &[Node]) -> Result<(), Error> {
+        match nodes.iter().find(|node| {
+            self.holder(**node)
+                .is_some_and(|holder| Some(holder) != client)
+        }) {
+            Some(node) => Err(locked_error(*node)),
+            None => Ok(()),
+        }
+    }
+
+    // Keeps the locks on the same nodes after the node order changed
+    pub fn reorder(&mut self, reorder: Reorder) {
+        let (node, new_id) = match reorder {
+            Reorder::Remove(node) => (node, None),
+            Reorder::Move(node, new_id) => (node, Some(new_id)),
+            Reorder::Replace => {
+                self.locks.clear();
+                return;
+            }
+        };
+        let id = node.id();
+        self.locks
+            .retain(|(locked, _)| new_id.is_some() || *locked != node);
+        for (locked, _) in self.locks.iter_mut().filter(|(n, _)| n.same_kind(node)) {
+            let locked_id = locked.id();
+            // Index as if the node was removed, then as if it was inserted again
+            let mut shifted = if locked_id > id {
+                locked_id - 1
+            } else {
+                locked_id
+            };
+            match new_id {
+                Some(new_id) if locked_id == id => shifted = new_id,
+                Some(new_id) if shifted >= new_id => shifted += 1,
+                _ => {}
+            }
+            *locked = locked.with_id(shifted);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
+}
+
+impl Reorder {
+    pub fn of(req: &ClientMessageKind) -> Option<Self> {
+        match req {
+            ClientMessageKind::RendererRequest(renderer::RequestKind::RemoveNode { id }) => {
+                Some(Reorder::Remove(Node::RenderNode(*id)))
+            }
+            ClientMessageKind::RendererRequest(renderer::RequestKind::MoveNode { id, new_id }) => {
+                Some(Reorder::Move(Node::RenderNode(*id), *new_id))
+            }
+            ClientMessageKind::ControllerRequest(controller::RequestKind::RemoveNode { id }) => {
+                Some(Reorder::Remove(Node::ControlNode(*id)))
+            }
+            ClientMessageKind::ControllerRequest(controller::RequestKind::MoveNode {
+                id,
+                new_id,
+            }) => Some(Reorder::Move(Node::ControlNode(*id), *new_id)),
+            ClientMessageKind::RendererRequest(renderer::RequestKind::LoadNodes(..))
+            | ClientMessageKind::ControllerRequest(controller::RequestKind::LoadSession(_)) => {
+                Some(Reorder::Replace)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn locked_error(node: Node) -> Error {
+    let message = match node {
+        Node::RenderNode(id) => format!("Render node {id} is locked by another client"),
+        Node::ControlNode(id) => format!("Control node {id} is locked by another client"),
+    };
+    Error::new(ErrorCode::Locked, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remove(id: usize) -> ClientMessageKind {
+        ClientMessageKind::RendererRequest(renderer::RequestKind::RemoveNode { id })
+    }
+
+    fn reorder(locks: &mut Locks, req: ClientMessageKind) {
+        locks.reorder(Reorder::of(&req).unwrap());
+    }
+
+    fn move_node(id: usize, new_id: usize) -> ClientMessageKind {
+        ClientMessageKind::RendererRequest(renderer::RequestKind::MoveNode { id, new_id })
+    }
+
+    #[test]
+    fn device_kind() {
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148";
+        let ipad = "Mozilla/5.0 (iPad; CPU OS 17_0 like Mac OS X)";
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+        assert_eq!(DeviceKind::from_user_agent(iphone), DeviceKind::Phone);
+        assert_eq!(DeviceKind::from_user_agent(ipad), DeviceKind::Tablet);
+        assert_eq!(DeviceKind::from_user_agent(firefox), DeviceKind::Desktop);
+        assert_eq!(DeviceKind::from_user_agent("curl/8.0"), DeviceKind::Unknown);
+    }
+
+    #[test]
+    fn locks() {
+        let mut locks = Locks::default();
+        locks.lock(Node::RenderNode(1), 1).unwrap();
+        assert!(locks.lock(Node::RenderNode(1), 2).is_err());
+        assert!(locks.unlock(Node::RenderNode(1), 2).is_err());
+        assert!(locks.check(Some(1), &remove(1)).is_ok());
+        assert_eq!(
+            locks.check(Some(2), &remove(1)).unwrap_err().code,
+            ErrorCode::Locked
+        );
+        assert!(locks.check(None, &remove(1)).is_err());
+        assert!(locks.check(Some(2), &remove(0)).is_ok());
+
+        locks.release_all(1);
+        assert!(locks.check(Some(2), &remove(1)).is_ok());
+    }
+
+    #[test]
+    fn locks_follow_nodes() {
+        let mut locks = Locks::default();
+        locks.lock(Node::RenderNode(1), 1).unwrap();
+        locks.lock(Node::RenderNode(3), 2).unwrap();
+        locks.lock(Node::ControlNode(3), 2).unwrap();
+
+        reorder(&mut locks, remove(0));
+        assert_eq!(locks.holder(Node::RenderNode(0)), Some(1));
+        assert_eq!(locks.holder(Node::RenderNode(2)), Some(2));
+        assert_eq!(locks.holder(Node::ControlNode(3)), Some(2));
+
+        reorder(&mut locks, move_node(2, 0));
+        assert_eq!(locks.holder(Node::RenderNode(0)), Some(2));
+        assert_eq!(locks.holder(Node::RenderNode(1)), Some(1));
+
+        reorder(&mut locks, move_node(0, 2));
+        assert_eq!(locks.holder(Node::RenderNode(2)), Some(2));
+        assert_eq!(locks.holder(Node::RenderNode(0)), Some(1));
+
+        reorder(&mut locks, remove(2));
+        assert_eq!(
+            locks.list(),
+            vec![(Node::RenderNode(0), 1), (Node::ControlNode(3), 2)]
+        );
+
+        // A session replaces every node, only the holder of all locks may load one
+        let load = ClientMessageKind::ControllerRequest(controller::RequestKind::LoadSession(
+            "session.json".into(),
+        ));
+        assert!(locks.check(Some(1), &load).is_err());
+        locks.unlock(Node::ControlNode(3), 2).unwrap();
+        assert!(locks.check(Some(1), &load).is_ok());
+        reorder(&mut locks, load);
+        assert!(locks.is_empty());
+    }
+}
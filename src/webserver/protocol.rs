@@ -0,0 +1,230 @@
+use super::{ClientMessage, ServerMessage};
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde_json::{Map, Value};
+
+// Bump on every change of the messages that breaks existing clients
+pub const VERSION: u32 = 8;
+
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+struct Protocol {
+    client: ClientMessage,
+    server: ServerMessage,
+}
+
+pub fn is_compatible(version: u32) -> bool {
+    version == VERSION
+}
+
+pub fn schema() -> RootSchema {
+    let mut schema = schema_for!(Protocol);
+    schema.schema.metadata().title = Some("AMI websocket protocol".into());
+    schema
+        .schema
+        .extensions
+        .insert("version".into(), VERSION.into());
+    schema
+}
+
+// TypeScript definitions for every type of the schema, the web client imports
+// them from JSDoc comments
+pub fn typescript() -> String {
+    let schema = serde_json::to_value(schema()).expect("Failed to serialize schema");
+    let mut out = format!(
+        "// Generated from the Rust protocol types, do not edit.\n\
+         // Regenerate with: AMI_UPDATE_PROTOCOL=1 cargo test protocol\n\n\
+         export declare const PROTOCOL_VERSION = {VERSION};\n"
+    );
+    if let Some(definitions) = schema["definitions"].as_object() {
+        for (name, definition) in definitions {
+            out += &format!("\nexport type {name} ={};\n", top_level_type(definition));
+        }
+    }
+    out += &format!("\nexport type Protocol ={};\n", top_level_type(&schema));
+    out
+}
+
+// Enums are written with one variant per line
+fn top_level_type(schema: &Value) -> String {
+    match schema.as_object().and_then(variants) {
+        Some(variants) => variants
+            .iter()
+            .map(|variant| format!("\n    | {}", ts_type(variant)))
+            .collect(),
+        None => format!(" {}", ts_type(schema)),
+    }
+}
+
+fn variants(schema: &Map<String, Value>) -> Option<&Vec<Value>> {
+    schema
+        .get("oneOf")
+        .or_else(|| schema.get("anyOf"))
+        .and_then(Value::as_array)
+}
+
+fn ts_type(schema: &Value) -> String {
+    let Value::Object(schema) = schema else {
+        return if schema == &Value::Bool(false) {
+            "never".into()
+        } else {
+            "unknown".into()
+        };
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        return reference.trim_start_matches("#/definitions/").into();
+    }
+    if let Some(variants) = variants(schema) {
+        return union(variants.iter().map(ts_type));
+    }
+    if let Some([single]) = schema
+        .get("allOf")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+    {
+        return ts_type(single);
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        return union(values.iter().map(Value::to_string));
+    }
+    match schema.get("type") {
+        Some(Value::String(ty)) => type_name(ty, schema),
+        Some(Value::Array(types)) => union(
+            types
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|ty| type_name(ty, schema)),
+        ),
+        _ => "unknown".into(),
+    }
+}
+
+fn type_name(ty: &str, schema: &Map<String, Value>) -> String {
+    match ty {
+        "string" => "string".into(),
+        "integer" | "number" => "number".into(),
+        "boolean" => "boolean".into(),
+        "null" => "null".into(),
+        "array" => match schema.get("items") {
+            Some(Value::Array(items)) => {
+                let items: Vec<_> = items.iter().map(ts_type).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Some(item) => {
+                let item = ts_type(item);
+                if item.contains(" | ") {
+                    format!("({item})[]")
+                } else {
+                    format!("{item}[]")
+                }
+            }
+            None => "unknown[]".into(),
+        },
+        "object" => object_type(schema),
+        _ => "unknown".into(),
+    }
+}
+
+fn object_type(schema: &Map<String, Value>) -> String {
+    let required: Vec<&str> = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let mut fields: Vec<String> = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .map(|properties| {
+            properties
+                .iter()
+                .map(|(name, property)| {
+                    let optional = if required.contains(&name.as_str()) {
+                        ""
+                    } else {
+                        "?"
+                    };
+                    format!("{name}{optional}: {}", ts_type(property))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    match schema.get("additionalProperties") {
+        None | Some(Value::Bool(false)) => {}
+        Some(value) => fields.push(format!("[key: string]: {}", ts_type(value))),
+    }
+    if fields.is_empty() {
+        "Record<string, never>".into()
+    } else {
+        format!("{{ {} }}", fields.join("; "))
+    }
+}
+
+fn union(types: impl Iterator<Item = String>) -> String {
+    let mut types: Vec<String> = types.collect();
+    types.dedup();
+    types.join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // Generated files the web client is built against, relative to the crate root
+    const SCHEMA_PATH: &str = "client/src/lib/protocol.schema.json";
+    const TYPESCRIPT_PATH: &str = "client/src/lib/js/protocol.d.ts";
+
+    fn schema_json() -> String {
+        let mut json = serde_json::to_string_pretty(&schema()).unwrap();
+        json.push('\n');
+        json
+    }
+
+    #[test]
+    fn typescript_types() {
+        let variant = serde_json::json!({
+            "type": "object",
+            "required": ["ConnectMidiInput"],
+            "properties": {
+                "ConnectMidiInput": {
+                    "type": "array",
+                    "items": [{ "type": "integer" }, { "type": "string" }]
+                },
+                "path": { "type": ["string", "null"] }
+            },
+            "additionalProperties": false
+        });
+        assert_eq!(
+            ts_type(&variant),
+            "{ ConnectMidiInput: [number, string]; path?: string | null }"
+        );
+        assert_eq!(
+            ts_type(
+                &serde_json::json!({ "type": "array", "items": { "$ref": "#/definitions/Zone" } })
+            ),
+            "Zone[]"
+        );
+    }
+
+    // The client is built against the committed files, so a change of the Rust
+    // types has to be followed by regenerating them
+    #[test]
+    fn protocol_files_are_up_to_date() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let files = [
+            (root.join(SCHEMA_PATH), schema_json()),
+            (root.join(TYPESCRIPT_PATH), typescript()),
+        ];
+        for (path, generated) in files {
+            if std::env::var_os("AMI_UPDATE_PROTOCOL").is_some() {
+                std::fs::write(&path, &generated).unwrap();
+            }
+            let committed = std::fs::read_to_string(&path).unwrap_or_default();
+            assert!(
+                committed == generated,
+                "{} is outdated, run `AMI_UPDATE_PROTOCOL=1 cargo test protocol` \
+                 and bump protocol::VERSION if the change breaks clients",
+                path.display()
+            );
+        }
+    }
+}